@@ -4,8 +4,34 @@ shopt -s inherit_errexit
 # TODO
 # - Disable quoting of paths by find. See section "UNUSUAL FILENAMES" of man find(1).
 
+hash_cache=
+root=
+excludes=()
+
+while [ $# -gt 2 ]; do
+    case "$1" in
+        --hash-cache)
+            hash_cache="$(realpath -m -- "$2")"
+            shift 2
+            ;;
+        --exclude)
+            excludes+=("$2")
+            shift 2
+            ;;
+        --root)
+            root="$2"
+            shift 2
+            ;;
+        *)
+            echo "error: unknown option '$1'" >&2
+            exit 1
+            ;;
+    esac
+done
+
 subject="$1"
-out="$2"
+# Absolute, since the walk may run from another directory.
+out="$(realpath -m -- "$2")"
 
 out_subject="$out/subject.txt"
 out_sha256sum="$out/sha256sum.txt"
@@ -23,18 +49,147 @@ if [ -e "$out" ]; then
     exit 1
 fi
 
+# Physical, so that a subject given as a symbolic link to a directory is walked rather than
+# recorded as a link.
+subject_abs="$(cd "$subject" && pwd -P)"
+
+# With --root, paths are recorded relative to the root rather than to the subject, so that the
+# subject appears at its place below the root. The root itself and the directories between it and
+# the subject are recorded too, but nothing else outside the subject. The walk then starts from
+# the subject's path relative to the root, and the root is recorded in subject.txt in its place,
+# since that is what the recorded paths are relative to.
+
+recorded_subject="$subject_abs"
+start="$subject_abs"
+walk_dir="$subject_abs"
+path_format='%P'
+ancestors=()
+if [ -n "$root" ]; then
+    if [ ! -d "$root" ]; then
+        echo "error: '$root' is not a directory" >&2
+        exit 1
+    fi
+    root_abs="$(cd "$root" && pwd -P)"
+    recorded_subject="$root_abs"
+    rel="$subject_abs/"
+    case "$rel" in
+        "${root_abs%/}/"*)
+            ;;
+        *)
+            echo "error: '$subject' is not under the root '$root'" >&2
+            exit 1
+            ;;
+    esac
+    rel="${rel#"${root_abs%/}/"}"
+    rel="${rel%/}"
+    if [ -n "$rel" ]; then
+        start="$rel"
+        walk_dir="$root_abs"
+        path_format='%p'
+        IFS=/ read -r -a components <<< "$rel"
+        ancestor=
+        for component in "${components[@]}"; do
+            if [ -n "$ancestor" ]; then
+                ancestors+=("$ancestor")
+                ancestor="$ancestor/$component"
+            else
+                ancestors+=(.)
+                ancestor="$component"
+            fi
+        done
+    fi
+fi
+
 mkdir "$out"
 
-(cd "$subject" && pwd) > "$out_subject"
+echo "$recorded_subject" > "$out_subject"
+
+# Exclude patterns follow rsync: a pattern containing a slash is matched against the path relative
+# to the subject (a leading slash is optional), otherwise against the name of each entry. A trailing
+# slash matches only directories. Excluded directories are not descended into.
+
+escape_pattern() {
+    printf '%s' "$1" | sed 's/[][*?\\]/\\&/g'
+}
 
-find "$subject" -fprintf "$out_nodes" '%y %#m %s %P\0 %l\0\n' -a -type f -fprintf "$out_files" '%P\0'
+start_pattern="$(escape_pattern "$start")"
+subject_pattern="$(escape_pattern "${start%%/}")"
+
+prune=()
+for pattern in "${excludes[@]}"; do
+    test=()
+    case "$pattern" in
+        */)
+            test+=(-type d -a)
+            pattern="${pattern%/}"
+            ;;
+    esac
+    case "$pattern" in
+        */*)
+            test+=(-path "$subject_pattern/${pattern#/}")
+            ;;
+        *)
+            test+=(-name "$pattern")
+            ;;
+    esac
+    if [ ${#prune[@]} -gt 0 ]; then
+        prune+=(-o)
+    fi
+    prune+=(\( "${test[@]}" \))
+done
+if [ ${#prune[@]} -gt 0 ]; then
+    prune=(! -path "$start_pattern" \( "${prune[@]}" \) -prune -o)
+fi
 
 (
-    cd "$subject"
+    cd "$walk_dir"
+    for ancestor in "${ancestors[@]}"; do
+        if [ "$ancestor" = . ]; then
+            find . -maxdepth 0 -printf '%y %#m %s \0 %l\0\n'
+        else
+            find "$ancestor" -maxdepth 0 -printf '%y %#m %s %p\0 %l\0\n'
+        fi
+    done
+    find "$start" "${prune[@]}" \( -printf "%y %#m %s $path_format\\0 %l\\0\\n" -a -type f -fprintf "$out_files" "$path_format\\0" \)
+) > "$out_nodes"
+
+# The hash cache maps (device, inode, mtime, ctime, size) to a digest. Since ctime changes on any
+# metadata change, a hit means the file is unchanged since it was last hashed. The cache is
+# rewritten with only the files seen in this snapshot.
+
+declare -A cached=()
+
+if [ -n "$hash_cache" ]; then
+    rm -f "$hash_cache.tmp"
+    if [ -f "$hash_cache" ]; then
+        while read -r dev ino mtime ctime size digest; do
+            cached["$dev $ino $mtime $ctime $size"]="$digest"
+        done < "$hash_cache"
+    fi
+fi
+
+(
+    cd "$walk_dir"
     while IFS= read -r -d $'\0' path; do
-        sha256sum -bz "$path"
+        if [ -z "$hash_cache" ]; then
+            sha256sum -bz -- "$path"
+        else
+            key="$(stat -c '%d %i %.9Y %.9Z %s' -- "$path")"
+            digest="${cached[$key]-}"
+            if [ -z "$digest" ]; then
+                digest="$(sha256sum -bz -- "$path" | head -c 64)"
+            fi
+            echo "$key $digest" >> "$hash_cache.tmp"
+            printf '%s *%s\0' "$digest" "$path"
+        fi
         echo
     done
 ) < "$out_files" > "$out_digests"
 
-sha256sum -b "$out_nodes" "$out_digests" > "$out_sha256sum"
+if [ -n "$hash_cache" ]; then
+    touch "$hash_cache.tmp"
+    mv "$hash_cache.tmp" "$hash_cache"
+fi
+
+# Relative to the snapshot directory, which is renamed into place once complete.
+(cd "$out" && sha256sum -b nodes digests) > "$out_sha256sum"
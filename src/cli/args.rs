@@ -1,12 +1,15 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::string::ToString;
+use std::time::Duration;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Result};
 use clap::{App, Arg, ArgMatches, SubCommand};
 
-use crate::ShadowPath;
+use crate::database::parse_tag;
+use crate::{CommitDates, ContentSha256, Reflink, ShadowPath, Timestamp};
 
 const ENV_GIT_DIR: &str = "GIT_DIR";
 const ENV_SUBSTANCE_DIR: &str = "SUBSTANCE_DIR";
@@ -14,7 +17,10 @@ const ENV_SUBSTANCE_DIR: &str = "SUBSTANCE_DIR";
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Args {
     pub git_dir: Option<PathBuf>,
+    // Further values of '--git-dir', for commands which can read several repositories.
+    pub other_git_dirs: Vec<PathBuf>,
     pub substance_dir: Option<PathBuf>,
+    pub reflink: Reflink,
     pub read_only: bool,
     pub verbosity: u64,
     pub command: Command,
@@ -28,40 +34,160 @@ pub enum Command {
         force: bool,
         remove_after: bool,
         snapshot_dir: PathBuf,
+        dry_run: bool,
+        hash_cache: Option<PathBuf>,
+        exclude: Vec<String>,
+        exclude_from: Vec<PathBuf>,
+        exclude_vcs: bool,
+        dates: CommitDates,
+        staging_ref: Option<String>,
+        pre_hook: Option<String>,
+        post_hook: Option<String>,
+        tags: BTreeMap<String, String>,
+        progress_fd: Option<i32>,
+        atomic: bool,
+        remove_subject: bool,
+        no_store: bool,
+        image: Option<PathBuf>,
+        verify_plant: bool,
+        expect_subject: Option<PathBuf>,
+        min_files: Option<u64>,
+        max_files: Option<u64>,
     },
     Mount {
         mountpoint: PathBuf,
         tree: String,
         uid: u32,
         gid: u32,
+        verify: Option<bool>,
+        metrics: bool,
+        allow_other: bool,
+        allow_root: bool,
+        writable: bool,
+        resolve_links: bool,
+        attr_timeout: Option<Duration>,
+        entry_timeout: Option<Duration>,
+        missing_timeout: Option<Duration>,
+        background: bool,
+        pid_file: Option<PathBuf>,
     },
     Diff {
         tree_a: String,
         tree_b: String,
+        color: ColorWhen,
+        raw: bool,
+        each: bool,
+        ignore_mode: bool,
+        summary_only: bool,
+        path: ShadowPath,
     },
     Check {
         tree: String,
+        max_file_count: Option<u64>,
+        no_empty_dirs: bool,
+        max_errors: Option<u64>,
+        format: OutputFormat,
     },
     UniqueBlobs {
         tree: String,
+        max_file_count: Option<u64>,
+    },
+    Find {
+        tree: String,
+        hash: ContentSha256,
+        max_file_count: Option<u64>,
+    },
+    Repair {
+        tree: String,
+        subject: PathBuf,
+        max_file_count: Option<u64>,
+    },
+    FilterTree {
+        tree: String,
+        max_file_count: Option<u64>,
+        include: Vec<String>,
+        exclude: Vec<String>,
+    },
+    Ls {
+        tree: String,
+        max_file_count: Option<u64>,
+        recursive: bool,
+        hash_only: bool,
+        null_terminated: bool,
+        follow_links: bool,
     },
     CheckBlobs {
         tree: String,
         deep: bool,
+        missing_only: bool,
+        invalid_only: bool,
+        on_invalid: Option<InvalidBlobAction>,
+        max_file_count: Option<u64>,
+        format: OutputFormat,
+        from_file: Option<PathBuf>,
     },
     Sha256Sum {
         path: PathBuf,
+        tag: bool,
+    },
+    LsSubstance {
+        verify: bool,
+    },
+    ReindexSubstance {
+        from_depth: Option<usize>,
+        to_depth: usize,
+    },
+    Gc {
+        keep_refs: Vec<String>,
+        older_than: Option<Duration>,
+        dry_run: bool,
+    },
+    Cat {
+        tree: String,
+        path: ShadowPath,
+        offset: u64,
+        length: Option<u64>,
     },
+    Compact {
+        from: String,
+        to: String,
+        message: Option<String>,
+    },
+    HashOf {
+        tree: String,
+        path: ShadowPath,
+    },
+    Notes {
+        rev: String,
+    },
+    Refs,
+    Version,
     TakeSnapshot {
         subject: PathBuf,
         out: PathBuf,
+        hash_cache: Option<PathBuf>,
+        exclude: Vec<String>,
+        exclude_from: Vec<PathBuf>,
+        exclude_vcs: bool,
+        root: Option<PathBuf>,
     },
     PlantSnapshot {
         snapshot: PathBuf,
+        subject: Option<PathBuf>,
+    },
+    StorePending {
+        since: Option<String>,
     },
     StoreSnapshot {
         tree: String,
         subject: PathBuf,
+        from_manifest: Option<PathBuf>,
+        snapshot: Option<PathBuf>,
+        max_file_count: Option<u64>,
+        strip_prefix: usize,
+        keep_going: bool,
+        jobs: usize,
+        checkpoint: Option<PathBuf>,
     },
     Append {
         big_tree: String,
@@ -69,6 +195,19 @@ pub enum Command {
         mode: String,
         object: String,
         force: bool,
+        allow_existing_identical: bool,
+        commit: bool,
+        commit_ref: Option<String>,
+        message: String,
+    },
+    Restore {
+        tree: String,
+        target: PathBuf,
+        only: Vec<ShadowPath>,
+        ignore_missing: bool,
+        max_file_count: Option<u64>,
+        verify: Option<bool>,
+        progress: bool,
     },
     Remove {
         big_tree: String,
@@ -79,6 +218,57 @@ pub enum Command {
         tree: String,
         relative_path: ShadowPath,
     },
+    ReadTree {
+        tree: String,
+    },
+    WriteTree,
+    ExportGitFastImport {
+        commit: String,
+        refname: String,
+        verify: Option<bool>,
+    },
+    Repack {
+        aggressive: bool,
+    },
+    Fsck {
+        connectivity: bool,
+    },
+    Stats {
+        tree: String,
+        max_file_count: Option<u64>,
+        dedup_trees: bool,
+    },
+    Size {
+        tree: String,
+        max_file_count: Option<u64>,
+    },
+    Verify {
+        tree: String,
+        max_file_count: Option<u64>,
+        deep: bool,
+        json: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorWhen {
+    Always,
+    Auto,
+    Never,
+}
+
+// With 'json', 'check' and 'check-blobs' print a summary object instead of a line per problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+// What 'check-blobs' does with blobs whose content does not match their hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidBlobAction {
+    Delete,
+    Quarantine,
 }
 
 fn app<'a, 'b>() -> App<'a, 'b> {
@@ -87,7 +277,10 @@ fn app<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("git-dir")
                 .long("git-dir")
                 .value_name("GIT_DIR")
-                .takes_value(true),
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("May be given more than once for the read-only 'refs' command."),
         )
         .arg(
             Arg::with_name("substance-dir")
@@ -95,6 +288,15 @@ fn app<'a, 'b>() -> App<'a, 'b> {
                 .value_name("SUBSTANCE_DIR")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("reflink")
+                .long("reflink")
+                .value_name("WHEN")
+                .possible_values(&["always", "auto", "never"])
+                .default_value("auto")
+                .takes_value(true)
+                .help("Whether to store blobs as copy-on-write clones of their sources, as on btrfs or XFS. With 'auto', fall back to copying if that is not possible."),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -119,6 +321,17 @@ fn app<'a, 'b>() -> App<'a, 'b> {
                         .long("--rm")
                         .help("Remove snapshot afterwards if success."),
                 )
+                .arg(
+                    Arg::with_name("remove_subject")
+                        .long("--rm-subject")
+                        .conflicts_with("dry_run")
+                        .help("Once the snapshot has been stored, committed, and merged (or REF advanced), and tagged, remove what it recorded of SUBJECT, deepest first. Files and directories added since the snapshot was taken are left in place, as are files whose size or mtime shows they changed. Nothing is removed if any earlier step fails. Refused under --ro."),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("--dry-run")
+                        .help("Take the snapshot and list its entries, but do not plant, store, or commit it."),
+                )
                 .arg(
                     Arg::with_name("snapshot_dir")
                         .long("--snapshot-dir")
@@ -127,6 +340,127 @@ fn app<'a, 'b>() -> App<'a, 'b> {
                         .default_value("tmp.snapshot")
                         .takes_value(true),
                 )
+                .arg(hash_cache_arg())
+                .arg(exclude_arg())
+                .arg(exclude_from_arg())
+                .arg(exclude_vcs_arg())
+                .arg(no_exclude_vcs_arg())
+                .arg(
+                    Arg::with_name("date")
+                        .long("--date")
+                        .alias("timestamp")
+                        .value_name("RFC3339")
+                        .takes_value(true)
+                        .help("Use this as both the author and committer date instead of the current time. Defaults to $SOURCE_DATE_EPOCH if set. Since the commit message is fixed, snapshots of the same tree onto the same parent with the same dates have the same commit id."),
+                )
+                .arg(
+                    Arg::with_name("author_date")
+                        .long("--author-date")
+                        .value_name("RFC3339")
+                        .takes_value(true)
+                        .help("Use this as the author date, overriding --date."),
+                )
+                .arg(
+                    Arg::with_name("commit_date")
+                        .long("--commit-date")
+                        .value_name("RFC3339")
+                        .takes_value(true)
+                        .help("Use this as the committer date, overriding --date."),
+                )
+                .arg(
+                    Arg::with_name("no_merge")
+                        .long("--no-merge")
+                        .requires("ref")
+                        .help("Commit on top of REF (or HEAD if REF does not exist yet) and advance REF, leaving HEAD alone."),
+                )
+                .arg(
+                    Arg::with_name("ref")
+                        .long("--ref")
+                        .value_name("REF")
+                        .takes_value(true)
+                        .requires("no_merge"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("--tag")
+                        .value_name("KEY=VALUE")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Attach a tag to the new commit as a git note. May be given more than once."),
+                )
+                .arg(
+                    Arg::with_name("pre_hook")
+                        .long("--pre-hook")
+                        .value_name("COMMAND")
+                        .takes_value(true)
+                        .help("Run COMMAND with sh before taking the snapshot. $KEEP_SUBJECT and $KEEP_SNAPSHOT_DIR are set."),
+                )
+                .arg(
+                    Arg::with_name("post_hook")
+                        .long("--post-hook")
+                        .value_name("COMMAND")
+                        .takes_value(true)
+                        .help("Run COMMAND with sh once the snapshot has been stored, even if taking or storing it failed."),
+                )
+                .arg(
+                    Arg::with_name("progress_json")
+                        .long("--progress-json")
+                        .help("Write progress events as lines of JSON, like {\"phase\":\"store\",\"stored\":10,\"skipped\":3}, to the --progress-fd file descriptor."),
+                )
+                .arg(
+                    Arg::with_name("progress_fd")
+                        .long("--progress-fd")
+                        .value_name("FD")
+                        .takes_value(true)
+                        .requires("progress_json")
+                        .help("The file descriptor for --progress-json. Default: 2."),
+                )
+                .arg(
+                    Arg::with_name("no_store")
+                        .long("--no-store")
+                        .conflicts_with("remove_subject")
+                        .help("Plant and commit the snapshot without storing its blobs, noting that they are pending. 'keep store-pending' stores them later, from SUBJECT, which must be left in place until then."),
+                )
+                .arg(
+                    Arg::with_name("expect_subject")
+                        .long("--expect-subject")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .help("Fail before doing anything unless SUBJECT is PATH, once links are resolved, to catch a script snapshotting the wrong directory."),
+                )
+                .arg(
+                    Arg::with_name("min_files")
+                        .long("--min-files")
+                        .value_name("N")
+                        .takes_value(true)
+                        .help("Fail before planting, and remove the snapshot, if it has fewer than N files, as when SUBJECT is unexpectedly empty."),
+                )
+                .arg(
+                    Arg::with_name("max_files")
+                        .long("--max-files")
+                        .value_name("N")
+                        .takes_value(true)
+                        .help("Fail before planting, and remove the snapshot, if it has more than N files, as when SUBJECT has grown out of hand."),
+                )
+                .arg(
+                    Arg::with_name("verify_plant")
+                        .long("--verify-plant")
+                        .help("Check the planted tree as 'keep check' would before storing or committing anything, failing on the first problem."),
+                )
+                .arg(
+                    Arg::with_name("image")
+                        .long("--image")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .conflicts_with_all(&["remove_subject", "no_store"])
+                        .help("Mount the filesystem image FILE read-only at SUBJECT, an existing directory, through a loop device, and snapshot that. It is unmounted afterwards, and the loop device detached, whether or not the snapshot succeeds. Mounting needs root, or CAP_SYS_ADMIN and access to /dev/loop-control."),
+                )
+                .arg(
+                    Arg::with_name("atomic")
+                        .long("--atomic")
+                        .help("If committing, tagging, or merging fails, put HEAD (or REF) and the tag notes back as they were, so that a failed snapshot changes no refs. Blobs already stored are left in the substance, unreferenced, and the snapshot directory is left in place. --rm only happens once everything else has succeeded, and a failure to remove does not undo the commit."),
+                )
                 .arg(Arg::with_name("SUBJECT").required(true).index(1))
                 .arg(Arg::with_name("RELATIVE_PATH").required(true).index(2)),
         )
@@ -147,43 +481,445 @@ fn app<'a, 'b>() -> App<'a, 'b> {
                     .value_name("GID")
                     .default_value("0")
                     .takes_value(true)
+                )
+                .arg(verify_arg())
+                .arg(no_verify_arg())
+                .arg(
+                    Arg::with_name("metrics")
+                        .long("--metrics")
+                        .help("Print operation counters to stderr on SIGUSR1 and on unmount."),
+                )
+                .arg(
+                    Arg::with_name("allow_other")
+                        .long("--allow-other")
+                        .conflicts_with("allow_root")
+                        .help("Let other users access the mount. Unless mounting as root, /etc/fuse.conf must contain 'user_allow_other'."),
+                )
+                .arg(
+                    Arg::with_name("allow_root")
+                        .long("--allow-root")
+                        .help("Let root access the mount too. Unless mounting as root, /etc/fuse.conf must contain 'user_allow_other'."),
+                )
+                .arg(
+                    Arg::with_name("writable")
+                        .long("--writable")
+                        .help("Mount without the read-only flag, which is set by default. Nothing can be written yet, so writes still fail. Never allowed under the global --ro."),
+                )
+                .arg(
+                    Arg::with_name("resolve_links")
+                        .long("--resolve-links")
+                        .help("Present each symbolic link which leads to an entry within the tree as that entry. Absolute links and ones leading out of the tree are presented as they are."),
+                )
+                .arg(
+                    Arg::with_name("attr_timeout")
+                        .long("--attr-timeout")
+                        .value_name("SECS")
+                        .takes_value(true)
+                        .help("Let the kernel cache file attributes it asks for again for SECS seconds. The attributes which come with looking up a name are cached for the entry timeout instead. The default is a day, which is safe because a mounted tree is read-only and never changes."),
+                )
+                .arg(
+                    Arg::with_name("entry_timeout")
+                        .long("--entry-timeout")
+                        .value_name("SECS")
+                        .takes_value(true)
+                        .help("Let the kernel cache the results of looking up names, along with the attributes which come with them, for SECS seconds. The default is a day, which is safe for the same reason."),
+                )
+                .arg(
+                    Arg::with_name("missing_timeout")
+                        .long("--missing-timeout")
+                        .value_name("SECS")
+                        .takes_value(true)
+                        .help("Once a blob is found to be missing from the substance, fail to open files with that content for SECS seconds without checking again. The default is 10, and 0 always checks."),
+                )
+                .arg(
+                    Arg::with_name("background")
+                        .long("--background")
+                        .help("Serve the filesystem from a detached child process, whose output is discarded, and exit once it is ready, or with the child's error if mounting fails. Unmount it with 'fusermount -u'."),
+                )
+                .arg(
+                    Arg::with_name("pid_file")
+                        .long("--pid-file")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .requires("background")
+                        .help("Write the pid of the process serving the filesystem to FILE."),
                 ),
         )
         .subcommand(
             SubCommand::with_name("diff")
+                .arg(
+                    Arg::with_name("color")
+                        .long("--color")
+                        .value_name("WHEN")
+                        .possible_values(&["always", "auto", "never"])
+                        .default_value("auto")
+                        .takes_value(true)
+                        .help("With 'auto', color is used only if stdout is a terminal and NO_COLOR is unset."),
+                )
+                .arg(
+                    Arg::with_name("raw")
+                        .long("--raw")
+                        .help("Print lines like those of 'git diff --raw', with decoded paths, and no summary."),
+                )
+                .arg(
+                    Arg::with_name("summary_only")
+                        .long("--summary-only")
+                        .conflicts_with("raw")
+                        .help("Instead of a line per difference, print only a summary of how many entries were added, removed, modified, or changed type, and the change in total size."),
+                )
+                .arg(
+                    Arg::with_name("each")
+                        .long("--each")
+                        .help("With a range A..B, print the changes of each commit in it, oldest first, instead of the cumulative change from A to B."),
+                )
+                .arg(
+                    Arg::with_name("ignore_mode")
+                        .long("--ignore-mode")
+                        .help("Do not report files whose content is unchanged but whose executable bit differs. Changes between files and links are still reported."),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("--path")
+                        .value_name("PREFIX")
+                        .takes_value(true)
+                        .help("Report only differences below the directory PREFIX, which must exist on at least one side."),
+                )
                 .arg(Arg::with_name("TREE_A").index(1))
                 .arg(Arg::with_name("TREE_B").index(2))
-                .help("Default: HEAD _ or HEAD^ HEAD."),
+                .help("Default: HEAD _ or HEAD^ HEAD. A single argument A..B compares the trees of commits A and B."),
         )
         .subcommand(
             SubCommand::with_name("check")
+                .arg(max_file_count_arg())
+                .arg(
+                    Arg::with_name("no_empty_dirs")
+                        .long("--no-empty-dirs")
+                        .help("Report directories which contain no entries."),
+                )
+                .arg(
+                    Arg::with_name("max_errors")
+                        .long("--max-errors")
+                        .value_name("N")
+                        .takes_value(true)
+                        .help("Report at most N problems, and stop checking on finding another."),
+                )
+                .arg(format_arg())
+                .arg(Arg::with_name("TREE").default_value("HEAD").index(1))
+                .after_help(CHECK_EXIT_STATUS),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .arg(max_file_count_arg())
+                .arg(
+                    Arg::with_name("rehash")
+                        .long("--rehash")
+                        .required(true)
+                        .help("Re-store missing or invalid blobs from the files in SUBJECT which still have the recorded content."),
+                )
+                .arg(
+                    Arg::with_name("subject")
+                        .long("--subject")
+                        .value_name("SUBJECT")
+                        .takes_value(true)
+                        .required(true),
+                )
                 .arg(Arg::with_name("TREE").default_value("HEAD").index(1)),
         )
+        .subcommand(
+            SubCommand::with_name("filter-tree")
+                .arg(max_file_count_arg())
+                .arg(
+                    Arg::with_name("include")
+                        .long("--include")
+                        .value_name("PATTERN")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Keep only entries matching PATTERN (or inside a directory matching it). May be given more than once."),
+                )
+                .arg(exclude_arg())
+                .arg(Arg::with_name("TREE").default_value("HEAD").index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .arg(max_file_count_arg())
+                .arg(
+                    Arg::with_name("recursive")
+                        .long("--recursive")
+                        .short("r")
+                        .help("List the contents of subdirectories too."),
+                )
+                .arg(
+                    Arg::with_name("hash_only")
+                        .long("--hash-only")
+                        .help("Print '<sha256><TAB><path>' for each file and nothing for other entries."),
+                )
+                .arg(
+                    Arg::with_name("null_terminated")
+                        .short("0")
+                        .help("Terminate lines with NUL instead of newline."),
+                )
+                .arg(
+                    Arg::with_name("follow_links")
+                        .long("--follow-links")
+                        .short("L")
+                        .help("List each symbolic link as the entry it leads to within the tree. Links which are absolute, lead out of the tree, dangle, or loop are listed as links, with the reason."),
+                )
+                .arg(Arg::with_name("TREE").default_value("HEAD").index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .arg(
+                    Arg::with_name("offset")
+                        .long("--offset")
+                        .value_name("N")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("Start N bytes into the file. Nothing is written if it is past the end."),
+                )
+                .arg(
+                    Arg::with_name("length")
+                        .long("--length")
+                        .value_name("N")
+                        .takes_value(true)
+                        .help("Write at most N bytes."),
+                )
+                .arg(Arg::with_name("TREE").required(true).index(1))
+                .arg(Arg::with_name("PATH").required(true).index(2))
+                .about("Write the content of the file at PATH in TREE, following links within TREE."),
+        )
         .subcommand(
             SubCommand::with_name("unique-blobs")
+                .arg(max_file_count_arg())
                 .arg(Arg::with_name("TREE").default_value("HEAD").index(1)),
         )
+        .subcommand(
+            SubCommand::with_name("find")
+                .arg(max_file_count_arg())
+                .arg(Arg::with_name("TREE").default_value("HEAD").index(1))
+                .arg(
+                    Arg::with_name("hash")
+                        .long("--hash")
+                        .value_name("SHA256")
+                        .takes_value(true)
+                        .required(true)
+                        .help("List every path with this content, including repeated occurrences."),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("check-blobs")
+                .arg(max_file_count_arg())
                 .arg(Arg::with_name("TREE").default_value("HEAD").index(1))
-                .arg(Arg::with_name("deep").long("--deep")),
+                .arg(Arg::with_name("deep").long("--deep"))
+                .arg(
+                    Arg::with_name("missing_only")
+                        .long("--missing-only")
+                        .conflicts_with("invalid_only")
+                        .help("Only report blobs absent from the substance, skipping --deep verification."),
+                )
+                .arg(
+                    Arg::with_name("invalid_only")
+                        .long("--invalid-only")
+                        .help("Only report present blobs whose content does not match their hash. Implies --deep."),
+                )
+                .arg(
+                    Arg::with_name("delete_invalid")
+                        .long("--delete-invalid")
+                        .conflicts_with_all(&["missing_only", "quarantine"])
+                        .help("Remove each invalid blob, so that it can be stored again. Implies --deep."),
+                )
+                .arg(
+                    Arg::with_name("quarantine")
+                        .long("--quarantine")
+                        .conflicts_with("missing_only")
+                        .help("Move each invalid blob to SUBSTANCE_DIR/quarantine/, so that it can be stored again. Implies --deep."),
+                )
+                .arg(
+                    Arg::with_name("from_file")
+                        .long("--from-file")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Check the blobs whose content hashes are listed in FILE, one per line, instead of those in TREE. Use '-' for stdin."),
+                )
+                .arg(format_arg())
+                .after_help(CHECK_EXIT_STATUS),
         )
         .subcommand(
-            SubCommand::with_name("sha256sum").arg(Arg::with_name("PATH").required(true).index(1)),
+            SubCommand::with_name("ls-substance").arg(
+                Arg::with_name("verify")
+                    .long("--verify")
+                    .help("Also check each blob against its content hash."),
+            ),
         )
+        .subcommand(
+            SubCommand::with_name("reindex-substance")
+                .arg(
+                    Arg::with_name("from_depth")
+                        .long("--from-depth")
+                        .value_name("DEPTH")
+                        .takes_value(true)
+                        .help("The number of levels of directories blobs are currently sharded into, if not as recorded in the substance, as when resuming after the record was lost."),
+                )
+                .arg(
+                    Arg::with_name("to_depth")
+                        .long("--to-depth")
+                        .value_name("DEPTH")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The number of levels of directories to shard blobs into, each named by the next three hex digits of the content hash. Running it again after an interruption picks up where it left off. The new depth is recorded only once every file among the blobs fits it."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .arg(
+                    Arg::with_name("keep_refs")
+                        .long("--keep-refs")
+                        .value_name("GLOB")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Keep only blobs referenced from the history of HEAD or of refs matching GLOB, like 'refs/keep/archive/*'. May be given more than once. By default, the history of every ref other than notes is kept."),
+                )
+                .arg(
+                    Arg::with_name("older_than")
+                        .long("--older-than")
+                        .value_name("DURATION")
+                        .takes_value(true)
+                        .help("Only remove blobs last written more than DURATION ago, like '30m', '24h', or '7d', so that blobs stored by an operation in progress are spared."),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("--dry-run")
+                        .help("List unreferenced blobs without removing them."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .arg(Arg::with_name("FROM").required(true).index(1))
+                .arg(Arg::with_name("TO").required(true).index(2))
+                .arg(
+                    Arg::with_name("message")
+                        .long("--message")
+                        .short("m")
+                        .value_name("MESSAGE")
+                        .takes_value(true)
+                        .help("The message of the commit replacing FROM..TO. Defaults to that of TO."),
+                )
+                .about("Replace the commits FROM..TO on the current branch with one commit holding the tree of TO, and rebuild the commits after TO on top of it. The tags of the replaced commits are merged onto the new one, and a replaced commit other than TO must have no blobs pending. The history from FROM to the tip must be linear. The branch as it was is kept at refs/keep/compacted/<old tip>, whose blobs gc keeps unless it is given '--keep-refs' globs which leave it out."),
+        )
+        .subcommand(
+            SubCommand::with_name("sha256sum")
+                .arg(
+                    Arg::with_name("tag")
+                        .long("--tag")
+                        .help("Print a BSD-style line like 'SHA256 (PATH) = HASH', as 'sha256sum --tag' does, instead of 'HASH *PATH'."),
+                )
+                .arg(Arg::with_name("PATH").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("hash-of")
+                .arg(Arg::with_name("PATH").required(true).index(1))
+                .arg(Arg::with_name("TREE").default_value("HEAD").index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("notes")
+                .arg(Arg::with_name("REV").default_value("HEAD").index(1)),
+        )
+        .subcommand(SubCommand::with_name("refs"))
+        .subcommand(SubCommand::with_name("version"))
         .subcommand(
             SubCommand::with_name("take-snapshot")
+                .arg(hash_cache_arg())
+                .arg(exclude_arg())
+                .arg(exclude_from_arg())
+                .arg(exclude_vcs_arg())
+                .arg(no_exclude_vcs_arg())
+                .arg(
+                    Arg::with_name("root")
+                        .long("--root")
+                        .alias("chroot")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .help("Record paths relative to DIR, which must contain SUBJECT, rather than to SUBJECT."),
+                )
                 .arg(Arg::with_name("SUBJECT").required(true).index(1))
                 .arg(Arg::with_name("OUT").required(true).index(2)),
         )
         .subcommand(
             SubCommand::with_name("plant-snapshot")
+                .arg(
+                    Arg::with_name("subject")
+                        .long("--subject")
+                        .value_name("SUBJECT")
+                        .takes_value(true)
+                        .help("Fail unless SNAPSHOT was taken of SUBJECT."),
+                )
                 .arg(Arg::with_name("SNAPSHOT").required(true).index(1)),
         )
+        .subcommand(
+            SubCommand::with_name("store-pending")
+                .about("Store the blobs of each snapshot committed with 'snapshot --no-store', on any ref, which are not stored yet.")
+                .arg(
+                    Arg::with_name("since")
+                        .long("--since")
+                        .value_name("REV")
+                        .takes_value(true)
+                        .help("Store only the blobs of pending snapshots committed after REV, leaving those in its history pending."),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("store-snapshot")
+                .arg(max_file_count_arg())
+                .arg(
+                    Arg::with_name("strip_prefix")
+                        .long("--strip-prefix")
+                        .value_name("N")
+                        .default_value("0")
+                        .takes_value(true)
+                        .help("Drop the first N components of each path in TREE before joining it onto SUBJECT."),
+                )
+                .arg(
+                    Arg::with_name("keep_going")
+                        .long("--keep-going")
+                        .help("Report blobs which cannot be stored and continue with the rest, failing at the end."),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("--jobs")
+                        .short("j")
+                        .value_name("N")
+                        .default_value("1")
+                        .takes_value(true)
+                        .help("Store up to N blobs at once."),
+                )
+                .arg(
+                    Arg::with_name("from_manifest")
+                        .long("--from-manifest")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Store the files listed in the manifest FILE ('<sha256> <size or ?> <path>' per line) instead of those in a tree. SUBJECT is then given in place of TREE."),
+                )
+                .arg(
+                    Arg::with_name("snapshot")
+                        .long("--snapshot")
+                        .value_name("SNAPSHOT")
+                        .takes_value(true)
+                        .help("Fail unless the snapshot directory SNAPSHOT, from which TREE was planted, was taken of SUBJECT."),
+                )
+                .arg(
+                    Arg::with_name("checkpoint")
+                        .long("--checkpoint")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .conflicts_with("from_manifest")
+                        .help("Record in FILE the blobs confirmed stored so far, and skip those recorded by an interrupted run for the same TREE without checking the substance for them. FILE is removed once every blob is stored."),
+                )
                 .arg(Arg::with_name("TREE").required(true).index(1))
-                .arg(Arg::with_name("SUBJECT").required(true).index(2)),
+                .arg(
+                    Arg::with_name("SUBJECT")
+                        .required_unless("from_manifest")
+                        .index(2),
+                ),
         )
         .subcommand(
             SubCommand::with_name("append")
@@ -193,11 +929,67 @@ fn app<'a, 'b>() -> App<'a, 'b> {
                         .short("f")
                         .help("Replace RELATIVE_PATH if it exists."),
                 )
+                .arg(
+                    Arg::with_name("allow_existing_identical")
+                        .long("--allow-existing-identical")
+                        .conflicts_with("force")
+                        .help("If RELATIVE_PATH is already OBJECT with MODE, succeed without changing anything."),
+                )
+                .arg(
+                    Arg::with_name("commit")
+                        .long("--commit")
+                        .help("Commit the new big tree on top of HEAD and merge it, as snapshot does, instead of printing the tree. BIG_TREE is taken from HEAD."),
+                )
+                .arg(
+                    Arg::with_name("ref")
+                        .long("--ref")
+                        .value_name("REF")
+                        .takes_value(true)
+                        .requires("commit")
+                        .help("With --commit, commit on top of REF (or HEAD if REF does not exist yet) and advance REF instead."),
+                )
+                .arg(
+                    Arg::with_name("message")
+                        .long("--message")
+                        .short("m")
+                        .value_name("MSG")
+                        .takes_value(true)
+                        .requires("commit")
+                        .help("The message of the commit made with --commit. Default: x."),
+                )
                 .arg(Arg::with_name("MODE").required(true).index(1))
                 .arg(Arg::with_name("OBJECT").required(true).index(2))
                 .arg(Arg::with_name("RELATIVE_PATH").required(true).index(3))
                 .arg(Arg::with_name("BIG_TREE").default_value("HEAD").index(4)),
         )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .arg(max_file_count_arg())
+                .arg(
+                    Arg::with_name("only")
+                        .long("--only")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Restore only PATH and what is below it, at the same place under TARGET. May be given more than once."),
+                )
+                .arg(
+                    Arg::with_name("ignore_missing")
+                        .long("--ignore-missing")
+                        .requires("only")
+                        .help("Skip --only paths which do not exist in TREE instead of failing."),
+                )
+                .arg(verify_arg())
+                .arg(no_verify_arg())
+                .arg(
+                    Arg::with_name("progress")
+                        .long("--progress")
+                        .help("Show a progress bar with the files and bytes written so far on stderr, after counting them in a first pass."),
+                )
+                .arg(Arg::with_name("TREE").required(true).index(1))
+                .arg(Arg::with_name("TARGET").required(true).index(2)),
+        )
         .subcommand(
             SubCommand::with_name("remove")
                 .arg(Arg::with_name("RELATIVE_PATH").required(true).index(1))
@@ -209,6 +1001,187 @@ fn app<'a, 'b>() -> App<'a, 'b> {
                 .arg(Arg::with_name("TREE").required(true).index(2))
                 .arg(Arg::with_name("RELATIVE_PATH").required(true).index(3)),
         )
+        .subcommand(
+            SubCommand::with_name("read-tree")
+                .arg(Arg::with_name("TREE").required(true).index(1)),
+        )
+        .subcommand(SubCommand::with_name("write-tree"))
+        .subcommand(
+            SubCommand::with_name("export-git-fast-import")
+                .arg(
+                    Arg::with_name("refname")
+                        .long("--ref")
+                        .value_name("REFNAME")
+                        .default_value("refs/heads/master")
+                        .takes_value(true),
+                )
+                .arg(verify_arg())
+                .arg(no_verify_arg())
+                .arg(Arg::with_name("COMMIT").default_value("HEAD").index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("repack").arg(
+                Arg::with_name("aggressive")
+                    .long("--aggressive")
+                    .help("Pass --aggressive to git gc."),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck").arg(
+                Arg::with_name("connectivity")
+                    .long("--connectivity")
+                    .help("Report every object referenced from a commit reachable from a ref which is missing."),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .arg(max_file_count_arg())
+                .arg(
+                    Arg::with_name("dedup_trees")
+                        .long("--dedup-trees")
+                        .help("Report tree objects which occur more than once."),
+                )
+                .arg(Arg::with_name("TREE").default_value("HEAD").index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("size")
+                .arg(max_file_count_arg())
+                .arg(Arg::with_name("TREE").default_value("HEAD").index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .arg(max_file_count_arg())
+                .arg(
+                    Arg::with_name("deep")
+                        .long("--deep")
+                        .help("Also check the content of each blob against its hash."),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("--json")
+                        .help("Print a single JSON document summarizing the checks and counts."),
+                )
+                .arg(Arg::with_name("TREE").default_value("HEAD").index(1)),
+        )
+}
+
+fn max_file_count_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("max_file_count")
+        .long("--max-file-count")
+        .value_name("N")
+        .takes_value(true)
+        .help("Abort if traversal visits more than N entries.")
+}
+
+fn verify_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("verify")
+        .long("--verify")
+        .overrides_with("no_verify")
+        .help("Check each blob against its content hash before using it. This is the default unless the substance is on a local filesystem.")
+}
+
+fn no_verify_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no_verify")
+        .long("--no-verify")
+        .overrides_with("verify")
+        .help("Use blobs without checking them against their content hashes.")
+}
+
+const CHECK_EXIT_STATUS: &str =
+    "Exits with 0 if no problems were found, 1 if any were, and 2 if the check could not be completed.";
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("--format")
+        .value_name("FORMAT")
+        .possible_values(&["text", "json"])
+        .default_value("text")
+        .takes_value(true)
+        .help("With 'json', print only a summary like {\"checked\":10,\"problems\":1} for 'check' or {\"checked\":10,\"missing\":1,\"invalid\":0} for 'check-blobs'.")
+}
+
+fn hash_cache_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("hash_cache")
+        .long("--hash-cache")
+        .value_name("FILE")
+        .takes_value(true)
+        .help("Reuse digests of files unchanged since they were recorded in FILE, and update it.")
+}
+
+fn exclude_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("exclude")
+        .long("--exclude")
+        .value_name("PATTERN")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Leave out entries matching PATTERN. May be given more than once.")
+}
+
+fn exclude_from_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("exclude_from")
+        .long("--exclude-from")
+        .value_name("FILE")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Read exclude patterns from FILE ('-' for stdin), one per line. Lines starting with '#' are ignored.")
+}
+
+fn exclude_vcs_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("exclude_vcs")
+        .long("--exclude-vcs")
+        .overrides_with("no_exclude_vcs")
+        .help("Leave out the metadata directories of version control systems, like .git, .svn, and .hg.")
+}
+
+fn no_exclude_vcs_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no_exclude_vcs")
+        .long("--no-exclude-vcs")
+        .overrides_with("exclude_vcs")
+        .help("Undo an earlier '--exclude-vcs'.")
+}
+
+fn date_of(submatches: &ArgMatches, name: &str) -> Result<Option<Timestamp>> {
+    match submatches
+        .value_of(name)
+        .or_else(|| submatches.value_of("date"))
+    {
+        Some(s) => s
+            .parse()
+            .map(Some)
+            .map_err(|err| anyhow!("invalid --{}: '{}': {}", name.replace('_', "-"), s, err)),
+        None => env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .map(|s| source_date_epoch(&s))
+            .transpose(),
+    }
+}
+
+// See https://reproducible-builds.org/specs/source-date-epoch/
+fn source_date_epoch(s: &str) -> Result<Timestamp> {
+    Ok(Timestamp {
+        seconds: s
+            .parse()
+            .map_err(|_| anyhow!("invalid SOURCE_DATE_EPOCH: '{}'", s))?,
+        offset_minutes: 0,
+    })
+}
+
+// A number followed by one of 's', 'm', 'h', or 'd'.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || anyhow!("invalid duration: '{}'", s);
+    let unit = match s.chars().last().ok_or_else(invalid)? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let n: u64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+    Ok(Duration::from_secs(
+        n.checked_mul(unit).ok_or_else(invalid)?,
+    ))
 }
 
 impl Args {
@@ -226,14 +1199,27 @@ impl Args {
     }
 
     fn match_<'a>(matches: ArgMatches<'a>) -> Result<Self> {
-        let git_dir = matches
-            .value_of("git-dir")
-            .map(PathBuf::from)
-            .or_else(|| path_from_env(ENV_GIT_DIR));
+        let mut git_dirs = matches
+            .values_of("git-dir")
+            .into_iter()
+            .flatten()
+            .map(PathBuf::from);
+        let git_dir = git_dirs.next().or_else(|| path_from_env(ENV_GIT_DIR));
+        let other_git_dirs = git_dirs.collect::<Vec<_>>();
+        if !other_git_dirs.is_empty() && matches.subcommand_name() != Some("refs") {
+            return Err(anyhow!(
+                "'--git-dir' can only be given more than once for 'refs'"
+            ));
+        }
         let substance_dir = matches
             .value_of("substance-dir")
             .map(PathBuf::from)
             .or_else(|| path_from_env(ENV_SUBSTANCE_DIR));
+        let reflink = match matches.value_of("reflink").unwrap() {
+            "always" => Reflink::Always,
+            "never" => Reflink::Never,
+            _ => Reflink::Auto,
+        };
         let read_only = matches.is_present("read-only");
         let verbosity = matches.occurrences_of("v");
 
@@ -262,6 +1248,61 @@ impl Args {
                 force: submatches.is_present("force"),
                 remove_after: submatches.is_present("remove_after"),
                 snapshot_dir: submatches.value_of("snapshot_dir").unwrap().parse()?,
+                dry_run: submatches.is_present("dry_run"),
+                hash_cache: submatches.value_of("hash_cache").map(PathBuf::from),
+                exclude: submatches
+                    .values_of("exclude")
+                    .into_iter()
+                    .flatten()
+                    .map(ToString::to_string)
+                    .collect(),
+                exclude_from: submatches
+                    .values_of("exclude_from")
+                    .into_iter()
+                    .flatten()
+                    .map(PathBuf::from)
+                    .collect(),
+                exclude_vcs: submatches.is_present("exclude_vcs"),
+                dates: CommitDates {
+                    author: date_of(submatches, "author_date")?,
+                    committer: date_of(submatches, "commit_date")?,
+                },
+                staging_ref: submatches.value_of("ref").map(ToString::to_string),
+                pre_hook: submatches.value_of("pre_hook").map(ToString::to_string),
+                post_hook: submatches.value_of("post_hook").map(ToString::to_string),
+                tags: submatches
+                    .values_of("tag")
+                    .into_iter()
+                    .flatten()
+                    .map(|tag| {
+                        let (key, value) = parse_tag(tag)?;
+                        Ok((key.to_owned(), value.to_owned()))
+                    })
+                    .collect::<Result<_>>()?,
+                progress_fd: if submatches.is_present("progress_json") {
+                    Some(submatches.value_of("progress_fd").unwrap_or("2").parse()?)
+                } else {
+                    None
+                },
+                atomic: submatches.is_present("atomic"),
+                remove_subject: if submatches.is_present("remove_subject") {
+                    ensure!(!read_only, "'--rm-subject' cannot be used with '--ro'");
+                    true
+                } else {
+                    false
+                },
+                no_store: submatches.is_present("no_store"),
+                image: submatches.value_of("image").map(PathBuf::from),
+                verify_plant: submatches.is_present("verify_plant"),
+                expect_subject: submatches.value_of("expect_subject").map(PathBuf::from),
+                min_files: submatches
+                    .value_of("min_files")
+                    .map(str::parse)
+                    .transpose()?,
+                max_files: submatches
+                    .value_of("max_files")
+                    .map(str::parse)
+                    .transpose()?,
             }
         } else if let Some(submatches) = matches.subcommand_matches("mount") {
             ensure_git_dir()?;
@@ -271,66 +1312,323 @@ impl Args {
                 tree: submatches.value_of("TREE").unwrap().to_string(),
                 uid: submatches.value_of("uid").unwrap().parse()?,
                 gid: submatches.value_of("gid").unwrap().parse()?,
+                verify: verify_of(submatches),
+                metrics: submatches.is_present("metrics"),
+                allow_other: submatches.is_present("allow_other"),
+                allow_root: submatches.is_present("allow_root"),
+                writable: if submatches.is_present("writable") {
+                    ensure!(!read_only, "'--writable' cannot be used with '--ro'");
+                    true
+                } else {
+                    false
+                },
+                resolve_links: submatches.is_present("resolve_links"),
+                attr_timeout: timeout_of(submatches, "attr_timeout")?,
+                entry_timeout: timeout_of(submatches, "entry_timeout")?,
+                missing_timeout: timeout_of(submatches, "missing_timeout")?,
+                background: submatches.is_present("background"),
+                pid_file: submatches.value_of("pid_file").map(PathBuf::from),
             }
         } else if let Some(submatches) = matches.subcommand_matches("diff") {
             ensure_git_dir()?;
-            let (tree_a, tree_b) =
-                match (submatches.value_of("TREE_A"), submatches.value_of("TREE_B")) {
-                    (None, None) => ("HEAD^", "HEAD"),
-                    (Some(tree_a), None) => ("HEAD", tree_a),
-                    (Some(tree_a), Some(tree_b)) => (tree_a, tree_b),
-                    _ => panic!(),
-                };
+            let range = submatches
+                .value_of("TREE_A")
+                .filter(|_| !submatches.is_present("TREE_B"))
+                .and_then(|tree_a| tree_a.split_once(".."));
+            let (tree_a, tree_b) = match (
+                range,
+                submatches.value_of("TREE_A"),
+                submatches.value_of("TREE_B"),
+            ) {
+                // As with git, an omitted end of a range means HEAD.
+                (Some((from, to)), _, _) => (
+                    if from.is_empty() { "HEAD" } else { from },
+                    if to.is_empty() { "HEAD" } else { to },
+                ),
+                (None, None, None) => ("HEAD^", "HEAD"),
+                (None, Some(tree_a), None) => ("HEAD", tree_a),
+                (None, Some(tree_a), Some(tree_b)) => (tree_a, tree_b),
+                _ => panic!(),
+            };
+            let each = submatches.is_present("each");
+            if each && range.is_none() {
+                return Err(anyhow!("'--each' requires a range of commits, like A..B"));
+            }
             Command::Diff {
                 tree_a: tree_a.to_string(),
                 tree_b: tree_b.to_string(),
+                color: match submatches.value_of("color").unwrap() {
+                    "always" => ColorWhen::Always,
+                    "never" => ColorWhen::Never,
+                    _ => ColorWhen::Auto,
+                },
+                raw: submatches.is_present("raw"),
+                each,
+                ignore_mode: submatches.is_present("ignore_mode"),
+                summary_only: submatches.is_present("summary_only"),
+                path: match submatches.value_of("path") {
+                    Some(path) => path.parse()?,
+                    None => ShadowPath::new(),
+                },
             }
         } else if let Some(submatches) = matches.subcommand_matches("check") {
             ensure_git_dir()?;
             Command::Check {
                 tree: submatches.value_of("TREE").unwrap().to_string(),
+                max_file_count: max_file_count_of(submatches)?,
+                no_empty_dirs: submatches.is_present("no_empty_dirs"),
+                max_errors: max_errors_of(submatches)?,
+                format: format_of(submatches),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("repair") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            ensure!(!read_only, "'repair' cannot be used with '--ro'");
+            Command::Repair {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                subject: submatches.value_of("subject").unwrap().parse()?,
+                max_file_count: max_file_count_of(submatches)?,
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("filter-tree") {
+            ensure_git_dir()?;
+            Command::FilterTree {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                max_file_count: max_file_count_of(submatches)?,
+                include: submatches
+                    .values_of("include")
+                    .into_iter()
+                    .flatten()
+                    .map(ToString::to_string)
+                    .collect(),
+                exclude: submatches
+                    .values_of("exclude")
+                    .into_iter()
+                    .flatten()
+                    .map(ToString::to_string)
+                    .collect(),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("ls") {
+            ensure_git_dir()?;
+            Command::Ls {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                max_file_count: max_file_count_of(submatches)?,
+                recursive: submatches.is_present("recursive"),
+                hash_only: submatches.is_present("hash_only"),
+                null_terminated: submatches.is_present("null_terminated"),
+                follow_links: submatches.is_present("follow_links"),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("cat") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            Command::Cat {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                path: submatches.value_of("PATH").unwrap().parse()?,
+                offset: submatches.value_of("offset").unwrap().parse()?,
+                length: submatches
+                    .value_of("length")
+                    .map(str::parse::<u64>)
+                    .transpose()?,
             }
         } else if let Some(submatches) = matches.subcommand_matches("unique-blobs") {
             ensure_git_dir()?;
             Command::UniqueBlobs {
                 tree: submatches.value_of("TREE").unwrap().to_string(),
+                max_file_count: max_file_count_of(submatches)?,
             }
-        } else if let Some(submatches) = matches.subcommand_matches("check-blobs") {
+        } else if let Some(submatches) = matches.subcommand_matches("find") {
             ensure_git_dir()?;
+            Command::Find {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                hash: submatches.value_of("hash").unwrap().parse()?,
+                max_file_count: max_file_count_of(submatches)?,
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("check-blobs") {
+            if submatches.is_present("from_file") {
+                ensure!(
+                    submatches.occurrences_of("TREE") == 0,
+                    "'--from-file' cannot be used with TREE"
+                );
+            } else {
+                ensure_git_dir()?;
+            }
             ensure_substance_dir()?;
+            ensure!(
+                !read_only
+                    || !(submatches.is_present("delete_invalid")
+                        || submatches.is_present("quarantine")),
+                "'--delete-invalid' and '--quarantine' cannot be used with '--ro'"
+            );
             Command::CheckBlobs {
                 tree: submatches.value_of("TREE").unwrap().to_string(),
                 deep: submatches.is_present("deep"),
+                missing_only: submatches.is_present("missing_only"),
+                invalid_only: submatches.is_present("invalid_only"),
+                on_invalid: if submatches.is_present("delete_invalid") {
+                    Some(InvalidBlobAction::Delete)
+                } else if submatches.is_present("quarantine") {
+                    Some(InvalidBlobAction::Quarantine)
+                } else {
+                    None
+                },
+                max_file_count: max_file_count_of(submatches)?,
+                format: format_of(submatches),
+                from_file: submatches.value_of("from_file").map(PathBuf::from),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("ls-substance") {
+            ensure_substance_dir()?;
+            Command::LsSubstance {
+                verify: submatches.is_present("verify"),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("reindex-substance") {
+            ensure_substance_dir()?;
+            ensure!(!read_only, "'reindex-substance' cannot be used with '--ro'");
+            Command::ReindexSubstance {
+                from_depth: submatches
+                    .value_of("from_depth")
+                    .map(str::parse)
+                    .transpose()?,
+                to_depth: submatches.value_of("to_depth").unwrap().parse()?,
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("gc") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            ensure!(
+                !read_only || submatches.is_present("dry_run"),
+                "'gc' cannot be used with '--ro' except with '--dry-run'"
+            );
+            Command::Gc {
+                keep_refs: submatches
+                    .values_of("keep_refs")
+                    .into_iter()
+                    .flatten()
+                    .map(ToString::to_string)
+                    .collect(),
+                older_than: submatches
+                    .value_of("older_than")
+                    .map(parse_duration)
+                    .transpose()?,
+                dry_run: submatches.is_present("dry_run"),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("compact") {
+            ensure_git_dir()?;
+            ensure!(!read_only, "'compact' cannot be used with '--ro'");
+            Command::Compact {
+                from: submatches.value_of("FROM").unwrap().to_string(),
+                to: submatches.value_of("TO").unwrap().to_string(),
+                message: submatches.value_of("message").map(ToString::to_string),
             }
         } else if let Some(submatches) = matches.subcommand_matches("sha256sum") {
             Command::Sha256Sum {
                 path: submatches.value_of("PATH").unwrap().parse()?,
+                tag: submatches.is_present("tag"),
             }
+        } else if let Some(submatches) = matches.subcommand_matches("hash-of") {
+            ensure_git_dir()?;
+            Command::HashOf {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                path: submatches.value_of("PATH").unwrap().parse()?,
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("notes") {
+            ensure_git_dir()?;
+            Command::Notes {
+                rev: submatches.value_of("REV").unwrap().to_string(),
+            }
+        } else if matches.subcommand_matches("refs").is_some() {
+            ensure_git_dir()?;
+            Command::Refs
+        } else if matches.subcommand_matches("version").is_some() {
+            Command::Version
         } else if let Some(submatches) = matches.subcommand_matches("take-snapshot") {
             Command::TakeSnapshot {
                 subject: submatches.value_of("SUBJECT").unwrap().parse()?,
                 out: submatches.value_of("OUT").unwrap().parse()?,
+                hash_cache: submatches.value_of("hash_cache").map(PathBuf::from),
+                exclude: submatches
+                    .values_of("exclude")
+                    .into_iter()
+                    .flatten()
+                    .map(ToString::to_string)
+                    .collect(),
+                exclude_from: submatches
+                    .values_of("exclude_from")
+                    .into_iter()
+                    .flatten()
+                    .map(PathBuf::from)
+                    .collect(),
+                exclude_vcs: submatches.is_present("exclude_vcs"),
+                root: submatches.value_of("root").map(PathBuf::from),
             }
         } else if let Some(submatches) = matches.subcommand_matches("plant-snapshot") {
             ensure_git_dir()?;
             Command::PlantSnapshot {
                 snapshot: submatches.value_of("SNAPSHOT").unwrap().parse()?,
+                subject: submatches.value_of("subject").map(PathBuf::from),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("store-pending") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            Command::StorePending {
+                since: submatches.value_of("since").map(ToString::to_string),
             }
         } else if let Some(submatches) = matches.subcommand_matches("store-snapshot") {
             ensure_git_dir()?;
             ensure_substance_dir()?;
+            let from_manifest = submatches.value_of("from_manifest").map(PathBuf::from);
+            let (tree, subject) = match &from_manifest {
+                None => (
+                    submatches.value_of("TREE").unwrap(),
+                    submatches.value_of("SUBJECT").unwrap(),
+                ),
+                Some(_) => {
+                    if submatches.is_present("SUBJECT") {
+                        return Err(anyhow!("TREE cannot be combined with '--from-manifest'"));
+                    }
+                    ("", submatches.value_of("TREE").unwrap())
+                }
+            };
             Command::StoreSnapshot {
-                tree: submatches.value_of("TREE").unwrap().parse()?,
-                subject: submatches.value_of("SUBJECT").unwrap().parse()?,
+                tree: tree.to_string(),
+                subject: subject.parse()?,
+                from_manifest,
+                snapshot: submatches.value_of("snapshot").map(PathBuf::from),
+                max_file_count: max_file_count_of(submatches)?,
+                strip_prefix: submatches.value_of("strip_prefix").unwrap().parse()?,
+                keep_going: submatches.is_present("keep_going"),
+                jobs: jobs_of(submatches)?,
+                checkpoint: submatches.value_of("checkpoint").map(PathBuf::from),
             }
         } else if let Some(submatches) = matches.subcommand_matches("append") {
             ensure_git_dir()?;
+            if submatches.is_present("commit") && submatches.occurrences_of("BIG_TREE") > 0 {
+                return Err(anyhow!("BIG_TREE cannot be combined with '--commit'"));
+            }
             Command::Append {
                 big_tree: submatches.value_of("BIG_TREE").unwrap().parse()?,
                 relative_path: submatches.value_of("RELATIVE_PATH").unwrap().parse()?,
                 mode: submatches.value_of("MODE").unwrap().parse()?,
                 object: submatches.value_of("OBJECT").unwrap().parse()?,
                 force: submatches.is_present("force"),
+                allow_existing_identical: submatches.is_present("allow_existing_identical"),
+                commit: submatches.is_present("commit"),
+                commit_ref: submatches.value_of("ref").map(ToString::to_string),
+                message: submatches.value_of("message").unwrap_or("x").to_string(),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("restore") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            Command::Restore {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                target: submatches.value_of("TARGET").unwrap().parse()?,
+                only: submatches
+                    .values_of("only")
+                    .into_iter()
+                    .flatten()
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+                ignore_missing: submatches.is_present("ignore_missing"),
+                max_file_count: max_file_count_of(submatches)?,
+                verify: verify_of(submatches),
+                progress: submatches.is_present("progress"),
             }
         } else if let Some(submatches) = matches.subcommand_matches("remove") {
             ensure_git_dir()?;
@@ -345,13 +1643,64 @@ impl Args {
                 tree: submatches.value_of("TREE").unwrap().parse()?,
                 relative_path: submatches.value_of("RELATIVE_PATH").unwrap().parse()?,
             }
+        } else if let Some(submatches) = matches.subcommand_matches("read-tree") {
+            ensure_git_dir()?;
+            Command::ReadTree {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+            }
+        } else if matches.subcommand_matches("write-tree").is_some() {
+            ensure_git_dir()?;
+            Command::WriteTree
+        } else if let Some(submatches) = matches.subcommand_matches("export-git-fast-import") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            Command::ExportGitFastImport {
+                commit: submatches.value_of("COMMIT").unwrap().to_string(),
+                refname: submatches.value_of("refname").unwrap().to_string(),
+                verify: verify_of(submatches),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("repack") {
+            ensure_git_dir()?;
+            Command::Repack {
+                aggressive: submatches.is_present("aggressive"),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("fsck") {
+            ensure_git_dir()?;
+            Command::Fsck {
+                connectivity: submatches.is_present("connectivity"),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("stats") {
+            ensure_git_dir()?;
+            Command::Stats {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                max_file_count: max_file_count_of(submatches)?,
+                dedup_trees: submatches.is_present("dedup_trees"),
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("size") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            Command::Size {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                max_file_count: max_file_count_of(submatches)?,
+            }
+        } else if let Some(submatches) = matches.subcommand_matches("verify") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            Command::Verify {
+                tree: submatches.value_of("TREE").unwrap().to_string(),
+                max_file_count: max_file_count_of(submatches)?,
+                deep: submatches.is_present("deep"),
+                json: submatches.is_present("json"),
+            }
         } else {
             panic!()
         };
 
         Ok(Args {
             git_dir,
+            other_git_dirs,
             substance_dir,
+            reflink,
             read_only,
             verbosity,
             command,
@@ -359,6 +1708,58 @@ impl Args {
     }
 }
 
+fn max_file_count_of(submatches: &ArgMatches) -> Result<Option<u64>> {
+    Ok(submatches
+        .value_of("max_file_count")
+        .map(str::parse::<u64>)
+        .transpose()?)
+}
+
+// None if neither '--verify' nor '--no-verify' was given, so that the substance decides.
+fn verify_of(submatches: &ArgMatches) -> Option<bool> {
+    if submatches.is_present("verify") {
+        Some(true)
+    } else if submatches.is_present("no_verify") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn timeout_of(submatches: &ArgMatches, name: &str) -> Result<Option<Duration>> {
+    Ok(submatches
+        .value_of(name)
+        .map(str::parse)
+        .transpose()?
+        .map(Duration::from_secs))
+}
+
+fn format_of(submatches: &ArgMatches) -> OutputFormat {
+    match submatches.value_of("format").unwrap() {
+        "json" => OutputFormat::Json,
+        _ => OutputFormat::Text,
+    }
+}
+
+fn jobs_of(submatches: &ArgMatches) -> Result<usize> {
+    let jobs = submatches.value_of("jobs").unwrap().parse()?;
+    if jobs == 0 {
+        return Err(anyhow!("'--jobs' must be positive"));
+    }
+    Ok(jobs)
+}
+
+fn max_errors_of(submatches: &ArgMatches) -> Result<Option<u64>> {
+    let max_errors = submatches
+        .value_of("max_errors")
+        .map(str::parse::<u64>)
+        .transpose()?;
+    if max_errors == Some(0) {
+        return Err(anyhow!("'--max-errors' must be positive"));
+    }
+    Ok(max_errors)
+}
+
 fn path_from_env(var: &str) -> Option<PathBuf> {
     env::var_os(var).map(|s| <OsString as AsRef<Path>>::as_ref(&s).to_path_buf())
 }
@@ -380,4 +1781,212 @@ mod tests {
         ])
         .unwrap();
     }
+
+    #[test]
+    fn parse_git_dirs() {
+        let args = Args::get_from(vec!["", "--git-dir", "a", "--git-dir", "b", "refs"]).unwrap();
+        assert_eq!(args.git_dir, Some(PathBuf::from("a")));
+        assert_eq!(args.other_git_dirs, vec![PathBuf::from("b")]);
+        assert!(Args::get_from(vec!["", "--git-dir", "a", "--git-dir", "b", "ls"]).is_err());
+    }
+
+    #[test]
+    fn parse_diff_range() {
+        let diff = |args: &[&str]| {
+            let mut argv = vec!["", "--git-dir", "x", "diff"];
+            argv.extend(args);
+            Args::get_from(argv).map(|args| match args.command {
+                Command::Diff {
+                    tree_a,
+                    tree_b,
+                    each,
+                    ..
+                } => (tree_a, tree_b, each),
+                _ => panic!(),
+            })
+        };
+        let range = |a: &str, b: &str, each| (a.to_owned(), b.to_owned(), each);
+        assert_eq!(diff(&["a..b"]).unwrap(), range("a", "b", false));
+        assert_eq!(diff(&["--each", "a.."]).unwrap(), range("a", "HEAD", true));
+        assert_eq!(diff(&["a"]).unwrap(), range("HEAD", "a", false));
+        assert!(diff(&["--each", "a", "b"]).is_err());
+    }
+
+    #[test]
+    fn parse_mount_writable() {
+        let mount = |args: &[&str]| {
+            let mut argv = vec!["", "--git-dir", "x", "--substance-dir", "y"];
+            argv.extend(args);
+            Args::get_from(argv)
+        };
+        match mount(&["mount", "--writable", "m"]).unwrap().command {
+            Command::Mount { writable, .. } => assert!(writable),
+            _ => panic!(),
+        }
+        assert!(mount(&["--ro", "mount", "--writable", "m"]).is_err());
+    }
+
+    #[test]
+    fn parse_gc_read_only() {
+        let gc = |args: &[&str]| {
+            let mut argv = vec!["", "--git-dir", "x", "--substance-dir", "y"];
+            argv.extend(args);
+            Args::get_from(argv)
+        };
+        assert!(gc(&["gc"]).is_ok());
+        assert!(gc(&["--ro", "gc"]).is_err());
+        assert!(gc(&["--ro", "gc", "--dry-run"]).is_ok());
+    }
+
+    #[test]
+    fn parse_compact_read_only() {
+        assert!(Args::get_from(vec!["", "--git-dir", "x", "compact", "a", "b"]).is_ok());
+        assert!(Args::get_from(vec!["", "--git-dir", "x", "--ro", "compact", "a", "b"]).is_err());
+    }
+
+    #[test]
+    fn parse_repair_read_only() {
+        let repair = |args: &[&str]| {
+            let mut argv = vec!["", "--git-dir", "x", "--substance-dir", "y"];
+            argv.extend(args);
+            Args::get_from(argv)
+        };
+        assert!(repair(&["repair", "--rehash", "--subject", "s"]).is_ok());
+        assert!(repair(&["--ro", "repair", "--rehash", "--subject", "s"]).is_err());
+    }
+
+    #[test]
+    fn parse_check_blobs_read_only() {
+        let check_blobs = |args: &[&str]| {
+            let mut argv = vec!["", "--git-dir", "x", "--substance-dir", "y"];
+            argv.extend(args);
+            Args::get_from(argv)
+        };
+        assert!(check_blobs(&["--ro", "check-blobs"]).is_ok());
+        assert!(check_blobs(&["check-blobs", "--delete-invalid"]).is_ok());
+        assert!(check_blobs(&["--ro", "check-blobs", "--delete-invalid"]).is_err());
+        assert!(check_blobs(&["--ro", "check-blobs", "--quarantine"]).is_err());
+    }
+
+    #[test]
+    fn parse_reindex_substance_read_only() {
+        let reindex = |args: &[&str]| {
+            let mut argv = vec!["", "--substance-dir", "y"];
+            argv.extend(args);
+            Args::get_from(argv)
+        };
+        assert!(reindex(&["reindex-substance", "--to-depth", "2"]).is_ok());
+        assert!(reindex(&["--ro", "reindex-substance", "--to-depth", "2"]).is_err());
+    }
+
+    #[test]
+    fn parse_max_file_count() {
+        let args = Args::get_from(vec![
+            "",
+            "--git-dir",
+            "x/y",
+            "check",
+            "--max-file-count",
+            "10",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Command::Check {
+                tree: "HEAD".to_string(),
+                max_file_count: Some(10),
+                no_empty_dirs: false,
+                max_errors: None,
+                format: OutputFormat::Text,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_max_errors() {
+        let check = |max_errors: &str| {
+            Args::get_from(vec![
+                "",
+                "--git-dir",
+                "x",
+                "check",
+                "--max-errors",
+                max_errors,
+            ])
+            .map(|args| match args.command {
+                Command::Check { max_errors, .. } => max_errors,
+                _ => panic!(),
+            })
+        };
+        assert_eq!(check("3").unwrap(), Some(3));
+        assert!(check("0").is_err());
+        assert!(check("00").is_err());
+        assert!(check("+0").is_err());
+    }
+
+    #[test]
+    fn parse_timestamp() {
+        let args = Args::get_from(vec![
+            "",
+            "--git-dir",
+            "x/y",
+            "--substance-dir",
+            "y/x",
+            "snapshot",
+            "--timestamp",
+            "2021-10-20T12:34:56Z",
+            "--author-date",
+            "1970-01-01T00:00:00Z",
+            "s",
+            "r",
+        ])
+        .unwrap();
+        match args.command {
+            Command::Snapshot { dates, .. } => {
+                assert_eq!(dates.author.unwrap().seconds, 0);
+                assert_eq!(dates.committer.unwrap().seconds, 1634733296);
+            }
+            _ => panic!(),
+        }
+        assert_eq!(source_date_epoch("1634733296").unwrap().seconds, 1634733296);
+        assert!(source_date_epoch("yesterday").is_err());
+    }
+
+    #[test]
+    fn parse_gc_older_than() {
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(30 * 60));
+        assert_eq!(parse_duration("24h").unwrap(), Duration::from_secs(86400));
+        assert_eq!(
+            parse_duration("7d").unwrap(),
+            Duration::from_secs(7 * 86400)
+        );
+        for invalid in &["", "d", "7", "7w", "-1h", "1.5h"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_append_commit() {
+        let append = |extra: &[&str]| {
+            let mut args = vec!["", "--git-dir", "x", "append"];
+            args.extend(extra);
+            args.extend(&["040000", "t", "a/b"]);
+            Args::get_from(args)
+        };
+        match append(&["--commit", "-m", "graft"]).unwrap().command {
+            Command::Append {
+                commit,
+                commit_ref,
+                message,
+                ..
+            } => {
+                assert!(commit);
+                assert_eq!(commit_ref, None);
+                assert_eq!(message, "graft");
+            }
+            _ => panic!(),
+        }
+        assert!(append(&["-m", "graft"]).is_err());
+        assert!(append(&["--ref", "refs/heads/x"]).is_err());
+    }
 }
@@ -1,14 +1,29 @@
-use std::io::Write;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::Result;
-use git2::{FileMode, Repository};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use fallible_iterator::FallibleIterator;
+use git2::{Commit, FileMode, Oid, Repository};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use thiserror::Error;
 
-use crate::{sha256sum, Database, FilesystemSubstance, ShallowDifferenceSide, Snapshot, Substance};
+use crate::database::{PENDING_NOTES_REF, TAGS_NOTES_REF};
+use crate::{
+    render_restore_progress, sha256sum, snapshot_commit_message, with_image_mounted,
+    with_progress_line, BlobChecks, BlobProblem, CheckPolicy, CommitDates, ContentSha256, Database,
+    DiffSummary, FilesystemSubstance, LinkTarget, LsEntryValue, Manifest, MountConfig,
+    ProgressEvent, ProgressWriter, RestoreConfig, RestoreProgress, Shadow, ShadowPath,
+    ShallowDifferenceKind, Snapshot, SnapshotConfig, SnapshotEntryValue, SnapshotFilter,
+    SnapshotHooks, StoreConfig, StoreProgress, Substance, TreeFilter,
+};
 
 mod args;
 
-use args::{Args, Command};
+use args::{Args, ColorWhen, Command, InvalidBlobAction, OutputFormat};
 
 pub fn cli_main() -> Result<()> {
     let args = Args::get()?;
@@ -16,15 +31,38 @@ pub fn cli_main() -> Result<()> {
     args.run_command()
 }
 
+// How 'check' and 'check-blobs' fail, distinguishing problems found from a check which could not
+// be completed.
+#[derive(Debug, Error)]
+enum CheckFailure {
+    #[error("{0} problems found")]
+    Problems(u64),
+    #[error("stopped after {0} problems")]
+    Stopped(u64),
+    #[error("check could not be completed")]
+    Incomplete,
+}
+
+// The exit status for an error returned by `cli_main`. 'check' and 'check-blobs' exit with 1 if
+// they found problems and 2 if the check could not be completed. Any other error gives 1.
+pub fn cli_exit_code(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<CheckFailure>() {
+        Some(CheckFailure::Incomplete) => 2,
+        _ => 1,
+    }
+}
+
 impl Args {
     fn database(&self) -> Result<Database> {
         let git_dir = self.git_dir.as_ref().unwrap();
-        Ok(Database::new(Repository::open_bare(git_dir)?))
+        Ok(Database::new(open_bare_repository(git_dir)?))
     }
 
     fn substance(&self) -> Result<FilesystemSubstance> {
         let substance_dir = self.substance_dir.as_ref().unwrap();
-        Ok(FilesystemSubstance::new(substance_dir))
+        let mut substance = FilesystemSubstance::new(substance_dir)?;
+        substance.set_reflink(self.reflink);
+        Ok(substance)
     }
 
     fn apply_verbosity(&self) {
@@ -47,110 +85,704 @@ impl Args {
                 force,
                 remove_after,
                 snapshot_dir,
+                dry_run,
+                hash_cache,
+                exclude,
+                exclude_from,
+                exclude_vcs,
+                dates,
+                staging_ref,
+                pre_hook,
+                post_hook,
+                tags,
+                progress_fd,
+                atomic,
+                remove_subject,
+                no_store,
+                image,
+                verify_plant,
+                expect_subject,
+                min_files,
+                max_files,
             } => {
+                if let Some(expect_subject) = expect_subject {
+                    ensure_expected_subject(subject, expect_subject)?;
+                }
                 let db = self.database()?;
                 let substance = self.substance()?;
+                let progress = match progress_fd {
+                    None => None,
+                    Some(fd) => Some(Arc::new(ProgressWriter::new(progress_file(*fd)?))),
+                };
                 let snapshot = Snapshot::new(&snapshot_dir);
                 log::info!(
                     "taking snapshot of {} to {}",
                     subject.display(),
                     snapshot.path().display()
                 );
-                snapshot.take(&subject)?;
-                log::info!("planting snapshot");
-                let (mode, tree) = db.plant_snapshot(&snapshot)?;
-                log::info!("planted: {:06o},{}", u32::from(mode), tree);
-                log::info!("storing snapshot");
-                db.store_snapshot(&substance, tree, &subject)?;
+                let config = snapshot_config(hash_cache, exclude, exclude_from, *exclude_vcs)?;
+                let hooks = SnapshotHooks {
+                    pre: pre_hook.clone(),
+                    post: post_hook.clone(),
+                };
+                let take = || {
+                    hooks.run(&subject, snapshot.path(), || {
+                        snapshot.take(&subject, &config)?;
+                        if let Some(progress) = &progress {
+                            let (files, bytes) = walk_counts(&snapshot)?;
+                            progress.emit(&ProgressEvent::Walk { files, bytes })?;
+                        }
+                        if min_files.is_some() || max_files.is_some() {
+                            let (files, _) = walk_counts(&snapshot)?;
+                            // A snapshot which fails the bounds is of no use, and would be in
+                            // the way of the next attempt.
+                            if let Err(err) = ensure_file_count(files, *min_files, *max_files) {
+                                snapshot.remove()?;
+                                return Err(err);
+                            }
+                        }
+                        if *dry_run {
+                            preview_snapshot(&snapshot)?;
+                            return Ok(None);
+                        }
+                        log::info!("planting snapshot");
+                        let (mode, tree) = db.plant_snapshot(&snapshot)?;
+                        log::info!("planted: {:06o},{}", u32::from(mode), tree);
+                        if *verify_plant {
+                            log::info!("checking planted tree");
+                            db.ensure_sound(tree)
+                                .with_context(|| format!("checking planted tree {}", tree))?;
+                        }
+                        if *no_store {
+                            log::info!("not storing snapshot");
+                            return Ok(Some((mode, tree)));
+                        }
+                        log::info!("storing snapshot");
+                        let report = match &progress {
+                            None => db.store_snapshot(
+                                &substance,
+                                tree,
+                                &subject,
+                                &StoreConfig::default(),
+                            )?,
+                            Some(progress) => {
+                                let counts = Arc::new(StoreProgress::default());
+                                let config = StoreConfig {
+                                    progress: Some(counts.clone()),
+                                    ..Default::default()
+                                };
+                                progress.while_storing(&counts, || {
+                                    db.store_snapshot(&substance, tree, &subject, &config)
+                                })?
+                            }
+                        };
+                        log::info!("{}", report);
+                        Ok(Some((mode, tree)))
+                    })
+                };
+                let planted = match image {
+                    Some(image) => with_image_mounted(image, &subject, take)?,
+                    None => take()?,
+                };
+                let (mode, tree) = match planted {
+                    Some(planted) => planted,
+                    None => {
+                        if *remove_after {
+                            snapshot.remove()?;
+                        }
+                        if let Some(progress) = &progress {
+                            progress.emit(&ProgressEvent::Done)?;
+                        }
+                        return Ok(());
+                    }
+                };
                 // log::info!("adding snapshot to index at {}", relative_path);
                 // db.add_to_index(mode, tree, relative_path)?;
-                let parent = db.repository().head()?.peel_to_commit()?;
-                let big_tree = parent.tree_id();
-                log::info!(
-                    "adding snapshot to HEAD^{{tree}} ({}) at {}",
-                    big_tree,
-                    relative_path
-                );
-                let new_big_tree = db.append(big_tree, &relative_path, mode, tree, *force)?;
-                let commit =
-                    db.commit_simple("x", &db.repository().find_tree(new_big_tree)?, &parent)?;
-                log::info!("new commit is {}. merging --ff-only into HEAD", commit);
-                db.safe_merge(commit)?;
+                let commit_snapshot = || -> Result<()> {
+                    let parent = parent_commit(&db, staging_ref.as_deref())?;
+                    let big_tree = match &parent {
+                        Some(parent) => parent.tree_id(),
+                        None => db.empty_tree()?,
+                    };
+                    log::info!("adding snapshot to {} at {}", big_tree, relative_path);
+                    let new_big_tree = db.append(big_tree, &relative_path, mode, tree, *force)?;
+                    let commit = commit_big_tree(
+                        &db,
+                        parent.as_ref(),
+                        staging_ref.as_deref(),
+                        &snapshot_commit_message("x", relative_path),
+                        new_big_tree,
+                        dates,
+                    )?;
+                    if !tags.is_empty() {
+                        db.add_tags(commit, tags)?;
+                    }
+                    if *no_store {
+                        db.mark_pending(commit, &relative_path, &subject)?;
+                    }
+                    Ok(())
+                };
+                if *atomic {
+                    // Everything before this point only writes objects and blobs, which nothing
+                    // refers to until a ref is updated.
+                    let target = staging_ref.as_deref().unwrap_or("HEAD");
+                    db.restore_refs_on_error(
+                        &[target, TAGS_NOTES_REF, PENDING_NOTES_REF],
+                        commit_snapshot,
+                    )?;
+                } else {
+                    commit_snapshot()?;
+                }
+                if *remove_subject {
+                    log::info!("removing {}", subject.display());
+                    snapshot.remove_subject(&subject)?;
+                }
                 if *remove_after {
                     snapshot.remove()?;
                 }
+                if let Some(progress) = &progress {
+                    progress.emit(&ProgressEvent::Done)?;
+                }
             }
-            Command::Mount { mountpoint, tree, uid, gid } => {
+            Command::Mount {
+                mountpoint,
+                tree,
+                uid,
+                gid,
+                verify,
+                metrics,
+                allow_other,
+                allow_root,
+                writable,
+                resolve_links,
+                attr_timeout,
+                entry_timeout,
+                missing_timeout,
+                background,
+                pid_file,
+            } => {
                 let db = self.database()?;
                 let substance = self.substance()?;
                 let tree = db.resolve_treeish(&tree)?;
-                db.mount(tree, &mountpoint, substance, *uid, *gid)?;
+                let config = MountConfig {
+                    uid: *uid,
+                    gid: *gid,
+                    verify: verify.unwrap_or_else(|| substance.verify_by_default()),
+                    metrics: *metrics,
+                    allow_other: *allow_other,
+                    allow_root: *allow_root,
+                    writable: *writable,
+                    resolve_links: *resolve_links,
+                    attr_timeout: *attr_timeout,
+                    entry_timeout: *entry_timeout,
+                    missing_timeout: *missing_timeout,
+                };
+                if !*background {
+                    return db.mount(tree, &mountpoint, substance, config);
+                }
+                let pid = db.mount_in_background(tree, &mountpoint, substance, config)?;
+                if let Some(pid_file) = pid_file {
+                    fs::write(pid_file, format!("{}\n", pid))
+                        .with_context(|| format!("writing {}", pid_file.display()))?;
+                }
             }
-            Command::Diff { tree_a, tree_b } => {
+            Command::Diff {
+                tree_a,
+                tree_b,
+                color,
+                raw,
+                each,
+                ignore_mode,
+                summary_only,
+                path,
+            } => {
                 let db = self.database()?;
-                let tree_a = db.resolve_treeish(&tree_a)?;
-                let tree_b = db.resolve_treeish(&tree_b)?;
-                let mut stdout = StandardStream::stdout(ColorChoice::Always);
-                db.shallow_diff(tree_a, tree_b, |difference| {
-                    let color = match difference.side {
-                        ShallowDifferenceSide::A => Color::Red,
-                        ShallowDifferenceSide::B => Color::Green,
+                let output = DiffOutput {
+                    color: *color,
+                    raw: *raw,
+                    ignore_mode: *ignore_mode,
+                    summary_only: *summary_only,
+                };
+                if !*each {
+                    let tree_a = db.resolve_treeish(&tree_a)?;
+                    let tree_b = db.resolve_treeish(&tree_b)?;
+                    return print_diff(&db, tree_a, tree_b, path, &output);
+                }
+                for commit in db.range_commits(tree_a, tree_b)? {
+                    let commit = db.repository().find_commit(commit)?;
+                    let parent_tree = match commit.parents().next() {
+                        Some(parent) => parent.tree_id(),
+                        None => db.empty_tree()?,
                     };
-                    stdout.set_color(ColorSpec::new().set_fg(Some(color)))?;
-                    writeln!(&mut stdout, "{}", difference)?;
+                    println!("commit {}", commit.id());
+                    print_diff(&db, parent_tree, commit.tree_id(), path, &output)?;
+                }
+            }
+            Command::Check {
+                tree,
+                max_file_count,
+                no_empty_dirs,
+                max_errors,
+                format,
+            } => {
+                let mut problems = 0;
+                let (checked, stopped) = (|| -> Result<(u64, bool)> {
+                    let mut db = self.database()?;
+                    db.set_max_file_count(*max_file_count);
+                    let tree = db.resolve_treeish(&tree)?;
+                    let policy = CheckPolicy {
+                        no_empty_dirs: *no_empty_dirs,
+                        max_problems: *max_errors,
+                    };
+                    db.check(tree, &policy, |path, problem| {
+                        if *format == OutputFormat::Text {
+                            println!("{}: {}", problem, path);
+                        }
+                        problems += 1;
+                        Ok(())
+                    })
+                })()
+                .context(CheckFailure::Incomplete)?;
+                if *format == OutputFormat::Json {
+                    println!(r#"{{"checked":{},"problems":{}}}"#, checked, problems);
+                }
+                if stopped {
+                    return Err(CheckFailure::Stopped(problems).into());
+                }
+                if problems > 0 {
+                    return Err(CheckFailure::Problems(problems).into());
+                }
+            }
+            Command::Repair {
+                tree,
+                subject,
+                max_file_count,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
+                let substance = self.substance()?;
+                let tree = db.resolve_treeish(&tree)?;
+                let mut unrepairable = 0;
+                db.repair_blobs(tree, &substance, &subject, |path, problem, outcome| {
+                    println!("{}: {}: {}", problem, outcome, path);
+                    if !outcome.is_repaired() {
+                        unrepairable += 1;
+                    }
                     Ok(())
                 })?;
-                stdout.reset()?;
+                ensure!(
+                    unrepairable == 0,
+                    "{} blobs could not be repaired",
+                    unrepairable
+                );
             }
-            Command::Check { tree } => {
-                let db = self.database()?;
+            Command::FilterTree {
+                tree,
+                max_file_count,
+                include,
+                exclude,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
                 let tree = db.resolve_treeish(&tree)?;
-                db.check(tree)?;
+                let filter = TreeFilter {
+                    include: include.clone(),
+                    exclude: exclude.clone(),
+                };
+                println!("{}", db.filter_tree(tree, &filter)?);
+            }
+            Command::Ls {
+                tree,
+                max_file_count,
+                recursive,
+                hash_only,
+                null_terminated,
+                follow_links,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
+                let tree = db.resolve_treeish(&tree)?;
+                let terminator = if *null_terminated { '\0' } else { '\n' };
+                let mut stdout = BufWriter::new(io::stdout());
+                for (path, value) in db.ls(tree, *recursive)? {
+                    let value = match value {
+                        LsEntryValue::Link(target) if *follow_links => {
+                            match db.resolve_link(tree, &path.parse()?)? {
+                                LinkTarget::Entry(resolved) => db.ls_entry(tree, &resolved)?,
+                                unresolved => {
+                                    if !*hash_only {
+                                        write!(
+                                            stdout,
+                                            "l {} -> {} ({}){}",
+                                            path, target, unresolved, terminator
+                                        )?;
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+                        value => value,
+                    };
+                    match (value, *hash_only) {
+                        (LsEntryValue::Shadow(shadow), true) => {
+                            write!(stdout, "{}\t{}", shadow.content_hash(), path)?
+                        }
+                        (_, true) => continue,
+                        (LsEntryValue::Tree, false) => write!(stdout, "d {}", path)?,
+                        (LsEntryValue::Shadow(shadow), false) => {
+                            write!(stdout, "f {} {}", shadow.content_hash(), path)?
+                        }
+                        (LsEntryValue::Link(target), false) => {
+                            write!(stdout, "l {} -> {}", path, target)?
+                        }
+                    }
+                    write!(stdout, "{}", terminator)?;
+                }
+                stdout.flush()?;
             }
-            Command::UniqueBlobs { tree } => {
+            Command::Cat {
+                tree,
+                path,
+                offset,
+                length,
+            } => {
                 let db = self.database()?;
+                let substance = self.substance()?;
+                let tree = db.resolve_treeish(&tree)?;
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                db.cat(tree, path, &substance, *offset, *length, &mut stdout)?;
+                stdout.flush()?;
+            }
+            Command::UniqueBlobs {
+                tree,
+                max_file_count,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
                 let tree = db.resolve_treeish(&tree)?;
                 db.unique_shadows(tree, |path, blob| {
                     println!("{} {}", blob.content_hash(), path);
                     Ok(())
                 })?;
             }
-            Command::CheckBlobs { tree, deep } => {
-                let db = self.database()?;
-                let substance = self.substance()?;
+            Command::Find {
+                tree,
+                hash,
+                max_file_count,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
                 let tree = db.resolve_treeish(&tree)?;
-                db.unique_shadows(tree, |path, blob| {
-                    // TODO check size
-                    if !substance.have_blob(blob.content_hash()) {
-                        println!("missing blob: {} {}", blob.content_hash(), path);
-                    }
-                    if *deep {
-                        if !substance.check_blob(blob.content_hash()).is_ok() {
-                            println!("invalid blob: {} {}", blob.content_hash(), path);
+                for path in db.blob_paths(tree, hash)? {
+                    println!("{}", path);
+                }
+            }
+            Command::CheckBlobs {
+                tree,
+                deep,
+                missing_only,
+                invalid_only,
+                on_invalid,
+                max_file_count,
+                format,
+                from_file,
+            } => {
+                let text = *format == OutputFormat::Text;
+                let (mut missing, mut invalid) = (0, 0);
+                let checked = (|| -> Result<u64> {
+                    let substance = self.substance()?;
+                    log::info!("checking blobs in {}", substance.describe());
+                    let checks = BlobChecks {
+                        missing: !*invalid_only,
+                        invalid: (*deep || *invalid_only || on_invalid.is_some()) && !*missing_only,
+                    };
+                    let mut on_problem =
+                        |blob: &ContentSha256, problem: BlobProblem, line: String| -> Result<()> {
+                            if text {
+                                println!("{}", line);
+                            }
+                            match problem {
+                                BlobProblem::Missing => missing += 1,
+                                BlobProblem::Invalid => invalid += 1,
+                            }
+                            let action = match (problem, on_invalid) {
+                                (BlobProblem::Invalid, Some(InvalidBlobAction::Delete)) => {
+                                    substance
+                                        .remove_blob(blob)
+                                        .with_context(|| format!("in {}", substance.describe()))?;
+                                    format!("deleted {}", blob)
+                                }
+                                (BlobProblem::Invalid, Some(InvalidBlobAction::Quarantine)) => {
+                                    let to = substance
+                                        .quarantine_blob(blob)
+                                        .with_context(|| format!("in {}", substance.describe()))?;
+                                    format!("quarantined {} to {}", blob, to.display())
+                                }
+                                _ => return Ok(()),
+                            };
+                            // Only the summary goes to stdout with --format json.
+                            if text {
+                                println!("{}", action);
+                            } else {
+                                log::info!("{}", action);
+                            }
+                            Ok(())
+                        };
+                    match from_file {
+                        Some(path) => {
+                            let blobs = read_hash_list(path)?;
+                            for blob in &blobs {
+                                if let Some(problem) = checks.check(&substance, blob) {
+                                    on_problem(blob, problem, format!("{}: {}", problem, blob))?;
+                                }
+                            }
+                            Ok(blobs.len() as u64)
+                        }
+                        None => {
+                            let mut db = self.database()?;
+                            db.set_max_file_count(*max_file_count);
+                            let tree = db.resolve_treeish(&tree)?;
+                            db.check_blobs(tree, &substance, &checks, |path, shadow, problem| {
+                                let blob = shadow.content_hash();
+                                on_problem(blob, problem, format!("{}: {} {}", problem, blob, path))
+                            })
                         }
                     }
+                })()
+                .context(CheckFailure::Incomplete)?;
+                if !text {
+                    println!(
+                        r#"{{"checked":{},"missing":{},"invalid":{}}}"#,
+                        checked, missing, invalid
+                    );
+                }
+                if missing + invalid > 0 {
+                    return Err(CheckFailure::Problems(missing + invalid).into());
+                }
+            }
+            Command::LsSubstance { verify } => {
+                let substance = self.substance()?;
+                let mut invalid = 0;
+                for blob in substance.list_blobs()? {
+                    let size = match substance.stored_size(&blob)? {
+                        Some(size) => size,
+                        // Removed since it was listed.
+                        None => continue,
+                    };
+                    if *verify {
+                        let status = if substance.check_blob(&blob).is_ok() {
+                            "ok"
+                        } else {
+                            invalid += 1;
+                            "invalid"
+                        };
+                        println!("{} {} {}", blob, size, status);
+                    } else {
+                        println!("{} {}", blob, size);
+                    }
+                }
+                if invalid > 0 {
+                    return Err(CheckFailure::Problems(invalid).into());
+                }
+            }
+            Command::ReindexSubstance {
+                from_depth,
+                to_depth,
+            } => {
+                let mut substance = self.substance()?;
+                // Only a depth given explicitly may differ from the recorded one.
+                let recover = from_depth.is_some();
+                let from_depth = from_depth.unwrap_or_else(|| substance.depth());
+                log::info!(
+                    "moving blobs in {} from depth {} to depth {}",
+                    substance.describe(),
+                    from_depth,
+                    to_depth
+                );
+                let moved = substance.reindex(from_depth, *to_depth, recover)?;
+                println!("moved {} blobs", moved);
+            }
+            Command::Gc {
+                keep_refs,
+                older_than,
+                dry_run,
+            } => {
+                let db = self.database()?;
+                let substance = self.substance()?;
+                log::info!("collecting garbage in {}", substance.describe());
+                db.gc(&substance, keep_refs, *older_than, *dry_run, |blob| {
+                    if *dry_run {
+                        println!("would remove {}", blob);
+                    } else {
+                        println!("removing {}", blob);
+                    }
                     Ok(())
                 })?;
             }
-            Command::Sha256Sum { path } => {
+            Command::Compact { from, to, message } => {
+                let db = self.database()?;
+                let compaction = db.compact(from, to, message.as_deref())?;
+                for (old, new) in &compaction.rewritten {
+                    println!("{} {}", old, new);
+                }
+                log::info!("the previous history is at {}", compaction.backup_ref);
+            }
+            Command::Sha256Sum { path, tag } => {
                 let blob = sha256sum(path)?;
-                println!("{} *{}", blob, path.display());
+                println!("{}", render_sha256sum(&blob, path, *tag));
+            }
+            Command::Notes { rev } => {
+                let db = self.database()?;
+                let commit = db.repository().revparse_single(rev)?.peel_to_commit()?;
+                for (key, value) in db.tags(commit.id())? {
+                    println!("{}={}", key, value);
+                }
             }
-            Command::TakeSnapshot { subject, out } => {
+            Command::Version => {
+                let git2 = git2::Version::get();
+                let (major, minor, patch) = git2.libgit2_version();
+                println!("keep {}", env!("CARGO_PKG_VERSION"));
+                println!(
+                    "git2 {} (libgit2 {}.{}.{}{})",
+                    git2.crate_version(),
+                    major,
+                    minor,
+                    patch,
+                    if git2.vendored() { ", vendored" } else { "" }
+                );
+                // keep has no optional cargo features yet.
+                println!("features: none");
+            }
+            Command::Refs => {
+                let git_dirs = self.git_dir.iter().chain(&self.other_git_dirs);
+                let prefix = !self.other_git_dirs.is_empty();
+                for git_dir in git_dirs {
+                    let db = Database::new(open_bare_repository(git_dir)?);
+                    for (name, oid) in db.refs()? {
+                        if prefix {
+                            println!("{} {} {}", git_dir.display(), oid, name);
+                        } else {
+                            println!("{} {}", oid, name);
+                        }
+                    }
+                }
+            }
+            Command::HashOf { tree, path } => {
+                let db = self.database()?;
+                let tree = db.resolve_treeish(&tree)?;
+                let entry = match db.lookup(tree, &path)? {
+                    Some(entry) => entry,
+                    None => bail!("'{}' does not exist", path),
+                };
+                let mode = entry.filemode();
+                if mode == i32::from(FileMode::Tree) {
+                    bail!("'{}' is a directory", path);
+                }
+                let blob = db.repository().find_blob(entry.id())?;
+                if mode == i32::from(FileMode::Link) {
+                    bail!(
+                        "'{}' is a symbolic link to '{}'",
+                        path,
+                        String::from_utf8_lossy(blob.content())
+                    );
+                }
+                let shadow = Shadow::from_bytes(blob.content())?;
+                match shadow.size() {
+                    Some(size) => println!("{} {}", shadow.content_hash(), size),
+                    None => println!("{} ?", shadow.content_hash()),
+                }
+            }
+            Command::TakeSnapshot {
+                subject,
+                out,
+                hash_cache,
+                exclude,
+                exclude_from,
+                exclude_vcs,
+                root,
+            } => {
                 let snapshot = Snapshot::new(out);
-                snapshot.take(&subject)?;
+                let mut config = snapshot_config(hash_cache, exclude, exclude_from, *exclude_vcs)?;
+                config.root = root.clone();
+                snapshot.take(&subject, &config)?;
             }
-            Command::PlantSnapshot { snapshot } => {
+            Command::PlantSnapshot { snapshot, subject } => {
                 let db = self.database()?;
                 let snapshot = Snapshot::new(snapshot);
+                snapshot.validate()?;
+                if let Some(subject) = subject {
+                    snapshot.ensure_subject(subject)?;
+                }
                 let (mode, tree) = db.plant_snapshot(&snapshot)?;
                 println!("{:06o},{}", u32::from(mode), tree)
             }
-            Command::StoreSnapshot { tree, subject } => {
+            Command::StorePending { since } => {
                 let db = self.database()?;
                 let substance = self.substance()?;
-                let tree = db.resolve_treeish(&tree)?;
-                db.store_snapshot(&substance, tree, &subject)?;
+                let since = since
+                    .as_ref()
+                    .map(|since| -> Result<Oid> {
+                        Ok(db
+                            .repository()
+                            .revparse_single(since)?
+                            .peel_to_commit()?
+                            .id())
+                    })
+                    .transpose()?;
+                db.store_pending(
+                    &substance,
+                    since,
+                    &StoreConfig::default(),
+                    |pending, report| {
+                        println!("{} {}: {}", pending.commit, pending.relative_path, report);
+                        Ok(())
+                    },
+                )?;
+            }
+            Command::StoreSnapshot {
+                tree,
+                subject,
+                from_manifest,
+                snapshot,
+                max_file_count,
+                strip_prefix,
+                keep_going,
+                jobs,
+                checkpoint,
+            } => {
+                if let Some(snapshot) = snapshot {
+                    Snapshot::new(snapshot).ensure_subject(subject)?;
+                }
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
+                let substance = self.substance()?;
+                let config = StoreConfig {
+                    strip_prefix: *strip_prefix,
+                    keep_going: *keep_going,
+                    jobs: *jobs,
+                    checkpoint: checkpoint.clone(),
+                    ..Default::default()
+                };
+                let report = match from_manifest {
+                    Some(from_manifest) => {
+                        let manifest = Manifest::read(from_manifest)?;
+                        db.store_manifest(&substance, &manifest, &subject, &config)?
+                    }
+                    None => {
+                        let tree = db.resolve_treeish(&tree)?;
+                        db.store_snapshot(&substance, tree, &subject, &config)?
+                    }
+                };
+                println!("{}", report);
+                for (path, err) in &report.failures {
+                    eprintln!("failed: {}: {}", path, err);
+                }
+                ensure!(
+                    report.failures.is_empty(),
+                    "failed to store {} blobs",
+                    report.failures.len()
+                );
             }
             Command::Append {
                 big_tree,
@@ -158,14 +790,83 @@ impl Args {
                 mode,
                 object,
                 force,
+                allow_existing_identical,
+                commit,
+                commit_ref,
+                message,
             } => {
                 let db = self.database()?;
-                let big_tree = db.resolve_treeish(&big_tree)?;
+                let parent = if *commit {
+                    parent_commit(&db, commit_ref.as_deref())?
+                } else {
+                    None
+                };
+                let big_tree = match (&parent, *commit) {
+                    (Some(parent), _) => parent.tree_id(),
+                    (None, true) => db.empty_tree()?,
+                    (None, false) => db.resolve_treeish(&big_tree)?,
+                };
                 assert_eq!(mode, &format!("{:06o}", u32::from(FileMode::Tree)));
                 let mode = FileMode::Tree;
                 let object = db.resolve_treeish(&object)?;
+                if *allow_existing_identical
+                    && db.is_appended(big_tree, &relative_path, mode, object)?
+                {
+                    log::info!("{} is already {}", relative_path, object);
+                    match &parent {
+                        Some(parent) => println!("{}", parent.id()),
+                        None => println!("{}", big_tree),
+                    }
+                    return Ok(());
+                }
                 let new_tree = db.append(big_tree, &relative_path, mode, object, *force)?;
-                println!("{}", new_tree)
+                if *commit {
+                    let commit = commit_big_tree(
+                        &db,
+                        parent.as_ref(),
+                        commit_ref.as_deref(),
+                        message,
+                        new_tree,
+                        &CommitDates::default(),
+                    )?;
+                    println!("{}", commit)
+                } else {
+                    println!("{}", new_tree)
+                }
+            }
+            Command::Restore {
+                tree,
+                target,
+                only,
+                ignore_missing,
+                max_file_count,
+                verify,
+                progress,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
+                let substance = self.substance()?;
+                let tree = db.resolve_treeish(&tree)?;
+                let config = RestoreConfig {
+                    only: only.clone(),
+                    ignore_missing: *ignore_missing,
+                    verify: verify.unwrap_or_else(|| substance.verify_by_default()),
+                    progress: if *progress {
+                        Some(Arc::new(RestoreProgress::default()))
+                    } else {
+                        None
+                    },
+                };
+                match &config.progress {
+                    None => db.restore(tree, &substance, target, &config)?,
+                    Some(progress) => {
+                        let progress = progress.clone();
+                        with_progress_line(
+                            move || render_restore_progress(progress.counts(), progress.totals()),
+                            || db.restore(tree, &substance, target, &config),
+                        )?
+                    }
+                }
             }
             Command::Remove {
                 big_tree,
@@ -186,7 +887,879 @@ impl Args {
                 assert_eq!(mode, &format!("{:06o}", u32::from(FileMode::Tree)));
                 db.add_to_index(FileMode::Tree, tree, relative_path)?;
             }
+            Command::ReadTree { tree } => {
+                let db = self.database()?;
+                let tree = db.resolve_treeish(&tree)?;
+                db.read_tree(tree)?;
+            }
+            Command::WriteTree => {
+                let db = self.database()?;
+                println!("{}", db.write_tree()?);
+            }
+            Command::ExportGitFastImport {
+                commit,
+                refname,
+                verify,
+            } => {
+                let db = self.database()?;
+                let substance = self.substance()?;
+                let commit = db.repository().revparse_single(commit)?.peel_to_commit()?;
+                let verify = verify.unwrap_or_else(|| substance.verify_by_default());
+                let stdout = io::stdout();
+                let mut out = BufWriter::new(stdout.lock());
+                db.export_git_fast_import(&substance, commit.id(), refname, verify, &mut out)?;
+                out.flush()?;
+            }
+            Command::Repack { aggressive } => {
+                let db = self.database()?;
+                let (before, after) = db.repack(*aggressive)?;
+                println!("before: {}", before);
+                println!("after: {}", after);
+            }
+            Command::Fsck { connectivity } => {
+                ensure!(*connectivity, "no checks selected (try --connectivity)");
+                let db = self.database()?;
+                let mut missing = 0;
+                db.fsck_connectivity(|object| {
+                    println!("{}", object);
+                    missing += 1;
+                    Ok(())
+                })?;
+                ensure!(missing == 0, "{} missing objects", missing);
+            }
+            Command::Stats {
+                tree,
+                max_file_count,
+                dedup_trees,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
+                let tree = db.resolve_treeish(&tree)?;
+                let stats = db.stats(tree)?;
+                println!("trees: {}", stats.trees);
+                println!("files: {}", stats.shadows);
+                println!("links: {}", stats.links);
+                if *dedup_trees {
+                    let shared = stats.shared_trees();
+                    let redundant = shared.iter().map(|(_, count, _)| count - 1).sum::<u64>();
+                    println!("distinct trees: {}", stats.distinct_trees());
+                    println!(
+                        "shared trees: {} ({} redundant occurrences)",
+                        shared.len(),
+                        redundant
+                    );
+                    for (oid, count, path) in shared {
+                        println!("{} {} {}", count, oid, path);
+                    }
+                }
+            }
+            Command::Size {
+                tree,
+                max_file_count,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
+                let substance = self.substance()?;
+                let tree = db.resolve_treeish(&tree)?;
+                let size = db.stored_size(tree, &substance)?;
+                println!("blobs: {}", size.blobs);
+                println!("stored bytes: {}", size.stored_bytes);
+                println!("logical bytes: {}", size.logical_bytes);
+                if size.missing > 0 {
+                    println!("missing blobs: {}", size.missing);
+                }
+            }
+            Command::Verify {
+                tree,
+                max_file_count,
+                deep,
+                json,
+            } => {
+                let mut db = self.database()?;
+                db.set_max_file_count(*max_file_count);
+                let substance = self.substance()?;
+                let tree = db.resolve_treeish(&tree)?;
+                let checks = BlobChecks {
+                    missing: true,
+                    invalid: *deep,
+                };
+                let report = db.verify(tree, &substance, &checks)?;
+                if *json {
+                    println!("{}", report.to_json());
+                } else {
+                    for (path, problem) in &report.tree_problems {
+                        println!("{}: {}", problem, path);
+                    }
+                    for (path, blob, problem) in &report.blob_problems {
+                        println!("{}: {} {}", problem, blob, path);
+                    }
+                    println!(
+                        "{} trees, {} files ({} distinct blobs), {} links",
+                        report.trees, report.files, report.blobs, report.links
+                    );
+                }
+                ensure!(
+                    report.ok(),
+                    "{} problems found",
+                    report.tree_problems.len() + report.blob_problems.len()
+                );
+            }
         }
         Ok(())
     }
 }
+
+// A handle on `fd` which can be closed without closing `fd` itself.
+fn progress_file(fd: RawFd) -> Result<File> {
+    let dup = unsafe { libc::dup(fd) };
+    ensure!(
+        dup >= 0,
+        "--progress-fd {}: {}",
+        fd,
+        io::Error::last_os_error()
+    );
+    Ok(unsafe { File::from_raw_fd(dup) })
+}
+
+// The number of files in a snapshot and their total size.
+fn walk_counts(snapshot: &Snapshot) -> Result<(u64, u64)> {
+    let mut counts = (0, 0);
+    let mut entries = snapshot.entries()?;
+    while let Some(entry) = entries.next()? {
+        if let SnapshotEntryValue::File { shadow, .. } = entry.value {
+            counts.0 += 1;
+            counts.1 += shadow.size().unwrap_or(0);
+        }
+    }
+    Ok(counts)
+}
+
+// Both paths must exist, and are compared once links are resolved.
+fn ensure_expected_subject(subject: &Path, expected: &Path) -> Result<()> {
+    let resolve = |path: &Path| {
+        path.canonicalize()
+            .with_context(|| format!("resolving {}", path.display()))
+    };
+    ensure!(
+        resolve(subject)? == resolve(expected)?,
+        "subject {} is not {}",
+        subject.display(),
+        expected.display()
+    );
+    Ok(())
+}
+
+fn ensure_file_count(files: u64, min: Option<u64>, max: Option<u64>) -> Result<()> {
+    if let Some(min) = min {
+        ensure!(
+            files >= min,
+            "the snapshot has {} files, fewer than the minimum of {}",
+            files,
+            min
+        );
+    }
+    if let Some(max) = max {
+        ensure!(
+            files <= max,
+            "the snapshot has {} files, more than the maximum of {}",
+            files,
+            max
+        );
+    }
+    Ok(())
+}
+
+// The commit which a new big tree goes on top of: that at `staging_ref` (or HEAD if it does not
+// exist yet), or HEAD.
+fn parent_commit<'a>(db: &'a Database, staging_ref: Option<&str>) -> Result<Option<Commit<'a>>> {
+    match staging_ref {
+        None => db.head_commit(),
+        Some(staging_ref) => db.ref_or_head_commit(staging_ref),
+    }
+}
+
+// Commit `big_tree` on top of `parent`, which came from `parent_commit`, and either merge it into
+// HEAD or advance `staging_ref`.
+fn commit_big_tree(
+    db: &Database,
+    parent: Option<&Commit>,
+    staging_ref: Option<&str>,
+    message: &str,
+    big_tree: Oid,
+    dates: &CommitDates,
+) -> Result<Oid> {
+    Ok(match staging_ref {
+        None => {
+            let commit = db.commit_simple(
+                message,
+                &db.repository().find_tree(big_tree)?,
+                &parent.into_iter().collect::<Vec<_>>(),
+                dates,
+            )?;
+            log::info!("new commit is {}. merging --ff-only into HEAD", commit);
+            db.safe_merge(commit)?;
+            commit
+        }
+        Some(staging_ref) => {
+            let commit = db.commit_to_ref(staging_ref, message, big_tree, dates)?;
+            log::info!("new commit is {}. advanced {}", commit, staging_ref);
+            commit
+        }
+    })
+}
+
+// Content hashes, one per line, from `path`, or from stdin if `path` is "-". Empty lines are
+// skipped.
+fn read_hash_list(path: &Path) -> Result<Vec<ContentSha256>> {
+    let content = if path == Path::new("-") {
+        let mut content = String::new();
+        io::stdin()
+            .read_to_string(&mut content)
+            .context("reading hashes from stdin")?;
+        content
+    } else {
+        fs::read_to_string(path)
+            .with_context(|| format!("reading hashes from {}", path.display()))?
+    };
+    parse_hash_list(&content)
+}
+
+fn parse_hash_list(content: &str) -> Result<Vec<ContentSha256>> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            line.trim()
+                .parse()
+                .map_err(|_| anyhow!("line {}: invalid content hash '{}'", i + 1, line))
+        })
+        .collect()
+}
+
+fn snapshot_config(
+    hash_cache: &Option<PathBuf>,
+    exclude: &[String],
+    exclude_from: &[PathBuf],
+    exclude_vcs: bool,
+) -> Result<SnapshotConfig> {
+    let mut filter = SnapshotFilter {
+        exclude: exclude.to_vec(),
+    };
+    if exclude_vcs {
+        filter.exclude_vcs();
+    }
+    for path in exclude_from {
+        filter.exclude_from(path)?;
+    }
+    Ok(SnapshotConfig {
+        hash_cache: hash_cache.clone(),
+        filter,
+        root: None,
+    })
+}
+
+struct DiffOutput {
+    color: ColorWhen,
+    raw: bool,
+    ignore_mode: bool,
+    summary_only: bool,
+}
+
+fn print_diff(
+    db: &Database,
+    tree_a: Oid,
+    tree_b: Oid,
+    path: &ShadowPath,
+    output: &DiffOutput,
+) -> Result<()> {
+    if output.raw {
+        let mut stdout = BufWriter::new(io::stdout());
+        db.shallow_diff_at(tree_a, tree_b, path, |difference| {
+            if output.ignore_mode && difference.kind.is_mode_only() {
+                return Ok(());
+            }
+            writeln!(stdout, "{}", difference.render_raw()?)?;
+            Ok(())
+        })?;
+        stdout.flush()?;
+        return Ok(());
+    }
+    let is_tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    let mut stdout = StandardStream::stdout(color_choice(
+        output.color,
+        env::var_os("NO_COLOR").is_some(),
+        is_tty,
+    ));
+    write_diff(db, tree_a, tree_b, path, output, &mut stdout)
+}
+
+// A line per difference, or with `summary_only`, just a summary of them.
+fn write_diff(
+    db: &Database,
+    tree_a: Oid,
+    tree_b: Oid,
+    path: &ShadowPath,
+    output: &DiffOutput,
+    out: &mut impl WriteColor,
+) -> Result<()> {
+    let mut summary = DiffSummary::default();
+    db.shallow_diff_at(tree_a, tree_b, path, |difference| {
+        if output.ignore_mode && difference.kind.is_mode_only() {
+            return Ok(());
+        }
+        if output.summary_only {
+            return db.summarize_difference(&mut summary, difference);
+        }
+        let color = match difference.kind {
+            ShallowDifferenceKind::Added(_) => Color::Green,
+            ShallowDifferenceKind::Removed(_) => Color::Red,
+            ShallowDifferenceKind::Modified { .. } | ShallowDifferenceKind::TypeChanged { .. } => {
+                Color::Yellow
+            }
+        };
+        out.set_color(ColorSpec::new().set_fg(Some(color)))?;
+        writeln!(out, "{}", difference)?;
+        Ok(())
+    })?;
+    out.reset()?;
+    if output.summary_only {
+        writeln!(out, "{}", summary)?;
+    }
+    Ok(())
+}
+
+// A line as 'sha256sum --binary' prints, or 'sha256sum --tag' with `tag`.
+fn render_sha256sum(blob: &ContentSha256, path: &Path, tag: bool) -> String {
+    if tag {
+        format!("SHA256 ({}) = {}", path.display(), blob)
+    } else {
+        format!("{} *{}", blob, path.display())
+    }
+}
+
+fn color_choice(color: ColorWhen, no_color: bool, is_tty: bool) -> ColorChoice {
+    match color {
+        ColorWhen::Always => ColorChoice::Always,
+        ColorWhen::Never => ColorChoice::Never,
+        ColorWhen::Auto if no_color || !is_tty => ColorChoice::Never,
+        ColorWhen::Auto => ColorChoice::Auto,
+    }
+}
+
+fn preview_snapshot(snapshot: &Snapshot) -> Result<()> {
+    let (mut trees, mut files, mut links, mut bytes) = (0, 0, 0, 0);
+    let mut entries = snapshot.entries()?;
+    while let Some(entry) = entries.next()? {
+        match &entry.value {
+            SnapshotEntryValue::Tree => {
+                trees += 1;
+                println!("d {}", entry.path);
+            }
+            SnapshotEntryValue::File { shadow, .. } => {
+                files += 1;
+                bytes += shadow.size().unwrap_or(0);
+                println!("f {} {}", shadow.content_hash(), entry.path);
+            }
+            SnapshotEntryValue::Link { target } => {
+                links += 1;
+                println!("l {} -> {}", entry.path, target);
+            }
+        }
+    }
+    println!(
+        "{} directories, {} files ({} bytes), {} links",
+        trees, files, bytes, links
+    );
+    Ok(())
+}
+
+// Trees in a keep repository hold shadows rather than content, so a working tree checked out from
+// one would be garbage. Say so rather than passing on libgit2's "could not find repository".
+fn open_bare_repository(git_dir: &Path) -> Result<Repository> {
+    match Repository::open_bare(git_dir) {
+        Ok(repository) => Ok(repository),
+        Err(err) => {
+            if Repository::open(git_dir).map_or(false, |repository| !repository.is_bare()) {
+                bail!(
+                    "{} is a non-bare git repository, but keep requires a bare one (create one with 'git init --bare DIR')",
+                    git_dir.display()
+                );
+            }
+            Err(anyhow::Error::from(err)
+                .context(format!("opening git repository {}", git_dir.display())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+    use crate::testing::{TempDir, TestRepo};
+
+    #[test]
+    fn sha256sum_styles() {
+        let blob = ContentSha256::from_hex(&"ab".repeat(32)).unwrap();
+        let path = Path::new("d/f");
+        assert_eq!(
+            render_sha256sum(&blob, path, false),
+            format!("{} *d/f", "ab".repeat(32))
+        );
+        assert_eq!(
+            render_sha256sum(&blob, path, true),
+            format!("SHA256 (d/f) = {}", "ab".repeat(32))
+        );
+    }
+
+    #[test]
+    fn diff_summary_only() {
+        let repo = TestRepo::new();
+        let tree_a = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree_b = repo.add_file(repo.empty_tree(), "b", b"bb\n");
+        let diff = |summary_only| {
+            let output = DiffOutput {
+                color: ColorWhen::Never,
+                raw: false,
+                ignore_mode: false,
+                summary_only,
+            };
+            let mut out = termcolor::NoColor::new(vec![]);
+            write_diff(
+                &repo.db,
+                tree_a,
+                tree_b,
+                &ShadowPath::new(),
+                &output,
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out.into_inner()).unwrap()
+        };
+        let summary = "1 added, 1 removed, 0 modified, 0 type changed, +1 bytes\n";
+        let full = diff(false);
+        assert_eq!(full.lines().count(), 2);
+        assert!(!full.contains(summary));
+        assert_eq!(diff(true), summary);
+    }
+
+    #[test]
+    fn diff_ignore_mode() {
+        let repo = TestRepo::new();
+        let tree_a = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree_a = repo.add_file(tree_a, "b", b"b\n");
+        let a = repo
+            .db
+            .lookup(tree_a, &"a".parse().unwrap())
+            .unwrap()
+            .unwrap()
+            .id();
+        let tree_b = repo.add_object(repo.empty_tree(), "a", FileMode::BlobExecutable, a);
+        let tree_b = repo.add_file(tree_b, "b", b"bb\n");
+        let diff = |flags: &[&str]| {
+            let mut argv = vec!["", "--git-dir", "x", "diff"];
+            argv.extend(flags);
+            argv.extend(&["A", "B"]);
+            let output = match Args::get_from(argv).unwrap().command {
+                Command::Diff {
+                    ignore_mode,
+                    summary_only,
+                    ..
+                } => DiffOutput {
+                    color: ColorWhen::Never,
+                    raw: false,
+                    ignore_mode,
+                    summary_only,
+                },
+                _ => panic!(),
+            };
+            let mut out = termcolor::NoColor::new(vec![]);
+            write_diff(
+                &repo.db,
+                tree_a,
+                tree_b,
+                &ShadowPath::new(),
+                &output,
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out.into_inner()).unwrap()
+        };
+        assert_eq!(diff(&[]).lines().count(), 2);
+        assert_eq!(diff(&["--ignore-mode"]).lines().count(), 1);
+        assert_eq!(
+            diff(&["--ignore-mode", "--summary-only"]),
+            "0 added, 0 removed, 1 modified, 0 type changed, +1 bytes\n"
+        );
+    }
+
+    // Needs /dev/fuse and fusermount.
+    #[test]
+    #[ignore]
+    fn mount_background() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let commit = repo.commit(tree, &[]);
+        let mountpoint = repo.dir.path().join("mnt");
+        fs::create_dir(&mountpoint).unwrap();
+        let pid_file = repo.dir.path().join("pid");
+        let path = |path: &Path| path.to_str().unwrap().to_owned();
+        Args::get_from(vec![
+            "".to_owned(),
+            "--git-dir".to_owned(),
+            path(repo.db.repository().path()),
+            "--substance-dir".to_owned(),
+            path(&repo.dir.path().join("substance")),
+            "mount".to_owned(),
+            "--background".to_owned(),
+            "--pid-file".to_owned(),
+            path(&pid_file),
+            path(&mountpoint),
+            commit.to_string(),
+        ])
+        .unwrap()
+        .run_command()
+        .unwrap();
+
+        let pid: libc::pid_t = fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim_end()
+            .parse()
+            .unwrap();
+        let alive = unsafe { libc::kill(pid, 0) } == 0;
+        let content = fs::read(mountpoint.join("a"));
+        let unmounted = std::process::Command::new("fusermount")
+            .arg("-u")
+            .arg(&mountpoint)
+            .status();
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert!(alive);
+        assert_eq!(content.unwrap(), b"a\n");
+        assert!(unmounted.unwrap().success());
+    }
+
+    #[test]
+    fn progress_json() {
+        let repo = TestRepo::new();
+        fs::write(repo.subject().join("a"), b"hello\n").unwrap();
+        fs::write(repo.subject().join("b"), b"bye\n").unwrap();
+        let events_path = repo.dir.path().join("events");
+        let events = File::create(&events_path).unwrap();
+        let path = |path: &Path| path.to_str().unwrap().to_owned();
+        let args = Args::get_from(vec![
+            "".to_owned(),
+            "--git-dir".to_owned(),
+            path(repo.db.repository().path()),
+            "--substance-dir".to_owned(),
+            path(&repo.dir.path().join("substance")),
+            "snapshot".to_owned(),
+            "--snapshot-dir".to_owned(),
+            path(&repo.dir.path().join("snapshot")),
+            "--no-merge".to_owned(),
+            "--ref".to_owned(),
+            "refs/heads/staging".to_owned(),
+            "--progress-json".to_owned(),
+            "--progress-fd".to_owned(),
+            events.as_raw_fd().to_string(),
+            path(&repo.subject()),
+            "s".to_owned(),
+        ])
+        .unwrap();
+        args.run_command().unwrap();
+
+        let events = fs::read_to_string(&events_path).unwrap();
+        let lines = events.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], r#"{"phase":"walk","files":2,"bytes":10}"#);
+        assert_eq!(lines[1], r#"{"phase":"store","stored":0,"skipped":0}"#);
+        assert_eq!(
+            lines[lines.len() - 2],
+            r#"{"phase":"store","stored":2,"skipped":0}"#
+        );
+        assert_eq!(lines[lines.len() - 1], r#"{"phase":"done"}"#);
+    }
+
+    #[test]
+    fn snapshot_dry_run() {
+        let repo = TestRepo::new();
+        fs::write(repo.subject().join("a"), b"a\n").unwrap();
+        fs::create_dir(repo.subject().join("d")).unwrap();
+        fs::write(repo.subject().join("d/b"), b"b\n").unwrap();
+        let objects = || {
+            let mut count = 0;
+            repo.db
+                .repository()
+                .odb()
+                .unwrap()
+                .foreach(|_| {
+                    count += 1;
+                    true
+                })
+                .unwrap();
+            count
+        };
+        let (objects_before, blobs_before) = (objects(), repo.substance.list_blobs().unwrap());
+
+        let path = |path: &Path| path.to_str().unwrap().to_owned();
+        Args::get_from(vec![
+            "".to_owned(),
+            "--git-dir".to_owned(),
+            path(repo.db.repository().path()),
+            "--substance-dir".to_owned(),
+            path(&repo.dir.path().join("substance")),
+            "snapshot".to_owned(),
+            "--snapshot-dir".to_owned(),
+            path(&repo.dir.path().join("snapshot")),
+            "--rm".to_owned(),
+            "--dry-run".to_owned(),
+            path(&repo.subject()),
+            "s".to_owned(),
+        ])
+        .unwrap()
+        .run_command()
+        .unwrap();
+
+        assert_eq!(objects(), objects_before);
+        assert_eq!(repo.substance.list_blobs().unwrap(), blobs_before);
+        assert!(repo.db.head_commit().unwrap().is_none());
+        assert!(!repo.dir.path().join("snapshot").exists());
+    }
+
+    #[test]
+    fn snapshot_guards() {
+        let repo = TestRepo::new();
+        fs::write(repo.subject().join("a"), b"a\n").unwrap();
+        fs::write(repo.subject().join("b"), b"b\n").unwrap();
+        let path = |path: &Path| path.to_str().unwrap().to_owned();
+        let snapshot = |guard: &[&str]| {
+            let mut args = vec![
+                "".to_owned(),
+                "--git-dir".to_owned(),
+                path(repo.db.repository().path()),
+                "--substance-dir".to_owned(),
+                path(&repo.dir.path().join("substance")),
+                "snapshot".to_owned(),
+                "--snapshot-dir".to_owned(),
+                path(&repo.dir.path().join("snapshot")),
+                "--rm".to_owned(),
+                "--no-merge".to_owned(),
+                "--ref".to_owned(),
+                "refs/heads/staging".to_owned(),
+            ];
+            args.extend(guard.iter().map(ToString::to_string));
+            args.extend(vec![path(&repo.subject()), "s".to_owned()]);
+            Args::get_from(args).unwrap().run_command()
+        };
+
+        let err = snapshot(&["--expect-subject", &path(repo.dir.path())]).unwrap_err();
+        assert!(err.to_string().starts_with("subject "));
+        let err = snapshot(&["--min-files", "3"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the snapshot has 2 files, fewer than the minimum of 3"
+        );
+        assert!(repo.db.ref_commit("refs/heads/staging").unwrap().is_none());
+
+        let subject = repo.subject().join(".");
+        snapshot(&["--expect-subject", &path(&subject), "--min-files", "2"]).unwrap();
+        assert!(repo.db.ref_commit("refs/heads/staging").unwrap().is_some());
+    }
+
+    #[test]
+    fn check_blobs_exit_code() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.add_unstored_file(tree, "b", b"b\n");
+        let commit = repo.commit(tree, &[]);
+        let git_dir = repo.db.repository().path().to_str().unwrap().to_owned();
+        let substance_dir = repo.dir.path().join("substance");
+        let check_blobs = |tree: &str| {
+            Args::get_from(vec![
+                "",
+                "--git-dir",
+                git_dir.as_str(),
+                "--substance-dir",
+                substance_dir.to_str().unwrap(),
+                "check-blobs",
+                "--format",
+                "json",
+                tree,
+            ])
+            .unwrap()
+            .run_command()
+        };
+
+        assert!(check_blobs(&repo.empty_tree().to_string()).is_ok());
+        let err = check_blobs(&commit.to_string()).unwrap_err();
+        assert_eq!(err.to_string(), "1 problems found");
+        assert_eq!(cli_exit_code(&err), 1);
+        let err = check_blobs("refs/heads/missing").unwrap_err();
+        assert_eq!(cli_exit_code(&err), 2);
+    }
+
+    #[test]
+    fn check_blobs_from_file() {
+        let repo = TestRepo::new();
+        let (stored, src) = repo.write_subject_file("a", b"a\n");
+        repo.substance.store(stored.content_hash(), &src).unwrap();
+        let (missing, _) = repo.write_subject_file("b", b"b\n");
+        let list = repo.dir.path().join("hashes");
+        let check_blobs = |hashes: String| {
+            fs::write(&list, hashes).unwrap();
+            Args::get_from(vec![
+                "",
+                "--substance-dir",
+                repo.dir.path().join("substance").to_str().unwrap(),
+                "check-blobs",
+                "--from-file",
+                list.to_str().unwrap(),
+            ])
+            .unwrap()
+            .run_command()
+        };
+
+        assert!(check_blobs(format!("{}\n", stored.content_hash())).is_ok());
+        let err = check_blobs(format!(
+            "{}\n\n{}\n",
+            stored.content_hash(),
+            missing.content_hash()
+        ))
+        .unwrap_err();
+        assert_eq!(err.to_string(), "1 problems found");
+
+        let err = parse_hash_list(&format!("{}\nxyz\n", stored.content_hash())).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid content hash 'xyz'");
+    }
+
+    #[test]
+    fn non_bare_repository() {
+        let dir = TempDir::new();
+        Repository::init(dir.path()).unwrap();
+        let err = open_bare_repository(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("non-bare"));
+
+        Repository::init_bare(dir.path().join("bare")).unwrap();
+        assert!(open_bare_repository(&dir.path().join("bare")).is_ok());
+        assert!(open_bare_repository(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn select_color_choice() {
+        for (color, no_color, is_tty, expected) in [
+            (ColorWhen::Always, false, false, ColorChoice::Always),
+            (ColorWhen::Always, true, true, ColorChoice::Always),
+            (ColorWhen::Never, false, true, ColorChoice::Never),
+            (ColorWhen::Auto, false, true, ColorChoice::Auto),
+            (ColorWhen::Auto, false, false, ColorChoice::Never),
+            (ColorWhen::Auto, true, true, ColorChoice::Never),
+        ] {
+            assert_eq!(color_choice(color, no_color, is_tty), expected);
+        }
+    }
+
+    #[test]
+    fn ls_substance_verify() {
+        let repo = TestRepo::new();
+        repo.add_file(repo.empty_tree(), "a", b"a\n");
+        repo.add_file(repo.empty_tree(), "b", b"b\n");
+        let blob = &repo.substance.list_blobs().unwrap()[0];
+        let blob_path = repo.substance.blob_path(blob);
+        fs::remove_file(&blob_path).unwrap();
+        fs::write(&blob_path, b"corrupt\n").unwrap();
+
+        let path = |path: &Path| path.to_str().unwrap().to_owned();
+        let ls_substance = |verify: bool| {
+            let mut argv = vec![
+                "".to_owned(),
+                "--substance-dir".to_owned(),
+                path(&repo.dir.path().join("substance")),
+                "ls-substance".to_owned(),
+            ];
+            if verify {
+                argv.push("--verify".to_owned());
+            }
+            Args::get_from(argv).unwrap().run_command()
+        };
+        ls_substance(false).unwrap();
+        let err = ls_substance(true).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CheckFailure>(),
+            Some(CheckFailure::Problems(1))
+        ));
+    }
+
+    #[test]
+    fn check_max_errors() {
+        let repo = TestRepo::new();
+        let tree = repo.add_object(repo.empty_tree(), "a", FileMode::Tree, repo.empty_tree());
+        let tree = repo.add_object(tree, "b", FileMode::Tree, repo.empty_tree());
+        let path = |path: &Path| path.to_str().unwrap().to_owned();
+        let check = |max_errors: &str| {
+            Args::get_from(vec![
+                "".to_owned(),
+                "--git-dir".to_owned(),
+                path(repo.db.repository().path()),
+                "check".to_owned(),
+                "--no-empty-dirs".to_owned(),
+                "--max-errors".to_owned(),
+                max_errors.to_owned(),
+                tree.to_string(),
+            ])
+            .unwrap()
+            .run_command()
+            .unwrap_err()
+        };
+        assert!(matches!(
+            check("1").downcast_ref::<CheckFailure>(),
+            Some(CheckFailure::Stopped(1))
+        ));
+        // Exactly as many problems as allowed, so the check is complete.
+        assert!(matches!(
+            check("2").downcast_ref::<CheckFailure>(),
+            Some(CheckFailure::Problems(2))
+        ));
+    }
+
+    #[test]
+    fn snapshot_no_store() {
+        let repo = TestRepo::new();
+        fs::write(repo.subject().join("a"), b"a\n").unwrap();
+        fs::write(repo.subject().join("b"), b"b\n").unwrap();
+        let path = |path: &Path| path.to_str().unwrap().to_owned();
+        let run = |args: &[&str]| {
+            let mut argv = vec![
+                "".to_owned(),
+                "--git-dir".to_owned(),
+                path(repo.db.repository().path()),
+                "--substance-dir".to_owned(),
+                path(&repo.dir.path().join("substance")),
+            ];
+            argv.extend(args.iter().map(|arg| arg.to_string()));
+            Args::get_from(argv).unwrap().run_command().unwrap();
+        };
+        run(&[
+            "snapshot",
+            "--snapshot-dir",
+            &path(&repo.dir.path().join("snapshot")),
+            "--no-merge",
+            "--ref",
+            "refs/heads/staging",
+            "--no-store",
+            &path(&repo.subject()),
+            "s",
+        ]);
+        let staging = repo.db.ref_commit("refs/heads/staging").unwrap().unwrap();
+        assert!(repo
+            .db
+            .lookup(staging.tree_id(), &"s/a".parse().unwrap())
+            .unwrap()
+            .is_some());
+        assert!(repo.substance.list_blobs().unwrap().is_empty());
+
+        // The commit is not in the history of HEAD.
+        run(&["store-pending"]);
+        assert_eq!(repo.substance.list_blobs().unwrap().len(), 2);
+    }
+}
@@ -4,6 +4,21 @@ use git2::{FileMode, Oid};
 use crate::{Database, ShadowPath, ShadowPathComponent, ShadowTreeEntryName};
 
 impl Database {
+    // Whether `path` in `big_tree` is already `object` with `mode`, so that appending it again
+    // would change nothing.
+    pub fn is_appended(
+        &self,
+        big_tree: Oid,
+        path: &ShadowPath,
+        mode: FileMode,
+        object: Oid,
+    ) -> Result<bool> {
+        Ok(match self.lookup(big_tree, path)? {
+            Some(entry) => entry.id() == object && entry.filemode() == i32::from(mode),
+            None => false,
+        })
+    }
+
     pub fn append(
         &self,
         big_tree: Oid,
@@ -80,3 +95,75 @@ impl Database {
         Ok(builder.write()?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+    use crate::CheckPolicy;
+
+    #[test]
+    fn append_identical() {
+        let repo = TestRepo::new();
+        let snapshot = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let other = repo.add_file(repo.empty_tree(), "b", b"b\n");
+        let path = "hosts/x".parse().unwrap();
+        let big_tree = repo
+            .db
+            .append(repo.empty_tree(), &path, FileMode::Tree, snapshot, false)
+            .unwrap();
+
+        assert!(repo
+            .db
+            .is_appended(big_tree, &path, FileMode::Tree, snapshot)
+            .unwrap());
+        assert!(!repo
+            .db
+            .is_appended(big_tree, &path, FileMode::Tree, other)
+            .unwrap());
+        assert!(!repo
+            .db
+            .is_appended(
+                big_tree,
+                &"hosts/y".parse().unwrap(),
+                FileMode::Tree,
+                snapshot
+            )
+            .unwrap());
+        assert!(repo
+            .db
+            .append(big_tree, &path, FileMode::Tree, other, false)
+            .is_err());
+    }
+
+    #[test]
+    fn append_deep() {
+        let repo = TestRepo::new();
+        let snapshot = repo.add_file(repo.empty_tree(), "x", b"x\n");
+        let tree = repo.add_file(repo.empty_tree(), "a/y", b"y\n");
+        let path = "a/b/c/d".parse().unwrap();
+        let tree = repo
+            .db
+            .append(tree, &path, FileMode::Tree, snapshot, false)
+            .unwrap();
+
+        assert!(repo
+            .db
+            .is_appended(tree, &path, FileMode::Tree, snapshot)
+            .unwrap());
+        assert!(repo
+            .db
+            .lookup(tree, &"a/y".parse().unwrap())
+            .unwrap()
+            .is_some());
+        // Each intermediate directory has a marker.
+        let mut problems = vec![];
+        repo.db
+            .check(tree, &CheckPolicy::default(), |path, problem| {
+                problems.push((path.clone(), problem.clone()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(problems, vec![]);
+    }
+}
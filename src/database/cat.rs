@@ -0,0 +1,84 @@
+use std::io::Write;
+
+use anyhow::{anyhow, bail, Result};
+use git2::{FileMode, Oid};
+
+use crate::{Database, LinkTarget, RangeReader, Shadow, ShadowPath, Substance};
+
+impl Database {
+    // Write the content of the file at `path` in `tree`, following links within `tree`, from
+    // `offset` on, and at most `length` bytes of it. Only that range is read from `substance`. An
+    // offset at or past the end writes nothing.
+    pub fn cat(
+        &self,
+        tree: Oid,
+        path: &ShadowPath,
+        substance: &impl Substance,
+        offset: u64,
+        length: Option<u64>,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        const CHUNK_SIZE: u64 = 1 << 20;
+        let mut entry = self
+            .lookup(tree, path)?
+            .ok_or_else(|| anyhow!("'{}' does not exist", path))?;
+        if entry.filemode() == FileMode::Link.into() {
+            entry = match self.resolve_link(tree, path)? {
+                LinkTarget::Entry(resolved) => self
+                    .lookup(tree, &resolved)?
+                    .ok_or_else(|| anyhow!("'{}' is a directory", path))?,
+                unresolved => bail!("'{}' is a link which is {}", path, unresolved),
+            };
+        }
+        if entry.filemode() == FileMode::Tree.into() {
+            bail!("'{}' is a directory", path);
+        }
+        let shadow = Shadow::from_bytes(self.repository().find_blob(entry.id())?.content())?;
+        let reader = substance.open_range_reader(shadow.content_hash())?;
+        let (mut offset, mut remaining) = (offset, length.unwrap_or(u64::MAX));
+        while remaining > 0 {
+            let data = reader.read_range(offset, remaining.min(CHUNK_SIZE) as usize)?;
+            if data.is_empty() {
+                break;
+            }
+            out.write_all(&data)?;
+            offset += data.len() as u64;
+            remaining -= data.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn range() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "d/f", b"0123456789");
+        let tree = repo.add_link(tree, "l", "d/f");
+
+        let cat = |path: &str, offset: u64, length: Option<u64>| {
+            let mut out = vec![];
+            repo.db
+                .cat(
+                    tree,
+                    &path.parse().unwrap(),
+                    &repo.substance,
+                    offset,
+                    length,
+                    &mut out,
+                )
+                .map(|()| out)
+        };
+        assert_eq!(cat("d/f", 0, None).unwrap(), b"0123456789");
+        assert_eq!(cat("d/f", 3, Some(4)).unwrap(), b"3456");
+        assert_eq!(cat("l", 8, Some(100)).unwrap(), b"89");
+        assert_eq!(cat("d/f", 10, None).unwrap(), b"");
+        assert_eq!(cat("d/f", 1000, Some(1)).unwrap(), b"");
+        assert!(cat("d", 0, None).is_err());
+        assert!(cat("missing", 0, None).is_err());
+    }
+}
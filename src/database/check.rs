@@ -0,0 +1,301 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use git2::{FileMode, ObjectType, Oid};
+
+use super::traverse::{MaxFileCount, OnUniqueBlobs, StopTraversal};
+use crate::{
+    Database, ShadowPath, ShadowTreeEntryName, TraversalCallbacks, Visit, VisitLink, VisitShadow,
+    VisitTree, VisitTreeDecision,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct CheckPolicy {
+    pub no_empty_dirs: bool,
+    // Stop traversing on finding a problem once this many have been reported.
+    pub max_problems: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckProblem {
+    MissingMarker,
+    MalformedMarker,
+    NonCanonicalMarker { oid: Oid },
+    EmptyDirectory,
+}
+
+impl fmt::Display for CheckProblem {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingMarker => write!(fmt, "missing marker"),
+            Self::MalformedMarker => write!(fmt, "malformed marker"),
+            Self::NonCanonicalMarker { oid } => {
+                write!(fmt, "marker {} is not the canonical empty blob", oid)
+            }
+            Self::EmptyDirectory => write!(fmt, "empty directory"),
+        }
+    }
+}
+
+impl Database {
+    // Trees whose marker is bad are reported and not descended into. Other malformations are
+    // fatal. Each tree is checked at every path it appears at, but each shadow and link only once.
+    // Returns the number of entries checked, and whether the check stopped short of the whole tree
+    // because of `max_problems`.
+    pub fn check(
+        &self,
+        tree: Oid,
+        policy: &CheckPolicy,
+        on_problem: impl FnMut(&ShadowPath, &CheckProblem) -> Result<()>,
+    ) -> Result<(u64, bool)> {
+        let callbacks = CheckCallbacks {
+            policy,
+            empty_blob_oid: Oid::hash_object(ObjectType::Blob, &[])?,
+            on_problem,
+            checked: 0,
+            problems: 0,
+            stopped: false,
+        };
+        let mut callbacks = MaxFileCount::new(OnUniqueBlobs::new(callbacks), self.max_file_count);
+        self.traverser(&mut callbacks).traverse(tree)?;
+        let callbacks = callbacks.into_inner().into_inner();
+        Ok((callbacks.checked, callbacks.stopped))
+    }
+
+    // Like `check` with the default policy, but failing on the first problem, for confirming that
+    // a freshly planted tree is sound before it is used.
+    pub fn ensure_sound(&self, tree: Oid) -> Result<u64> {
+        let (checked, _) = self.check(tree, &CheckPolicy::default(), |path, problem| {
+            bail!("'{}': {}", path, problem)
+        })?;
+        Ok(checked)
+    }
+}
+
+struct CheckCallbacks<'a, T> {
+    policy: &'a CheckPolicy,
+    empty_blob_oid: Oid,
+    on_problem: T,
+    checked: u64,
+    problems: u64,
+    stopped: bool,
+}
+
+impl<'a, T> CheckCallbacks<'a, T> {
+    fn check_tree(&self, visit: &Visit<VisitTree>) -> Result<Option<CheckProblem>> {
+        let tree = visit.read_tree()?;
+        let marker = match tree.iter().next() {
+            Some(entry) if entry.name() == Some(ShadowTreeEntryName::encode_marker().as_str()) => {
+                entry
+            }
+            _ => return Ok(Some(CheckProblem::MissingMarker)),
+        };
+        if marker.filemode() != FileMode::Blob.into() || marker.kind() != Some(ObjectType::Blob) {
+            return Ok(Some(CheckProblem::MalformedMarker));
+        }
+        if marker.id() != self.empty_blob_oid {
+            return Ok(Some(CheckProblem::NonCanonicalMarker { oid: marker.id() }));
+        }
+        if self.policy.no_empty_dirs && tree.len() == 1 {
+            return Ok(Some(CheckProblem::EmptyDirectory));
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, T: FnMut(&ShadowPath, &CheckProblem) -> Result<()>> TraversalCallbacks
+    for CheckCallbacks<'a, T>
+{
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        self.checked += 1;
+        let _ = visit.read_shadow()?;
+        Ok(())
+    }
+
+    fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+        self.checked += 1;
+        let _ = visit.read_link()?;
+        Ok(())
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        self.checked += 1;
+        Ok(match self.check_tree(visit)? {
+            None => VisitTreeDecision::Descend,
+            Some(problem) => {
+                if self.policy.max_problems == Some(self.problems) {
+                    self.stopped = true;
+                    return Err(StopTraversal.into());
+                }
+                (self.on_problem)(visit.path(), &problem)?;
+                self.problems += 1;
+                match problem {
+                    CheckProblem::EmptyDirectory => VisitTreeDecision::Descend,
+                    _ => VisitTreeDecision::Skip,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{FileMode, Oid};
+
+    use super::*;
+    use crate::testing::TestRepo;
+
+    fn problems(repo: &TestRepo, tree: Oid, policy: &CheckPolicy) -> Vec<(String, CheckProblem)> {
+        problems_and_stopped(repo, tree, policy).0
+    }
+
+    fn problems_and_stopped(
+        repo: &TestRepo,
+        tree: Oid,
+        policy: &CheckPolicy,
+    ) -> (Vec<(String, CheckProblem)>, bool) {
+        let mut problems = vec![];
+        let (_, stopped) = repo
+            .db
+            .check(tree, policy, |path, problem| {
+                problems.push((path.to_string(), problem.clone()));
+                Ok(())
+            })
+            .unwrap();
+        (problems, stopped)
+    }
+
+    fn tree_with_marker(repo: &TestRepo, marker: Option<Oid>) -> Oid {
+        let repository = repo.db.repository();
+        let mut builder = repository.treebuilder(None).unwrap();
+        if let Some(marker) = marker {
+            builder
+                .insert(
+                    ShadowTreeEntryName::Marker.encode(),
+                    marker,
+                    FileMode::Blob.into(),
+                )
+                .unwrap();
+        }
+        builder
+            .insert("0_x", repository.blob(b"").unwrap(), FileMode::Link.into())
+            .unwrap();
+        builder.write().unwrap()
+    }
+
+    #[test]
+    fn valid_marker() {
+        let repo = TestRepo::new();
+        let empty_blob_oid = repo.db.empty_blob_oid().unwrap();
+        let sub_tree = tree_with_marker(&repo, Some(empty_blob_oid));
+        let tree = repo.add_object(repo.empty_tree(), "a", FileMode::Tree, sub_tree);
+        assert_eq!(problems(&repo, tree, &CheckPolicy::default()), vec![]);
+    }
+
+    #[test]
+    fn missing_marker() {
+        let repo = TestRepo::new();
+        let sub_tree = tree_with_marker(&repo, None);
+        let tree = repo.add_object(repo.empty_tree(), "a/b", FileMode::Tree, sub_tree);
+        assert_eq!(
+            problems(&repo, tree, &CheckPolicy::default()),
+            vec![("a/b".to_string(), CheckProblem::MissingMarker)]
+        );
+    }
+
+    #[test]
+    fn non_canonical_marker() {
+        let repo = TestRepo::new();
+        let oid = repo.db.repository().blob(b"x").unwrap();
+        let sub_tree = tree_with_marker(&repo, Some(oid));
+        let tree = repo.add_object(repo.empty_tree(), "a", FileMode::Tree, sub_tree);
+        assert_eq!(
+            problems(&repo, tree, &CheckPolicy::default()),
+            vec![("a".to_string(), CheckProblem::NonCanonicalMarker { oid })]
+        );
+    }
+
+    #[test]
+    fn empty_directory() {
+        let repo = TestRepo::new();
+        let tree = repo.add_object(repo.empty_tree(), "a", FileMode::Tree, repo.empty_tree());
+        assert_eq!(problems(&repo, tree, &CheckPolicy::default()), vec![]);
+        let policy = CheckPolicy {
+            no_empty_dirs: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            problems(&repo, tree, &policy),
+            vec![("a".to_string(), CheckProblem::EmptyDirectory)]
+        );
+    }
+
+    #[test]
+    fn repeated_trees() {
+        let repo = TestRepo::new();
+        let tree = repo.add_object(repo.empty_tree(), "a", FileMode::Tree, repo.empty_tree());
+        let tree = repo.add_object(tree, "b", FileMode::Tree, repo.empty_tree());
+        let sub_tree = tree_with_marker(&repo, None);
+        let tree = repo.add_object(tree, "c/x", FileMode::Tree, sub_tree);
+        let tree = repo.add_object(tree, "d/x", FileMode::Tree, sub_tree);
+        let policy = CheckPolicy {
+            no_empty_dirs: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            problems(&repo, tree, &policy),
+            vec![
+                ("a".to_string(), CheckProblem::EmptyDirectory),
+                ("b".to_string(), CheckProblem::EmptyDirectory),
+                ("c/x".to_string(), CheckProblem::MissingMarker),
+                ("d/x".to_string(), CheckProblem::MissingMarker),
+            ]
+        );
+    }
+
+    #[test]
+    fn ensure_sound() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a/b", b"b\n");
+        let tree = repo.add_link(tree, "l", "a/b");
+        assert_eq!(repo.db.ensure_sound(tree).unwrap(), 4);
+
+        let sub_tree = tree_with_marker(&repo, None);
+        let missing_marker = repo.add_object(tree, "c", FileMode::Tree, sub_tree);
+        let err = repo.db.ensure_sound(missing_marker).unwrap_err();
+        assert_eq!(err.to_string(), "'c': missing marker");
+
+        let bad_shadow = repo.db.repository().blob(b"not a shadow").unwrap();
+        let bad_shadow = repo.add_object(tree, "d", FileMode::Blob, bad_shadow);
+        assert!(repo.db.ensure_sound(bad_shadow).is_err());
+    }
+
+    #[test]
+    fn max_problems() {
+        let repo = TestRepo::new();
+        let mut tree = repo.empty_tree();
+        for name in ["a", "b", "c", "d", "e"] {
+            tree = repo.add_object(tree, name, FileMode::Tree, repo.empty_tree());
+        }
+        let mut policy = CheckPolicy {
+            no_empty_dirs: true,
+            ..Default::default()
+        };
+        assert_eq!(problems_and_stopped(&repo, tree, &policy).0.len(), 5);
+        policy.max_problems = Some(3);
+        let (problems, stopped) = problems_and_stopped(&repo, tree, &policy);
+        assert_eq!(
+            problems
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>(),
+            vec!["a", "b", "c"]
+        );
+        assert!(stopped);
+        // Exactly as many problems as allowed, with the whole tree checked.
+        policy.max_problems = Some(5);
+        let (problems, stopped) = problems_and_stopped(&repo, tree, &policy);
+        assert_eq!(problems.len(), 5);
+        assert!(!stopped);
+    }
+}
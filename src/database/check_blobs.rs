@@ -0,0 +1,138 @@
+use std::fmt;
+
+use anyhow::Result;
+use git2::Oid;
+
+use crate::{ContentSha256, Database, Shadow, ShadowPath, Substance};
+
+// Which classes of problem to look for. Checking for invalid blobs requires hashing their
+// content, whereas checking for missing blobs is cheap.
+#[derive(Clone, Debug)]
+pub struct BlobChecks {
+    pub missing: bool,
+    pub invalid: bool,
+}
+
+impl BlobChecks {
+    pub fn check(&self, substance: &impl Substance, blob: &ContentSha256) -> Option<BlobProblem> {
+        if !substance.have_blob(blob) {
+            if self.missing {
+                return Some(BlobProblem::Missing);
+            }
+        } else if self.invalid && substance.check_blob(blob).is_err() {
+            return Some(BlobProblem::Invalid);
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobProblem {
+    Missing,
+    Invalid,
+}
+
+impl fmt::Display for BlobProblem {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing => write!(fmt, "missing blob"),
+            Self::Invalid => write!(fmt, "invalid blob"),
+        }
+    }
+}
+
+impl Database {
+    // Returns the number of distinct blobs checked.
+    pub fn check_blobs(
+        &self,
+        tree: Oid,
+        substance: &impl Substance,
+        checks: &BlobChecks,
+        mut on_problem: impl FnMut(&ShadowPath, &Shadow, BlobProblem) -> Result<()>,
+    ) -> Result<u64> {
+        let mut checked = 0;
+        self.unique_shadows(tree, |path, shadow| {
+            checked += 1;
+            // TODO check size
+            if let Some(problem) = checks.check(substance, shadow.content_hash()) {
+                on_problem(path, shadow, problem)?;
+            }
+            Ok(())
+        })?;
+        Ok(checked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::testing::TestRepo;
+
+    fn problems(repo: &TestRepo, tree: Oid, missing: bool, invalid: bool) -> Vec<String> {
+        let mut problems = vec![];
+        repo.db
+            .check_blobs(
+                tree,
+                &repo.substance,
+                &BlobChecks { missing, invalid },
+                |path, _shadow, problem| {
+                    problems.push(format!("{} {}", problem, path));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        problems
+    }
+
+    #[test]
+    fn filters() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "good", b"good\n");
+        let tree = repo.add_file(tree, "corrupt", b"corrupt\n");
+        let tree = repo.add_unstored_file(tree, "missing", b"missing\n");
+
+        let (shadow, _) = repo.write_subject_file("corrupt", b"corrupt\n");
+        let blob_path = repo.substance.blob_path(shadow.content_hash());
+        fs::set_permissions(&blob_path, Permissions::from_mode(0o644)).unwrap();
+        fs::write(&blob_path, b"tampered\n").unwrap();
+
+        assert_eq!(
+            problems(&repo, tree, true, true),
+            vec!["invalid blob corrupt", "missing blob missing"]
+        );
+        assert_eq!(
+            problems(&repo, tree, true, false),
+            vec!["missing blob missing"]
+        );
+        assert_eq!(
+            problems(&repo, tree, false, true),
+            vec!["invalid blob corrupt"]
+        );
+    }
+
+    #[test]
+    fn quarantine() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "corrupt", b"corrupt\n");
+        let (shadow, _) = repo.write_subject_file("corrupt", b"corrupt\n");
+        let blob = shadow.content_hash();
+        let blob_path = repo.substance.blob_path(blob);
+        fs::set_permissions(&blob_path, Permissions::from_mode(0o644)).unwrap();
+        fs::write(&blob_path, b"tampered\n").unwrap();
+        assert_eq!(
+            problems(&repo, tree, false, true),
+            vec!["invalid blob corrupt"]
+        );
+
+        let quarantined = repo.substance.quarantine_blob(blob).unwrap();
+        assert_eq!(fs::read(&quarantined).unwrap(), b"tampered\n");
+        assert!(!repo.substance.have_blob(blob));
+        assert_eq!(
+            problems(&repo, tree, true, false),
+            vec!["missing blob corrupt"]
+        );
+    }
+}
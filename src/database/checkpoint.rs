@@ -0,0 +1,100 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use git2::Oid;
+
+use crate::ContentSha256;
+
+// A record of the blobs of a tree which have been confirmed stored, so that a resumed
+// `store_snapshot` can skip asking the substance about them again. The file starts with a line
+// naming the tree, followed by a content hash per line.
+pub(super) struct Checkpoint {
+    path: PathBuf,
+    stored: BTreeSet<ContentSha256>,
+    writer: Mutex<(BufWriter<File>, u64)>,
+}
+
+impl Checkpoint {
+    // Entries are written out after this many blobs, so that little is lost if the run is
+    // interrupted.
+    const FLUSH_EVERY: u64 = 256;
+
+    // A checkpoint for another tree is not trusted, and the file is started afresh.
+    pub(super) fn open(path: &Path, tree: Oid) -> Result<Self> {
+        let header = format!("tree {}", tree);
+        let (stored, file) = match Self::read(path, &header)? {
+            Some(stored) => (stored, OpenOptions::new().append(true).open(path)?),
+            None => {
+                let mut file = File::create(path)?;
+                writeln!(file, "{}", header)?;
+                (BTreeSet::new(), file)
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            stored,
+            writer: Mutex::new((BufWriter::new(file), 0)),
+        })
+    }
+
+    fn read(path: &Path, header: &str) -> Result<Option<BTreeSet<ContentSha256>>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        match lines.next().transpose()? {
+            Some(line) if line == header => {}
+            _ => {
+                log::warn!(
+                    "checkpoint {} is not for this tree, so starting over",
+                    path.display()
+                );
+                return Ok(None);
+            }
+        }
+        let mut stored = BTreeSet::new();
+        for line in lines {
+            // The last line may have been cut short by an interruption.
+            match line?.parse() {
+                Ok(blob) => stored.insert(blob),
+                Err(_) => break,
+            };
+        }
+        Ok(Some(stored))
+    }
+
+    pub(super) fn contains(&self, blob: &ContentSha256) -> bool {
+        self.stored.contains(blob)
+    }
+
+    pub(super) fn record(&self, blob: &ContentSha256) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let (file, unflushed) = &mut *writer;
+        writeln!(file, "{}", blob)?;
+        *unflushed += 1;
+        if *unflushed == Self::FLUSH_EVERY {
+            file.flush()?;
+            *unflushed = 0;
+        }
+        Ok(())
+    }
+
+    pub(super) fn flush(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.0.flush()?;
+        writer.1 = 0;
+        Ok(())
+    }
+
+    // Once every blob is stored, the checkpoint has served its purpose.
+    pub(super) fn remove(self) -> Result<()> {
+        fs::remove_file(&self.path)
+            .with_context(|| format!("removing checkpoint {}", self.path.display()))
+    }
+}
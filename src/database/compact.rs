@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use git2::{ErrorCode, Oid, Signature};
+
+use super::PENDING_NOTES_REF;
+use crate::Database;
+
+// The branch as it was before `compact` is kept at this prefix followed by the id of its tip.
+pub const COMPACT_BACKUP_REF_PREFIX: &str = "refs/keep/compacted/";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Compaction {
+    pub backup_ref: String,
+    // Each original commit with the commit which replaces it, oldest first. The first is `to` and
+    // the commit holding its tree in place of `from..to`.
+    pub rewritten: Vec<(Oid, Oid)>,
+}
+
+impl Database {
+    // Replace the commits `from..to` on the current branch with a single commit holding the tree
+    // of `to`, and rebuild the commits after `to` on top of it, each with its original tree,
+    // message, and dates. Notes follow the commits they were on, and the tags of the replaced
+    // commits are merged onto the new one, later values winning. The history from `from` to the
+    // tip of the branch must be linear, so that no commit is left out, and the branch as it was is
+    // kept at a backup ref. A replaced commit other than `to` must have no pending stores, as its
+    // snapshot would no longer be in the history.
+    pub fn compact(&self, from: &str, to: &str, message: Option<&str>) -> Result<Compaction> {
+        let head = self.repository().head()?;
+        ensure!(head.is_branch(), "HEAD is not on a branch");
+        let branch = head.name().unwrap().to_owned();
+        let tip = head.peel_to_commit()?;
+        let from = self.repository().revparse_single(from)?.peel_to_commit()?;
+        let to = self.repository().revparse_single(to)?.peel_to_commit()?;
+        ensure!(
+            self.repository().graph_descendant_of(to.id(), from.id())?,
+            "{} is not an ancestor of {}",
+            from.id(),
+            to.id()
+        );
+        ensure!(
+            to.id() == tip.id() || self.repository().graph_descendant_of(tip.id(), to.id())?,
+            "{} is not on {}",
+            to.id(),
+            branch
+        );
+        let range = self.range_commits(&from.id().to_string(), &to.id().to_string())?;
+        ensure!(
+            range.len() > 1,
+            "{}..{} is a single commit, so there is nothing to compact",
+            from.id(),
+            to.id()
+        );
+        let after = self.range_commits(&to.id().to_string(), &tip.id().to_string())?;
+        for commit in range.iter().chain(&after) {
+            let parents = self.repository().find_commit(*commit)?.parent_count();
+            ensure!(
+                parents == 1,
+                "{} has {} parents, so compacting would leave part of the history out",
+                commit,
+                parents
+            );
+        }
+        for commit in &range[..range.len() - 1] {
+            match self
+                .repository()
+                .find_note(Some(PENDING_NOTES_REF), *commit)
+            {
+                Ok(_) => bail!(
+                    "{} has blobs yet to be stored, so run 'store-pending' before compacting it",
+                    commit
+                ),
+                Err(err) if err.code() == ErrorCode::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let mut rewritten = vec![];
+        let compacted = self.repository().commit(
+            None,
+            &to.author(),
+            &to.committer(),
+            message.unwrap_or_else(|| to.message().unwrap_or_default()),
+            &to.tree()?,
+            &[&from],
+        )?;
+        rewritten.push((to.id(), compacted));
+        let mut parent = self.repository().find_commit(compacted)?;
+        for commit in after {
+            let commit = self.repository().find_commit(commit)?;
+            let new = self.repository().commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                commit.message().unwrap_or_default(),
+                &commit.tree()?,
+                &[&parent],
+            )?;
+            rewritten.push((commit.id(), new));
+            parent = self.repository().find_commit(new)?;
+        }
+        ensure!(
+            parent.tree_id() == tip.tree_id(),
+            "the rewritten tip does not have the tree of {}",
+            tip.id()
+        );
+
+        self.copy_notes(&range, compacted)?;
+        for (old, new) in &rewritten[1..] {
+            self.copy_notes(&[*old], *new)?;
+        }
+        let backup_ref = format!("{}{}", COMPACT_BACKUP_REF_PREFIX, tip.id());
+        self.repository()
+            .reference(&backup_ref, tip.id(), true, "keep: compact backup")?;
+        self.repository().reference_matching(
+            &branch,
+            parent.id(),
+            true,
+            tip.id(),
+            "keep: compact",
+        )?;
+        Ok(Compaction {
+            backup_ref,
+            rewritten,
+        })
+    }
+
+    // Put the notes of the commits `olds`, oldest first, on `new`.
+    fn copy_notes(&self, olds: &[Oid], new: Oid) -> Result<()> {
+        let mut tags = BTreeMap::new();
+        for old in olds {
+            tags.extend(self.tags(*old)?);
+        }
+        if !tags.is_empty() {
+            self.add_tags(new, &tags)?;
+        }
+        for old in olds {
+            let note = match self.repository().find_note(Some(PENDING_NOTES_REF), *old) {
+                Ok(note) => note,
+                Err(err) if err.code() == ErrorCode::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let message = note
+                .message()
+                .ok_or_else(|| anyhow!("note on {} is not valid utf-8", old))?;
+            let signature = Signature::now("x", "x@x")?;
+            self.repository().note(
+                &signature,
+                &signature,
+                Some(PENDING_NOTES_REF),
+                new,
+                message,
+                true,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+    use crate::ShadowPath;
+
+    #[test]
+    fn compact() {
+        let repo = TestRepo::new();
+        let mut commits = vec![];
+        let mut tree = repo.empty_tree();
+        for name in &["a", "b", "c", "d", "e"] {
+            tree = repo.add_file(tree, name, name.as_bytes());
+            commits.push(repo.commit(tree, &commits[commits.len().saturating_sub(1)..]));
+        }
+        let repository = repo.db.repository();
+        repository
+            .reference("refs/heads/main", commits[4], true, "")
+            .unwrap();
+        repository.set_head("refs/heads/main").unwrap();
+        let tags = vec![("k".to_owned(), "v".to_owned())].into_iter().collect();
+        repo.db.add_tags(commits[4], &tags).unwrap();
+        let tag = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+        let squashed_tags = vec![tag("a", "1"), tag("b", "1")].into_iter().collect();
+        repo.db.add_tags(commits[1], &squashed_tags).unwrap();
+        let to_tags = vec![tag("b", "3")].into_iter().collect();
+        repo.db.add_tags(commits[3], &to_tags).unwrap();
+
+        let compaction = repo
+            .db
+            .compact(&commits[0].to_string(), &commits[3].to_string(), None)
+            .unwrap();
+        assert_eq!(
+            compaction.backup_ref,
+            format!("{}{}", COMPACT_BACKUP_REF_PREFIX, commits[4])
+        );
+        assert_eq!(
+            repository.refname_to_id(&compaction.backup_ref).unwrap(),
+            commits[4]
+        );
+        let old = compaction
+            .rewritten
+            .iter()
+            .map(|(old, _)| *old)
+            .collect::<Vec<_>>();
+        assert_eq!(old, vec![commits[3], commits[4]]);
+
+        let head = repo.db.head_commit().unwrap().unwrap();
+        assert_eq!(head.id(), compaction.rewritten[1].1);
+        assert_eq!(head.tree_id(), tree);
+        assert_eq!(repo.db.tags(head.id()).unwrap(), tags);
+        let compacted = head.parent(0).unwrap();
+        assert_eq!(
+            compacted.tree_id(),
+            repository.find_commit(commits[3]).unwrap().tree_id()
+        );
+        assert_eq!(compacted.parent_id(0).unwrap(), commits[0]);
+        assert_eq!(
+            repo.db.tags(compacted.id()).unwrap(),
+            vec![tag("a", "1"), tag("b", "3")]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        );
+
+        // Nothing to compact.
+        let head = head.id().to_string();
+        assert!(repo.db.compact(&head, &head, None).is_err());
+    }
+
+    #[test]
+    fn compact_pending() {
+        let repo = TestRepo::new();
+        let mut commits = vec![];
+        let mut tree = repo.empty_tree();
+        for name in &["a", "b", "c"] {
+            tree = repo.add_file(tree, name, name.as_bytes());
+            commits.push(repo.commit(tree, &commits[commits.len().saturating_sub(1)..]));
+        }
+        let repository = repo.db.repository();
+        repository
+            .reference("refs/heads/main", commits[2], true, "")
+            .unwrap();
+        repository.set_head("refs/heads/main").unwrap();
+        let (from, to) = (commits[0].to_string(), commits[2].to_string());
+
+        repo.db
+            .mark_pending(commits[1], &ShadowPath::new(), &repo.subject())
+            .unwrap();
+        assert!(repo.db.compact(&from, &to, None).is_err());
+        assert_eq!(repo.db.head_commit().unwrap().unwrap().id(), commits[2]);
+
+        let signature = Signature::now("x", "x@x").unwrap();
+        repository
+            .note_delete(commits[1], Some(PENDING_NOTES_REF), &signature, &signature)
+            .unwrap();
+        repo.db
+            .mark_pending(commits[2], &ShadowPath::new(), &repo.subject())
+            .unwrap();
+        let compaction = repo.db.compact(&from, &to, None).unwrap();
+        let compacted = compaction.rewritten[0].1;
+        assert!(repository
+            .find_note(Some(PENDING_NOTES_REF), compacted)
+            .is_ok());
+    }
+}
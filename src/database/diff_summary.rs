@@ -0,0 +1,137 @@
+use std::fmt;
+use std::str;
+
+use anyhow::Result;
+use git2::{FileMode, Oid};
+
+use crate::{
+    Database, Shadow, ShadowTreeEntryName, ShallowDifference, ShallowDifferenceKind, ShallowEntry,
+    TraversalCallbacks, Visit, VisitShadow,
+};
+
+// Counts of the differences between two trees, as reported by `shallow_diff`, so an added or
+// removed directory counts once.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub added: u64,
+    pub removed: u64,
+    pub modified: u64,
+    pub type_changed: u64,
+    // The change in the sum of the sizes recorded in shadows, including those below added and
+    // removed directories. Shadows without one count as 0.
+    pub size_delta: i64,
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} added, {} removed, {} modified, {} type changed, {:+} bytes",
+            self.added, self.removed, self.modified, self.type_changed, self.size_delta
+        )
+    }
+}
+
+impl Database {
+    // Account for `difference` in `summary`. Markers are left out, as their trees are accounted
+    // for by the trees' other entries.
+    pub fn summarize_difference(
+        &self,
+        summary: &mut DiffSummary,
+        difference: &ShallowDifference,
+    ) -> Result<()> {
+        if ShadowTreeEntryName::decode(str::from_utf8(difference.name)?)?.is_marker() {
+            return Ok(());
+        }
+        let (old, new) = match difference.kind {
+            ShallowDifferenceKind::Added(new) => {
+                summary.added += 1;
+                (None, Some(new))
+            }
+            ShallowDifferenceKind::Removed(old) => {
+                summary.removed += 1;
+                (Some(old), None)
+            }
+            ShallowDifferenceKind::Modified { old, new } => {
+                summary.modified += 1;
+                (Some(old), Some(new))
+            }
+            ShallowDifferenceKind::TypeChanged { old, new } => {
+                summary.type_changed += 1;
+                (Some(old), Some(new))
+            }
+        };
+        for (entry, sign) in vec![(old, -1), (new, 1)] {
+            if let Some(entry) = entry {
+                summary.size_delta += sign * self.entry_size(&entry)? as i64;
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_size(&self, entry: &ShallowEntry) -> Result<u64> {
+        if entry.mode == i32::from(FileMode::Link) {
+            Ok(0)
+        } else if entry.mode == i32::from(FileMode::Tree) {
+            self.tree_size(entry.oid)
+        } else {
+            let blob = self.repository().find_blob(entry.oid)?;
+            Ok(Shadow::from_bytes(blob.content())?.size().unwrap_or(0))
+        }
+    }
+
+    // Repeated shadows are counted once per occurrence.
+    fn tree_size(&self, tree: Oid) -> Result<u64> {
+        struct SizeCallbacks {
+            size: u64,
+        }
+        impl TraversalCallbacks for SizeCallbacks {
+            fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+                self.size += visit.read_shadow()?.size().unwrap_or(0);
+                Ok(())
+            }
+        }
+        let mut callbacks = SizeCallbacks { size: 0 };
+        self.traverser(&mut callbacks).traverse(tree)?;
+        Ok(callbacks.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn summarize() {
+        let repo = TestRepo::new();
+        let tree_a = repo.add_file(repo.empty_tree(), "modified", b"a\n");
+        let tree_a = repo.add_file(tree_a, "removed/x", b"abc\n");
+        let tree_a = repo.add_file(tree_a, "removed/y", b"abc\n");
+        let tree_a = repo.add_file(tree_a, "retyped", b"a\n");
+        let tree_b = repo.add_file(repo.empty_tree(), "modified", b"bbbb\n");
+        let tree_b = repo.add_file(tree_b, "added", b"b\n");
+        let tree_b = repo.add_link(tree_b, "retyped", "added");
+
+        let mut summary = DiffSummary::default();
+        repo.db
+            .shallow_diff(tree_a, tree_b, |difference| {
+                repo.db.summarize_difference(&mut summary, difference)
+            })
+            .unwrap();
+        assert_eq!(
+            summary,
+            DiffSummary {
+                added: 1,
+                removed: 1,
+                modified: 1,
+                type_changed: 1,
+                size_delta: 3 + 2 - 8 - 2,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "1 added, 1 removed, 1 modified, 1 type changed, -5 bytes"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+
+use anyhow::{ensure, Context, Result};
+use git2::{FileMode, Oid, Signature, Sort};
+
+use crate::{
+    ContentSha256, Database, Substance, TraversalCallbacks, Visit, VisitLink, VisitShadow,
+};
+
+impl Database {
+    // Emit a `git fast-import` stream which recreates the history leading up to `commit` on
+    // `refname`, with the content of each shadow fetched from `substance`. With `verify`, each blob
+    // is checked against its content hash before it is emitted.
+    pub fn export_git_fast_import(
+        &self,
+        substance: &impl Substance,
+        commit: Oid,
+        refname: &str,
+        verify: bool,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let mut revwalk = self.repository().revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.push(commit)?;
+
+        let mut marks = Marks::new();
+        for oid in revwalk {
+            let commit = self.repository().find_commit(oid?)?;
+
+            let mut callbacks = ExportCallbacks {
+                substance,
+                out: &mut *out,
+                marks: &mut marks,
+                files: Vec::new(),
+                verify,
+            };
+            self.traverser(&mut callbacks).traverse(commit.tree_id())?;
+            let files = callbacks.files;
+
+            if commit.parent_count() == 0 {
+                writeln!(out, "reset {}", refname)?;
+            }
+            writeln!(out, "commit {}", refname)?;
+            writeln!(out, "mark :{}", marks.commit(commit.id()))?;
+            writeln!(out, "author {}", render_signature(&commit.author()))?;
+            writeln!(out, "committer {}", render_signature(&commit.committer()))?;
+            write_data(out, commit.message_bytes())?;
+            for (i, parent) in commit.parent_ids().enumerate() {
+                let command = if i == 0 { "from" } else { "merge" };
+                writeln!(out, "{} :{}", command, marks.commits[&parent])?;
+            }
+            writeln!(out, "deleteall")?;
+            for (mode, mark, path) in files {
+                writeln!(out, "M {:06o} :{} {}", mode, mark, quote_path(&path))?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+struct Marks {
+    next: u64,
+    blobs: BTreeMap<ContentSha256, u64>,
+    links: BTreeMap<Oid, u64>,
+    commits: BTreeMap<Oid, u64>,
+}
+
+impl Marks {
+    fn new() -> Self {
+        Self {
+            next: 1,
+            blobs: BTreeMap::new(),
+            links: BTreeMap::new(),
+            commits: BTreeMap::new(),
+        }
+    }
+
+    fn fresh(&mut self) -> u64 {
+        let mark = self.next;
+        self.next += 1;
+        mark
+    }
+
+    fn commit(&mut self, oid: Oid) -> u64 {
+        let mark = self.fresh();
+        self.commits.insert(oid, mark);
+        mark
+    }
+}
+
+struct ExportCallbacks<'a, S, W> {
+    substance: &'a S,
+    out: &'a mut W,
+    marks: &'a mut Marks,
+    files: Vec<(u32, u64, String)>,
+    verify: bool,
+}
+
+impl<'a, S: Substance, W: Write> TraversalCallbacks for ExportCallbacks<'a, S, W> {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        let shadow = visit.read_shadow()?;
+        let content_hash = shadow.content_hash();
+        let mark = match self.marks.blobs.get(content_hash) {
+            Some(mark) => *mark,
+            None => {
+                ensure!(
+                    self.substance.have_blob(content_hash),
+                    "blob {} for {} is missing from the substance",
+                    content_hash,
+                    visit.path()
+                );
+                if self.verify {
+                    self.substance
+                        .check_blob(content_hash)
+                        .with_context(|| format!("verifying the blob for '{}'", visit.path()))?;
+                }
+                let size = match shadow.size() {
+                    Some(size) => size,
+                    None => fs::metadata(self.substance.blob_path(content_hash))?.len(),
+                };
+                let mut reader = self.substance.open(content_hash)?;
+                let mark = self.marks.fresh();
+                writeln!(self.out, "blob")?;
+                writeln!(self.out, "mark :{}", mark)?;
+                writeln!(self.out, "data {}", size)?;
+                let copied = io::copy(&mut reader, &mut self.out)?;
+                ensure!(
+                    copied == size,
+                    "blob {} has {} bytes, expected {}",
+                    content_hash,
+                    copied,
+                    size
+                );
+                writeln!(self.out)?;
+                self.marks.blobs.insert(content_hash.clone(), mark);
+                mark
+            }
+        };
+        let mode = if visit.executable() {
+            FileMode::BlobExecutable
+        } else {
+            FileMode::Blob
+        };
+        self.files
+            .push((u32::from(mode), mark, visit.path().to_string()));
+        Ok(())
+    }
+
+    fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+        let mark = match self.marks.links.get(&visit.oid()) {
+            Some(mark) => *mark,
+            None => {
+                let target = visit.read_link()?;
+                let mark = self.marks.fresh();
+                writeln!(self.out, "blob")?;
+                writeln!(self.out, "mark :{}", mark)?;
+                write_data(self.out, target.as_bytes())?;
+                self.marks.links.insert(visit.oid(), mark);
+                mark
+            }
+        };
+        self.files
+            .push((u32::from(FileMode::Link), mark, visit.path().to_string()));
+        Ok(())
+    }
+}
+
+fn write_data(out: &mut impl Write, data: &[u8]) -> Result<()> {
+    writeln!(out, "data {}", data.len())?;
+    out.write_all(data)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn render_signature(signature: &Signature) -> String {
+    let when = signature.when();
+    let offset = when.offset_minutes();
+    format!(
+        "{} <{}> {} {}{:02}{:02}",
+        String::from_utf8_lossy(signature.name_bytes()),
+        String::from_utf8_lossy(signature.email_bytes()),
+        when.seconds(),
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60,
+    )
+}
+
+// See "path" under `git help fast-import`.
+fn quote_path(path: &str) -> String {
+    if !path.starts_with('"') && !path.contains(&['\n', '\\', '"'][..]) {
+        return path.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    for c in path.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn import_into_git() {
+        let repo = TestRepo::new();
+        let tree = repo.empty_tree();
+        let tree = repo.add_file(tree, "a/b.txt", b"hello\n");
+        let first = repo.commit(tree, &[]);
+        let tree = repo.add_file(tree, "a/c.txt", b"world\n");
+        let second = repo.commit(tree, &[first]);
+
+        let mut stream = Vec::new();
+        repo.db
+            .export_git_fast_import(
+                &repo.substance,
+                second,
+                "refs/heads/master",
+                true,
+                &mut stream,
+            )
+            .unwrap();
+
+        let target = repo.dir.path().join("target.git");
+        Command::new("git")
+            .arg("init")
+            .arg("--quiet")
+            .arg("--bare")
+            .arg(&target)
+            .status()
+            .unwrap()
+            .exit_ok()
+            .unwrap();
+        let mut child = Command::new("git")
+            .env("GIT_DIR", &target)
+            .arg("fast-import")
+            .arg("--quiet")
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&stream).unwrap();
+        child.wait().unwrap().exit_ok().unwrap();
+
+        let show = |spec: &str| {
+            let output = Command::new("git")
+                .env("GIT_DIR", &target)
+                .arg("show")
+                .arg(spec)
+                .output()
+                .unwrap();
+            output.status.exit_ok().unwrap();
+            output.stdout
+        };
+        assert_eq!(show("refs/heads/master:a/c.txt"), b"world\n");
+        assert_eq!(show("refs/heads/master~1:a/b.txt"), b"hello\n");
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::Result;
+use git2::{FileMode, Oid};
+use regex::Regex;
+
+use super::traverse::MaxFileCount;
+use crate::{
+    Database, ShadowPath, ShadowTreeEntryName, TraversalCallbacks, Visit, VisitLink, VisitShadow,
+    VisitTree, VisitTreeDecision,
+};
+
+// Globs selecting paths in a tree. As with snapshot excludes, a pattern containing '/' is
+// matched against the whole path, otherwise against the last component, and a trailing '/'
+// restricts a pattern to directories. Unlike snapshot excludes, which are matched by find(1),
+// '*' and '?' never match '/' while '**' does, and there are no bracket expressions or escapes.
+//
+// A path is kept if it or one of its ancestors matches an include pattern (or there are none),
+// and neither it nor any of its ancestors matches an exclude pattern.
+#[derive(Clone, Debug, Default)]
+pub struct TreeFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Database {
+    // Directories which are empty after filtering are dropped.
+    pub fn filter_tree(&self, tree: Oid, filter: &TreeFilter) -> Result<Oid> {
+        let callbacks = FilterTreeCallbacks {
+            include: filter.include.iter().map(|p| Glob::new(p)).collect(),
+            exclude: filter.exclude.iter().map(|p| Glob::new(p)).collect(),
+            kept: vec![],
+        };
+        let mut callbacks = MaxFileCount::new(callbacks, self.max_file_count);
+        self.traverser(&mut callbacks).traverse(tree)?;
+        let kept = callbacks.into_inner().kept;
+        match self.build_filtered_tree(&kept, 0, self.empty_blob_oid()?)? {
+            Some(oid) => Ok(oid),
+            None => self.empty_tree(),
+        }
+    }
+
+    // `entries` are in traversal order, so the entries below each directory are contiguous.
+    // Unlike when planting a snapshot, a directory may turn out to be empty, so its tree is
+    // only written once its entries are known.
+    fn build_filtered_tree(
+        &self,
+        entries: &[(ShadowPath, FileMode, Oid)],
+        depth: usize,
+        empty_blob_oid: Oid,
+    ) -> Result<Option<Oid>> {
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = self.repository().treebuilder(None)?;
+        builder.insert(
+            ShadowTreeEntryName::Marker.encode(),
+            empty_blob_oid,
+            FileMode::Blob.into(),
+        )?;
+        let mut rest = entries;
+        while let Some((path, mode, oid)) = rest.first() {
+            let name = &path.components()[depth];
+            if path.components().len() == depth + 1 {
+                builder.insert(name.encode(), *oid, (*mode).into())?;
+                rest = &rest[1..];
+            } else {
+                let n = rest
+                    .iter()
+                    .take_while(|(other, _, _)| &other.components()[depth] == name)
+                    .count();
+                if let Some(child) =
+                    self.build_filtered_tree(&rest[..n], depth + 1, empty_blob_oid)?
+                {
+                    builder.insert(name.encode(), child, FileMode::Tree.into())?;
+                }
+                rest = &rest[n..];
+            }
+        }
+        Ok(Some(builder.write()?))
+    }
+}
+
+struct Glob {
+    regex: Regex,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Glob {
+    fn new(pattern: &str) -> Self {
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    regex.push_str(".*");
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                _ => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Self {
+            regex: Regex::new(&regex).unwrap(),
+            anchored,
+            dir_only,
+        }
+    }
+
+    fn matches(&self, path: &ShadowPath, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.regex.is_match(&path.to_string())
+        } else {
+            match path.components().last() {
+                Some(name) => self.regex.is_match(name.as_ref()),
+                None => false,
+            }
+        }
+    }
+}
+
+struct FilterTreeCallbacks {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    kept: Vec<(ShadowPath, FileMode, Oid)>,
+}
+
+impl FilterTreeCallbacks {
+    // Ancestors need not be checked against exclude patterns, since excluded directories are
+    // not descended into.
+    fn keep(&self, path: &ShadowPath, is_dir: bool) -> bool {
+        if self.exclude.iter().any(|glob| glob.matches(path, is_dir)) {
+            return false;
+        }
+        if self.include.is_empty() {
+            return true;
+        }
+        let components = path.components();
+        let mut prefix = ShadowPath::new();
+        for (i, component) in components.iter().enumerate() {
+            prefix.push(component.clone());
+            let prefix_is_dir = is_dir || i + 1 < components.len();
+            if self
+                .include
+                .iter()
+                .any(|glob| glob.matches(&prefix, prefix_is_dir))
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl TraversalCallbacks for FilterTreeCallbacks {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        if self.keep(visit.path(), false) {
+            let mode = if visit.executable() {
+                FileMode::BlobExecutable
+            } else {
+                FileMode::Blob
+            };
+            self.kept.push((visit.path().clone(), mode, visit.oid()));
+        }
+        Ok(())
+    }
+
+    fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+        if self.keep(visit.path(), false) {
+            self.kept
+                .push((visit.path().clone(), FileMode::Link, visit.oid()));
+        }
+        Ok(())
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        let excluded = self
+            .exclude
+            .iter()
+            .any(|glob| glob.matches(visit.path(), true));
+        Ok(if excluded {
+            VisitTreeDecision::Skip
+        } else {
+            VisitTreeDecision::Descend
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    fn filtered(repo: &TestRepo, tree: Oid, include: &[&str], exclude: &[&str]) -> Vec<String> {
+        let filter = TreeFilter {
+            include: include.iter().map(|s| s.to_string()).collect(),
+            exclude: exclude.iter().map(|s| s.to_string()).collect(),
+        };
+        let tree = repo.db.filter_tree(tree, &filter).unwrap();
+        repo.db
+            .ls(tree, true)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    #[test]
+    fn filter() {
+        let repo = TestRepo::new();
+        let mut tree = repo.empty_tree();
+        for path in &["a/x", "a/y.log", "b/z", "c/secret/k", "c/l/m.log"] {
+            tree = repo.add_file(tree, path, path.as_bytes());
+        }
+        tree = repo.add_link(tree, "a/w", "x");
+
+        assert_eq!(filtered(&repo, tree, &[], &[]).len(), 11);
+        assert_eq!(
+            filtered(&repo, tree, &["a", "/c"], &["*.log", "secret/"]),
+            vec!["a", "a/w", "a/x"]
+        );
+        assert_eq!(
+            filtered(&repo, tree, &["**.log"], &["a/"]),
+            vec!["c", "c/l", "c/l/m.log"]
+        );
+        assert_eq!(filtered(&repo, tree, &["b/*"], &[]), vec!["b", "b/z"]);
+        assert_eq!(
+            filtered(&repo, tree, &["nothing"], &[]),
+            Vec::<String>::new()
+        );
+    }
+}
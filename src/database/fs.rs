@@ -1,37 +1,231 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::{TryFrom, TryInto};
 use std::error::Error;
 use std::ffi::OsStr;
-use std::fs::{File, OpenOptions};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
 use std::iter::{FromIterator, IntoIterator};
-use std::os::unix::io::AsRawFd;
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
-use std::time::{Duration, UNIX_EPOCH};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, ensure, Result};
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, Request,
+    FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request, TimeOrNow,
 };
-use git2::{FileMode, ObjectType, Oid, Repository, TreeEntry};
-use libc::{EINVAL, ENOENT};
+use git2::{FileMode, Oid, Repository, TreeEntry};
+use libc::{EINVAL, EIO, ENOENT, EROFS};
 use log::error;
 
-use crate::{Database, Shadow, ShadowPathComponent, ShadowTreeEntryName, Substance};
+use crate::{
+    ContentSha256, Database, LinkTarget, RangeReader, Shadow, ShadowPath, ShadowPathComponent,
+    ShadowTreeEntryName, Substance,
+};
 
 const FS_NAME: &str = "keep";
 
+#[derive(Clone, Debug, Default)]
+pub struct MountConfig {
+    pub uid: u32,
+    pub gid: u32,
+    // Check the content of each blob against its hash before serving it. A blob which passes is
+    // not checked again for the lifetime of the mount.
+    pub verify: bool,
+    // Print operation counters to stderr on SIGUSR1 and on unmount.
+    pub metrics: bool,
+    // Let users other than the one mounting (or only root, in addition) access the mount. Unless
+    // mounting as root, either needs 'user_allow_other' in /etc/fuse.conf.
+    pub allow_other: bool,
+    pub allow_root: bool,
+    // Mount without the FUSE read-only flag. Nothing can be written yet, so opening a file for
+    // writing and changing attributes still fail with EROFS.
+    pub writable: bool,
+    // Present each symbolic link which leads to an entry within the tree as that entry. Other
+    // links, such as absolute ones, are presented as they are, and so dangle within the mount.
+    pub resolve_links: bool,
+    // How long the kernel may cache the attributes of an inode, and what a name in a directory
+    // leads to, or `DEFAULT_TTL` if None. A lookup replies with a single timeout for both the name
+    // and the attributes of what it leads to, so `entry_timeout` covers those attributes too, and
+    // `attr_timeout` only those fetched afterwards. A mounted tree is read-only and never changes,
+    // so nothing the kernel caches can go stale, however long it is kept.
+    pub attr_timeout: Option<Duration>,
+    pub entry_timeout: Option<Duration>,
+    // How long a blob found to be missing from the substance is remembered as missing, so that
+    // opening files with that content fails without asking the substance again, or
+    // `DEFAULT_MISSING_TTL` if None. Zero disables this. Unlike the tree, the substance may
+    // change while mounted, as when it is still being synced, so this is kept short.
+    pub missing_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+pub struct MountMetrics {
+    pub lookups: AtomicU64,
+    pub getattrs: AtomicU64,
+    pub readdirs: AtomicU64,
+    pub opens: AtomicU64,
+    pub reads: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub verify_cache_hits: AtomicU64,
+    pub verify_cache_misses: AtomicU64,
+    pub missing_cache_hits: AtomicU64,
+}
+
+impl MountMetrics {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for MountMetrics {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write!(
+            fmt,
+            "lookups {}, getattrs {}, readdirs {}, opens {}, reads {} ({} bytes), verify cache hits {}, misses {}, missing cache hits {}",
+            get(&self.lookups),
+            get(&self.getattrs),
+            get(&self.readdirs),
+            get(&self.opens),
+            get(&self.reads),
+            get(&self.bytes_read),
+            get(&self.verify_cache_hits),
+            get(&self.verify_cache_misses),
+            get(&self.missing_cache_hits),
+        )
+    }
+}
+
 impl Database {
     pub fn mount(
         &self,
         tree: Oid,
         mountpoint: impl AsRef<Path>,
         substance: impl Substance,
-        uid: u32,
-        gid: u32,
+        config: MountConfig,
     ) -> Result<()> {
-        let options = &[
-            MountOption::RO,
+        self.mount_inner(tree, mountpoint.as_ref(), substance, config, None)
+    }
+
+    // Like `mount`, but serve the filesystem from a child process detached from the terminal, with
+    // its standard streams on /dev/null, returning its pid once the filesystem is ready. If
+    // mounting fails, so does this, with the child's error.
+    pub fn mount_in_background(
+        &self,
+        tree: Oid,
+        mountpoint: impl AsRef<Path>,
+        substance: impl Substance,
+        config: MountConfig,
+    ) -> Result<libc::pid_t> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let (read_end, write_end) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        io::stdout().flush()?;
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if pid == 0 {
+            drop(read_end);
+            let status = match self.serve_detached(
+                tree,
+                mountpoint.as_ref(),
+                substance,
+                config,
+                write_end,
+            ) {
+                Ok(()) => 0,
+                Err(err) => {
+                    error!("{:#}", err);
+                    1
+                }
+            };
+            process::exit(status);
+        }
+        drop(write_end);
+        let mut line = String::new();
+        BufReader::new(read_end).read_line(&mut line)?;
+        if line == format!("{}\n", READY) {
+            return Ok(pid);
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        match line.strip_prefix(FAILED_PREFIX) {
+            Some(err) => bail!("{}", err.trim_end()),
+            None => bail!(
+                "the mount process exited with wait status {} before the filesystem was ready",
+                status
+            ),
+        }
+    }
+
+    // In the child of `mount_in_background`, a line reporting readiness or failure is written to
+    // `signal`.
+    fn serve_detached(
+        &self,
+        tree: Oid,
+        mountpoint: &Path,
+        substance: impl Substance,
+        config: MountConfig,
+        signal: File,
+    ) -> Result<()> {
+        let mut failure_signal = signal.try_clone()?;
+        let detached = (|| -> Result<()> {
+            if unsafe { libc::setsid() } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let null = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/null")?;
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+            }
+            Ok(())
+        })();
+        let result = detached
+            .and_then(|()| self.mount_inner(tree, mountpoint, substance, config, Some(signal)));
+        if let Err(err) = &result {
+            // Once the filesystem is ready, no one is listening, and this fails harmlessly.
+            let _ = writeln!(failure_signal, "{}{:#}", FAILED_PREFIX, err);
+        }
+        result
+    }
+
+    fn mount_inner(
+        &self,
+        tree: Oid,
+        mountpoint: &Path,
+        substance: impl Substance,
+        config: MountConfig,
+        ready: Option<File>,
+    ) -> Result<()> {
+        ensure!(
+            !(config.allow_other && config.allow_root),
+            "allow_other and allow_root are mutually exclusive"
+        );
+        if (config.allow_other || config.allow_root) && unsafe { libc::geteuid() } != 0 {
+            let conf = fs::read_to_string(FUSE_CONF).unwrap_or_default();
+            ensure!(
+                fuse_conf_allows_other(&conf),
+                "{} does not permit allow_other or allow_root for users other than root (add 'user_allow_other' to it)",
+                FUSE_CONF
+            );
+        }
+        let mut options = vec![
             MountOption::NoDev,
             MountOption::NoExec,
             MountOption::NoAtime,
@@ -42,13 +236,69 @@ impl Database {
             // MountOption::AutoUnmount,
             MountOption::CUSTOM("auto_unmount".to_string()),
         ];
-        let fs = DatabaseFilesystem::new(self.repository(), tree, substance, uid, gid);
-        fuser::mount2(fs, mountpoint, options)?;
+        if !config.writable {
+            options.push(MountOption::RO);
+        }
+        if config.allow_other {
+            options.push(MountOption::AllowOther);
+        }
+        if config.allow_root {
+            options.push(MountOption::AllowRoot);
+        }
+        let report_metrics = config.metrics;
+        let mut fs = DatabaseFilesystem::new(self, tree, substance, config);
+        fs.ready = ready;
+        let metrics = fs.metrics.clone();
+        if report_metrics {
+            report_metrics_on_sigusr1(metrics.clone())?;
+        }
+        fuser::mount2(fs, mountpoint, &options)?;
+        if report_metrics {
+            eprintln!("{}", metrics);
+        }
         Ok(())
     }
 }
 
-const TTL: Duration = Duration::from_secs(1);
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+// What the child of `mount_in_background` reports, on a line of its own.
+const READY: &str = "ready";
+const FAILED_PREFIX: &str = "failed: ";
+
+fn fuse_conf_allows_other(conf: &str) -> bool {
+    conf.lines()
+        .any(|line| line.split('#').next().unwrap().trim() == "user_allow_other")
+}
+
+// Block SIGUSR1 in this thread, and so in the threads of the FUSE session which it goes on to
+// spawn, and instead wait for it in a dedicated thread.
+fn report_metrics_on_sigusr1(metrics: Arc<MountMetrics>) -> Result<()> {
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        set
+    };
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    ensure!(ret == 0, "pthread_sigmask failed: {}", ret);
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            eprintln!("{}", metrics);
+        }
+    });
+    Ok(())
+}
+
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_MISSING_TTL: Duration = Duration::from_secs(10);
+// At most this many blobs are remembered as missing, the earliest found being forgotten first.
+const MAX_MISSING: usize = 4096;
+
+#[derive(thiserror::Error, Debug)]
+#[error("blob {0} is missing from the substance")]
+struct MissingBlob(ContentSha256);
 
 const ROOT_INODE: u64 = 1;
 
@@ -67,32 +317,54 @@ macro_rules! fry {
 
 type Inode = u64;
 
+// The `path` of a tree is where it is in the mounted tree, which differs from where it appears in
+// the mount if it was reached through a resolved link.
 enum InodeEntry {
-    File { oid: Oid, executable: bool },
-    Link { oid: Oid },
-    Tree { oid: Oid, parent: Inode },
+    File {
+        oid: Oid,
+        executable: bool,
+    },
+    Link {
+        oid: Oid,
+    },
+    Tree {
+        oid: Oid,
+        parent: Inode,
+        path: ShadowPath,
+    },
 }
 
 pub struct DatabaseFilesystem<'a, T> {
+    database: &'a Database,
     repository: &'a Repository,
     inodes: BTreeMap<Inode, InodeEntry>,
     family_tree: BTreeMap<(Inode, usize), Inode>,
+    // The inode of each tree reached through a resolved link, by its path in the mounted tree, so
+    // that a link to one of its own ancestors leads back to the same inode rather than to an
+    // endless succession of new ones.
+    resolved_trees: BTreeMap<ShadowPath, Inode>,
     next_inode: Inode,
-    file_handles: BTreeMap<Inode, SharedFile>,
+    file_handles: BTreeMap<Inode, SharedBlob>,
+    verified: BTreeSet<ContentSha256>,
+    // When each blob was last found to be missing. Expired entries are pruned on insertion.
+    missing: BTreeMap<ContentSha256, Instant>,
     substance: T,
-    uid: u32,
-    gid: u32,
+    config: MountConfig,
+    metrics: Arc<MountMetrics>,
+    // Told once the kernel has initialized the filesystem.
+    ready: Option<File>,
 }
 
-struct SharedFile {
-    file: File,
+// Opens of the same file share one open blob, which is closed on the last release.
+struct SharedBlob {
+    reader: Box<dyn RangeReader>,
     reference_count: usize,
 }
 
-impl SharedFile {
-    fn new(file: File) -> Self {
+impl SharedBlob {
+    fn new(reader: Box<dyn RangeReader>) -> Self {
         Self {
-            file,
+            reader,
             reference_count: 1,
         }
     }
@@ -108,62 +380,190 @@ impl SharedFile {
 }
 
 impl<'a, T: Substance> DatabaseFilesystem<'a, T> {
-    pub fn new(repository: &'a Repository, tree: Oid, substance: T, uid: u32, gid: u32) -> Self {
+    pub fn new(database: &'a Database, tree: Oid, substance: T, config: MountConfig) -> Self {
         Self {
-            repository,
+            database,
+            repository: database.repository(),
             inodes: BTreeMap::from_iter([(
                 ROOT_INODE,
                 InodeEntry::Tree {
                     parent: ROOT_INODE,
                     oid: tree,
+                    path: ShadowPath::new(),
                 },
             )]),
             family_tree: BTreeMap::new(),
+            resolved_trees: BTreeMap::new(),
             next_inode: ROOT_INODE + 1,
             file_handles: BTreeMap::new(),
+            verified: BTreeSet::new(),
+            missing: BTreeMap::new(),
             substance,
-            uid,
-            gid,
+            config,
+            metrics: Arc::new(MountMetrics::default()),
+            ready: None,
         }
     }
 
     fn get_inode(&mut self, parent: Inode, entry: TreeEntry<'static>) -> Result<Inode> {
-        let ino = self.next_inode;
-        self.next_inode += 1;
-        let oid = entry.id();
-        let mode = entry.filemode();
-        let entry = match entry.kind().unwrap() {
-            ObjectType::Blob => {
-                if mode == FileMode::Link.into() {
-                    InodeEntry::Link { oid }
-                } else {
-                    let executable = if mode == FileMode::Blob.into() {
-                        true
-                    } else if mode == FileMode::BlobExecutable.into() {
-                        false
-                    } else {
-                        bail!("")
-                    };
-                    InodeEntry::File { oid, executable }
+        let mut path = match self.inodes.get(&parent) {
+            Some(InodeEntry::Tree { path, .. }) => path.clone(),
+            _ => bail!("inode {} is not a directory", parent),
+        };
+        match ShadowTreeEntryName::decode(entry.name().unwrap())? {
+            ShadowTreeEntryName::Child(child) => path.push(child),
+            ShadowTreeEntryName::Marker => bail!("markers have no inode"),
+        }
+        let (mut oid, mut mode) = (entry.id(), entry.filemode());
+        let mut resolved_tree = false;
+        if self.config.resolve_links && mode == FileMode::Link.into() {
+            let root = match self.inodes.get(&ROOT_INODE) {
+                Some(InodeEntry::Tree { oid, .. }) => *oid,
+                _ => unreachable!(),
+            };
+            if let LinkTarget::Entry(resolved) = self.database.resolve_link(root, &path)? {
+                match self.database.lookup(root, &resolved)? {
+                    Some(target) => {
+                        oid = target.id();
+                        mode = target.filemode();
+                    }
+                    // The link leads to the root.
+                    None => {
+                        oid = root;
+                        mode = FileMode::Tree.into();
+                    }
+                }
+                path = resolved;
+                resolved_tree = mode == FileMode::Tree.into();
+                if let Some(ino) = self.resolved_trees.get(&path) {
+                    return Ok(*ino);
                 }
             }
-            ObjectType::Tree => {
-                ensure!(mode == FileMode::Tree.into());
-                InodeEntry::Tree { oid, parent }
+        }
+        let entry = if mode == FileMode::Tree.into() {
+            InodeEntry::Tree { oid, parent, path }
+        } else if mode == FileMode::Link.into() {
+            InodeEntry::Link { oid }
+        } else if mode == FileMode::Blob.into() {
+            InodeEntry::File {
+                oid,
+                executable: false,
             }
-            _ => {
-                bail!("");
+        } else if mode == FileMode::BlobExecutable.into() {
+            InodeEntry::File {
+                oid,
+                executable: true,
             }
+        } else {
+            bail!("unexpected mode {:o} for '{}'", mode, path)
         };
+        let ino = self.next_inode;
+        self.next_inode += 1;
+        if resolved_tree {
+            self.resolved_trees.insert(path.clone(), ino);
+        }
         self.inodes.insert(ino, entry);
         Ok(ino)
     }
 
+    fn attr_ttl(&self) -> Duration {
+        self.config.attr_timeout.unwrap_or(DEFAULT_TTL)
+    }
+
+    fn entry_ttl(&self) -> Duration {
+        self.config.entry_timeout.unwrap_or(DEFAULT_TTL)
+    }
+
+    // The attributes of the entry `name` in directory `parent`, if there is one, along with how
+    // long the kernel may cache them.
+    fn lookup_entry(
+        &mut self,
+        parent: Inode,
+        name: &OsStr,
+    ) -> Result<Option<(Duration, FileAttr)>> {
+        let oid = match self.inodes.get(&parent) {
+            Some(InodeEntry::Tree { oid, .. }) => *oid,
+            _ => bail!("lookup: parent inode {} not present", parent),
+        };
+        // No entry can have a name which is not a valid component.
+        let entry_name = match name
+            .to_str()
+            .and_then(|name| name.parse::<ShadowPathComponent>().ok())
+        {
+            Some(component) => component.encode(),
+            None => return Ok(None),
+        };
+        let repository = self.repository;
+        let tree = repository.find_tree(oid)?;
+        for (i, entry) in tree.iter().enumerate() {
+            if entry.name() == Some(entry_name.as_str()) {
+                let ino = self.child_inode(parent, i, &entry)?;
+                return Ok(Some((self.entry_ttl(), self.fetch_attr(ino)?)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_attr(&self, ino: Inode) -> Result<(Duration, FileAttr)> {
+        Ok((self.attr_ttl(), self.fetch_attr(ino)?))
+    }
+
+    // The inode of the `i`th entry of the tree at `parent`, allocated on first use.
+    fn child_inode(&mut self, parent: Inode, i: usize, entry: &TreeEntry) -> Result<Inode> {
+        if let Some(ino) = self.family_tree.get(&(parent, i)) {
+            return Ok(*ino);
+        }
+        let ino = self.get_inode(parent, entry.to_owned())?;
+        self.family_tree.insert((parent, i), ino);
+        Ok(ino)
+    }
+
+    // Pass the entries of directory `ino` from position `offset` onwards to `add`, along with
+    // the position of the entry after each, until `add` reports that it is full. Positions 0
+    // and 1 are "." and "..", and position i + 2 is the ith tree entry. The marker entry
+    // occupies a position but is not listed, which keeps positions stable.
+    fn read_dir(
+        &mut self,
+        ino: Inode,
+        offset: i64,
+        mut add: impl FnMut(Inode, i64, FileType, &str) -> bool,
+    ) -> Result<()> {
+        let (oid, parent) = match self.inodes.get(&ino) {
+            Some(InodeEntry::Tree { oid, parent, .. }) => (*oid, *parent),
+            _ => bail!("readdir: inode {} is not a directory", ino),
+        };
+        let offset = usize::try_from(offset)?;
+        if offset < 1 && add(ino, 1, FileType::Directory, ".") {
+            return Ok(());
+        }
+        if offset < 2 && add(parent, 2, FileType::Directory, "..") {
+            return Ok(());
+        }
+        let repository = self.repository;
+        let tree = repository.find_tree(oid)?;
+        for (i, entry) in tree.iter().enumerate().skip(offset.saturating_sub(2)) {
+            let name = match ShadowTreeEntryName::decode(entry.name().unwrap())? {
+                ShadowTreeEntryName::Marker => continue,
+                ShadowTreeEntryName::Child(child) => child.to_string(),
+            };
+            let child = self.child_inode(ino, i, &entry)?;
+            let kind = match self.inodes.get(&child).unwrap() {
+                InodeEntry::File { .. } => FileType::RegularFile,
+                InodeEntry::Link { .. } => FileType::Symlink,
+                InodeEntry::Tree { .. } => FileType::Directory,
+            };
+            if add(child, (i + 3) as i64, kind, &name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     fn fetch_attr(&self, ino: u64) -> Result<FileAttr> {
         let (kind, perm, size) = match self.inodes.get(&ino).unwrap() {
             InodeEntry::File { oid, executable } => {
                 let kind = FileType::RegularFile;
-                let perm = 0o444 | (if *executable { 0o000 } else { 0o111 });
+                let perm = 0o444 | (if *executable { 0o111 } else { 0o000 });
                 let blob = self.repository.find_blob(oid.clone())?;
                 let shadow = Shadow::from_bytes(blob.content())?;
                 let size = shadow.size().unwrap_or(0);
@@ -194,31 +594,85 @@ impl<'a, T: Substance> DatabaseFilesystem<'a, T> {
             kind,
             perm,
             nlink: 0,
-            uid: self.uid,
-            gid: self.gid,
+            uid: self.config.uid,
+            gid: self.config.gid,
             rdev: 0,
             blksize: 0,
             flags: 0,
         })
     }
 
-    fn open_blob(&mut self, ino: u64) -> Result<()> {
-        if let Some(shared) = self.file_handles.get_mut(&ino) {
-            shared.increment();
-            return Ok(());
-        }
+    fn read_shadow(&self, ino: u64) -> Result<Shadow> {
         let oid = match self.inodes.get(&ino).unwrap() {
             InodeEntry::File { oid, .. } => oid,
             _ => bail!("not a file"),
         };
         let blob = self.repository.find_blob(oid.clone())?;
-        let shadow = Shadow::from_bytes(blob.content())?;
-        let blob_path = self.substance.blob_path(&shadow.content_hash());
-        let file = OpenOptions::new().read(true).open(blob_path)?;
-        self.file_handles.insert(ino, SharedFile::new(file));
+        Ok(Shadow::from_bytes(blob.content())?)
+    }
+
+    fn verify_blob(&mut self, ino: u64) -> Result<()> {
+        let shadow = self.read_shadow(ino)?;
+        if self.verified.contains(shadow.content_hash()) {
+            MountMetrics::add(&self.metrics.verify_cache_hits, 1);
+        } else {
+            MountMetrics::add(&self.metrics.verify_cache_misses, 1);
+            self.ensure_present(shadow.content_hash())?;
+            self.substance.check_blob(shadow.content_hash())?;
+            self.verified.insert(shadow.content_hash().clone());
+        }
+        Ok(())
+    }
+
+    fn open_blob(&mut self, ino: u64) -> Result<()> {
+        if let Some(shared) = self.file_handles.get_mut(&ino) {
+            shared.increment();
+            return Ok(());
+        }
+        let shadow = self.read_shadow(ino)?;
+        self.ensure_present(shadow.content_hash())?;
+        let reader = self.substance.open_range_reader(shadow.content_hash())?;
+        self.file_handles.insert(ino, SharedBlob::new(reader));
+        Ok(())
+    }
+
+    fn ensure_present(&mut self, blob: &ContentSha256) -> Result<()> {
+        let ttl = self.config.missing_timeout.unwrap_or(DEFAULT_MISSING_TTL);
+        if let Some(found_missing) = self.missing.get(blob) {
+            if found_missing.elapsed() < ttl {
+                MountMetrics::add(&self.metrics.missing_cache_hits, 1);
+                return Err(MissingBlob(blob.clone()).into());
+            }
+            self.missing.remove(blob);
+        }
+        if !self.substance.have_blob(blob) {
+            if ttl > Duration::from_secs(0) {
+                self.missing
+                    .retain(|_, found_missing| found_missing.elapsed() < ttl);
+                if self.missing.len() >= MAX_MISSING {
+                    let earliest = self
+                        .missing
+                        .iter()
+                        .min_by_key(|(_, found_missing)| **found_missing)
+                        .map(|(blob, _)| blob.clone())
+                        .unwrap();
+                    self.missing.remove(&earliest);
+                }
+                self.missing.insert(blob.clone(), Instant::now());
+            }
+            return Err(MissingBlob(blob.clone()).into());
+        }
         Ok(())
     }
 
+    fn read_blob(&self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
+        MountMetrics::add(&self.metrics.reads, 1);
+        let reader = &self.file_handles.get(&ino).unwrap().reader;
+        let data = reader.read_range(u64::try_from(offset)?, size.try_into()?)?;
+        MountMetrics::add(&self.metrics.bytes_read, data.len() as u64);
+        Ok(data)
+    }
+
     fn close_blob(&mut self, ino: u64) -> Result<()> {
         if !self.file_handles.get_mut(&ino).unwrap().decrement() {
             self.file_handles.remove(&ino);
@@ -228,40 +682,22 @@ impl<'a, T: Substance> DatabaseFilesystem<'a, T> {
 }
 
 impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let oid = fry!(
-            reply,
-            match self.inodes.get_mut(&parent).unwrap() {
-                InodeEntry::Tree { oid, .. } => Ok(oid),
-                _ => Err(Box::<dyn Error>::from(format!(
-                    "lookup: parent inode {} not present",
-                    parent
-                ))),
-            }
-        );
-        let tree = self.repository.find_tree(oid.clone()).unwrap();
-        let entry_name = name
-            .to_str()
-            .unwrap()
-            .parse::<ShadowPathComponent>()
-            .unwrap()
-            .encode();
-        for (i, entry) in tree.iter().enumerate() {
-            if entry.name().unwrap() == entry_name {
-                let ino = match self.family_tree.get(&(parent, i)) {
-                    Some(ino) => *ino,
-                    None => {
-                        let ino = fry!(reply, self.get_inode(parent, entry.to_owned()));
-                        self.family_tree.insert((parent, i), ino);
-                        ino
-                    }
-                };
-                let attr = fry!(reply, self.fetch_attr(ino));
-                reply.entry(&TTL, &attr, 0);
-                return;
+    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
+        if let Some(mut ready) = self.ready.take() {
+            if let Err(err) = writeln!(ready, "{}", READY) {
+                error!("failed to report that the filesystem is ready: {}", err);
             }
         }
-        reply.error(ENOENT);
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        MountMetrics::add(&self.metrics.lookups, 1);
+        match fry!(reply, self.lookup_entry(parent, name)) {
+            // fuser uses the one timeout for both the entry and its attributes.
+            Some((ttl, attr)) => reply.entry(&ttl, &attr, 0),
+            None => reply.error(ENOENT),
+        }
     }
 
     fn readdir(
@@ -272,58 +708,19 @@ impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let (oid, parent) = fry!(
+        MountMetrics::add(&self.metrics.readdirs, 1);
+        fry!(
             reply,
-            match self.inodes.get(&ino).unwrap() {
-                InodeEntry::Tree { oid, parent } => Ok((*oid, *parent)),
-                _ => Err(Box::<dyn Error>::from(format!(
-                    "readdir: inode {} not present",
-                    ino
-                ))),
-            }
+            self.read_dir(ino, offset, |ino, next, kind, name| reply
+                .add(ino, next, kind, name))
         );
-        let always: Vec<Result<Option<(u64, FileType, String)>>> = vec![
-            Ok(Some((ino, FileType::Directory, ".".into()))),
-            Ok(Some((parent, FileType::Directory, "..".into()))),
-        ];
-        let tree = self.repository.clone().find_tree(oid).unwrap();
-        let entries = always
-            .into_iter()
-            .chain(tree.iter().enumerate().map(|(i, entry)| {
-                let name = match ShadowTreeEntryName::decode(entry.name().unwrap()).unwrap() {
-                    ShadowTreeEntryName::Marker => return Ok(None),
-                    ShadowTreeEntryName::Child(child) => child.to_string(),
-                };
-                let ino = match self.family_tree.get(&(ino, i)) {
-                    Some(ino) => *ino,
-                    None => {
-                        let ino = self.get_inode(ino, entry.to_owned())?;
-                        self.family_tree.insert((ino, i), ino);
-                        ino
-                    }
-                };
-                let kind = match self.inodes.get(&ino).unwrap() {
-                    InodeEntry::File { .. } => FileType::RegularFile,
-                    InodeEntry::Link { .. } => FileType::Symlink,
-                    InodeEntry::Tree { .. } => FileType::Directory,
-                };
-                Ok(Some((ino, kind, name)))
-            }));
-        for (i, fallible_entry) in entries.enumerate().skip(offset.try_into().unwrap()) {
-            if let Some((ino, kind, name)) = fallible_entry.unwrap() {
-                // i + 1 means the index of the next entry
-                let full = reply.add(ino, (i + 1) as i64, kind, name);
-                if full {
-                    break;
-                }
-            }
-        }
         reply.ok();
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let attr = fry!(reply, self.fetch_attr(ino));
-        reply.attr(&TTL, &attr);
+        MountMetrics::add(&self.metrics.getattrs, 1);
+        let (ttl, attr) = fry!(reply, self.get_attr(ino));
+        reply.attr(&ttl, &attr);
     }
 
     fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
@@ -342,8 +739,49 @@ impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
         reply.data(target);
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
-        fry!(reply, self.open_blob(ino));
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        MountMetrics::add(&self.metrics.opens, 1);
+        // The kernel already refuses writes to a read-only mount, but do not rely on it.
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(EROFS);
+            return;
+        }
+        if self.config.verify {
+            if let Err(err) = self.verify_blob(ino) {
+                error!("verification failed for inode {}: {}", ino, err);
+                reply.error(EIO);
+                return;
+            }
+        }
+        match self.open_blob(ino) {
+            Err(err) if err.is::<MissingBlob>() => {
+                error!("cannot open inode {}: {}", ino, err);
+                reply.error(EIO);
+                return;
+            }
+            result => fry!(reply, result),
+        }
         reply.opened(0, 0)
     }
 
@@ -372,18 +810,279 @@ impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        let file = &mut self.file_handles.get_mut(&ino).unwrap().file;
-        let mut buf = vec![0u8; size.try_into().unwrap()];
-        let n = unsafe {
-            libc::pread(
-                file.as_raw_fd(),
-                buf.as_mut_ptr() as *mut libc::c_void,
-                size.try_into().unwrap(),
-                offset,
-            )
+        let data = fry!(reply, self.read_blob(ino, offset, size));
+        reply.data(&data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+    use crate::FilesystemSubstance;
+
+    #[test]
+    fn read_metrics() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"hello\n");
+        let substance = FilesystemSubstance::new(repo.dir.path().join("substance")).unwrap();
+        let mut fs = DatabaseFilesystem::new(&repo.db, tree, substance, MountConfig::default());
+        let entry = repo
+            .db
+            .repository()
+            .find_tree(tree)
+            .unwrap()
+            .get_name("0_a")
+            .unwrap()
+            .to_owned();
+        let ino = fs.get_inode(ROOT_INODE, entry).unwrap();
+        fs.open_blob(ino).unwrap();
+        for offset in 0..3 {
+            assert_eq!(
+                fs.read_blob(ino, offset * 4, 4).unwrap().len(),
+                [4, 3, 0][offset as usize]
+            );
+        }
+        assert_eq!(fs.metrics.reads.load(Ordering::Relaxed), 3);
+        assert_eq!(fs.metrics.bytes_read.load(Ordering::Relaxed), 7);
+    }
+
+    #[test]
+    fn read_dir_pages() {
+        let repo = TestRepo::new();
+        let mut tree = repo.empty_tree();
+        let mut names = (0..300).map(|i| format!("f{}", i)).collect::<Vec<_>>();
+        for name in &names {
+            tree = repo.add_file(tree, name, name.as_bytes());
+        }
+        let substance = FilesystemSubstance::new(repo.dir.path().join("substance")).unwrap();
+        let mut fs = DatabaseFilesystem::new(&repo.db, tree, substance, MountConfig::default());
+
+        // Mimic a reply buffer with room for 6 entries, which rejects the 7th.
+        let list = |fs: &mut DatabaseFilesystem<_>| {
+            let mut listed = vec![];
+            let mut offset = 0;
+            loop {
+                let mut page = vec![];
+                fs.read_dir(ROOT_INODE, offset, |ino, next, _, name| {
+                    if page.len() == 6 {
+                        return true;
+                    }
+                    page.push((ino, next, name.to_owned()));
+                    false
+                })
+                .unwrap();
+                match page.last() {
+                    None => break,
+                    Some((_, next, _)) => offset = *next,
+                }
+                listed.extend(page.into_iter().map(|(ino, _, name)| (ino, name)));
+            }
+            listed
+        };
+        let first = list(&mut fs);
+        assert_eq!(list(&mut fs), first);
+
+        names.sort_by_key(|name| format!("0_{}", name));
+        names.splice(0..0, vec![".".to_owned(), "..".to_owned()]);
+        assert_eq!(
+            first.iter().map(|(_, name)| name).collect::<Vec<_>>(),
+            names.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn resolve_links() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "etc/passwd", b"root\n");
+        let tree = repo.add_link(tree, "etc/l", "passwd");
+        let tree = repo.add_link(tree, "d", "etc");
+        let tree = repo.add_link(tree, "abs", "/etc");
+        let mount = |resolve_links| {
+            let substance = FilesystemSubstance::new(repo.dir.path().join("substance")).unwrap();
+            let config = MountConfig {
+                resolve_links,
+                ..Default::default()
+            };
+            DatabaseFilesystem::new(&repo.db, tree, substance, config)
+        };
+        let child = |fs: &mut DatabaseFilesystem<_>, parent: Inode, name: &str| {
+            let mut found = None;
+            fs.read_dir(parent, 2, |ino, _, _, entry_name| {
+                if entry_name == name {
+                    found = Some(ino);
+                }
+                false
+            })
+            .unwrap();
+            found.unwrap()
+        };
+
+        let mut fs = mount(false);
+        let d = child(&mut fs, ROOT_INODE, "d");
+        assert!(matches!(fs.inodes[&d], InodeEntry::Link { .. }));
+
+        let mut fs = mount(true);
+        let d = child(&mut fs, ROOT_INODE, "d");
+        assert!(matches!(fs.inodes[&d], InodeEntry::Tree { .. }));
+        let l = child(&mut fs, d, "l");
+        fs.open_blob(l).unwrap();
+        assert_eq!(fs.read_blob(l, 0, 100).unwrap(), b"root\n");
+        let abs = child(&mut fs, ROOT_INODE, "abs");
+        assert!(matches!(fs.inodes[&abs], InodeEntry::Link { .. }));
+    }
+
+    #[test]
+    fn resolve_link_cycle() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a/f", b"f\n");
+        let tree = repo.add_link(tree, "a/root", "..");
+        let substance = FilesystemSubstance::new(repo.dir.path().join("substance")).unwrap();
+        let config = MountConfig {
+            resolve_links: true,
+            ..Default::default()
+        };
+        let mut fs = DatabaseFilesystem::new(&repo.db, tree, substance, config);
+        let child = |fs: &mut DatabaseFilesystem<_>, parent: Inode, name: &str| {
+            let (_, attr) = fs.lookup_entry(parent, OsStr::new(name)).unwrap().unwrap();
+            attr.ino
+        };
+
+        let a = child(&mut fs, ROOT_INODE, "a");
+        let root = child(&mut fs, a, "root");
+        assert!(matches!(fs.inodes[&root], InodeEntry::Tree { .. }));
+        let a = child(&mut fs, root, "a");
+        assert_eq!(child(&mut fs, a, "root"), root);
+        let inodes = fs.inodes.len();
+        let a = child(&mut fs, root, "a");
+        child(&mut fs, a, "root");
+        assert_eq!(fs.inodes.len(), inodes);
+    }
+
+    #[test]
+    fn timeouts() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let mount = |config| {
+            let substance = FilesystemSubstance::new(repo.dir.path().join("substance")).unwrap();
+            DatabaseFilesystem::new(&repo.db, tree, substance, config)
+        };
+
+        let mut fs = mount(MountConfig::default());
+        let (ttl, attr) = fs
+            .lookup_entry(ROOT_INODE, OsStr::new("a"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ttl, DEFAULT_TTL);
+        assert_eq!(fs.get_attr(attr.ino).unwrap().0, DEFAULT_TTL);
+        assert!(fs
+            .lookup_entry(ROOT_INODE, OsStr::new("missing"))
+            .unwrap()
+            .is_none());
+
+        let mut fs = mount(MountConfig {
+            attr_timeout: Some(Duration::from_secs(5)),
+            entry_timeout: Some(Duration::from_secs(7)),
+            ..Default::default()
+        });
+        let (ttl, attr) = fs
+            .lookup_entry(ROOT_INODE, OsStr::new("a"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ttl, Duration::from_secs(7));
+        assert_eq!(fs.get_attr(attr.ino).unwrap().0, Duration::from_secs(5));
+        assert_eq!(fs.get_attr(ROOT_INODE).unwrap().0, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn missing_cache() {
+        let repo = TestRepo::new();
+        let tree = repo.add_unstored_file(repo.empty_tree(), "a", b"a\n");
+        let mount = |missing_timeout| {
+            let substance = FilesystemSubstance::new(repo.dir.path().join("substance")).unwrap();
+            let config = MountConfig {
+                missing_timeout,
+                ..Default::default()
+            };
+            DatabaseFilesystem::new(&repo.db, tree, substance, config)
         };
-        assert!(n >= 0);
-        let n = usize::try_from(n).unwrap();
-        reply.data(&buf[..n]);
+        let open = |fs: &mut DatabaseFilesystem<_>| {
+            let ino = fs
+                .lookup_entry(ROOT_INODE, OsStr::new("a"))
+                .unwrap()
+                .unwrap()
+                .1
+                .ino;
+            fs.open_blob(ino)
+        };
+
+        let mut fs = mount(None);
+        let mut uncached = mount(Some(Duration::from_secs(0)));
+        assert!(open(&mut fs).unwrap_err().is::<MissingBlob>());
+        assert!(open(&mut uncached).is_err());
+        let (shadow, src) = repo.write_subject_file("a", b"a\n");
+        repo.substance.store(shadow.content_hash(), &src).unwrap();
+        // Still taken to be missing until the entry expires.
+        assert!(open(&mut fs).unwrap_err().is::<MissingBlob>());
+        assert_eq!(fs.metrics.missing_cache_hits.load(Ordering::Relaxed), 1);
+        open(&mut uncached).unwrap();
+        assert!(uncached.missing.is_empty());
+    }
+
+    #[test]
+    fn missing_cache_pruned() {
+        let repo = TestRepo::new();
+        let tree = repo.add_unstored_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.add_unstored_file(tree, "b", b"b\n");
+        let substance = FilesystemSubstance::new(repo.dir.path().join("substance")).unwrap();
+        let config = MountConfig {
+            missing_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let mut fs = DatabaseFilesystem::new(&repo.db, tree, substance, config);
+        let mut open = |name: &str| {
+            let ino = fs
+                .lookup_entry(ROOT_INODE, OsStr::new(name))
+                .unwrap()
+                .unwrap()
+                .1
+                .ino;
+            assert!(fs.open_blob(ino).is_err());
+            fs.missing.len()
+        };
+
+        assert_eq!(open("a"), 1);
+        thread::sleep(Duration::from_millis(20));
+        // The entry for "a" has expired, so it goes when "b" is found missing.
+        assert_eq!(open("b"), 1);
+    }
+
+    #[test]
+    fn background_failure() {
+        let repo = TestRepo::new();
+        let substance = FilesystemSubstance::new(repo.dir.path().join("substance")).unwrap();
+        let config = MountConfig {
+            allow_other: true,
+            allow_root: true,
+            ..Default::default()
+        };
+        let err = repo
+            .db
+            .mount_in_background(repo.empty_tree(), repo.dir.path(), substance, config)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "allow_other and allow_root are mutually exclusive"
+        );
+    }
+
+    #[test]
+    fn fuse_conf() {
+        assert!(fuse_conf_allows_other(
+            "# mount_max = 1000\nuser_allow_other\n"
+        ));
+        assert!(fuse_conf_allows_other("  user_allow_other # for keep\n"));
+        assert!(!fuse_conf_allows_other("#user_allow_other\n"));
+        assert!(!fuse_conf_allows_other(""));
     }
 }
@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use anyhow::Result;
+use git2::{ObjectType, Odb, Oid, Sort};
+
+use crate::{Database, ShadowPath, ShadowTreeEntryName};
+
+// An object referenced from the tree of `commit` at `path` which is absent from the object
+// database. A missing marker is reported at the path of its tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingObject {
+    pub commit: Oid,
+    pub path: ShadowPath,
+    pub oid: Oid,
+}
+
+impl fmt::Display for MissingObject {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "missing {} at '{}' in commit {}",
+            self.oid, self.path, self.commit
+        )
+    }
+}
+
+impl Database {
+    // Walk every commit reachable from any ref and report each referenced object which is
+    // absent, rather than failing at the first one. Missing subtrees are not descended into.
+    // Notes refs are skipped, as their trees are not shadow trees.
+    pub fn fsck_connectivity(
+        &self,
+        mut on_missing: impl FnMut(&MissingObject) -> Result<()>,
+    ) -> Result<()> {
+        let mut revwalk = self.repository().revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL)?;
+        for reference in self.repository().references()? {
+            let reference = reference?;
+            if reference.name_bytes().starts_with(b"refs/notes/") {
+                continue;
+            }
+            if let Ok(commit) = reference.peel_to_commit() {
+                revwalk.push(commit.id())?;
+            }
+        }
+
+        let mut checker = ConnectivityChecker {
+            odb: self.repository().odb()?,
+            database: self,
+            seen: BTreeSet::new(),
+            on_missing: &mut on_missing,
+        };
+        for commit in revwalk {
+            let commit = self.repository().find_commit(commit?)?;
+            checker.check(commit.id(), &mut ShadowPath::new(), commit.tree_id())?;
+        }
+        Ok(())
+    }
+}
+
+struct ConnectivityChecker<'a, T> {
+    odb: Odb<'a>,
+    database: &'a Database,
+    seen: BTreeSet<Oid>,
+    on_missing: &'a mut T,
+}
+
+impl<'a, T: FnMut(&MissingObject) -> Result<()>> ConnectivityChecker<'a, T> {
+    fn check(&mut self, commit: Oid, path: &mut ShadowPath, oid: Oid) -> Result<()> {
+        if !self.seen.insert(oid) {
+            return Ok(());
+        }
+        if !self.odb.exists(oid) {
+            return (self.on_missing)(&MissingObject {
+                commit,
+                path: path.clone(),
+                oid,
+            });
+        }
+        let tree = self.database.repository().find_tree(oid)?;
+        for entry in tree.iter() {
+            match ShadowTreeEntryName::decode(entry.name().unwrap())? {
+                ShadowTreeEntryName::Marker => {
+                    self.check_leaf(commit, path, entry.id())?;
+                }
+                ShadowTreeEntryName::Child(name) => {
+                    path.push(name);
+                    if entry.kind() == Some(ObjectType::Tree) {
+                        self.check(commit, path, entry.id())?;
+                    } else {
+                        self.check_leaf(commit, path, entry.id())?;
+                    }
+                    path.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_leaf(&mut self, commit: Oid, path: &ShadowPath, oid: Oid) -> Result<()> {
+        if self.seen.insert(oid) && !self.odb.exists(oid) {
+            (self.on_missing)(&MissingObject {
+                commit,
+                path: path.clone(),
+                oid,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::FileMode;
+
+    use super::*;
+    use crate::testing::TestRepo;
+    use crate::CommitDates;
+
+    #[test]
+    fn dangling_references() {
+        let repo = TestRepo::new();
+        let sub_tree = repo.add_file(repo.empty_tree(), "c", b"c\n");
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.add_link(tree, "l", "a");
+        let tree = repo.add_object(tree, "b", FileMode::Tree, sub_tree);
+        let commit = repo.commit(tree, &[]);
+        repo.db
+            .repository()
+            .reference("refs/heads/master", commit, true, "x")
+            .unwrap();
+
+        let link = repo.db.repository().blob(b"a").unwrap();
+        repo.remove_object(link);
+        repo.remove_object(sub_tree);
+
+        let mut missing = vec![];
+        repo.db
+            .fsck_connectivity(|object| {
+                missing.push((object.path.to_string(), object.oid));
+                Ok(())
+            })
+            .unwrap();
+        missing.sort();
+        assert_eq!(
+            missing,
+            vec![("b".to_string(), sub_tree), ("l".to_string(), link)]
+        );
+    }
+
+    #[test]
+    fn tagged_commit() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let commit = repo
+            .db
+            .commit_to_ref("refs/heads/master", "x", tree, &CommitDates::default())
+            .unwrap();
+        let tags = vec![("host".to_owned(), "web1".to_owned())]
+            .into_iter()
+            .collect();
+        repo.db.add_tags(commit, &tags).unwrap();
+
+        let mut missing = vec![];
+        repo.db
+            .fsck_connectivity(|object| {
+                missing.push(object.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert!(missing.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{ensure, Context, Result};
+use git2::Oid;
+
+use super::traverse::OnUnique;
+use crate::{ContentSha256, Database, Substance, TraversalCallbacks, Visit, VisitShadow};
+
+impl Database {
+    // HEAD, if it is born, along with every ref matching one of `keep_refs`, which are globs as
+    // understood by 'git for-each-ref', or every ref other than notes if there are none.
+    pub fn gc_roots(&self, keep_refs: &[String]) -> Result<Vec<Oid>> {
+        let mut roots = vec![];
+        if let Some(head) = self.head_commit()? {
+            roots.push(head.id());
+        }
+        if keep_refs.is_empty() {
+            for reference in self.repository().references()? {
+                let reference = reference?;
+                if !reference.name_bytes().starts_with(b"refs/notes/") {
+                    roots.push(reference.peel_to_commit()?.id());
+                }
+            }
+        }
+        for glob in keep_refs {
+            for reference in self.repository().references_glob(glob)? {
+                roots.push(reference?.peel_to_commit()?.id());
+            }
+        }
+        Ok(roots)
+    }
+
+    // The content hashes of every shadow in the history of each of `commits`.
+    pub fn referenced_blobs(&self, commits: &[Oid]) -> Result<BTreeSet<ContentSha256>> {
+        let mut revwalk = self.repository().revwalk()?;
+        for commit in commits {
+            revwalk.push(*commit)?;
+        }
+        let mut blobs = BTreeSet::new();
+        // Shared across commits, so that trees which are unchanged between them are visited once.
+        let mut callbacks = OnUnique::new(ReferencedBlobsCallbacks { blobs: &mut blobs });
+        for commit in revwalk {
+            let tree = self.repository().find_commit(commit?)?.tree_id();
+            self.traverser(&mut callbacks).traverse(tree)?;
+        }
+        Ok(blobs)
+    }
+
+    // Remove each blob in `substance` which is not referenced from the history of one of the
+    // roots given by `gc_roots`, calling `on_unreferenced` for each one first. With
+    // `older_than`, only blobs last written or stored again longer ago than that are removed, so
+    // that those which an operation in progress has just stored are spared. With `dry_run`,
+    // nothing is removed.
+    pub fn gc(
+        &self,
+        substance: &impl Substance,
+        keep_refs: &[String],
+        older_than: Option<Duration>,
+        dry_run: bool,
+        mut on_unreferenced: impl FnMut(&ContentSha256) -> Result<()>,
+    ) -> Result<()> {
+        let roots = self.gc_roots(keep_refs)?;
+        // Otherwise every blob would go.
+        ensure!(
+            !roots.is_empty(),
+            "HEAD is unborn and no ref is to be kept, so there is nothing to keep"
+        );
+        let referenced = self.referenced_blobs(&roots)?;
+        let cutoff = older_than.map(|older_than| SystemTime::now() - older_than);
+        for blob in substance.list_blobs()? {
+            if !referenced.contains(&blob) {
+                if let Some(cutoff) = cutoff {
+                    match substance.modified(&blob)? {
+                        Some(modified) if modified < cutoff => {}
+                        _ => continue,
+                    }
+                }
+                on_unreferenced(&blob)?;
+                if !dry_run {
+                    substance
+                        .remove_blob(&blob)
+                        .with_context(|| format!("in {}", substance.describe()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ReferencedBlobsCallbacks<'a> {
+    blobs: &'a mut BTreeSet<ContentSha256>,
+}
+
+impl<'a> TraversalCallbacks for ReferencedBlobsCallbacks<'a> {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        self.blobs
+            .insert(visit.read_shadow()?.content_hash().clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    fn gc(repo: &TestRepo, keep_refs: &[&str], older_than: Option<Duration>) -> Vec<ContentSha256> {
+        let keep_refs = keep_refs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut removed = vec![];
+        repo.db
+            .gc(&repo.substance, &keep_refs, older_than, false, |blob| {
+                removed.push(blob.clone());
+                Ok(())
+            })
+            .unwrap();
+        removed
+    }
+
+    #[test]
+    fn keep_refs() {
+        let repo = TestRepo::new();
+        let old = repo.add_file(repo.empty_tree(), "old", b"old\n");
+        let old = repo.commit(old, &[]);
+        let head = repo.add_file(repo.empty_tree(), "head", b"head\n");
+        let head = repo.commit(head, &[old]);
+        repo.db.repository().set_head_detached(head).unwrap();
+        let archived = repo.add_file(repo.empty_tree(), "archived", b"archived\n");
+        let archived = repo.commit(archived, &[]);
+        repo.db
+            .repository()
+            .reference("refs/keep/archive/a", archived, false, "")
+            .unwrap();
+        let (archived_blob, _) = repo.write_subject_file("archived", b"archived\n");
+        let archived_blob = archived_blob.content_hash();
+
+        // Blobs referenced only by history are kept too.
+        assert!(gc(&repo, &["refs/keep/archive/*"], None).is_empty());
+        assert!(repo.substance.have_blob(archived_blob));
+        // Every ref is kept by default.
+        assert!(gc(&repo, &[], None).is_empty());
+        assert!(repo.substance.have_blob(archived_blob));
+        assert_eq!(
+            gc(&repo, &["refs/heads/*"], None),
+            vec![archived_blob.clone()]
+        );
+        assert!(!repo.substance.have_blob(archived_blob));
+        assert_eq!(repo.substance.list_blobs().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn older_than() {
+        let repo = TestRepo::new();
+        let head = repo.add_file(repo.empty_tree(), "head", b"head\n");
+        let head = repo.commit(head, &[]);
+        repo.db.repository().set_head_detached(head).unwrap();
+        let (fresh, _) = repo.write_subject_file("fresh", b"fresh\n");
+        let fresh = fresh.content_hash();
+
+        // Just stored, so within the grace period.
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert!(gc(&repo, &[], Some(day)).is_empty());
+        assert!(repo.substance.have_blob(fresh));
+        assert_eq!(gc(&repo, &[], None), vec![fresh.clone()]);
+        assert!(!repo.substance.have_blob(fresh));
+    }
+}
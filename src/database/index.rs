@@ -1,31 +1,53 @@
+use std::io::Write;
+use std::process::Stdio;
+
 use anyhow::Result;
-use git2::{FileMode, Oid};
+use git2::{FileMode, ObjectType, Oid, TreeWalkMode, TreeWalkResult};
 
 use crate::{Database, ShadowPath};
 
 impl Database {
-    fn add_to_index_unchecked(
-        &self,
-        mode: FileMode,
-        tree: Oid,
-        path: &str,
-        add_trailing_slash: bool,
-    ) -> Result<()> {
-        let trailing_slash = if add_trailing_slash { "/" } else { "" };
+    fn add_to_index_unchecked(&self, mode: FileMode, oid: Oid, path: &str) -> Result<()> {
         self.invoke_git(&[
             "update-index".to_string(),
             "--add".to_string(),
             "--cacheinfo".to_string(),
-            format!(
-                "{:06o},{},{}{}",
-                u32::from(mode),
-                tree,
-                path,
-                trailing_slash
-            ),
+            format!("{:06o},{},{}", u32::from(mode), oid, path),
         ])
     }
 
+    // The index holds only leaves, so a tree is staged as the entries below it, with `prefix`
+    // prepended to their paths.
+    fn add_tree_to_index_unchecked(&self, tree: Oid, prefix: &str) -> Result<()> {
+        let mut index_info = vec![];
+        self.repository()
+            .find_tree(tree)?
+            .walk(TreeWalkMode::PreOrder, |root, entry| {
+                if entry.kind() != Some(ObjectType::Tree) {
+                    index_info.extend_from_slice(
+                        format!(
+                            "{:06o} {}\t{}{}",
+                            entry.filemode(),
+                            entry.id(),
+                            prefix,
+                            root
+                        )
+                        .as_bytes(),
+                    );
+                    index_info.extend_from_slice(entry.name_bytes());
+                    index_info.push(0);
+                }
+                TreeWalkResult::Ok
+            })?;
+        let mut child = self
+            .git_command(&["update-index", "-z", "--index-info"])
+            .stdin(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(&index_info)?;
+        child.wait()?.exit_ok()?;
+        Ok(())
+    }
+
     pub fn add_to_index(
         &self,
         mode: FileMode,
@@ -35,14 +57,56 @@ impl Database {
         let empty_blob_oid = self.empty_blob_oid()?;
         let mut ancestor = ShadowPath::new();
         for component in relative_path.components() {
-            self.add_to_index_unchecked(
-                FileMode::Blob,
-                empty_blob_oid,
-                &ancestor.encode_marker(),
-                false,
-            )?;
+            self.add_to_index_unchecked(FileMode::Blob, empty_blob_oid, &ancestor.encode_marker())?;
             ancestor.push(component.clone());
         }
-        self.add_to_index_unchecked(mode, tree, &relative_path.encode(), true)
+        if mode == FileMode::Tree {
+            let prefix = if relative_path.components().is_empty() {
+                String::new()
+            } else {
+                format!("{}/", relative_path.encode())
+            };
+            self.add_tree_to_index_unchecked(tree, &prefix)
+        } else {
+            self.add_to_index_unchecked(mode, tree, &relative_path.encode())
+        }
+    }
+
+    // Replace the contents of the index with those of `tree`.
+    pub fn read_tree(&self, tree: Oid) -> Result<()> {
+        self.invoke_git(&["read-tree".to_string(), tree.to_string()])
+    }
+
+    // Write the contents of the index as a tree.
+    pub fn write_tree(&self) -> Result<Oid> {
+        let output = self.invoke_git_output(&["write-tree"])?;
+        Ok(std::str::from_utf8(&output)?.trim().parse()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn write_and_read_tree() {
+        let repo = TestRepo::new();
+        let a = repo.add_file(repo.empty_tree(), "x", b"x\n");
+        let b = repo.add_link(repo.empty_tree(), "l", "x");
+        let a_path = "snapshots/a".parse().unwrap();
+        let b_path = "b".parse().unwrap();
+        repo.db.add_to_index(FileMode::Tree, a, &a_path).unwrap();
+        repo.db.add_to_index(FileMode::Tree, b, &b_path).unwrap();
+
+        let tree = repo.db.write_tree().unwrap();
+        let expected = repo.add_object(repo.empty_tree(), "snapshots/a", FileMode::Tree, a);
+        let expected = repo.add_object(expected, "b", FileMode::Tree, b);
+        assert_eq!(tree, expected);
+
+        repo.db.read_tree(a).unwrap();
+        assert_eq!(repo.db.write_tree().unwrap(), a);
+        repo.db.read_tree(tree).unwrap();
+        assert_eq!(repo.db.write_tree().unwrap(), tree);
     }
 }
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str;
+
+use anyhow::{bail, Result};
+use git2::{FileMode, Oid, TreeEntry};
+
+use super::traverse::MaxFileCount;
+use crate::{ContentSha256, Database, ShadowPath, TraversalCallbacks, Visit, VisitShadow};
+
+// Links followed while resolving one, beyond which it is taken to be a loop, as in Linux.
+const MAX_LINK_FOLLOWS: usize = 40;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkTarget {
+    // The entry, other than a link, which the link leads to. The empty path is the root.
+    Entry(ShadowPath),
+    // The link or one it leads to is absolute or climbs above the root, so it is not followed.
+    OutOfTree,
+    Missing,
+    Loop,
+}
+
+impl fmt::Display for LinkTarget {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Entry(path) => write!(fmt, "'{}'", path),
+            Self::OutOfTree => write!(fmt, "out of tree"),
+            Self::Missing => write!(fmt, "missing"),
+            Self::Loop => write!(fmt, "loop"),
+        }
+    }
+}
+
+impl Database {
+    // Find the entry at `path` (which must be non-empty) in `tree`, or None if some component is
+    // absent or is not a directory.
+    pub fn lookup(&self, tree: Oid, path: &ShadowPath) -> Result<Option<TreeEntry<'static>>> {
+        let (last, init) = match path.components().split_last() {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        let mut tree = self.repository().find_tree(tree)?;
+        for component in init {
+            tree = match tree.get_name(&component.encode()) {
+                Some(entry) if entry.filemode() == FileMode::Tree.into() => {
+                    self.repository().find_tree(entry.id())?
+                }
+                _ => return Ok(None),
+            };
+        }
+        Ok(tree.get_name(&last.encode()).map(|entry| entry.to_owned()))
+    }
+
+    // Follow the link at `path` in `tree`, and any links it leads to, resolving targets relative
+    // to the directory containing each link.
+    pub fn resolve_link(&self, tree: Oid, path: &ShadowPath) -> Result<LinkTarget> {
+        let mut resolved = path.clone();
+        // Components yet to be resolved, last first.
+        let mut remaining = match resolved.pop() {
+            Some(last) => vec![last.to_string()],
+            None => bail!("the root is not a link"),
+        };
+        let mut follows = 0;
+        while let Some(component) = remaining.pop() {
+            match component.as_str() {
+                "" | "." => continue,
+                ".." => {
+                    if resolved.pop().is_none() {
+                        return Ok(LinkTarget::OutOfTree);
+                    }
+                    continue;
+                }
+                _ => resolved.push(component.parse()?),
+            }
+            let entry = match self.lookup(tree, &resolved)? {
+                Some(entry) => entry,
+                None => return Ok(LinkTarget::Missing),
+            };
+            if entry.filemode() == FileMode::Link.into() {
+                if follows == MAX_LINK_FOLLOWS {
+                    return Ok(LinkTarget::Loop);
+                }
+                follows += 1;
+                let blob = self.repository().find_blob(entry.id())?;
+                let target = str::from_utf8(blob.content())?;
+                if target.starts_with('/') {
+                    return Ok(LinkTarget::OutOfTree);
+                }
+                resolved.pop();
+                remaining.extend(target.split('/').rev().map(ToOwned::to_owned));
+            } else if entry.filemode() != FileMode::Tree.into() && !remaining.is_empty() {
+                // Like 'file/x' (or 'file/').
+                return Ok(LinkTarget::Missing);
+            }
+        }
+        Ok(LinkTarget::Entry(resolved))
+    }
+
+    // Every path in `tree` whose content is `blob`, in traversal order. Unlike `unique_shadows`,
+    // repeated occurrences are included.
+    pub fn blob_paths(&self, tree: Oid, blob: &ContentSha256) -> Result<Vec<ShadowPath>> {
+        let callbacks = BlobPathsCallbacks {
+            blob,
+            // Whether each shadow seen so far refers to `blob`, to avoid reading it again.
+            matches: BTreeMap::new(),
+            paths: vec![],
+        };
+        let mut callbacks = MaxFileCount::new(callbacks, self.max_file_count);
+        self.traverser(&mut callbacks).traverse(tree)?;
+        Ok(callbacks.into_inner().paths)
+    }
+}
+
+struct BlobPathsCallbacks<'a> {
+    blob: &'a ContentSha256,
+    matches: BTreeMap<Oid, bool>,
+    paths: Vec<ShadowPath>,
+}
+
+impl<'a> TraversalCallbacks for BlobPathsCallbacks<'a> {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        let is_match = match self.matches.get(&visit.oid()) {
+            Some(is_match) => *is_match,
+            None => {
+                let is_match = visit.read_shadow()?.content_hash() == self.blob;
+                self.matches.insert(visit.oid(), is_match);
+                is_match
+            }
+        };
+        if is_match {
+            self.paths.push(visit.path().clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn lookup() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "etc/passwd", b"root\n");
+        let tree = repo.add_link(tree, "etc/l", "passwd");
+
+        let lookup = |path: &str| repo.db.lookup(tree, &path.parse().unwrap()).unwrap();
+        assert_eq!(lookup("etc").unwrap().kind(), Some(git2::ObjectType::Tree));
+        assert_eq!(
+            lookup("etc/l").unwrap().filemode(),
+            git2::FileMode::Link.into()
+        );
+        assert!(lookup("etc/passwd").is_some());
+        assert!(lookup("etc/shadow").is_none());
+        assert!(lookup("etc/passwd/x").is_none());
+        assert!(lookup("").is_none());
+    }
+
+    #[test]
+    fn blob_paths() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"same\n");
+        let tree = repo.add_file(tree, "d/b", b"same\n");
+        let tree = repo.add_file(tree, "d/c", b"other\n");
+        let tree = repo.add_link(tree, "l", "a");
+        let (shadow, _) = repo.write_subject_file("x", b"same\n");
+
+        let paths = repo.db.blob_paths(tree, shadow.content_hash()).unwrap();
+        assert_eq!(
+            paths.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec!["a", "d/b"]
+        );
+        let (absent, _) = repo.write_subject_file("y", b"absent\n");
+        assert!(repo
+            .db
+            .blob_paths(tree, absent.content_hash())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn resolve_link() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "etc/passwd", b"root\n");
+        let tree = repo.add_link(tree, "etc/l", "passwd");
+        let tree = repo.add_link(tree, "a/b/up", "../../etc/./l");
+        let tree = repo.add_link(tree, "a/root", "..");
+        let tree = repo.add_link(tree, "a/abs", "/etc/passwd");
+        let tree = repo.add_link(tree, "a/out", "../..");
+        let tree = repo.add_link(tree, "a/missing", "b/nothing");
+        let tree = repo.add_link(tree, "a/not-dir", "../etc/passwd/x");
+        let tree = repo.add_link(tree, "x", "y");
+        let tree = repo.add_link(tree, "y", "a/../x");
+
+        let resolve = |path: &str| repo.db.resolve_link(tree, &path.parse().unwrap()).unwrap();
+        let entry = |path: &str| LinkTarget::Entry(path.parse().unwrap());
+        assert_eq!(resolve("etc/l"), entry("etc/passwd"));
+        assert_eq!(resolve("a/b/up"), entry("etc/passwd"));
+        assert_eq!(resolve("a/root"), entry(""));
+        assert_eq!(resolve("a/abs"), LinkTarget::OutOfTree);
+        assert_eq!(resolve("a/out"), LinkTarget::OutOfTree);
+        assert_eq!(resolve("a/missing"), LinkTarget::Missing);
+        assert_eq!(resolve("a/not-dir"), LinkTarget::Missing);
+        assert_eq!(resolve("x"), LinkTarget::Loop);
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{anyhow, Result};
+use git2::{FileMode, Oid};
+
+use super::traverse::MaxFileCount;
+use crate::{
+    Database, Shadow, ShadowPath, TraversalCallbacks, Visit, VisitLink, VisitShadow, VisitTree,
+    VisitTreeDecision,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LsEntryValue {
+    Tree,
+    Shadow(Shadow),
+    Link(String),
+}
+
+impl Database {
+    // Entries below `tree`, sorted bytewise by path so that listings can be compared with diff.
+    // Unlike `unique_shadows`, every occurrence of a shared object is listed.
+    pub fn ls(&self, tree: Oid, recursive: bool) -> Result<Vec<(String, LsEntryValue)>> {
+        let mut callbacks = MaxFileCount::new(
+            LsCallbacks {
+                recursive,
+                entries: vec![],
+            },
+            self.max_file_count,
+        );
+        self.traverser(&mut callbacks).traverse(tree)?;
+        let mut entries = callbacks.into_inner().entries;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    // The entry at `path` in `tree`, where the empty path is the root itself.
+    pub fn ls_entry(&self, tree: Oid, path: &ShadowPath) -> Result<LsEntryValue> {
+        if path.components().is_empty() {
+            return Ok(LsEntryValue::Tree);
+        }
+        let entry = self
+            .lookup(tree, path)?
+            .ok_or_else(|| anyhow!("'{}' does not exist", path))?;
+        let mode = entry.filemode();
+        Ok(if mode == FileMode::Tree.into() {
+            LsEntryValue::Tree
+        } else {
+            let blob = self.repository().find_blob(entry.id())?;
+            if mode == FileMode::Link.into() {
+                LsEntryValue::Link(std::str::from_utf8(blob.content())?.to_owned())
+            } else {
+                LsEntryValue::Shadow(Shadow::from_bytes(blob.content())?)
+            }
+        })
+    }
+}
+
+struct LsCallbacks {
+    recursive: bool,
+    entries: Vec<(String, LsEntryValue)>,
+}
+
+impl TraversalCallbacks for LsCallbacks {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        self.entries.push((
+            visit.path().to_string(),
+            LsEntryValue::Shadow(visit.read_shadow()?),
+        ));
+        Ok(())
+    }
+
+    fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+        self.entries.push((
+            visit.path().to_string(),
+            LsEntryValue::Link(visit.read_link()?),
+        ));
+        Ok(())
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        if visit.path().components().is_empty() {
+            return Ok(VisitTreeDecision::Descend);
+        }
+        self.entries
+            .push((visit.path().to_string(), LsEntryValue::Tree));
+        Ok(if self.recursive {
+            VisitTreeDecision::Descend
+        } else {
+            VisitTreeDecision::Skip
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    fn paths(entries: &[(String, LsEntryValue)]) -> Vec<&str> {
+        entries.iter().map(|(path, _)| path.as_str()).collect()
+    }
+
+    #[test]
+    fn sorted() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a-", b"x\n");
+        let tree = repo.add_file(tree, "a/x", b"x\n");
+        let tree = repo.add_link(tree, "b", "a");
+
+        let entries = repo.db.ls(tree, true).unwrap();
+        assert_eq!(paths(&entries), vec!["a", "a-", "a/x", "b"]);
+        assert_eq!(entries[1].1, entries[2].1);
+        assert_eq!(entries[3].1, LsEntryValue::Link("a".to_owned()));
+        assert_eq!(repo.db.ls(tree, true).unwrap(), entries);
+
+        assert_eq!(
+            paths(&repo.db.ls(tree, false).unwrap()),
+            vec!["a", "a-", "b"]
+        );
+    }
+}
@@ -1,44 +1,164 @@
-use std::process::Command;
+use std::cell::Cell;
+use std::process::{Command, Stdio};
 
-use anyhow::{Error, Result};
-use git2::{Commit, Oid, Repository, Signature, Tree};
+use anyhow::{anyhow, ensure, Error, Result};
+use git2::{Commit, ErrorCode, FileMode, Oid, Reference, Repository, Signature, Sort, Tree};
 
-use crate::{shallow_diff, ShallowDifference};
+use crate::{
+    shallow_diff, shallow_diff_under, ShadowPath, ShadowTreeEntryName, ShallowDifference, Timestamp,
+};
 
 mod append;
 mod remove;
 mod traverse;
+mod check;
+mod check_blobs;
+mod fsck;
 mod snapshot;
 mod index;
 mod fs;
+mod export;
+mod repack;
+mod stats;
+mod lookup;
+mod notes;
+mod ls;
+mod filter_tree;
+mod repair;
+mod verify;
+mod gc;
+mod restore;
+mod pending;
+mod compact;
+mod cat;
+mod recent;
+mod diff_summary;
+mod checkpoint;
 
+pub use check::{CheckPolicy, CheckProblem};
+pub use check_blobs::{BlobChecks, BlobProblem};
+pub use compact::{Compaction, COMPACT_BACKUP_REF_PREFIX};
+pub use diff_summary::DiffSummary;
+pub use filter_tree::TreeFilter;
+pub use fs::{MountConfig, MountMetrics};
+pub use fsck::MissingObject;
+pub use lookup::LinkTarget;
+pub use ls::LsEntryValue;
+pub(crate) use notes::{parse_tag, TAGS_NOTES_REF};
+pub use pending::PendingStore;
+pub(crate) use pending::PENDING_NOTES_REF;
+pub use recent::{snapshot_commit_message, SnapshotRef, SNAPSHOT_PATH_TRAILER};
+pub use repack::ObjectCounts;
+pub use repair::RepairOutcome;
+pub use restore::{RestoreConfig, RestoreProgress};
+pub use snapshot::{StoreConfig, StoreProgress, StoreReport};
+pub use stats::{StoredSize, TreeStats};
 pub use traverse::{
-    TraversalCallbacks, Traverser, Visit, VisitLink, VisitShadow, VisitTree, VisitTreeDecision,
+    StopTraversal, TraversalCallbacks, Traverser, Visit, VisitLink, VisitShadow, VisitTree,
+    VisitTreeDecision,
 };
+pub use verify::VerifyReport;
+
+// The shortest abbreviated object id which libgit2 will look up.
+const MIN_OID_PREFIX_LEN: usize = 4;
+
+// Dates to record in commits, where None means the current time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitDates {
+    pub author: Option<Timestamp>,
+    pub committer: Option<Timestamp>,
+}
 
 pub struct Database {
     repository: Repository,
+    max_file_count: Option<u64>,
+    max_depth: usize,
+    empty_tree: Cell<Option<Oid>>,
 }
 
 impl Database {
+    // Enough for any path that fits in PATH_MAX, while keeping recursive traversals well clear of
+    // the end of the stack.
+    pub const DEFAULT_MAX_DEPTH: usize = 2048;
+
     pub fn new(repository: Repository) -> Self {
-        Self { repository }
+        Self {
+            repository,
+            max_file_count: None,
+            max_depth: Self::DEFAULT_MAX_DEPTH,
+            empty_tree: Cell::new(None),
+        }
     }
 
     pub fn repository(&self) -> &Repository {
         &self.repository
     }
 
+    // Abort traversals which visit more than `max` entries. Unlimited by default.
+    pub fn set_max_file_count(&mut self, max: Option<u64>) {
+        self.max_file_count = max;
+    }
+
+    // Abort traversals which reach trees nested more than `max` deep.
+    pub fn set_max_depth(&mut self, max: usize) {
+        self.max_depth = max;
+    }
+
     pub fn resolve_treeish(&self, treeish: &str) -> Result<Oid> {
-        // TODO validate treeish?
-        Ok(self
-            .repository()
-            .revparse_single(treeish)?
-            .peel_to_tree()?
-            .id())
+        let object = match self.repository().revparse_single(treeish) {
+            Ok(object) => object,
+            Err(err) => return Err(self.explain_revparse_error(treeish, err)),
+        };
+        Ok(object.peel_to_tree()?.id())
     }
 
-    pub fn invoke_git(&self, args: &[impl AsRef<str>]) -> Result<()> {
+    // libgit2 accepts abbreviated object ids of at least 4 hex digits, but says little about why
+    // one fails to resolve.
+    fn explain_revparse_error(&self, treeish: &str, err: git2::Error) -> Error {
+        let is_hex = !treeish.is_empty() && treeish.bytes().all(|b| b.is_ascii_hexdigit());
+        if !is_hex {
+            return err.into();
+        }
+        if treeish.len() < MIN_OID_PREFIX_LEN {
+            return anyhow!(
+                "'{}' is too short to be an abbreviated object id (at least {} hex digits are needed)",
+                treeish,
+                MIN_OID_PREFIX_LEN
+            );
+        }
+        if err.code() != ErrorCode::Ambiguous {
+            return err.into();
+        }
+        let prefix = treeish.to_ascii_lowercase();
+        let mut candidates = vec![];
+        let listed = self.repository().odb().and_then(|odb| {
+            odb.foreach(|oid| {
+                if oid.to_string().starts_with(&prefix) {
+                    candidates.push(*oid);
+                }
+                true
+            })
+        });
+        if listed.is_err() || candidates.is_empty() {
+            return err.into();
+        }
+        candidates.sort();
+        let candidates = candidates
+            .iter()
+            .map(|oid| match self.repository().find_object(*oid, None) {
+                Ok(object) => format!("  {} {}", oid, object.kind().map_or("?", |kind| kind.str())),
+                Err(_) => format!("  {}", oid),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        anyhow!(
+            "abbreviated object id '{}' is ambiguous. Candidates are:\n{}",
+            treeish,
+            candidates
+        )
+    }
+
+    fn git_command(&self, args: &[impl AsRef<str>]) -> Command {
         let mut cmd = Command::new("git");
         cmd.env_clear();
         cmd.env("GIT_DIR", self.repository().path());
@@ -46,15 +166,114 @@ impl Database {
             cmd.arg(arg.as_ref());
         }
         eprintln!("{:?}", cmd);
-        cmd.status()?.exit_ok()?;
+        cmd
+    }
+
+    pub fn invoke_git(&self, args: &[impl AsRef<str>]) -> Result<()> {
+        self.git_command(args).status()?.exit_ok()?;
         Ok(())
     }
 
+    pub fn invoke_git_output(&self, args: &[impl AsRef<str>]) -> Result<Vec<u8>> {
+        let output = self.git_command(args).stderr(Stdio::inherit()).output()?;
+        output.status.exit_ok()?;
+        Ok(output.stdout)
+    }
+
     pub fn empty_blob_oid(&self) -> Result<Oid> {
         let writer = self.repository().blob_writer(None)?;
         Ok(writer.commit()?)
     }
 
+    // The big tree with no entries besides its marker.
+    pub fn empty_tree(&self) -> Result<Oid> {
+        if let Some(oid) = self.empty_tree.get() {
+            return Ok(oid);
+        }
+        let mut builder = self.repository().treebuilder(None)?;
+        builder.insert(
+            ShadowTreeEntryName::Marker.encode(),
+            self.empty_blob_oid()?,
+            FileMode::Blob.into(),
+        )?;
+        let oid = builder.write()?;
+        self.empty_tree.set(Some(oid));
+        Ok(oid)
+    }
+
+    // The commit at HEAD, or None if HEAD is unborn.
+    pub fn head_commit(&self) -> Result<Option<Commit<'_>>> {
+        match self.repository().head() {
+            Ok(head) => Ok(Some(head.peel_to_commit()?)),
+            Err(err) if err.code() == ErrorCode::UnbornBranch => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Every ref other than HEAD, with the object it resolves to, sorted by name.
+    pub fn refs(&self) -> Result<Vec<(String, Oid)>> {
+        let mut refs = vec![];
+        for reference in self.repository().references()? {
+            let reference = reference?;
+            if let (Some(name), Some(target)) = (reference.name(), reference.resolve()?.target()) {
+                refs.push((name.to_owned(), target));
+            }
+        }
+        refs.sort();
+        Ok(refs)
+    }
+
+    // The commit at `refname`, or None if the ref does not exist.
+    pub fn ref_commit(&self, refname: &str) -> Result<Option<Commit<'_>>> {
+        match self.repository().find_reference(refname) {
+            Ok(reference) => Ok(Some(reference.peel_to_commit()?)),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // The commit at `refname`, falling back to the commit at HEAD if the ref does not exist.
+    pub fn ref_or_head_commit(&self, refname: &str) -> Result<Option<Commit<'_>>> {
+        match self.ref_commit(refname)? {
+            Some(commit) => Ok(Some(commit)),
+            None => self.head_commit(),
+        }
+    }
+
+    // Commit `tree` on top of the commit at `refname` (or at HEAD if the ref does not exist yet)
+    // and advance the ref, failing if it has moved in the meantime.
+    pub fn commit_to_ref(
+        &self,
+        refname: &str,
+        message: &str,
+        tree: Oid,
+        dates: &CommitDates,
+    ) -> Result<Oid> {
+        ensure!(
+            Reference::is_valid_name(refname) && refname.starts_with("refs/"),
+            "invalid ref name '{}'",
+            refname
+        );
+        let tip = self.ref_commit(refname)?;
+        let parent = match &tip {
+            Some(tip) => Some(tip.clone()),
+            None => self.head_commit()?,
+        };
+        let tree = self.repository().find_tree(tree)?;
+        let commit =
+            self.commit_simple(message, &tree, &parent.iter().collect::<Vec<_>>(), dates)?;
+        match tip {
+            Some(tip) => {
+                self.repository()
+                    .reference_matching(refname, commit, true, tip.id(), message)?
+            }
+            None => self
+                .repository()
+                .reference(refname, commit, false, message)?,
+        };
+        Ok(commit)
+    }
+
     pub fn shallow_diff(
         &self,
         tree_a: Oid,
@@ -64,16 +283,86 @@ impl Database {
         shallow_diff(&self.repository, tree_a, tree_b, callback).map_err(Error::from)
     }
 
+    // Like `shallow_diff`, but only for the entries below `path`, which is compared as a subtree on
+    // each side rather than by walking the rest of the trees. It must be a directory on at least
+    // one side, and is taken as empty on a side which lacks it.
+    pub fn shallow_diff_at(
+        &self,
+        tree_a: Oid,
+        tree_b: Oid,
+        path: &ShadowPath,
+        callback: impl for<'b> FnMut(&ShallowDifference<'b>) -> Result<(), Error>,
+    ) -> Result<()> {
+        if path.components().is_empty() {
+            return self.shallow_diff(tree_a, tree_b, callback);
+        }
+        let subtree = |tree| -> Result<Option<Oid>> {
+            match self.lookup(tree, path)? {
+                Some(entry) if entry.filemode() == FileMode::Tree.into() => Ok(Some(entry.id())),
+                Some(_) => Err(anyhow!("'{}' is not a directory in {}", path, tree)),
+                None => Ok(None),
+            }
+        };
+        let (subtree_a, subtree_b) = (subtree(tree_a)?, subtree(tree_b)?);
+        ensure!(
+            subtree_a.is_some() || subtree_b.is_some(),
+            "'{}' exists in neither {} nor {}",
+            path,
+            tree_a,
+            tree_b
+        );
+        let empty_tree = self.empty_tree()?;
+        let prefix = path
+            .components()
+            .iter()
+            .map(|component| component.encode().into_bytes())
+            .collect();
+        shallow_diff_under(
+            &self.repository,
+            prefix,
+            subtree_a.unwrap_or(empty_tree),
+            subtree_b.unwrap_or(empty_tree),
+            callback,
+        )
+        .map_err(Error::from)
+    }
+
+    // The commits reachable from `to` but not from `from`, as in 'git log from..to', parents
+    // first.
+    pub fn range_commits(&self, from: &str, to: &str) -> Result<Vec<Oid>> {
+        let mut revwalk = self.repository().revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.push(
+            self.repository()
+                .revparse_single(to)?
+                .peel_to_commit()?
+                .id(),
+        )?;
+        revwalk.hide(
+            self.repository()
+                .revparse_single(from)?
+                .peel_to_commit()?
+                .id(),
+        )?;
+        Ok(revwalk.collect::<Result<_, _>>()?)
+    }
+
     pub fn commit_simple(
         &self,
         message: &str,
         tree: &Tree<'_>,
-        parent: &Commit<'_>,
+        parents: &[&Commit<'_>],
+        dates: &CommitDates,
     ) -> Result<Oid> {
-        let dummy_sig = Signature::now("x", "x@x")?;
+        let dummy_sig = |date: Option<Timestamp>| match date {
+            None => Signature::now("x", "x@x"),
+            Some(date) => Signature::new("x", "x@x", &date.to_git_time()),
+        };
+        let author = dummy_sig(dates.author)?;
+        let committer = dummy_sig(dates.committer)?;
         Ok(self
             .repository()
-            .commit(None, &dummy_sig, &dummy_sig, message, tree, &[parent])?)
+            .commit(None, &author, &committer, message, tree, parents)?)
     }
 
     pub fn safe_merge(&self, progress: Oid) -> Result<()> {
@@ -84,4 +373,332 @@ impl Database {
             progress.to_string(),
         ])
     }
+
+    // Run `f`, and if it fails, put each of `refnames` back to where it pointed beforehand,
+    // deleting those which did not exist. "HEAD" stands for the branch it refers to. Objects
+    // written by `f` are left behind, unreferenced.
+    pub fn restore_refs_on_error<T>(
+        &self,
+        refnames: &[&str],
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let mut saved = vec![];
+        for refname in refnames {
+            let refname = if *refname == "HEAD" {
+                let head = self.repository().find_reference("HEAD")?;
+                head.symbolic_target().unwrap_or("HEAD").to_owned()
+            } else {
+                refname.to_string()
+            };
+            let target = match self.repository().refname_to_id(&refname) {
+                Ok(oid) => Some(oid),
+                Err(err) if err.code() == ErrorCode::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            saved.push((refname, target));
+        }
+        let result = f();
+        if result.is_err() {
+            for (refname, target) in &saved {
+                if let Err(err) = self.restore_ref(refname, *target) {
+                    log::error!("failed to restore {}: {}", refname, err);
+                }
+            }
+        }
+        result
+    }
+
+    // Put `refname` back to `target`, deleting it if None. Each update is conditional on the ref
+    // still having the value read here, which is what the failed operation left, so that a ref
+    // moved by someone else in the meantime is not clobbered.
+    fn restore_ref(&self, refname: &str, target: Option<Oid>) -> Result<(), git2::Error> {
+        let message = "keep: restore after failure";
+        let reference = match self.repository().find_reference(refname) {
+            Ok(reference) => Some(reference),
+            Err(err) if err.code() == ErrorCode::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        let written = reference.as_ref().and_then(|reference| reference.target());
+        if written == target {
+            return Ok(());
+        }
+        match (target, written, reference) {
+            (Some(oid), Some(written), Some(_)) => self
+                .repository()
+                .reference_matching(refname, oid, true, written, message)
+                .map(|_| ()),
+            (Some(oid), _, None) => self
+                .repository()
+                .reference(refname, oid, false, message)
+                .map(|_| ()),
+            // Deleting fails if the reference has changed since it was looked up.
+            (None, _, Some(mut reference)) => reference.delete(),
+            (Some(_), None, Some(_)) => Err(git2::Error::from_str("not a direct reference")),
+            (None, _, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::testing::TestRepo;
+    use crate::{ShallowDifferenceKind, ShallowEntry};
+
+    // The path of a difference, with what was there before and after.
+    fn change(
+        difference: &ShallowDifference,
+    ) -> Result<(String, Option<ShallowEntry>, Option<ShallowEntry>)> {
+        let path = difference.decode_path()?.to_string();
+        Ok(match difference.kind {
+            ShallowDifferenceKind::Added(new) => (path, None, Some(new)),
+            ShallowDifferenceKind::Removed(old) => (path, Some(old), None),
+            ShallowDifferenceKind::Modified { old, new }
+            | ShallowDifferenceKind::TypeChanged { old, new } => (path, Some(old), Some(new)),
+        })
+    }
+
+    #[test]
+    fn empty_tree() {
+        let repo = TestRepo::new();
+        let tree = repo.db.empty_tree().unwrap();
+        assert_eq!(repo.db.empty_tree().unwrap(), tree);
+        assert_eq!(repo.db.repository().find_tree(tree).unwrap().len(), 1);
+        let stats = repo.db.stats(tree).unwrap();
+        assert_eq!((stats.trees, stats.shadows, stats.links), (1, 0, 0));
+    }
+
+    #[test]
+    fn abbreviated_oids() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let commit = repo.commit(tree, &[]).to_string();
+        assert_eq!(repo.db.resolve_treeish(&commit[..7]).unwrap(), tree);
+        assert_eq!(
+            repo.db
+                .resolve_treeish(&commit[..7].to_ascii_uppercase())
+                .unwrap(),
+            tree
+        );
+
+        let err = repo.db.resolve_treeish(&commit[..3]).unwrap_err();
+        assert!(err.to_string().contains("too short"));
+
+        // Write blobs until two share a 4 digit prefix.
+        let mut seen = BTreeMap::new();
+        let (a, b) = (0..)
+            .find_map(|i| {
+                let oid = repo
+                    .db
+                    .repository()
+                    .blob(format!("{}", i).as_bytes())
+                    .unwrap();
+                seen.insert(oid.to_string()[..4].to_owned(), oid)
+                    .map(|other| (other, oid))
+            })
+            .unwrap();
+        let err = repo
+            .db
+            .resolve_treeish(&a.to_string()[..4])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("ambiguous"));
+        assert!(err.contains(&format!("{} blob", a)));
+        assert!(err.contains(&format!("{} blob", b)));
+    }
+
+    #[test]
+    fn refs() {
+        let repo = TestRepo::new();
+        assert!(repo.db.refs().unwrap().is_empty());
+        let dates = CommitDates::default();
+        let tree = repo.add_file(repo.empty_tree(), "b", b"b\n");
+        let b = repo
+            .db
+            .commit_to_ref("refs/heads/b", "x", tree, &dates)
+            .unwrap();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let a = repo
+            .db
+            .commit_to_ref("refs/heads/a", "x", tree, &dates)
+            .unwrap();
+        repo.db
+            .repository()
+            .reference_symbolic("refs/heads/c", "refs/heads/a", false, "")
+            .unwrap();
+        assert_eq!(
+            repo.db.refs().unwrap(),
+            vec![
+                ("refs/heads/a".to_owned(), a),
+                ("refs/heads/b".to_owned(), b),
+                ("refs/heads/c".to_owned(), a),
+            ]
+        );
+    }
+
+    #[test]
+    fn restore_refs_on_error() {
+        let repo = TestRepo::new();
+        let dates = CommitDates::default();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let first = repo
+            .db
+            .commit_to_ref("refs/heads/a", "x", tree, &dates)
+            .unwrap();
+        let tags: BTreeMap<_, _> = vec![("k".to_owned(), "v".to_owned())].into_iter().collect();
+        let head = repo.db.repository().find_reference("HEAD").unwrap();
+        let branch = head.symbolic_target().unwrap().to_owned();
+        let refnames = ["refs/heads/a", "refs/heads/b", "HEAD", TAGS_NOTES_REF];
+        let result = repo.db.restore_refs_on_error(&refnames, || {
+            let tree = repo.add_file(tree, "b", b"b\n");
+            let second = repo.db.commit_to_ref("refs/heads/a", "x", tree, &dates)?;
+            repo.db.commit_to_ref("refs/heads/b", "x", tree, &dates)?;
+            repo.db.add_tags(second, &tags)?;
+            repo.db.repository().reference(&branch, second, true, "")?;
+            Err::<(), _>(anyhow!("failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            repo.db.refs().unwrap(),
+            vec![("refs/heads/a".to_owned(), first)]
+        );
+        assert!(repo.db.head_commit().unwrap().is_none());
+    }
+
+    #[test]
+    fn range_commits() {
+        let repo = TestRepo::new();
+        let tree_1 = repo.add_file(repo.empty_tree(), "a", b"1\n");
+        let tree_1 = repo.add_file(tree_1, "b", b"1\n");
+        let tree_2 = repo.add_file(tree_1, "a", b"2\n");
+        let tree_2 = repo.add_file(tree_2, "c", b"2\n");
+        let tree_3 = repo.db.remove(tree_2, &"b".parse().unwrap()).unwrap();
+        let tree_3 = repo.add_file(tree_3, "a", b"3\n");
+        let commit_1 = repo.commit(tree_1, &[]);
+        let commit_2 = repo.commit(tree_2, &[commit_1]);
+        let commit_3 = repo.commit(tree_3, &[commit_2]);
+
+        let range = repo
+            .db
+            .range_commits(&commit_1.to_string(), &commit_3.to_string())
+            .unwrap();
+        assert_eq!(range, vec![commit_2, commit_3]);
+
+        // Compose the changes of each commit into one change per path.
+        let mut composed = BTreeMap::new();
+        let mut parent_tree = tree_1;
+        for commit in range {
+            let tree = repo.db.repository().find_commit(commit).unwrap().tree_id();
+            repo.db
+                .shallow_diff(parent_tree, tree, |difference| {
+                    let (path, old, new) = change(difference)?;
+                    composed.entry(path).or_insert((old, new)).1 = new;
+                    Ok(())
+                })
+                .unwrap();
+            parent_tree = tree;
+        }
+        composed.retain(|_, (old, new)| old != new);
+
+        let mut cumulative = BTreeMap::new();
+        repo.db
+            .shallow_diff(tree_1, tree_3, |difference| {
+                let (path, old, new) = change(difference)?;
+                cumulative.insert(path, (old, new));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(composed, cumulative);
+        assert_eq!(cumulative.keys().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn shallow_diff_at() {
+        let repo = TestRepo::new();
+        let tree_a = repo.add_file(repo.empty_tree(), "d/e/x", b"a\n");
+        let tree_a = repo.add_file(tree_a, "y", b"a\n");
+        let tree_b = repo.add_file(repo.empty_tree(), "d/e/x", b"b\n");
+        let tree_b = repo.add_file(tree_b, "d/z", b"b\n");
+        let tree_b = repo.add_file(tree_b, "n/w", b"b\n");
+
+        let diff_at = |path: &str| {
+            let mut paths = vec![];
+            repo.db
+                .shallow_diff_at(tree_a, tree_b, &path.parse().unwrap(), |difference| {
+                    paths.push(change(difference)?.0);
+                    Ok(())
+                })
+                .map(|()| paths)
+        };
+        assert_eq!(diff_at("d/e").unwrap(), vec!["d/e/x"]);
+        assert_eq!(diff_at("d").unwrap(), vec!["d/e/x", "d/z"]);
+        // Only in tree_b, so compared with an empty tree.
+        assert_eq!(diff_at("n").unwrap(), vec!["n/w"]);
+        assert!(diff_at("missing").is_err());
+        assert!(diff_at("y").is_err());
+    }
+
+    #[test]
+    fn commit_to_ref() {
+        let repo = TestRepo::new();
+        let refname = "refs/heads/backup";
+        let dates = CommitDates::default();
+        assert!(repo.db.ref_commit(refname).unwrap().is_none());
+        let first_tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let first = repo
+            .db
+            .commit_to_ref(refname, "x", first_tree, &dates)
+            .unwrap();
+        let second_tree = repo.add_file(first_tree, "b", b"b\n");
+        let second = repo
+            .db
+            .commit_to_ref(refname, "x", second_tree, &dates)
+            .unwrap();
+        let tip = repo.db.ref_commit(refname).unwrap().unwrap();
+        assert_eq!(tip.id(), second);
+        assert_eq!(tip.tree_id(), second_tree);
+        assert_eq!(tip.parent_ids().collect::<Vec<_>>(), vec![first]);
+        assert!(repo
+            .db
+            .commit_to_ref("backup", "x", second_tree, &dates)
+            .is_err());
+    }
+
+    #[test]
+    fn reproducible_commit() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.db.repository().find_tree(tree).unwrap();
+        let timestamp = "2021-10-20T12:34:56+02:00".parse().ok();
+        let dates = CommitDates {
+            author: timestamp,
+            committer: timestamp,
+        };
+        let commit = || repo.db.commit_simple("x", &tree, &[], &dates).unwrap();
+        assert_eq!(commit(), commit());
+    }
+
+    #[test]
+    fn commit_to_new_ref_from_head() {
+        let repo = TestRepo::new();
+        let head_tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let head = repo.commit(head_tree, &[]);
+        repo.db
+            .repository()
+            .reference("refs/heads/master", head, true, "x")
+            .unwrap();
+
+        let staging = "refs/keep/staging";
+        let tree = repo.add_file(head_tree, "b", b"b\n");
+        let commit = repo
+            .db
+            .commit_to_ref(staging, "x", tree, &CommitDates::default())
+            .unwrap();
+        assert_eq!(repo.db.head_commit().unwrap().unwrap().id(), head);
+        let tip = repo.db.ref_commit(staging).unwrap().unwrap();
+        assert_eq!(tip.id(), commit);
+        assert_eq!(tip.parent_ids().collect::<Vec<_>>(), vec![head]);
+    }
 }
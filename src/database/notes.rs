@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, ensure, Result};
+use git2::{ErrorCode, Oid, Signature};
+
+use crate::Database;
+
+// Key/value tags attached to commits, stored as git notes with one "key=value" per line.
+pub const TAGS_NOTES_REF: &str = "refs/notes/keep";
+
+impl Database {
+    pub fn tags(&self, commit: Oid) -> Result<BTreeMap<String, String>> {
+        let note = match self.repository().find_note(Some(TAGS_NOTES_REF), commit) {
+            Ok(note) => note,
+            Err(err) if err.code() == ErrorCode::NotFound => return Ok(BTreeMap::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let message = note
+            .message()
+            .ok_or_else(|| anyhow!("note on {} is not valid utf-8", commit))?;
+        message
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (key, value) = parse_tag(line)?;
+                Ok((key.to_owned(), value.to_owned()))
+            })
+            .collect()
+    }
+
+    // Merge `tags` into those already attached to `commit`, replacing the values of existing keys.
+    pub fn add_tags(&self, commit: Oid, tags: &BTreeMap<String, String>) -> Result<()> {
+        let mut merged = self.tags(commit)?;
+        for (key, value) in tags {
+            ensure!(
+                !key.is_empty() && !key.contains(&['=', '\n'][..]) && !value.contains('\n'),
+                "invalid tag '{}={}'",
+                key,
+                value
+            );
+            merged.insert(key.clone(), value.clone());
+        }
+        let message = merged
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect::<String>();
+        let signature = Signature::now("x", "x@x")?;
+        self.repository().note(
+            &signature,
+            &signature,
+            Some(TAGS_NOTES_REF),
+            commit,
+            &message,
+            true,
+        )?;
+        Ok(())
+    }
+}
+
+pub fn parse_tag(s: &str) -> Result<(&str, &str)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key, value)),
+        _ => Err(anyhow!("malformed tag '{}' (expected KEY=VALUE)", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn tags() {
+        let repo = TestRepo::new();
+        let commit = repo.commit(repo.empty_tree(), &[]);
+        assert!(repo.db.tags(commit).unwrap().is_empty());
+
+        let tags = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        repo.db
+            .add_tags(commit, &tags(&[("host", "web1"), ("policy", "hourly")]))
+            .unwrap();
+        repo.db
+            .add_tags(commit, &tags(&[("policy", "daily=x")]))
+            .unwrap();
+        assert_eq!(
+            repo.db.tags(commit).unwrap(),
+            tags(&[("host", "web1"), ("policy", "daily=x")])
+        );
+        assert!(repo.db.add_tags(commit, &tags(&[("", "x")])).is_err());
+
+        assert_eq!(parse_tag("a=b=c").unwrap(), ("a", "b=c"));
+        assert!(parse_tag("=b").is_err());
+        assert!(parse_tag("a").is_err());
+    }
+}
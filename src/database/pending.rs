@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, ensure, Result};
+use git2::{ErrorCode, Oid, Signature};
+
+use crate::{Database, ShadowPath, StoreConfig, StoreReport, Substance};
+
+// Commits made without storing the snapshot's blobs carry a note with the path of the snapshot in
+// the big tree and the subject to store its blobs from, one per line.
+pub const PENDING_NOTES_REF: &str = "refs/notes/keep-pending";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingStore {
+    pub commit: Oid,
+    pub relative_path: ShadowPath,
+    pub subject: PathBuf,
+}
+
+impl Database {
+    // Record that the blobs of the snapshot at `relative_path` in `commit` are yet to be stored
+    // from `subject`.
+    pub fn mark_pending(
+        &self,
+        commit: Oid,
+        relative_path: &ShadowPath,
+        subject: &Path,
+    ) -> Result<()> {
+        let subject = subject.canonicalize()?;
+        let subject = subject
+            .to_str()
+            .filter(|subject| !subject.contains('\n'))
+            .ok_or_else(|| anyhow!("cannot record subject {}", subject.display()))?;
+        let signature = Signature::now("x", "x@x")?;
+        self.repository().note(
+            &signature,
+            &signature,
+            Some(PENDING_NOTES_REF),
+            commit,
+            &format!("{}\n{}\n", relative_path, subject),
+            true,
+        )?;
+        Ok(())
+    }
+
+    // The pending stores of every commit which has one, except those in the history of `since`,
+    // oldest first. Commits need not be reachable from HEAD, as when snapshots are committed to
+    // another ref.
+    pub fn pending_stores(&self, since: Option<Oid>) -> Result<Vec<PendingStore>> {
+        let notes = match self.repository().notes(Some(PENDING_NOTES_REF)) {
+            Ok(notes) => notes,
+            Err(err) if err.code() == ErrorCode::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut pending = vec![];
+        for note in notes {
+            let (_, commit) = note?;
+            if let Some(since) = since {
+                if commit == since || self.repository().graph_descendant_of(since, commit)? {
+                    continue;
+                }
+            }
+            let note = self
+                .repository()
+                .find_note(Some(PENDING_NOTES_REF), commit)?;
+            let (relative_path, subject) = note
+                .message()
+                .and_then(|message| message.strip_suffix('\n'))
+                .and_then(|message| message.split_once('\n'))
+                .ok_or_else(|| anyhow!("malformed pending note on {}", commit))?;
+            let time = self.repository().find_commit(commit)?.time().seconds();
+            pending.push((
+                time,
+                PendingStore {
+                    commit,
+                    relative_path: relative_path.parse()?,
+                    subject: subject.into(),
+                },
+            ));
+        }
+        pending.sort_by_key(|(time, pending)| (*time, pending.commit));
+        Ok(pending.into_iter().map(|(_, pending)| pending).collect())
+    }
+
+    // Store the blobs of each pending snapshot, except those in the history of `since`, and then
+    // remove its note, so that it is no longer pending. Blobs which are already present are
+    // skipped as usual.
+    pub fn store_pending(
+        &self,
+        substance: &(impl Substance + Sync),
+        since: Option<Oid>,
+        config: &StoreConfig,
+        mut on_stored: impl FnMut(&PendingStore, &StoreReport) -> Result<()>,
+    ) -> Result<()> {
+        for pending in self.pending_stores(since)? {
+            let big_tree = self.repository().find_commit(pending.commit)?.tree_id();
+            let tree = self
+                .lookup(big_tree, &pending.relative_path)?
+                .ok_or_else(|| {
+                    anyhow!(
+                        "'{}' does not exist in {}",
+                        pending.relative_path,
+                        pending.commit
+                    )
+                })?
+                .id();
+            let report = self.store_snapshot(substance, tree, &pending.subject, config)?;
+            ensure!(
+                report.failures.is_empty(),
+                "failed to store {} blobs of '{}' from {}",
+                report.failures.len(),
+                pending.relative_path,
+                pending.commit
+            );
+            let signature = Signature::now("x", "x@x")?;
+            self.repository().note_delete(
+                pending.commit,
+                Some(PENDING_NOTES_REF),
+                &signature,
+                &signature,
+            )?;
+            on_stored(&pending, &report)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::FileMode;
+
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn store_pending() {
+        let repo = TestRepo::new();
+        let stored = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let first = repo.commit(stored, &[]);
+        let snapshot = repo.add_unstored_file(repo.empty_tree(), "a", b"a\n");
+        let snapshot = repo.add_unstored_file(snapshot, "b", b"b\n");
+        let big_tree = repo.add_object(repo.empty_tree(), "s", FileMode::Tree, snapshot);
+        let second = repo.commit(big_tree, &[first]);
+        let path = "s".parse().unwrap();
+        repo.db
+            .mark_pending(second, &path, &repo.subject())
+            .unwrap();
+
+        let store = |since| {
+            let mut reports = vec![];
+            repo.db
+                .store_pending(
+                    &repo.substance,
+                    since,
+                    &StoreConfig::default(),
+                    |pending, report| {
+                        reports.push((pending.commit, report.blobs_stored, report.blobs_skipped));
+                        Ok(())
+                    },
+                )
+                .unwrap();
+            reports
+        };
+        assert!(store(Some(second)).is_empty());
+        // No ref leads to the commit.
+        assert_eq!(store(None), vec![(second, 1, 1)]);
+        // Nothing is pending once stored.
+        assert!(store(None).is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use git2::{Commit, Oid};
+
+use crate::{Database, ShadowPath, Timestamp};
+
+// Commits made by 'snapshot' end their message with this trailer, followed by where in the big
+// tree the snapshot was added.
+pub const SNAPSHOT_PATH_TRAILER: &str = "Snapshot-Path: ";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotRef {
+    pub commit: Oid,
+    // When the commit was made.
+    pub time: Timestamp,
+    pub message: String,
+    pub big_tree: Oid,
+    // None for commits without the trailer, such as those made by other commands or before the
+    // trailer was recorded.
+    pub relative_path: Option<ShadowPath>,
+}
+
+impl SnapshotRef {
+    fn new(commit: &Commit) -> Self {
+        let message = String::from_utf8_lossy(commit.message_bytes()).into_owned();
+        let relative_path = message
+            .lines()
+            .rev()
+            .find_map(|line| line.strip_prefix(SNAPSHOT_PATH_TRAILER))
+            .and_then(|path| path.parse().ok());
+        Self {
+            commit: commit.id(),
+            time: Timestamp {
+                seconds: commit.time().seconds(),
+                offset_minutes: commit.time().offset_minutes(),
+            },
+            message,
+            big_tree: commit.tree_id(),
+            relative_path,
+        }
+    }
+}
+
+// The message of a commit adding a snapshot at `relative_path`. A path which cannot be recorded
+// on one line is left out.
+pub fn snapshot_commit_message(subject: &str, relative_path: &ShadowPath) -> String {
+    let relative_path = relative_path.to_string();
+    if relative_path.contains('\n') {
+        return subject.to_owned();
+    }
+    format!(
+        "{}\n\n{}{}\n",
+        subject, SNAPSHOT_PATH_TRAILER, relative_path
+    )
+}
+
+impl Database {
+    // Up to `n` of the latest commits on HEAD, newest first, following first parents so that
+    // commits merged in from elsewhere are left out. Empty if HEAD is unborn.
+    pub fn recent_snapshots(&self, n: usize) -> Result<Vec<SnapshotRef>> {
+        let mut snapshots = vec![];
+        let mut next = self.head_commit()?;
+        while let Some(commit) = next {
+            if snapshots.len() == n {
+                break;
+            }
+            snapshots.push(SnapshotRef::new(&commit));
+            next = commit.parents().next();
+        }
+        Ok(snapshots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+    use crate::CommitDates;
+
+    #[test]
+    fn recent_snapshots() {
+        let repo = TestRepo::new();
+        assert!(repo.db.recent_snapshots(10).unwrap().is_empty());
+
+        let mut parent = None;
+        let mut commits = vec![];
+        for (i, message) in vec![
+            "other".to_owned(),
+            snapshot_commit_message("x", &"a/b".parse().unwrap()),
+            snapshot_commit_message("x", &"c".parse().unwrap()),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let tree = repo.add_file(repo.empty_tree(), &format!("f{}", i), b"f\n");
+            let dates = CommitDates {
+                committer: Some(Timestamp {
+                    seconds: 1000 + i as i64,
+                    offset_minutes: 60,
+                }),
+                ..Default::default()
+            };
+            let tree = repo.db.repository().find_tree(tree).unwrap();
+            let commit = repo
+                .db
+                .commit_simple(&message, &tree, &parent.iter().collect::<Vec<_>>(), &dates)
+                .unwrap();
+            commits.push(commit);
+            parent = Some(repo.db.repository().find_commit(commit).unwrap());
+        }
+        repo.db.repository().set_head_detached(commits[2]).unwrap();
+
+        let recent = repo.db.recent_snapshots(2).unwrap();
+        assert_eq!(
+            recent.iter().map(|s| s.commit).collect::<Vec<_>>(),
+            vec![commits[2], commits[1]]
+        );
+        assert_eq!(recent[0].relative_path, Some("c".parse().unwrap()));
+        assert_eq!(recent[1].relative_path, Some("a/b".parse().unwrap()));
+        assert_eq!(
+            recent[0].time,
+            Timestamp {
+                seconds: 1002,
+                offset_minutes: 60
+            }
+        );
+        assert_eq!(recent[0].big_tree, parent.unwrap().tree_id());
+
+        let all = repo.db.recent_snapshots(10).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].message, "other");
+        assert_eq!(all[2].relative_path, None);
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str;
+
+use anyhow::{anyhow, Result};
+
+use crate::Database;
+
+impl Database {
+    // Pack the loose objects which accumulate as snapshots are planted, returning object counts
+    // from before and after.
+    pub fn repack(&self, aggressive: bool) -> Result<(ObjectCounts, ObjectCounts)> {
+        let before = self.count_objects()?;
+        let mut args = vec!["gc", "--quiet"];
+        if aggressive {
+            args.push("--aggressive");
+        }
+        self.invoke_git(&args[..])?;
+        let after = self.count_objects()?;
+        Ok((before, after))
+    }
+
+    pub fn count_objects(&self) -> Result<ObjectCounts> {
+        let output = self.invoke_git_output(&["count-objects", "-v"])?;
+        ObjectCounts::parse(str::from_utf8(&output)?)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectCounts {
+    pub loose_objects: u64,
+    pub loose_size_kib: u64,
+    pub packed_objects: u64,
+    pub packs: u64,
+    pub packed_size_kib: u64,
+}
+
+impl ObjectCounts {
+    // Parses the output of `git count-objects -v`.
+    fn parse(s: &str) -> Result<Self> {
+        let fields = s
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .collect::<BTreeMap<&str, &str>>();
+        let field = |key: &str| -> Result<u64> {
+            Ok(fields
+                .get(key)
+                .ok_or_else(|| anyhow!("missing '{}' in output of git count-objects", key))?
+                .parse()?)
+        };
+        Ok(Self {
+            loose_objects: field("count")?,
+            loose_size_kib: field("size")?,
+            packed_objects: field("in-pack")?,
+            packs: field("packs")?,
+            packed_size_kib: field("size-pack")?,
+        })
+    }
+}
+
+impl fmt::Display for ObjectCounts {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} loose objects ({} KiB), {} packed objects in {} packs ({} KiB)",
+            self.loose_objects,
+            self.loose_size_kib,
+            self.packed_objects,
+            self.packs,
+            self.packed_size_kib
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_count_objects() {
+        let output = "count: 12\nsize: 48\nin-pack: 3\npacks: 1\nsize-pack: 2\n\
+                      prune-packable: 0\ngarbage: 0\nsize-garbage: 0\n";
+        assert_eq!(
+            ObjectCounts::parse(output).unwrap(),
+            ObjectCounts {
+                loose_objects: 12,
+                loose_size_kib: 48,
+                packed_objects: 3,
+                packs: 1,
+                packed_size_kib: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+use git2::Oid;
+
+use super::snapshot::source_path;
+use crate::{sha256sum, BlobChecks, BlobProblem, Database, ShadowPath, Substance};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepairOutcome {
+    Repaired,
+    SourceMissing,
+    SourceChanged,
+}
+
+impl RepairOutcome {
+    pub fn is_repaired(&self) -> bool {
+        *self == Self::Repaired
+    }
+}
+
+impl fmt::Display for RepairOutcome {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Repaired => write!(fmt, "repaired"),
+            Self::SourceMissing => write!(fmt, "unrepairable (source missing)"),
+            Self::SourceChanged => write!(fmt, "unrepairable (source changed)"),
+        }
+    }
+}
+
+impl Database {
+    // Re-store each missing or invalid blob in `tree` from the corresponding file in `subject`,
+    // provided that file still has the recorded content. As with `check_blobs`, a blob which
+    // occurs at several paths is only attempted from the first.
+    pub fn repair_blobs(
+        &self,
+        tree: Oid,
+        substance: &impl Substance,
+        subject: &Path,
+        mut on_outcome: impl FnMut(&ShadowPath, BlobProblem, RepairOutcome) -> Result<()>,
+    ) -> Result<()> {
+        let checks = BlobChecks {
+            missing: true,
+            invalid: true,
+        };
+        self.check_blobs(tree, substance, &checks, |path, shadow, problem| {
+            let blob = shadow.content_hash();
+            let src = source_path(subject, path, 0)?;
+            let outcome = if !src.is_file() {
+                RepairOutcome::SourceMissing
+            } else if sha256sum(&src)? != *blob {
+                RepairOutcome::SourceChanged
+            } else {
+                if problem == BlobProblem::Invalid {
+                    substance.remove_blob(blob)?;
+                }
+                substance.store(blob, &src)?;
+                RepairOutcome::Repaired
+            };
+            on_outcome(path, problem, outcome)
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn rehash() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "missing", b"missing\n");
+        let tree = repo.add_file(tree, "corrupt", b"corrupt\n");
+        let tree = repo.add_file(tree, "gone", b"gone\n");
+        let tree = repo.add_file(tree, "changed", b"changed\n");
+        for path in &["missing", "gone", "changed"] {
+            let (shadow, _) = repo.write_subject_file(path, format!("{}\n", path).as_bytes());
+            fs::remove_file(repo.substance.blob_path(shadow.content_hash())).unwrap();
+        }
+        let (corrupt, _) = repo.write_subject_file("corrupt", b"corrupt\n");
+        let blob_path = repo.substance.blob_path(corrupt.content_hash());
+        fs::set_permissions(&blob_path, Permissions::from_mode(0o644)).unwrap();
+        fs::write(&blob_path, b"tampered\n").unwrap();
+        fs::remove_file(repo.subject().join("gone")).unwrap();
+        fs::write(repo.subject().join("changed"), b"different\n").unwrap();
+
+        let mut outcomes = vec![];
+        repo.db
+            .repair_blobs(
+                tree,
+                &repo.substance,
+                &repo.subject(),
+                |path, _, outcome| {
+                    outcomes.push(format!("{}: {}", outcome, path));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            outcomes,
+            vec![
+                "unrepairable (source changed): changed",
+                "repaired: corrupt",
+                "unrepairable (source missing): gone",
+                "repaired: missing",
+            ]
+        );
+        let mut remaining = vec![];
+        repo.db
+            .check_blobs(
+                tree,
+                &repo.substance,
+                &BlobChecks {
+                    missing: true,
+                    invalid: true,
+                },
+                |path, _, _| {
+                    remaining.push(path.to_string());
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(remaining, vec!["changed", "gone"]);
+    }
+}
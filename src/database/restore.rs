@@ -0,0 +1,349 @@
+use std::fs::{self, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use git2::Oid;
+
+use super::traverse::MaxFileCount;
+use crate::{
+    Database, ShadowPath, Substance, TraversalCallbacks, Visit, VisitLink, VisitShadow, VisitTree,
+    VisitTreeDecision,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct RestoreConfig {
+    // If non-empty, just the entries at or below these paths are written, at the same place
+    // relative to the target.
+    pub only: Vec<ShadowPath>,
+    // Warn about paths in `only` which do not exist in the tree rather than failing.
+    pub ignore_missing: bool,
+    // Check each blob against its content hash before it is written.
+    pub verify: bool,
+    // Updated as each file is written, for reporting progress from another thread. The totals are
+    // counted in a first pass over the tree, before anything is written.
+    pub progress: Option<Arc<RestoreProgress>>,
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreProgress {
+    counted: AtomicBool,
+    total_files: AtomicU64,
+    total_bytes: AtomicU64,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl RestoreProgress {
+    // The numbers of files and bytes to write, or None while they are still being counted. Bytes
+    // are summed from the sizes in shadows, so shadows without one count for nothing.
+    pub fn totals(&self) -> Option<(u64, u64)> {
+        if self.counted.load(Ordering::Relaxed) {
+            Some((
+                self.total_files.load(Ordering::Relaxed),
+                self.total_bytes.load(Ordering::Relaxed),
+            ))
+        } else {
+            None
+        }
+    }
+
+    // The numbers of files and bytes written so far.
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.files.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl Database {
+    // Write the files, links, and directories of `tree` under `target`, with content from
+    // `substance`, as `config` says. Existing files are not overwritten.
+    pub fn restore(
+        &self,
+        tree: Oid,
+        substance: &impl Substance,
+        target: &Path,
+        config: &RestoreConfig,
+    ) -> Result<()> {
+        let mut present = vec![];
+        for path in &config.only {
+            if self.lookup(tree, path)?.is_some() {
+                present.push(path.clone());
+            } else if config.ignore_missing {
+                log::warn!("'{}' does not exist", path);
+            } else {
+                bail!("'{}' does not exist", path);
+            }
+        }
+        if !config.only.is_empty() && present.is_empty() {
+            return Ok(());
+        }
+        if let Some(progress) = &config.progress {
+            let mut counter = MaxFileCount::new(
+                CountCallbacks {
+                    only: &present,
+                    files: 0,
+                    bytes: 0,
+                },
+                self.max_file_count,
+            );
+            self.traverser(&mut counter).traverse(tree)?;
+            let counter = counter.into_inner();
+            progress.total_files.store(counter.files, Ordering::Relaxed);
+            progress.total_bytes.store(counter.bytes, Ordering::Relaxed);
+            progress.counted.store(true, Ordering::Relaxed);
+        }
+        let callbacks = RestoreCallbacks {
+            substance,
+            target,
+            only: &present,
+            verify: config.verify,
+            progress: config.progress.as_deref(),
+        };
+        let mut callbacks = MaxFileCount::new(callbacks, self.max_file_count);
+        self.traverser(&mut callbacks).traverse(tree)
+    }
+}
+
+// Whether `path` is at or below one of `only`, where an empty `only` selects everything.
+fn selected(only: &[ShadowPath], path: &ShadowPath) -> bool {
+    only.is_empty()
+        || only
+            .iter()
+            .any(|only| path.components().starts_with(only.components()))
+}
+
+// Whether the tree at `path` holds anything selected.
+fn descend(only: &[ShadowPath], path: &ShadowPath) -> VisitTreeDecision {
+    if selected(only, path)
+        || only
+            .iter()
+            .any(|only| only.components().starts_with(path.components()))
+    {
+        VisitTreeDecision::Descend
+    } else {
+        VisitTreeDecision::Skip
+    }
+}
+
+struct CountCallbacks<'a> {
+    only: &'a [ShadowPath],
+    files: u64,
+    bytes: u64,
+}
+
+impl<'a> TraversalCallbacks for CountCallbacks<'a> {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        if selected(self.only, visit.path()) {
+            self.files += 1;
+            self.bytes += visit.read_shadow()?.size().unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        Ok(descend(self.only, visit.path()))
+    }
+}
+
+struct RestoreCallbacks<'a, S> {
+    substance: &'a S,
+    target: &'a Path,
+    only: &'a [ShadowPath],
+    verify: bool,
+    progress: Option<&'a RestoreProgress>,
+}
+
+impl<'a, S: Substance> RestoreCallbacks<'a, S> {
+    fn dest(&self, path: &ShadowPath) -> PathBuf {
+        path.components()
+            .iter()
+            .fold(self.target.to_path_buf(), |dest, component| {
+                dest.join(component.as_ref())
+            })
+    }
+
+    // The parent directory may not exist yet if only this entry was selected.
+    fn create_parent(&self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest.parent().unwrap())?;
+        Ok(())
+    }
+}
+
+impl<'a, S: Substance> TraversalCallbacks for RestoreCallbacks<'a, S> {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        if !selected(self.only, visit.path()) {
+            return Ok(());
+        }
+        let dest = self.dest(visit.path());
+        self.create_parent(&dest)?;
+        let shadow = visit.read_shadow()?;
+        if self.verify {
+            self.substance
+                .check_blob(shadow.content_hash())
+                .with_context(|| format!("verifying the blob for '{}'", visit.path()))?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&dest)
+            .with_context(|| format!("creating {}", dest.display()))?;
+        let copied = io::copy(&mut self.substance.open(shadow.content_hash())?, &mut file)?;
+        if visit.executable() {
+            file.set_permissions(Permissions::from_mode(0o755))?;
+        }
+        if let Some(progress) = self.progress {
+            progress.files.fetch_add(1, Ordering::Relaxed);
+            progress.bytes.fetch_add(copied, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+        if !selected(self.only, visit.path()) {
+            return Ok(());
+        }
+        let dest = self.dest(visit.path());
+        self.create_parent(&dest)?;
+        symlink(visit.read_link()?, &dest)
+            .with_context(|| format!("creating {}", dest.display()))?;
+        Ok(())
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        if selected(self.only, visit.path()) {
+            fs::create_dir_all(self.dest(visit.path()))?;
+        }
+        Ok(descend(self.only, visit.path()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::FileMode;
+
+    use super::*;
+    use crate::testing::{TempDir, TestRepo};
+
+    #[test]
+    fn only() {
+        let repo = TestRepo::new();
+        let mut tree = repo.empty_tree();
+        for path in &["a/b/x", "a/b/e/y", "a/c/z", "d/w", "v"] {
+            tree = repo.add_file(tree, path, path.as_bytes());
+        }
+        tree = repo.add_link(tree, "a/b/l", "x");
+
+        let out = TempDir::new();
+        let target = out.path().join("target");
+        let config = RestoreConfig {
+            only: vec!["a/b".parse().unwrap(), "v".parse().unwrap()],
+            ..Default::default()
+        };
+        repo.db
+            .restore(tree, &repo.substance, &target, &config)
+            .unwrap();
+        assert_eq!(fs::read(target.join("a/b/e/y")).unwrap(), b"a/b/e/y");
+        assert_eq!(fs::read_link(target.join("a/b/l")).unwrap(), Path::new("x"));
+        assert_eq!(fs::read(target.join("v")).unwrap(), b"v");
+        assert!(!target.join("a/c").exists());
+        assert!(!target.join("d").exists());
+
+        let mut config = RestoreConfig {
+            only: vec!["a/nothing".parse().unwrap()],
+            ..Default::default()
+        };
+        let target = out.path().join("missing");
+        assert!(repo
+            .db
+            .restore(tree, &repo.substance, &target, &config)
+            .is_err());
+        config.ignore_missing = true;
+        repo.db
+            .restore(tree, &repo.substance, &target, &config)
+            .unwrap();
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn executable() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "f", b"f\n");
+        let shadow = repo
+            .db
+            .lookup(tree, &"f".parse().unwrap())
+            .unwrap()
+            .unwrap()
+            .id();
+        let tree = repo.add_object(tree, "x", FileMode::BlobExecutable, shadow);
+
+        let out = TempDir::new();
+        let target = out.path().join("target");
+        repo.db
+            .restore(tree, &repo.substance, &target, &Default::default())
+            .unwrap();
+        let mode = |name: &str| {
+            fs::metadata(target.join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+        };
+        assert_eq!(mode("f") & 0o111, 0);
+        assert_eq!(mode("x") & 0o777, 0o755);
+    }
+
+    #[test]
+    fn verify() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "corrupt", b"corrupt\n");
+        let (shadow, _) = repo.write_subject_file("corrupt", b"corrupt\n");
+        let blob_path = repo.substance.blob_path(shadow.content_hash());
+        fs::set_permissions(&blob_path, Permissions::from_mode(0o644)).unwrap();
+        fs::write(&blob_path, b"tampered\n").unwrap();
+
+        let out = TempDir::new();
+        let target = out.path().join("verified");
+        let config = RestoreConfig {
+            verify: true,
+            ..Default::default()
+        };
+        assert!(repo
+            .db
+            .restore(tree, &repo.substance, &target, &config)
+            .is_err());
+        assert!(!target.join("corrupt").exists());
+        let target = out.path().join("unverified");
+        repo.db
+            .restore(tree, &repo.substance, &target, &Default::default())
+            .unwrap();
+        assert_eq!(fs::read(target.join("corrupt")).unwrap(), b"tampered\n");
+    }
+
+    #[test]
+    fn progress() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a/x", b"12345");
+        let tree = repo.add_file(tree, "a/y", b"123");
+        let tree = repo.add_file(tree, "b/z", b"1");
+        let tree = repo.add_link(tree, "a/l", "x");
+
+        let progress = Arc::new(RestoreProgress::default());
+        assert_eq!(progress.totals(), None);
+        let config = RestoreConfig {
+            only: vec!["a".parse().unwrap()],
+            progress: Some(progress.clone()),
+            ..Default::default()
+        };
+        let out = TempDir::new();
+        repo.db
+            .restore(tree, &repo.substance, &out.path().join("target"), &config)
+            .unwrap();
+        assert_eq!(progress.totals(), Some((2, 8)));
+        assert_eq!(progress.counts(), (2, 8));
+    }
+}
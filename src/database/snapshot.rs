@@ -1,15 +1,142 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use fallible_iterator::{FallibleIterator, Peekable};
 use git2::{FileMode, Oid};
 
+use super::checkpoint::Checkpoint;
+use crate::parallel;
 use crate::{
-    Database, ShadowTreeEntryName, Snapshot, SnapshotEntries, SnapshotEntry, SnapshotEntryValue,
-    Substance,
+    Database, Manifest, Shadow, ShadowPath, ShadowTreeEntryName, Snapshot, SnapshotEntries,
+    SnapshotEntry, SnapshotEntryValue, Stored, Substance,
 };
 
+#[derive(Clone, Debug, Default)]
+pub struct StoreConfig {
+    // The first `strip_prefix` components of each path are dropped before joining it onto the
+    // subject.
+    pub strip_prefix: usize,
+    // Record failures to store individual blobs in the report rather than aborting.
+    pub keep_going: bool,
+    // The number of blobs to store at once. 0 is treated as 1.
+    pub jobs: usize,
+    // Updated as each blob is stored, for reporting progress from another thread.
+    pub progress: Option<Arc<StoreProgress>>,
+    // With `store_snapshot`, a file recording the blobs confirmed stored so far. Those recorded
+    // by an earlier, interrupted run for the same tree are counted as skipped without consulting
+    // the substance. The file is removed once every blob is stored.
+    pub checkpoint: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct StoreProgress {
+    scanning: AtomicBool,
+    scanned: AtomicU64,
+    stored: AtomicU64,
+    skipped: AtomicU64,
+}
+
+impl StoreProgress {
+    // While the tree is being walked to find the blobs to store, the number of entries visited so
+    // far.
+    pub fn scanning(&self) -> Option<u64> {
+        if self.scanning.load(Ordering::Relaxed) {
+            Some(self.scanned.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+
+    // The numbers of blobs stored and skipped so far.
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.stored.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+        )
+    }
+
+    fn record(&self, stored: Stored) {
+        let counter = match stored {
+            Stored::New => &self.stored,
+            Stored::AlreadyPresent => &self.skipped,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// What `store_snapshot` did with each distinct blob in the tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreReport {
+    pub blobs_total: u64,
+    pub blobs_stored: u64,
+    pub blobs_skipped: u64,
+    pub bytes_stored: u64,
+    // Only populated with `keep_going`.
+    pub failures: Vec<(ShadowPath, String)>,
+}
+
+impl StoreReport {
+    fn record(
+        &mut self,
+        path: &ShadowPath,
+        config: &StoreConfig,
+        result: Result<(Stored, u64)>,
+    ) -> Result<()> {
+        self.blobs_total += 1;
+        match result {
+            Ok((Stored::New, size)) => {
+                self.blobs_stored += 1;
+                self.bytes_stored += size;
+            }
+            Ok((Stored::AlreadyPresent, _)) => {
+                self.blobs_skipped += 1;
+            }
+            Err(err) if config.keep_going => {
+                log::warn!("failed to store '{}': {:#}", path, err);
+                self.failures.push((path.clone(), format!("{:#}", err)));
+            }
+            Err(err) => return Err(err.context(format!("storing '{}'", path))),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for StoreReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "stored {} new blobs ({}), skipped {} existing",
+            self.blobs_stored,
+            human_bytes(self.bytes_stored),
+            self.blobs_skipped
+        )?;
+        if !self.failures.is_empty() {
+            write!(fmt, ", failed to store {}", self.failures.len())?;
+        }
+        Ok(())
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
 impl Database {
     pub fn plant_snapshot(&self, snapshot: &Snapshot) -> Result<(FileMode, Oid)> {
         let mut entries = snapshot.entries()?.peekable();
@@ -75,15 +202,386 @@ impl Database {
 
     pub fn store_snapshot(
         &self,
-        substance: &impl Substance,
+        substance: &(impl Substance + Sync),
         tree: Oid,
         subject: &Path,
-    ) -> Result<()> {
-        self.unique_shadows(tree, |path, shadow| {
-            let src = subject.join(path.to_string());
-            substance.store(shadow.content_hash(), &src)?;
-            Ok(())
-        })?;
-        Ok(())
+        config: &StoreConfig,
+    ) -> Result<StoreReport> {
+        let progress = config.progress.as_deref();
+        if let Some(progress) = progress {
+            progress.scanning.store(true, Ordering::Relaxed);
+        }
+        let mut jobs = vec![];
+        let scanned = self.unique_shadows_with_progress(
+            tree,
+            |entries| {
+                if let Some(progress) = progress {
+                    progress.scanned.store(entries, Ordering::Relaxed);
+                }
+                Ok(())
+            },
+            |path, shadow| {
+                let src = source_path(subject, path, config.strip_prefix)?;
+                jobs.push((path.clone(), shadow.clone(), src));
+                Ok(())
+            },
+        );
+        if let Some(progress) = progress {
+            progress.scanning.store(false, Ordering::Relaxed);
+        }
+        scanned?;
+        let checkpoint = match &config.checkpoint {
+            Some(path) => Some(Checkpoint::open(path, tree)?),
+            None => None,
+        };
+        let report = store_shadows(substance, jobs, config, false, checkpoint.as_ref())?;
+        if let Some(checkpoint) = checkpoint {
+            if report.failures.is_empty() {
+                checkpoint.remove()?;
+            }
+        }
+        Ok(report)
+    }
+
+    // Like `store_snapshot`, but for content which has been hashed and listed in a manifest but
+    // not yet planted. Sizes given in the manifest are checked before storing, and hashes are
+    // checked by the substance as usual.
+    pub fn store_manifest(
+        &self,
+        substance: &(impl Substance + Sync),
+        manifest: &Manifest,
+        subject: &Path,
+        config: &StoreConfig,
+    ) -> Result<StoreReport> {
+        let mut jobs = vec![];
+        let mut seen = BTreeSet::new();
+        for entry in manifest.entries() {
+            if !seen.insert(entry.shadow.content_hash()) {
+                continue;
+            }
+            let src = source_path(subject, &entry.path, config.strip_prefix)?;
+            jobs.push((entry.path.clone(), entry.shadow.clone(), src));
+        }
+        store_shadows(substance, jobs, config, true, None)
+    }
+}
+
+// Store each blob on `config.jobs` threads. Outcomes are recorded in the order of `jobs`, and
+// without `keep_going` the first failure stops the rest.
+fn store_shadows(
+    substance: &(impl Substance + Sync),
+    jobs: Vec<(ShadowPath, Shadow, PathBuf)>,
+    config: &StoreConfig,
+    check_sizes: bool,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<StoreReport> {
+    let outcomes = parallel::run_bounded(jobs, config.jobs, |(path, shadow, src)| {
+        let blob = shadow.content_hash();
+        let recorded = checkpoint.filter(|checkpoint| checkpoint.contains(blob));
+        let result = if recorded.is_some() {
+            Ok((Stored::AlreadyPresent, shadow.size().unwrap_or(0)))
+        } else if check_sizes {
+            check_size(&shadow, &src).and_then(|()| store_shadow(substance, &shadow, &src))
+        } else {
+            store_shadow(substance, &shadow, &src)
+        };
+        if let (Some(checkpoint), None, Ok(_)) = (checkpoint, recorded, &result) {
+            checkpoint.record(blob)?;
+        }
+        if let (Some(progress), Ok((stored, _))) = (&config.progress, &result) {
+            progress.record(*stored);
+        }
+        match result {
+            Err(err) if !config.keep_going => Err(err.context(format!("storing '{}'", path))),
+            result => Ok((path, result)),
+        }
+    });
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.flush()?;
+    }
+    let outcomes = outcomes?;
+    let mut report = StoreReport::default();
+    for (path, result) in outcomes {
+        report.record(&path, config, result)?;
+    }
+    Ok(report)
+}
+
+// Returns the number of bytes in the blob along with the outcome.
+fn store_shadow(substance: &impl Substance, shadow: &Shadow, src: &Path) -> Result<(Stored, u64)> {
+    let stored = substance.store(shadow.content_hash(), src)?;
+    let size = match shadow.size() {
+        Some(size) => size,
+        None => fs::metadata(src)?.len(),
+    };
+    Ok((stored, size))
+}
+
+fn check_size(shadow: &Shadow, src: &Path) -> Result<()> {
+    if let Some(size) = shadow.size() {
+        let actual = fs::metadata(src)?.len();
+        ensure!(
+            actual == size,
+            "{} has size {}, expected {}",
+            src.display(),
+            actual,
+            size
+        );
+    }
+    Ok(())
+}
+
+pub(super) fn source_path(
+    subject: &Path,
+    path: &ShadowPath,
+    strip_prefix: usize,
+) -> Result<PathBuf> {
+    let components = path.components();
+    ensure!(
+        components.len() > strip_prefix,
+        "cannot strip {} components from '{}'",
+        strip_prefix,
+        path
+    );
+    Ok(components[strip_prefix..]
+        .iter()
+        .fold(subject.to_path_buf(), |src, component| {
+            src.join(component.as_ref())
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn strip_prefix() {
+        let path = "old/root/a/b".parse().unwrap();
+        assert_eq!(
+            source_path(Path::new("/subject"), &path, 0).unwrap(),
+            Path::new("/subject/old/root/a/b")
+        );
+        assert_eq!(
+            source_path(Path::new("/subject"), &path, 2).unwrap(),
+            Path::new("/subject/a/b")
+        );
+        assert!(source_path(Path::new("/subject"), &path, 4).is_err());
+    }
+
+    #[test]
+    fn store_report() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.add_unstored_file(tree, "b", b"bb\n");
+        let tree = repo.add_unstored_file(tree, "c", b"bb\n");
+
+        let report = repo
+            .db
+            .store_snapshot(
+                &repo.substance,
+                tree,
+                &repo.subject(),
+                &StoreConfig::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            report,
+            StoreReport {
+                blobs_total: 2,
+                blobs_stored: 1,
+                blobs_skipped: 1,
+                bytes_stored: 3,
+                failures: vec![],
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "stored 1 new blobs (3 B), skipped 1 existing"
+        );
+    }
+
+    #[test]
+    fn progress() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.add_unstored_file(tree, "b", b"b\n");
+        let tree = repo.add_unstored_file(tree, "c", b"c\n");
+        let progress = Arc::new(StoreProgress::default());
+        let config = StoreConfig {
+            progress: Some(progress.clone()),
+            ..Default::default()
+        };
+        repo.db
+            .store_snapshot(&repo.substance, tree, &repo.subject(), &config)
+            .unwrap();
+        assert_eq!(progress.counts(), (2, 1));
+        assert_eq!(progress.scanning(), None);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.add_unstored_file(tree, "b", b"b\n");
+        let tree = repo.add_unstored_file(tree, "c", b"c\n");
+        let checkpoint = repo.dir.path().join("checkpoint");
+        let store = || {
+            repo.db
+                .store_snapshot(
+                    &repo.substance,
+                    tree,
+                    &repo.subject(),
+                    &StoreConfig {
+                        keep_going: true,
+                        checkpoint: Some(checkpoint.clone()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+        };
+        let (b, _) = repo.write_subject_file("b", b"b\n");
+
+        fs::remove_file(repo.subject().join("c")).unwrap();
+        let report = store();
+        assert_eq!(report.failures.len(), 1);
+        let recorded = fs::read_to_string(&checkpoint).unwrap();
+        assert_eq!(recorded.lines().next().unwrap(), format!("tree {}", tree));
+        assert_eq!(recorded.lines().count(), 3);
+
+        // The checkpoint is trusted over the substance, so 'b' is not stored again.
+        fs::remove_file(repo.substance.blob_path(b.content_hash())).unwrap();
+        repo.write_subject_file("c", b"c\n");
+        let report = store();
+        assert_eq!((report.blobs_stored, report.blobs_skipped), (1, 2));
+        assert!(!repo.substance.have_blob(b.content_hash()));
+        assert!(!checkpoint.exists());
+
+        // A checkpoint for another tree is ignored.
+        fs::write(
+            &checkpoint,
+            format!("tree {}\n{}\n", repo.empty_tree(), b.content_hash()),
+        )
+        .unwrap();
+        let report = store();
+        assert_eq!((report.blobs_stored, report.blobs_skipped), (1, 2));
+        assert!(repo.substance.have_blob(b.content_hash()));
+    }
+
+    #[test]
+    fn parallel_store() {
+        let repo = TestRepo::new();
+        let mut tree = repo.empty_tree();
+        for i in 0..50 {
+            tree = repo.add_unstored_file(tree, &format!("f{}", i), format!("{}\n", i).as_bytes());
+        }
+        fs::remove_file(repo.subject().join("f20")).unwrap();
+        fs::remove_file(repo.subject().join("f40")).unwrap();
+
+        let store = |keep_going| {
+            repo.db.store_snapshot(
+                &repo.substance,
+                tree,
+                &repo.subject(),
+                &StoreConfig {
+                    keep_going,
+                    jobs: 4,
+                    ..Default::default()
+                },
+            )
+        };
+        let err = store(false).unwrap_err();
+        assert!(format!("{:#}", err).starts_with("storing 'f20'"));
+        let report = store(true).unwrap();
+        assert_eq!((report.blobs_total, report.failures.len()), (50, 2));
+        assert_eq!(report.failures[0].0.to_string(), "f20");
+        assert_eq!(report.failures[1].0.to_string(), "f40");
+    }
+
+    #[test]
+    fn keep_going() {
+        let repo = TestRepo::new();
+        let tree = repo.add_unstored_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.add_unstored_file(tree, "b", b"b\n");
+        let (c, _) = repo.write_subject_file("c", b"c\n");
+        let tree = repo.add_shadow(tree, "c", &c);
+        fs::remove_file(repo.subject().join("b")).unwrap();
+
+        let store = |keep_going| {
+            repo.db.store_snapshot(
+                &repo.substance,
+                tree,
+                &repo.subject(),
+                &StoreConfig {
+                    keep_going,
+                    ..Default::default()
+                },
+            )
+        };
+        assert!(store(false).is_err());
+        let report = store(true).unwrap();
+        assert_eq!(report.blobs_total, 3);
+        assert_eq!(report.blobs_stored + report.blobs_skipped, 2);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0.to_string(), "b");
+        assert!(repo.substance.have_blob(c.content_hash()));
+    }
+
+    #[test]
+    fn store_manifest() {
+        let repo = TestRepo::new();
+        let (a, _) = repo.write_subject_file("x/a", b"a\n");
+        let (b, _) = repo.write_subject_file("x/b", b"bb\n");
+        let manifest = format!(
+            "{} 2 x/a\n{} ? x/b\n{} 2 x/a\n",
+            a.content_hash(),
+            b.content_hash(),
+            a.content_hash()
+        );
+        let manifest = Manifest::parse(manifest.as_bytes()).unwrap();
+
+        let report = repo
+            .db
+            .store_manifest(
+                &repo.substance,
+                &manifest,
+                &repo.subject().join("x"),
+                &StoreConfig {
+                    strip_prefix: 1,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!((report.blobs_total, report.blobs_stored), (2, 2));
+        for (shadow, content) in [(&a, b"a\n" as &[u8]), (&b, b"bb\n")] {
+            let blob_path = repo.substance.blob_path(shadow.content_hash());
+            assert_eq!(fs::read(blob_path).unwrap(), content);
+        }
+
+        let (c, _) = repo.write_subject_file("c", b"c\n");
+        let (d, _) = repo.write_subject_file("d", b"d\n");
+        let wrong_hash = format!("{} ? c\n", d.content_hash());
+        let wrong_size = format!("{} 3 c\n", c.content_hash());
+        for manifest in [wrong_hash, wrong_size] {
+            let manifest = Manifest::parse(manifest.as_bytes()).unwrap();
+            assert!(repo
+                .db
+                .store_manifest(
+                    &repo.substance,
+                    &manifest,
+                    &repo.subject(),
+                    &StoreConfig::default()
+                )
+                .is_err());
+        }
+        assert!(!repo.substance.have_blob(c.content_hash()));
+        assert!(!repo.substance.have_blob(d.content_hash()));
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_bytes(1023), "1023 B");
+        assert_eq!(human_bytes(1024), "1.0 KiB");
+        assert_eq!(human_bytes(1288490189), "1.2 GiB");
     }
 }
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use git2::Oid;
+
+use super::traverse::MaxFileCount;
+use crate::{
+    Database, ShadowPath, Substance, TraversalCallbacks, Visit, VisitLink, VisitShadow, VisitTree,
+    VisitTreeDecision,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    pub trees: u64,
+    pub shadows: u64,
+    pub links: u64,
+    // Number of occurrences of each tree object, along with the path at which it was first seen.
+    pub tree_occurrences: BTreeMap<Oid, (u64, ShadowPath)>,
+}
+
+impl TreeStats {
+    pub fn distinct_trees(&self) -> u64 {
+        self.tree_occurrences.len() as u64
+    }
+
+    // Trees which occur more than once, most frequent first.
+    pub fn shared_trees(&self) -> Vec<(Oid, u64, &ShadowPath)> {
+        let mut shared = self
+            .tree_occurrences
+            .iter()
+            .filter(|(_, (count, _))| *count > 1)
+            .map(|(oid, (count, path))| (*oid, *count, path))
+            .collect::<Vec<_>>();
+        shared.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.2.cmp(b.2)));
+        shared
+    }
+}
+
+// The footprint in the substance of the distinct blobs referenced by a tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoredSize {
+    pub blobs: u64,
+    pub stored_bytes: u64,
+    // The sum of the sizes recorded in shadows, for comparison. Shadows without one count as 0.
+    pub logical_bytes: u64,
+    // Blobs absent from the substance, which contribute nothing to `stored_bytes`.
+    pub missing: u64,
+}
+
+impl Database {
+    // Unlike most traversals, this one does not skip objects it has already seen, so that shared
+    // subtrees are counted once per occurrence.
+    pub fn stats(&self, tree: Oid) -> Result<TreeStats> {
+        let mut callbacks = MaxFileCount::new(StatsCallbacks::default(), self.max_file_count);
+        self.traverser(&mut callbacks).traverse(tree)?;
+        Ok(callbacks.into_inner().stats)
+    }
+
+    pub fn stored_size(&self, tree: Oid, substance: &impl Substance) -> Result<StoredSize> {
+        let mut size = StoredSize::default();
+        self.unique_shadows(tree, |_, shadow| {
+            size.blobs += 1;
+            size.logical_bytes += shadow.size().unwrap_or(0);
+            match substance.stored_size(shadow.content_hash())? {
+                Some(stored) => size.stored_bytes += stored,
+                None => size.missing += 1,
+            }
+            Ok(())
+        })?;
+        Ok(size)
+    }
+}
+
+#[derive(Default)]
+struct StatsCallbacks {
+    stats: TreeStats,
+}
+
+impl TraversalCallbacks for StatsCallbacks {
+    fn on_shadow(&mut self, _visit: &Visit<VisitShadow>) -> Result<()> {
+        self.stats.shadows += 1;
+        Ok(())
+    }
+
+    fn on_link(&mut self, _visit: &Visit<VisitLink>) -> Result<()> {
+        self.stats.links += 1;
+        Ok(())
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        self.stats.trees += 1;
+        self.stats
+            .tree_occurrences
+            .entry(visit.oid())
+            .or_insert_with(|| (0, visit.path().clone()))
+            .0 += 1;
+        Ok(VisitTreeDecision::Descend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn shared_subtrees() {
+        let repo = TestRepo::new();
+        let tree = repo.empty_tree();
+        let tree = repo.add_file(tree, "a/node_modules/x", b"x\n");
+        let tree = repo.add_file(tree, "b/node_modules/x", b"x\n");
+        let tree = repo.add_file(tree, "b/y", b"y\n");
+
+        let stats = repo.db.stats(tree).unwrap();
+        assert_eq!(stats.trees, 5);
+        assert_eq!(stats.shadows, 3);
+        assert_eq!(stats.distinct_trees(), 4);
+        let shared = stats.shared_trees();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].1, 2);
+        assert_eq!(shared[0].2.to_string(), "a/node_modules");
+    }
+
+    #[test]
+    fn stored_size() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"hello\n");
+        let tree = repo.add_file(tree, "b", b"hello\n");
+        let tree = repo.add_unstored_file(tree, "c", b"bye\n");
+
+        // Blocks allocated, however few bytes are in them.
+        let blob = &repo.substance.list_blobs().unwrap()[0];
+        let blocks = fs::metadata(repo.substance.blob_path(blob))
+            .unwrap()
+            .blocks();
+        assert_eq!(
+            repo.db.stored_size(tree, &repo.substance).unwrap(),
+            StoredSize {
+                blobs: 2,
+                stored_bytes: blocks * 512,
+                logical_bytes: 10,
+                missing: 1,
+            }
+        );
+    }
+}
@@ -2,7 +2,8 @@ use std::collections::BTreeSet;
 use std::str;
 
 use anyhow::{bail, ensure, Result};
-use git2::{FileMode, ObjectType, Oid, Repository};
+use git2::{FileMode, ObjectType, Oid, Repository, Tree};
+use thiserror::Error;
 
 use crate::{Database, Shadow, ShadowPath, ShadowTreeEntryName};
 
@@ -15,28 +16,25 @@ impl Database {
             repository: &self.repository(),
             callbacks,
             empty_blob_oid: None,
+            max_depth: self.max_depth,
         }
     }
 
-    pub fn check(&self, tree: Oid) -> Result<()> {
-        struct CheckCallbacks;
-        impl TraversalCallbacks for CheckCallbacks {
-            fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
-                let _ = visit.read_shadow()?;
-                Ok(())
-            }
-            fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
-                let _ = visit.read_link()?;
-                Ok(())
-            }
-        }
-        let mut callbacks = OnUnique::new(CheckCallbacks);
-        self.traverser(&mut callbacks).traverse(tree)
+    pub fn unique_shadows(
+        &self,
+        tree: Oid,
+        callback: impl FnMut(&ShadowPath, &Shadow) -> Result<()>,
+    ) -> Result<()> {
+        self.unique_shadows_with_progress(tree, |_| Ok(()), callback)
     }
 
-    pub fn unique_shadows(
+    // Like `unique_shadows`, but `on_visit` is also called with the number of entries visited so
+    // far after each one, for reporting the progress of the walk itself. This counts trees, links,
+    // and repeated shadows, which `callback` does not see.
+    pub fn unique_shadows_with_progress(
         &self,
         tree: Oid,
+        on_visit: impl FnMut(u64) -> Result<()>,
         callback: impl FnMut(&ShadowPath, &Shadow) -> Result<()>,
     ) -> Result<()> {
         struct UniqueShadowsCallbacks<T> {
@@ -51,7 +49,13 @@ impl Database {
                 Ok(())
             }
         }
-        let mut callbacks = OnUnique::new(UniqueShadowsCallbacks { callback });
+        let mut callbacks = VisitProgress::new(
+            MaxFileCount::new(
+                OnUnique::new(UniqueShadowsCallbacks { callback }),
+                self.max_file_count,
+            ),
+            on_visit,
+        );
         self.traverser(&mut callbacks).traverse(tree)
     }
 }
@@ -70,6 +74,12 @@ pub trait TraversalCallbacks {
     }
 }
 
+// Callbacks may return this error to end a traversal early. `Traverser::traverse` then returns
+// successfully.
+#[derive(Error, Debug)]
+#[error("traversal stopped")]
+pub struct StopTraversal;
+
 pub struct OnUnique<T> {
     seen: BTreeSet<Oid>,
     callbacks: T,
@@ -82,6 +92,10 @@ impl<T> OnUnique<T> {
             callbacks,
         }
     }
+
+    pub fn into_inner(self) -> T {
+        self.callbacks
+    }
 }
 
 impl<T: TraversalCallbacks> TraversalCallbacks for OnUnique<T> {
@@ -110,6 +124,139 @@ impl<T: TraversalCallbacks> TraversalCallbacks for OnUnique<T> {
     }
 }
 
+// Like `OnUnique`, but every tree is visited at each of its paths, and only shadows and links are
+// passed on once per oid. For callbacks which judge trees by where they are, like `check`, since
+// every empty directory, for one, is the same tree.
+pub struct OnUniqueBlobs<T> {
+    seen: BTreeSet<Oid>,
+    callbacks: T,
+}
+
+impl<T> OnUniqueBlobs<T> {
+    pub fn new(callbacks: T) -> Self {
+        Self {
+            seen: BTreeSet::new(),
+            callbacks,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.callbacks
+    }
+}
+
+impl<T: TraversalCallbacks> TraversalCallbacks for OnUniqueBlobs<T> {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        if self.seen.insert(visit.oid()) {
+            self.callbacks.on_shadow(visit)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+        if self.seen.insert(visit.oid()) {
+            self.callbacks.on_link(visit)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        self.callbacks.on_tree(visit)
+    }
+}
+
+pub struct MaxFileCount<T> {
+    max: Option<u64>,
+    count: u64,
+    callbacks: T,
+}
+
+impl<T> MaxFileCount<T> {
+    pub fn new(callbacks: T, max: Option<u64>) -> Self {
+        Self {
+            max,
+            count: 0,
+            callbacks,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.callbacks
+    }
+
+    fn count(&mut self) -> Result<()> {
+        self.count += 1;
+        if let Some(max) = self.max {
+            ensure!(
+                self.count <= max,
+                "traversal exceeded maximum file count of {}",
+                max
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<T: TraversalCallbacks> TraversalCallbacks for MaxFileCount<T> {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        self.count()?;
+        self.callbacks.on_shadow(visit)
+    }
+
+    fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+        self.count()?;
+        self.callbacks.on_link(visit)
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        self.count()?;
+        self.callbacks.on_tree(visit)
+    }
+}
+
+// Calls `on_visit` with a running count of the entries visited.
+pub struct VisitProgress<T, F> {
+    count: u64,
+    on_visit: F,
+    callbacks: T,
+}
+
+impl<T, F: FnMut(u64) -> Result<()>> VisitProgress<T, F> {
+    pub fn new(callbacks: T, on_visit: F) -> Self {
+        Self {
+            count: 0,
+            on_visit,
+            callbacks,
+        }
+    }
+
+    fn count(&mut self) -> Result<()> {
+        self.count += 1;
+        (self.on_visit)(self.count)
+    }
+}
+
+impl<T: TraversalCallbacks, F: FnMut(u64) -> Result<()>> TraversalCallbacks
+    for VisitProgress<T, F>
+{
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        self.count()?;
+        self.callbacks.on_shadow(visit)
+    }
+
+    fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+        self.count()?;
+        self.callbacks.on_link(visit)
+    }
+
+    fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
+        self.count()?;
+        self.callbacks.on_tree(visit)
+    }
+}
+
 pub struct Visit<'a, T> {
     repository: &'a Repository,
     path: &'a ShadowPath,
@@ -117,6 +264,9 @@ pub struct Visit<'a, T> {
     extra: T,
 }
 
+// PATH_MAX, less the terminating NUL.
+const MAX_LINK_TARGET_LEN: usize = 4095;
+
 pub struct VisitShadow {
     executable: bool,
 }
@@ -150,10 +300,22 @@ impl<'a> Visit<'a, VisitShadow> {
     }
 }
 
+impl<'a> Visit<'a, VisitTree> {
+    pub fn read_tree(&self) -> Result<Tree<'a>> {
+        Ok(self.repository.find_tree(self.oid)?)
+    }
+}
+
 impl<'a> Visit<'a, VisitLink> {
     pub fn read_link(&self) -> Result<String> {
         let blob = self.repository.find_blob(self.oid)?;
-        Ok(str::from_utf8(blob.content())?.to_owned())
+        let target = blob.content();
+        ensure!(
+            !target.is_empty() && target.len() <= MAX_LINK_TARGET_LEN && !target.contains(&0),
+            "'{}' is not a valid symbolic link target",
+            self.path
+        );
+        Ok(str::from_utf8(target)?.to_owned())
     }
 }
 
@@ -161,25 +323,43 @@ pub struct Traverser<'a, T> {
     repository: &'a Repository,
     callbacks: &'a mut T,
     empty_blob_oid: Option<Oid>,
+    max_depth: usize,
 }
 
 impl<'a, T: TraversalCallbacks> Traverser<'a, T> {
-    fn ensure_blob_is_empty(&mut self, oid: Oid) -> Result<()> {
-        if let Some(expected_oid) = self.empty_blob_oid {
-            ensure!(oid == expected_oid);
-        } else {
-            let blob = self.repository.find_blob(oid)?;
-            ensure!(blob.size() == 0);
-            self.empty_blob_oid = Some(oid);
-        }
+    fn ensure_marker_is_empty_blob(&mut self, path: &ShadowPath, oid: Oid) -> Result<()> {
+        let expected_oid = match self.empty_blob_oid {
+            Some(expected_oid) => expected_oid,
+            None => {
+                let expected_oid = Oid::hash_object(ObjectType::Blob, &[])?;
+                self.empty_blob_oid = Some(expected_oid);
+                expected_oid
+            }
+        };
+        ensure!(
+            oid == expected_oid,
+            "marker of '{}' is {}, not the canonical empty blob {}",
+            path,
+            oid,
+            expected_oid
+        );
         Ok(())
     }
 
     pub fn traverse(&mut self, tree: Oid) -> Result<()> {
-        self.traverse_from(&mut ShadowPath::new(), tree)
+        match self.traverse_from(&mut ShadowPath::new(), tree) {
+            Err(err) if err.is::<StopTraversal>() => Ok(()),
+            result => result,
+        }
     }
 
     pub fn traverse_from(&mut self, path: &mut ShadowPath, tree: Oid) -> Result<()> {
+        ensure!(
+            path.components().len() <= self.max_depth,
+            "'{}' exceeds the maximum depth of {}",
+            path,
+            self.max_depth
+        );
         if let VisitTreeDecision::Skip = self.callbacks.on_tree(&Visit {
             repository: self.repository,
             path: &path,
@@ -199,10 +379,13 @@ impl<'a, T: TraversalCallbacks> Traverser<'a, T> {
             let oid = entry.id();
 
             if first {
-                ensure!(name.is_marker());
-                ensure!(mode == FileMode::Blob.into());
-                ensure!(kind == ObjectType::Blob);
-                self.ensure_blob_is_empty(oid)?;
+                ensure!(name.is_marker(), "missing marker in '{}'", path);
+                ensure!(
+                    mode == FileMode::Blob.into() && kind == ObjectType::Blob,
+                    "marker of '{}' is not a regular blob",
+                    path
+                );
+                self.ensure_marker_is_empty_blob(path, oid)?;
                 first = false;
                 continue;
             }
@@ -220,9 +403,9 @@ impl<'a, T: TraversalCallbacks> Traverser<'a, T> {
                         })?;
                     } else {
                         let executable = if mode == FileMode::Blob.into() {
-                            true
-                        } else if mode == FileMode::BlobExecutable.into() {
                             false
+                        } else if mode == FileMode::BlobExecutable.into() {
+                            true
                         } else {
                             bail!("")
                         };
@@ -247,3 +430,77 @@ impl<'a, T: TraversalCallbacks> Traverser<'a, T> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    struct ReadLinks;
+
+    impl TraversalCallbacks for ReadLinks {
+        fn on_link(&mut self, visit: &Visit<VisitLink>) -> Result<()> {
+            visit.read_link()?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn unique_shadows_progress() {
+        let repo = TestRepo::new();
+        let dir = repo.add_file(repo.empty_tree(), "x", b"x\n");
+        let dir = repo.add_link(dir, "l", "x");
+        let tree = repo.add_file(repo.empty_tree(), "y", b"x\n");
+        let tree = repo.add_object(tree, "a", FileMode::Tree, dir);
+        let tree = repo.add_object(tree, "b", FileMode::Tree, dir);
+        let mut visited = vec![];
+        let mut shadows = 0;
+        repo.db
+            .unique_shadows_with_progress(
+                tree,
+                |n| {
+                    visited.push(n);
+                    Ok(())
+                },
+                |_, _| {
+                    shadows += 1;
+                    Ok(())
+                },
+            )
+            .unwrap();
+        // The root, a, a/l, a/x, b, y. The second copy of the tree is not descended into.
+        assert_eq!(visited, (1..=6).collect::<Vec<_>>());
+        assert_eq!(shadows, 1);
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a/b/c/d", b"d\n");
+        repo.db.set_max_depth(3);
+        assert!(repo.db.traverser(&mut ReadLinks).traverse(tree).is_ok());
+        repo.db.set_max_depth(2);
+        let err = repo
+            .db
+            .traverser(&mut ReadLinks)
+            .traverse(tree)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "'a/b/c' exceeds the maximum depth of 2");
+    }
+
+    #[test]
+    fn link_targets() {
+        let repo = TestRepo::new();
+        for (target, valid) in [
+            ("a".to_owned(), true),
+            ("a".repeat(MAX_LINK_TARGET_LEN), true),
+            ("a".repeat(MAX_LINK_TARGET_LEN + 1), false),
+            ("".to_owned(), false),
+            ("a\0b".to_owned(), false),
+        ] {
+            let tree = repo.add_link(repo.empty_tree(), "l", &target);
+            let result = repo.db.traverser(&mut ReadLinks).traverse(tree);
+            assert_eq!(result.is_ok(), valid, "{:?}", target);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use git2::Oid;
+
+use crate::{
+    BlobChecks, BlobProblem, CheckPolicy, CheckProblem, ContentSha256, Database, ShadowPath,
+    Substance,
+};
+
+// The findings of `check` and `check_blobs` for a tree, along with its size, for a monitoring
+// pipeline to ingest as one document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub tree: Oid,
+    pub trees: u64,
+    pub files: u64,
+    pub links: u64,
+    // Distinct blobs, which is also the number checked.
+    pub blobs: u64,
+    pub tree_problems: Vec<(ShadowPath, CheckProblem)>,
+    pub blob_problems: Vec<(ShadowPath, ContentSha256, BlobProblem)>,
+}
+
+impl VerifyReport {
+    pub fn ok(&self) -> bool {
+        self.tree_problems.is_empty() && self.blob_problems.is_empty()
+    }
+
+    // A single line of JSON.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(
+            json,
+            r#"{{"tree":"{}","ok":{},"tree_valid":{},"counts":{{"trees":{},"files":{},"links":{},"blobs":{}}}"#,
+            self.tree,
+            self.ok(),
+            self.tree_problems.is_empty(),
+            self.trees,
+            self.files,
+            self.links,
+            self.blobs
+        )
+        .unwrap();
+        json.push_str(r#","tree_problems":["#);
+        for (i, (path, problem)) in self.tree_problems.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                r#"{{"path":{},"problem":{}}}"#,
+                json_string(&path.to_string()),
+                json_string(&problem.to_string())
+            )
+            .unwrap();
+        }
+        json.push_str(r#"],"blob_problems":["#);
+        for (i, (path, blob, problem)) in self.blob_problems.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let problem = match problem {
+                BlobProblem::Missing => "missing",
+                BlobProblem::Invalid => "invalid",
+            };
+            write!(
+                json,
+                r#"{{"path":{},"sha256":"{}","problem":"{}"}}"#,
+                json_string(&path.to_string()),
+                blob,
+                problem
+            )
+            .unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl Database {
+    pub fn verify(
+        &self,
+        tree: Oid,
+        substance: &impl Substance,
+        checks: &BlobChecks,
+    ) -> Result<VerifyReport> {
+        let mut report = VerifyReport {
+            tree,
+            trees: 0,
+            files: 0,
+            links: 0,
+            blobs: 0,
+            tree_problems: vec![],
+            blob_problems: vec![],
+        };
+        self.check(tree, &CheckPolicy::default(), |path, problem| {
+            report.tree_problems.push((path.clone(), problem.clone()));
+            Ok(())
+        })?;
+        let mut blob_problems = vec![];
+        let counted = self.stats(tree).and_then(|stats| {
+            let blobs = self.check_blobs(tree, substance, checks, |path, shadow, problem| {
+                blob_problems.push((path.clone(), shadow.content_hash().clone(), problem));
+                Ok(())
+            })?;
+            Ok((stats, blobs))
+        });
+        match counted {
+            Ok((stats, blobs)) => {
+                report.trees = stats.trees;
+                report.files = stats.shadows;
+                report.links = stats.links;
+                report.blobs = blobs;
+                report.blob_problems = blob_problems;
+            }
+            // Traversals refuse trees with some of the problems `check` reports, such as a
+            // missing marker, so such trees are reported without counts or blob checks.
+            Err(_) if !report.tree_problems.is_empty() => {}
+            Err(err) => return Err(err),
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::FileMode;
+
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn json() {
+        let repo = TestRepo::new();
+        let tree = repo.add_file(repo.empty_tree(), "a", b"a\n");
+        let tree = repo.add_link(tree, "l", "a");
+        let checks = BlobChecks {
+            missing: true,
+            invalid: true,
+        };
+
+        let report = repo.db.verify(tree, &repo.substance, &checks).unwrap();
+        assert!(report.ok());
+        assert_eq!(
+            report.to_json(),
+            format!(
+                r#"{{"tree":"{}","ok":true,"tree_valid":true,"counts":{{"trees":1,"files":1,"links":1,"blobs":1}},"tree_problems":[],"blob_problems":[]}}"#,
+                tree
+            )
+        );
+
+        let (missing, _) = repo.write_subject_file("dir/\"b\"", b"b\n");
+        let tree = repo.add_shadow(tree, "dir/\"b\"", &missing);
+        let report = repo.db.verify(tree, &repo.substance, &checks).unwrap();
+        assert!(!report.ok());
+        assert_eq!(
+            report.to_json(),
+            format!(
+                r#"{{"tree":"{}","ok":false,"tree_valid":true,"counts":{{"trees":2,"files":2,"links":1,"blobs":2}},"tree_problems":[],"blob_problems":[{{"path":"dir/\"b\"","sha256":"{}","problem":"missing"}}]}}"#,
+                tree,
+                missing.content_hash()
+            )
+        );
+    }
+
+    #[test]
+    fn missing_marker() {
+        let repo = TestRepo::new();
+        let repository = repo.db.repository();
+        let mut builder = repository.treebuilder(None).unwrap();
+        builder
+            .insert("0_x", repository.blob(b"").unwrap(), FileMode::Link.into())
+            .unwrap();
+        let sub_tree = builder.write().unwrap();
+        let tree = repo.add_object(repo.empty_tree(), "a", FileMode::Tree, sub_tree);
+        let checks = BlobChecks {
+            missing: true,
+            invalid: true,
+        };
+
+        let report = repo.db.verify(tree, &repo.substance, &checks).unwrap();
+        assert!(!report.ok());
+        assert_eq!(
+            report.tree_problems,
+            vec![("a".parse().unwrap(), CheckProblem::MissingMarker)]
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+    }
+}
@@ -6,10 +6,17 @@ mod paths;
 mod shadow;
 mod substance;
 mod snapshot;
+mod manifest;
+mod progress;
 mod shallow_diff;
+mod timestamp;
+mod parallel;
 mod database;
 mod cli;
 
+#[cfg(test)]
+mod testing;
+
 #[rustfmt::skip]
 pub use crate::{
     paths::{
@@ -19,22 +26,36 @@ pub use crate::{
         Shadow, ContentSha256,
     },
     substance::{
-        Substance, FilesystemSubstance, MockSubstance,
+        Substance, FilesystemSubstance, MockSubstance, RangeReader, Reflink, Stored,
         sha256sum,
     },
     snapshot::{
-        Snapshot, SnapshotEntries, SnapshotEntry, SnapshotEntryValue,
+        Snapshot, SnapshotConfig, SnapshotEntries, SnapshotEntry, SnapshotEntryValue,
+        SnapshotFilter, SnapshotHooks, with_image_mounted,
+    },
+    manifest::{
+        Manifest, ManifestEntry,
+    },
+    progress::{
+        ProgressEvent, ProgressWriter, render_restore_progress, with_progress_line,
     },
     shallow_diff::{
-        ShallowDifference, ShallowDifferenceSide,
-        shallow_diff,
+        ShallowDifference, ShallowDifferenceKind, ShallowEntry,
+        shallow_diff, shallow_diff_under,
+    },
+    timestamp::{
+        Timestamp, TimestampError,
     },
     database::{
-        Database,
-        TraversalCallbacks, Traverser,
+        Database, CommitDates, ObjectCounts, TreeFilter, TreeStats,
+        BlobChecks, BlobProblem, CheckPolicy, CheckProblem, Compaction, DiffSummary, LinkTarget, LsEntryValue, MissingObject,
+        MountConfig,
+        MountMetrics, PendingStore, RepairOutcome, RestoreConfig, RestoreProgress, SnapshotRef, StoreConfig, StoreProgress, StoreReport, StoredSize,
+        snapshot_commit_message, SNAPSHOT_PATH_TRAILER,
+        StopTraversal, TraversalCallbacks, Traverser, VerifyReport,
         Visit, VisitShadow, VisitLink, VisitTree, VisitTreeDecision,
     },
     cli::{
-        cli_main,
+        cli_main, cli_exit_code,
     },
 };
@@ -1,5 +1,10 @@
-use keep::cli_main;
+use std::process;
 
-fn main() -> anyhow::Result<()> {
-    cli_main()
+use keep::{cli_exit_code, cli_main};
+
+fn main() {
+    if let Err(err) = cli_main() {
+        eprintln!("Error: {:?}", err);
+        process::exit(cli_exit_code(&err));
+    }
 }
@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{Shadow, ShadowPath};
+
+// A listing of the files in a subject along with their shadows, one per line:
+//
+//     <sha256> <size or ?> <path>
+//
+// This is enough to store content without a planted tree, so hashing and storing can happen on
+// different machines.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: ShadowPath,
+    pub shadow: Shadow,
+}
+
+impl Manifest {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        Self::parse(io::BufReader::new(file))
+            .with_context(|| format!("reading manifest {}", path.display()))
+    }
+
+    pub fn parse(reader: impl BufRead) -> Result<Self> {
+        let mut entries = vec![];
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry = parse_line(&line).with_context(|| format!("line {}", i + 1))?;
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn entries(&self) -> &[ManifestEntry] {
+        &self.entries
+    }
+}
+
+fn parse_line(line: &str) -> Result<ManifestEntry> {
+    let mut fields = line.splitn(3, ' ');
+    let (hash, size, path) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(hash), Some(size), Some(path)) => (hash, size, path),
+        _ => return Err(anyhow!("expected '<sha256> <size> <path>'")),
+    };
+    let size = match size {
+        "?" => None,
+        _ => Some(size.parse()?),
+    };
+    Ok(ManifestEntry {
+        path: path.parse()?,
+        shadow: Shadow::new(hash.parse()?, size),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let hash = "9a271f2a916b0b6ee6cecb2426f0b3206ef074578be55d9bc94f6f3fe3ab86aa";
+        let manifest =
+            Manifest::parse(format!("{} 6 a/b c\n\n{} ? d\n", hash, hash).as_bytes()).unwrap();
+        let entries = manifest.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path.to_string(), "a/b c");
+        assert_eq!(entries[0].shadow.size(), Some(6));
+        assert_eq!(entries[1].shadow.size(), None);
+        assert_eq!(entries[1].shadow.content_hash().to_hex(), hash);
+
+        assert!(Manifest::parse(format!("{} 6\n", hash).as_bytes()).is_err());
+        assert!(Manifest::parse("xyz 6 a\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::mem;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{Error, Result};
+
+// Apply `f` to each of `jobs` on a pool of `workers` threads, returning the results in the order
+// of `jobs`. Once a job fails no more are started, and after those already running have finished
+// the error of the earliest failed job is returned. Since jobs are started in order, this is the
+// same error that running them one at a time would give.
+pub fn run_bounded<J, T, F>(jobs: Vec<J>, workers: usize, f: F) -> Result<Vec<T>>
+where
+    J: Send,
+    T: Send,
+    F: Fn(J) -> Result<T> + Sync,
+{
+    let workers = workers.max(1).min(jobs.len());
+    if workers <= 1 {
+        return jobs.into_iter().map(f).collect();
+    }
+
+    let n = jobs.len();
+    let queue = Mutex::new(jobs.into_iter().enumerate());
+    let cancelled = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel::<(usize, Result<T>)>();
+    let mut results = (0..n).map(|_| None).collect::<Vec<Option<T>>>();
+    let mut first_err: Option<(usize, Error)> = None;
+
+    let mut tasks: Vec<Box<dyn FnOnce() + Send + '_>> = vec![];
+    for _ in 0..workers {
+        let (queue, cancelled, f, tx) = (&queue, &cancelled, &f, tx.clone());
+        tasks.push(Box::new(move || {
+            let _cancel_on_panic = CancelOnPanic(cancelled);
+            while !cancelled.load(Ordering::SeqCst) {
+                let (i, job) = match queue.lock().unwrap().next() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = f(job);
+                if result.is_err() {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+                if tx.send((i, result)).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+    drop(tx);
+    with_threads(tasks, || {
+        for (i, result) in rx {
+            match result {
+                Ok(value) => results[i] = Some(value),
+                Err(err) => {
+                    if first_err.as_ref().map_or(true, |(j, _)| i < *j) {
+                        first_err = Some((i, err));
+                    }
+                }
+            }
+        }
+    });
+
+    match first_err {
+        Some((_, err)) => Err(err),
+        None => Ok(results.into_iter().map(Option::unwrap).collect()),
+    }
+}
+
+// Stops the remaining jobs if a worker panics, as if its job had failed.
+struct CancelOnPanic<'a>(&'a AtomicBool);
+
+impl<'a> Drop for CancelOnPanic<'a> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+// Run `main` while each of `workers` runs on a thread of its own, then propagate the first panic
+// of a worker, if any. Workers may borrow from the caller, since every thread is joined before
+// this returns, even by unwinding.
+fn with_threads<'a, R>(workers: Vec<Box<dyn FnOnce() + Send + 'a>>, main: impl FnOnce() -> R) -> R {
+    let mut threads = JoinOnDrop(vec![]);
+    for worker in workers {
+        // SAFETY: `threads` is local to this function, so it cannot be leaked, and it joins every
+        // thread when dropped, before anything a worker borrows can go away.
+        let worker: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(worker) };
+        threads.0.push(thread::spawn(worker));
+    }
+    let ret = main();
+    let mut panicked = None;
+    for handle in threads.0.drain(..) {
+        if let Err(payload) = handle.join() {
+            panicked.get_or_insert(payload);
+        }
+    }
+    if let Some(payload) = panicked {
+        panic::resume_unwind(payload);
+    }
+    ret
+}
+
+struct JoinOnDrop(Vec<JoinHandle<()>>);
+
+impl Drop for JoinOnDrop {
+    fn drop(&mut self) {
+        for handle in self.0.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn ordered() {
+        // Later jobs finish first.
+        let results = run_bounded((0..20).collect(), 4, |i: u64| {
+            thread::sleep(Duration::from_millis(20 - i));
+            Ok(i * i)
+        })
+        .unwrap();
+        assert_eq!(results, (0..20).map(|i| i * i).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn earliest_error() {
+        // Job 3 fails after job 5 has already failed.
+        let err = run_bounded((0..8).collect(), 8, |i: u64| match i {
+            3 => {
+                thread::sleep(Duration::from_millis(50));
+                Err(anyhow!("job {}", i))
+            }
+            5 => Err(anyhow!("job {}", i)),
+            _ => Ok(i),
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "job 3");
+    }
+
+    #[test]
+    fn cancelled() {
+        let started = AtomicUsize::new(0);
+        let borrowed = &started;
+        let result = run_bounded((0..1000).collect(), 4, |i: u64| {
+            borrowed.fetch_add(1, Ordering::SeqCst);
+            if i == 0 {
+                return Err(anyhow!("job 0"));
+            }
+            thread::sleep(Duration::from_millis(5));
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert!(started.load(Ordering::SeqCst) < 100);
+    }
+
+    #[test]
+    fn panicked() {
+        let started = AtomicUsize::new(0);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            run_bounded((0..1000).collect(), 4, |i: u64| {
+                started.fetch_add(1, Ordering::SeqCst);
+                if i == 0 {
+                    panic!("job 0");
+                }
+                thread::sleep(Duration::from_millis(5));
+                Ok(())
+            })
+        }));
+        assert!(result.is_err());
+        assert!(started.load(Ordering::SeqCst) < 100);
+    }
+}
@@ -39,6 +39,10 @@ impl FromStr for ShadowPathComponent {
     }
 }
 
+// Displayed as its components joined with '/', without any escaping. Since components are UTF-8 and
+// cannot contain '/' or NUL, `FromStr` is an exact inverse, whatever else they contain (spaces,
+// newlines, backslashes, ...). Names which are not UTF-8 cannot be represented, and are rejected
+// where they are read rather than converted lossily.
 #[derive(Clone, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub struct ShadowPath(Vec<ShadowPathComponent>);
 
@@ -230,6 +234,29 @@ mod tests {
         ensure_inverse::<ShadowPath>("x/y");
     }
 
+    #[test]
+    fn tricky_names() {
+        for name in &[
+            " ",
+            "a b",
+            "line\nbreak\n",
+            "tab\there",
+            "back\\slash",
+            "...",
+            "-n",
+            "0_",
+            "\u{1f600}",
+        ] {
+            ensure_inverse::<ShadowPathComponent>(name);
+            let path = format!("{}/x/{}", name, name);
+            let parsed = ShadowPath::from_str(&path).unwrap();
+            assert_eq!(parsed.components().len(), 3);
+            assert_eq!(parsed.to_string(), path);
+            let decoded = ShadowTreeEntryName::decode(&parsed.components()[0].encode()).unwrap();
+            assert_eq!(decoded.child().unwrap().as_ref(), *name);
+        }
+    }
+
     #[test]
     fn encoding() {
         assert_eq!(ShadowPath::from_str("x/y").unwrap().encode(), "0_x/0_y");
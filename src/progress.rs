@@ -0,0 +1,209 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::StoreProgress;
+
+// Events for `snapshot --progress-json`, written one JSON object per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    Walk { files: u64, bytes: u64 },
+    // Finding the blobs to store, before any are stored.
+    Scan { entries: u64 },
+    Store { stored: u64, skipped: u64 },
+    Done,
+}
+
+impl ProgressEvent {
+    fn store(progress: &StoreProgress) -> Self {
+        if let Some(entries) = progress.scanning() {
+            return Self::Scan { entries };
+        }
+        let (stored, skipped) = progress.counts();
+        Self::Store { stored, skipped }
+    }
+
+    pub fn to_json(&self) -> String {
+        match self {
+            Self::Walk { files, bytes } => {
+                format!(r#"{{"phase":"walk","files":{},"bytes":{}}}"#, files, bytes)
+            }
+            Self::Scan { entries } => format!(r#"{{"phase":"scan","entries":{}}}"#, entries),
+            Self::Store { stored, skipped } => format!(
+                r#"{{"phase":"store","stored":{},"skipped":{}}}"#,
+                stored, skipped
+            ),
+            Self::Done => r#"{"phase":"done"}"#.to_owned(),
+        }
+    }
+}
+
+pub struct ProgressWriter {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl ProgressWriter {
+    // How often updates are written while a phase is in progress.
+    pub const INTERVAL: Duration = Duration::from_millis(250);
+
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    pub fn emit(&self, event: &ProgressEvent) -> Result<()> {
+        let mut out = self.out.lock().unwrap();
+        writeln!(out, "{}", event.to_json())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    // Run `f`, writing a scan or store event when it starts, at most every `INTERVAL` while it runs
+    // if `progress` has changed, and when it finishes.
+    pub fn while_storing<T>(
+        self: &Arc<Self>,
+        progress: &Arc<StoreProgress>,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let mut last = ProgressEvent::store(progress);
+        self.emit(&last)?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        let ticker = {
+            let (events, progress) = (self.clone(), progress.clone());
+            thread::spawn(move || -> Result<()> {
+                loop {
+                    // A disconnection means that `f` has returned, but there may be one last
+                    // change to report.
+                    let finished =
+                        stopped.recv_timeout(Self::INTERVAL) != Err(RecvTimeoutError::Timeout);
+                    let event = ProgressEvent::store(&progress);
+                    if event != last {
+                        events.emit(&event)?;
+                        last = event;
+                    }
+                    if finished {
+                        return Ok(());
+                    }
+                }
+            })
+        };
+        let result = f();
+        drop(stop);
+        let ticked = ticker
+            .join()
+            .map_err(|_| anyhow!("progress thread panicked"))?;
+        let value = result?;
+        ticked?;
+        Ok(value)
+    }
+}
+
+// Run `f`, redrawing a line on stderr with `render()` every `ProgressWriter::INTERVAL` while it
+// runs, and once more when it finishes.
+pub fn with_progress_line<T>(
+    render: impl Fn() -> String + Send + 'static,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let (stop, stopped) = mpsc::channel::<()>();
+    let ticker = thread::spawn(move || loop {
+        let finished =
+            stopped.recv_timeout(ProgressWriter::INTERVAL) != Err(RecvTimeoutError::Timeout);
+        let mut err = io::stderr();
+        let _ = write!(err, "\r{}", render());
+        if finished {
+            let _ = writeln!(err);
+            return;
+        }
+    });
+    let result = f();
+    drop(stop);
+    ticker
+        .join()
+        .map_err(|_| anyhow!("progress thread panicked"))?;
+    result
+}
+
+// A line for `restore --progress`, with a bar filled by bytes, or by files if no sizes are known.
+pub fn render_restore_progress(counts: (u64, u64), totals: Option<(u64, u64)>) -> String {
+    const WIDTH: usize = 30;
+    let (files, bytes) = counts;
+    let (total_files, total_bytes) = match totals {
+        Some(totals) => totals,
+        None => return format!("counting files... ({} written)", files),
+    };
+    let fraction = if total_bytes > 0 {
+        bytes as f64 / total_bytes as f64
+    } else if total_files > 0 {
+        files as f64 / total_files as f64
+    } else {
+        1.0
+    }
+    .min(1.0);
+    let filled = (fraction * WIDTH as f64) as usize;
+    format!(
+        "[{}{}] {:>3}% {}/{} files, {}/{} bytes",
+        "=".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        (fraction * 100.0) as u64,
+        files,
+        total_files,
+        bytes,
+        total_bytes
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            ProgressEvent::Walk {
+                files: 1234,
+                bytes: 5678
+            }
+            .to_json(),
+            r#"{"phase":"walk","files":1234,"bytes":5678}"#
+        );
+        assert_eq!(
+            ProgressEvent::Store {
+                stored: 10,
+                skipped: 3
+            }
+            .to_json(),
+            r#"{"phase":"store","stored":10,"skipped":3}"#
+        );
+        assert_eq!(
+            ProgressEvent::Scan { entries: 42 }.to_json(),
+            r#"{"phase":"scan","entries":42}"#
+        );
+        assert_eq!(ProgressEvent::Done.to_json(), r#"{"phase":"done"}"#);
+    }
+
+    #[test]
+    fn restore_line() {
+        assert_eq!(
+            render_restore_progress((3, 0), None),
+            "counting files... (3 written)"
+        );
+        assert_eq!(
+            render_restore_progress((1, 50), Some((4, 100))),
+            format!(
+                "[{}{}]  50% 1/4 files, 50/100 bytes",
+                "=".repeat(15),
+                " ".repeat(15)
+            )
+        );
+        // Only empty files, so the bar follows the file count.
+        assert_eq!(
+            render_restore_progress((4, 0), Some((4, 0))),
+            format!("[{}] 100% 4/4 files, 0/0 bytes", "=".repeat(30))
+        );
+    }
+}
@@ -5,29 +5,15 @@ use std::str::{self, Utf8Error};
 
 use git2::{Error, FileMode, Oid, Repository, TreeEntry, TreeIter};
 
+use crate::{ShadowPath, ShadowTreeEntryName};
+
 pub struct ShallowDifference<'a> {
     pub parent: &'a [Vec<u8>],
-    pub side: &'a ShallowDifferenceSide,
-    pub mode: i32,
-    pub oid: Oid,
     pub name: &'a [u8],
+    pub kind: ShallowDifferenceKind,
 }
 
 impl<'a> ShallowDifference<'a> {
-    fn new(
-        parent: &'a [Vec<u8>],
-        side: &'a ShallowDifferenceSide,
-        entry: &'a TreeEntry<'a>,
-    ) -> Self {
-        Self {
-            parent,
-            side,
-            mode: entry.filemode(),
-            oid: entry.id(),
-            name: entry.name_bytes(),
-        }
-    }
-
     pub fn render_path(&self) -> Result<String, Utf8Error> {
         self.parent
             .iter()
@@ -37,18 +23,123 @@ impl<'a> ShallowDifference<'a> {
             .intersperse(Ok("/"))
             .collect::<Result<String, Utf8Error>>()
     }
+
+    // The path with each component decoded. A differing marker is attributed to its tree.
+    pub fn decode_path(&self) -> anyhow::Result<ShadowPath> {
+        let mut path = ShadowPath::new();
+        for name in self.parent.iter().map(AsRef::as_ref).chain([self.name]) {
+            if let ShadowTreeEntryName::Child(child) =
+                ShadowTreeEntryName::decode(str::from_utf8(name)?)?
+            {
+                path.push(child);
+            }
+        }
+        Ok(path)
+    }
+
+    // A line in the format of `git diff --raw`, but with the decoded path. An absent side has
+    // mode 0 and the null oid.
+    pub fn render_raw(&self) -> anyhow::Result<String> {
+        let absent = ShallowEntry {
+            mode: 0,
+            oid: Oid::zero(),
+        };
+        let (old, new, status) = match self.kind {
+            ShallowDifferenceKind::Added(new) => (absent, new, 'A'),
+            ShallowDifferenceKind::Removed(old) => (old, absent, 'D'),
+            ShallowDifferenceKind::Modified { old, new } => (old, new, 'M'),
+            ShallowDifferenceKind::TypeChanged { old, new } => (old, new, 'T'),
+        };
+        Ok(format!(
+            ":{:06o} {:06o} {} {} {}\t{}",
+            old.mode,
+            new.mode,
+            old.oid,
+            new.oid,
+            status,
+            self.decode_path()?
+        ))
+    }
 }
 
-pub enum ShallowDifferenceSide {
-    A,
-    B,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShallowEntry {
+    pub mode: i32,
+    pub oid: Oid,
+}
+
+impl ShallowEntry {
+    fn new(entry: &TreeEntry) -> Self {
+        Self {
+            mode: entry.filemode(),
+            oid: entry.id(),
+        }
+    }
+
+    fn entry_type(&self) -> EntryType {
+        if self.mode == i32::from(FileMode::Tree) {
+            EntryType::Tree
+        } else if self.mode == i32::from(FileMode::Link) {
+            EntryType::Link
+        } else {
+            EntryType::Blob
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EntryType {
+    Tree,
+    Link,
+    Blob,
+}
+
+// Entries are matched up by name. A modification is a change of content or executable bit.
+// Changes between trees, links, and blobs are type changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShallowDifferenceKind {
+    Added(ShallowEntry),
+    Removed(ShallowEntry),
+    Modified {
+        old: ShallowEntry,
+        new: ShallowEntry,
+    },
+    TypeChanged {
+        old: ShallowEntry,
+        new: ShallowEntry,
+    },
+}
+
+impl ShallowDifferenceKind {
+    // Whether only the executable bit changed, with the content the same. A change between a link
+    // and a file is a type change, not a mode change.
+    pub fn is_mode_only(&self) -> bool {
+        match self {
+            ShallowDifferenceKind::Modified { old, new } => old.oid == new.oid,
+            _ => false,
+        }
+    }
 }
 
-impl fmt::Display for ShallowDifferenceSide {
+impl fmt::Display for ShallowDifferenceKind {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ShallowDifferenceSide::A => write!(fmt, "-"),
-            ShallowDifferenceSide::B => write!(fmt, "+"),
+            ShallowDifferenceKind::Added(entry) => {
+                write!(fmt, "+ {:06o} {}", entry.mode, entry.oid)
+            }
+            ShallowDifferenceKind::Removed(entry) => {
+                write!(fmt, "- {:06o} {}", entry.mode, entry.oid)
+            }
+            ShallowDifferenceKind::Modified { old, new } => write!(
+                fmt,
+                "M {:06o} {:06o} {} {}",
+                old.mode, new.mode, old.oid, new.oid
+            ),
+            ShallowDifferenceKind::TypeChanged { old, new } => write!(
+                fmt,
+                "T {:06o} {:06o} {} {}",
+                old.mode, new.mode, old.oid, new.oid
+            ),
         }
     }
 }
@@ -56,7 +147,7 @@ impl fmt::Display for ShallowDifferenceSide {
 impl<'a> fmt::Display for ShallowDifference<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let path = self.render_path().map_err(|_| fmt::Error)?;
-        write!(fmt, "{} {:06o} {} {}", self.side, self.mode, self.oid, path)
+        write!(fmt, "{} {}", self.kind, path)
     }
 }
 
@@ -65,11 +156,23 @@ pub fn shallow_diff<'a, E: From<Error> + 'static>(
     tree_a: Oid,
     tree_b: Oid,
     callback: impl for<'b> FnMut(&ShallowDifference<'b>) -> Result<(), E>,
+) -> Result<(), E> {
+    shallow_diff_under(repository, Vec::new(), tree_a, tree_b, callback)
+}
+
+// Differences between two subtrees, reported with paths under `prefix`, the encoded names of the
+// entries leading to them.
+pub fn shallow_diff_under<'a, E: From<Error> + 'static>(
+    repository: &'a Repository,
+    prefix: Vec<Vec<u8>>,
+    tree_a: Oid,
+    tree_b: Oid,
+    callback: impl for<'b> FnMut(&ShallowDifference<'b>) -> Result<(), E>,
 ) -> Result<(), E> {
     let mut differ = Differ {
         repository,
         callback,
-        path: Vec::new(),
+        path: prefix,
         phantom: PhantomData,
     };
     differ.diff_inner(tree_a, tree_b)
@@ -103,49 +206,42 @@ where
                     break;
                 }
                 (Some(entry_a), None) => {
-                    self.exhaust(&ShallowDifferenceSide::A, &entry_a, &mut it_a)?;
+                    self.exhaust(Side::A, &entry_a, &mut it_a)?;
                     opt_entry_a = None;
                 }
                 (None, Some(entry_b)) => {
-                    self.exhaust(&ShallowDifferenceSide::B, &entry_b, &mut it_b)?;
+                    self.exhaust(Side::B, &entry_b, &mut it_b)?;
                     opt_entry_b = None;
                 }
                 (Some(entry_a), Some(entry_b)) => {
                     match entry_a.name_bytes().cmp(&entry_b.name_bytes()) {
                         Ordering::Less => {
-                            opt_entry_a = self.report_until(
-                                &entry_b,
-                                &ShallowDifferenceSide::A,
-                                &entry_a,
-                                &mut it_a,
-                            )?;
+                            opt_entry_a =
+                                self.report_until(&entry_b, Side::A, &entry_a, &mut it_a)?;
                         }
                         Ordering::Greater => {
-                            opt_entry_b = self.report_until(
-                                &entry_a,
-                                &ShallowDifferenceSide::B,
-                                &entry_b,
-                                &mut it_b,
-                            )?;
+                            opt_entry_b =
+                                self.report_until(&entry_a, Side::B, &entry_b, &mut it_b)?;
                         }
                         Ordering::Equal => {
-                            let news = if entry_a.filemode() != entry_b.filemode() {
-                                true
-                            } else if entry_a.id() != entry_b.id() {
-                                if entry_a.filemode() == i32::from(FileMode::Tree) {
-                                    self.path.push(entry_a.name_bytes().to_vec());
-                                    self.diff_inner(entry_a.id(), entry_b.id())?;
-                                    self.path.pop();
-                                    false
-                                } else {
-                                    true
+                            let old = ShallowEntry::new(&entry_a);
+                            let new = ShallowEntry::new(&entry_b);
+                            if old != new {
+                                match (old.entry_type(), new.entry_type()) {
+                                    (EntryType::Tree, EntryType::Tree) => {
+                                        self.path.push(entry_a.name_bytes().to_vec());
+                                        self.diff_inner(old.oid, new.oid)?;
+                                        self.path.pop();
+                                    }
+                                    (type_a, type_b) => {
+                                        let kind = if type_a == type_b {
+                                            ShallowDifferenceKind::Modified { old, new }
+                                        } else {
+                                            ShallowDifferenceKind::TypeChanged { old, new }
+                                        };
+                                        self.report(entry_a.name_bytes(), kind)?;
+                                    }
                                 }
-                            } else {
-                                false
-                            };
-                            if news {
-                                self.report(&ShallowDifferenceSide::A, &entry_a)?;
-                                self.report(&ShallowDifferenceSide::B, &entry_b)?;
                             }
                             opt_entry_a = it_a.next();
                             opt_entry_b = it_b.next();
@@ -157,36 +253,25 @@ where
         Ok(())
     }
 
-    fn exhaust<'b>(
-        &mut self,
-        side: &'b ShallowDifferenceSide,
-        current: &'b TreeEntry,
-        it: &'b mut TreeIter,
-    ) -> Result<(), E>
-    where
-        'a: 'b,
-    {
-        self.report(side, current)?;
+    fn exhaust(&mut self, side: Side, current: &TreeEntry, it: &mut TreeIter) -> Result<(), E> {
+        self.report_side(side, current)?;
         for entry in it {
-            self.report(side, &entry)?;
+            self.report_side(side, &entry)?;
         }
         Ok(())
     }
 
-    fn report_until<'b>(
+    fn report_until(
         &mut self,
         target_entry: &TreeEntry,
-        side: &'b ShallowDifferenceSide,
-        current: &'b TreeEntry,
-        it: &'b mut TreeIter,
-    ) -> Result<Option<TreeEntry<'static>>, E>
-    where
-        'a: 'b,
-    {
-        self.report(side, current)?;
+        side: Side,
+        current: &TreeEntry,
+        it: &mut TreeIter,
+    ) -> Result<Option<TreeEntry<'static>>, E> {
+        self.report_side(side, current)?;
         for entry in it {
             if &entry < target_entry {
-                self.report(side, &entry)?;
+                self.report_side(side, &entry)?;
             } else {
                 return Ok(Some(entry.to_owned()));
             }
@@ -194,10 +279,150 @@ where
         Ok(None)
     }
 
-    fn report<'b>(&mut self, side: &'b ShallowDifferenceSide, entry: &'b TreeEntry) -> Result<(), E>
-    where
-        'a: 'b,
-    {
-        (self.callback)(&ShallowDifference::new(&self.path, side, entry))
+    fn report_side(&mut self, side: Side, entry: &TreeEntry) -> Result<(), E> {
+        let shallow_entry = ShallowEntry::new(entry);
+        let kind = match side {
+            Side::A => ShallowDifferenceKind::Removed(shallow_entry),
+            Side::B => ShallowDifferenceKind::Added(shallow_entry),
+        };
+        self.report(entry.name_bytes(), kind)
+    }
+
+    fn report(&mut self, name: &[u8], kind: ShallowDifferenceKind) -> Result<(), E> {
+        (self.callback)(&ShallowDifference {
+            parent: &self.path,
+            name,
+            kind,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    A,
+    B,
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::FileMode;
+
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn classify() {
+        let repo = TestRepo::new();
+        let tree_a = repo.empty_tree();
+        let tree_a = repo.add_file(tree_a, "d/kept", b"a\n");
+        let tree_a = repo.add_file(tree_a, "d/modified", b"a\n");
+        let tree_a = repo.add_file(tree_a, "removed", b"a\n");
+        let tree_a = repo.add_file(tree_a, "retyped", b"a\n");
+        let tree_b = repo.empty_tree();
+        let tree_b = repo.add_file(tree_b, "added", b"b\n");
+        let tree_b = repo.add_file(tree_b, "d/kept", b"a\n");
+        let tree_b = repo.add_file(tree_b, "d/modified", b"b\n");
+        let tree_b = repo.add_link(tree_b, "retyped", "kept");
+
+        let mut differences = vec![];
+        shallow_diff::<Error>(repo.db.repository(), tree_a, tree_b, |difference| {
+            differences.push((difference.render_path().unwrap(), difference.kind));
+            Ok(())
+        })
+        .unwrap();
+
+        let kinds = differences
+            .iter()
+            .map(|(path, kind)| {
+                let tag = match kind {
+                    ShallowDifferenceKind::Added(entry) => {
+                        assert_eq!(entry.mode, i32::from(FileMode::Blob));
+                        "added"
+                    }
+                    ShallowDifferenceKind::Removed(_) => "removed",
+                    ShallowDifferenceKind::Modified { old, new } => {
+                        assert_ne!(old.oid, new.oid);
+                        "modified"
+                    }
+                    ShallowDifferenceKind::TypeChanged { new, .. } => {
+                        assert_eq!(new.mode, i32::from(FileMode::Link));
+                        "type changed"
+                    }
+                };
+                (path.as_str(), tag)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("0_added", "added"),
+                ("0_d/0_modified", "modified"),
+                ("0_removed", "removed"),
+                ("0_retyped", "type changed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw() {
+        let repo = TestRepo::new();
+        let tree_a = repo.add_file(repo.empty_tree(), "d/modified", b"a\n");
+        let tree_a = repo.add_file(tree_a, "removed", b"a\n");
+        let tree_b = repo.add_file(repo.empty_tree(), "d/modified", b"b\n");
+        let tree_b = repo.add_file(tree_b, "d/added", b"b\n");
+
+        let mut lines = vec![];
+        shallow_diff::<anyhow::Error>(repo.db.repository(), tree_a, tree_b, |difference| {
+            lines.push(difference.render_raw()?);
+            Ok(())
+        })
+        .unwrap();
+
+        let blob = |content: &[u8]| {
+            let (shadow, _) = repo.write_subject_file("x", content);
+            repo.db.repository().blob(&shadow.to_bytes()).unwrap()
+        };
+        let (a, b, zero) = (blob(b"a\n"), blob(b"b\n"), Oid::zero());
+        assert_eq!(
+            lines,
+            vec![
+                format!(":000000 100644 {} {} A\td/added", zero, b),
+                format!(":100644 100644 {} {} M\td/modified", a, b),
+                format!(":100644 000000 {} {} D\tremoved", a, zero),
+            ]
+        );
+    }
+
+    #[test]
+    fn mode_only() {
+        let repo = TestRepo::new();
+        let tree_a = repo.add_file(repo.empty_tree(), "x", b"a\n");
+        let tree_a = repo.add_file(tree_a, "y", b"a\n");
+        let tree_a = repo.add_file(tree_a, "l", b"a\n");
+        let x = repo
+            .db
+            .lookup(tree_a, &"x".parse().unwrap())
+            .unwrap()
+            .unwrap()
+            .id();
+        let tree_b = repo.add_object(repo.empty_tree(), "x", FileMode::BlobExecutable, x);
+        let tree_b = repo.add_file(tree_b, "y", b"b\n");
+        let tree_b = repo.add_object(tree_b, "l", FileMode::Link, x);
+
+        let mut differences = vec![];
+        shallow_diff::<Error>(repo.db.repository(), tree_a, tree_b, |difference| {
+            differences.push((difference.render_path().unwrap(), difference.kind));
+            Ok(())
+        })
+        .unwrap();
+
+        let mode_only = differences
+            .iter()
+            .map(|(path, kind)| (path.as_str(), kind.is_mode_only()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mode_only,
+            vec![("0_l", false), ("0_x", true), ("0_y", false)]
+        );
     }
 }
@@ -1,12 +1,14 @@
 use std::ffi::OsStr;
 use std::fs;
-use std::io;
+use std::io::{self, Read};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{self, Command};
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
-use anyhow::{anyhow, Context, Error, Result};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
 use fallible_iterator::FallibleIterator;
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -15,6 +17,149 @@ use crate::{Shadow, ShadowPath};
 
 const TAKE_SNAPSHOT_SCRIPT: &'static [u8] = include_bytes!("../scripts/take-snapshot.bash");
 
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotConfig {
+    // File mapping file metadata to digests, reused across snapshots to skip hashing unchanged
+    // files.
+    pub hash_cache: Option<PathBuf>,
+    pub filter: SnapshotFilter,
+    // Record paths relative to this directory, which must contain the subject, rather than to the
+    // subject itself. The directories between the two are recorded as well.
+    pub root: Option<PathBuf>,
+}
+
+// Entries to leave out of a snapshot. Patterns are globs with rsync-like semantics: see
+// take-snapshot.bash.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotFilter {
+    pub exclude: Vec<String>,
+}
+
+impl SnapshotFilter {
+    // The metadata directories of version control systems, as left out by tar --exclude-vcs.
+    pub const VCS_EXCLUDES: &'static [&'static str] = &[
+        ".git", ".svn", ".hg", ".bzr", "_darcs", "CVS", "RCS", "SCCS",
+    ];
+
+    pub fn exclude_vcs(&mut self) {
+        self.exclude
+            .extend(Self::VCS_EXCLUDES.iter().map(|pattern| pattern.to_string()));
+    }
+
+    // Add the patterns in `path`, one per line, or on stdin if `path` is "-". Empty lines and lines
+    // starting with '#' are ignored.
+    pub fn exclude_from(&mut self, path: &Path) -> Result<()> {
+        let content = if path == Path::new("-") {
+            let mut content = String::new();
+            io::stdin()
+                .read_to_string(&mut content)
+                .context("reading exclude patterns from stdin")?;
+            content
+        } else {
+            fs::read_to_string(path)
+                .with_context(|| format!("reading exclude patterns from {}", path.display()))?
+        };
+        self.exclude_lines(&content);
+        Ok(())
+    }
+
+    fn exclude_lines(&mut self, content: &str) {
+        self.exclude.extend(
+            content
+                .lines()
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(ToOwned::to_owned),
+        );
+    }
+}
+
+// Shell commands to run before and after a snapshot, e.g. to freeze and thaw the filesystem
+// containing the subject. They receive the subject and snapshot directory in $KEEP_SUBJECT and
+// $KEEP_SNAPSHOT_DIR.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotHooks {
+    pub pre: Option<String>,
+    pub post: Option<String>,
+}
+
+impl SnapshotHooks {
+    // Run `f` between the hooks. Once the pre-hook has succeeded, the post-hook runs even if `f`
+    // fails, in which case the error from `f` takes precedence.
+    pub fn run<T>(
+        &self,
+        subject: &Path,
+        snapshot_dir: &Path,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        if let Some(pre) = &self.pre {
+            run_hook("pre-hook", pre, subject, snapshot_dir)?;
+        }
+        let result = f();
+        if let Some(post) = &self.post {
+            match (&result, run_hook("post-hook", post, subject, snapshot_dir)) {
+                (Ok(_), Err(err)) => return Err(err),
+                (Err(_), Err(err)) => log::error!("{:#}", err),
+                _ => {}
+            }
+        }
+        result
+    }
+}
+
+fn run_hook(name: &str, hook: &str, subject: &Path, snapshot_dir: &Path) -> Result<()> {
+    log::info!("running {}", name);
+    Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("KEEP_SUBJECT", subject)
+        .env("KEEP_SNAPSHOT_DIR", snapshot_dir)
+        .status()?
+        .exit_ok()
+        .with_context(|| format!("{} failed", name))?;
+    Ok(())
+}
+
+// Mount the filesystem image `image` read-only at `mountpoint` through a loop device, run `f`, and
+// then unmount it, even if `f` fails. Mounting needs root, or CAP_SYS_ADMIN along with access to
+// /dev/loop-control. mount(8) sets the loop device up to be detached when the filesystem is
+// unmounted, or if mounting it fails.
+pub fn with_image_mounted<T>(
+    image: &Path,
+    mountpoint: &Path,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    log::info!("mounting {} at {}", image.display(), mountpoint.display());
+    Command::new("mount")
+        .arg("-o")
+        .arg("ro,loop,nodev,nosuid,noexec")
+        .arg("--")
+        .arg(image)
+        .arg(mountpoint)
+        .status()?
+        .exit_ok()
+        .with_context(|| format!("mounting {}", image.display()))?;
+    let result = f();
+    log::info!("unmounting {}", mountpoint.display());
+    let unmounted = Command::new("umount")
+        .arg("--")
+        .arg(mountpoint)
+        .status()
+        .map_err(Error::from)
+        .and_then(|status| Ok(status.exit_ok()?))
+        .with_context(|| {
+            format!(
+                "unmounting {}, which leaves its loop device attached",
+                mountpoint.display()
+            )
+        });
+    match (&result, unmounted) {
+        (Ok(_), Err(err)) => return Err(err),
+        (Err(_), Err(err)) => log::error!("{:#}", err),
+        _ => {}
+    }
+    result
+}
+
 pub struct Snapshot<'a> {
     path: &'a Path,
 }
@@ -39,26 +184,253 @@ impl<'a> Snapshot<'a> {
         self.path().join("digests")
     }
 
+    fn open(&self, path: &Path) -> Result<io::BufReader<fs::File>> {
+        let file = fs::File::open(path).with_context(|| {
+            format!(
+                "opening {} of snapshot {}",
+                path.file_name().unwrap().to_string_lossy(),
+                self.path().display()
+            )
+        })?;
+        Ok(io::BufReader::new(file))
+    }
+
+    fn read(&self, file: &str) -> Result<Vec<u8>> {
+        fs::read(self.path().join(file))
+            .with_context(|| format!("reading {} of snapshot {}", file, self.path().display()))
+    }
+
+    // The absolute path of the subject as it was when the snapshot was taken, with links resolved.
+    // This is the root instead if one was given, since recorded paths are relative to it.
+    pub fn subject(&self) -> Result<PathBuf> {
+        let content = self.read("subject.txt")?;
+        let subject = content
+            .strip_suffix(b"\n")
+            .filter(|subject| !subject.is_empty() && !subject.contains(&b'\n'))
+            .ok_or_else(|| {
+                anyhow!(
+                    "malformed subject.txt in snapshot {}",
+                    self.path().display()
+                )
+            })?;
+        Ok(PathBuf::from(OsStr::from_bytes(subject)))
+    }
+
+    // The output of sha256sum for the nodes and digests files, as recorded when they were written,
+    // with paths relative to the snapshot directory.
+    pub fn recorded_sha256sum(&self) -> Result<String> {
+        String::from_utf8(self.read("sha256sum.txt")?).map_err(|_| {
+            anyhow!(
+                "malformed sha256sum.txt in snapshot {}",
+                self.path().display()
+            )
+        })
+    }
+
+    // Catch planting or storing a snapshot with a subject other than the one it was taken of.
+    // Paths are compared after resolving links where they exist.
+    pub fn ensure_subject(&self, subject: &Path) -> Result<()> {
+        let recorded = self.subject()?;
+        let resolve = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        ensure!(
+            resolve(&recorded) == resolve(subject),
+            "snapshot {} was taken of {}, not {}",
+            self.path().display(),
+            recorded.display(),
+            subject.display()
+        );
+        Ok(())
+    }
+
     pub fn entries(&self) -> Result<SnapshotEntries<impl io::BufRead>> {
         Ok(SnapshotEntries {
             nodes_entries: NodesEntries {
-                reader: io::BufReader::new(fs::File::open(self.nodes_path())?),
+                reader: self.open(&self.nodes_path())?,
             },
             digests_entries: DigestsEntries {
-                reader: io::BufReader::new(fs::File::open(self.digests_path())?),
+                reader: self.open(&self.digests_path())?,
             },
         })
     }
 
-    pub fn take(&self, subject: &Path) -> Result<()> {
-        Command::new("bash")
+    // Catch a mistaken snapshot path before doing any work with it.
+    pub fn ensure_complete(&self) -> Result<()> {
+        let missing = Self::FILES
+            .iter()
+            .filter(|file| !self.path().join(file).is_file())
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "{} is not a snapshot directory (missing {})",
+                self.path().display(),
+                missing.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    // Check everything that planting relies on, so that a truncated or corrupt snapshot is
+    // reported up front rather than partway through.
+    pub fn validate(&self) -> Result<()> {
+        self.ensure_complete()?;
+        let mut nodes = NodesEntries {
+            reader: self.open(&self.nodes_path())?,
+        };
+        let mut digests = DigestsEntries {
+            reader: self.open(&self.digests_path())?,
+        };
+        let (mut n_nodes, mut n_digests) = (0, 0);
+        while let Some(node) = nodes
+            .next()
+            .with_context(|| format!("reading node {}", n_nodes + 1))?
+        {
+            n_nodes += 1;
+            node.path
+                .parse::<ShadowPath>()
+                .with_context(|| format!("invalid path {:?} at node {}", node.path, n_nodes))?;
+            if node.ty != 'f' {
+                continue;
+            }
+            let digest = digests
+                .next()
+                .with_context(|| format!("reading digest {}", n_digests + 1))?
+                .ok_or_else(|| anyhow!("digest missing for node {} ({:?})", n_nodes, node.path))?;
+            n_digests += 1;
+            ensure!(
+                digest.path == node.path,
+                "digest {} is for {:?}, but node {} is {:?}",
+                n_digests,
+                digest.path,
+                n_nodes,
+                node.path
+            );
+        }
+        if let Some(digest) = digests
+            .next()
+            .with_context(|| format!("reading digest {}", n_digests + 1))?
+        {
+            bail!("digest {} ({:?}) has no node", n_digests + 1, digest.path);
+        }
+        Ok(())
+    }
+
+    // The snapshot is written to a temporary directory beside `path` and renamed into place once
+    // complete, so that a concurrent take to the same path fails rather than interleaving with
+    // this one, and a failed take leaves nothing at `path`.
+    pub fn take(&self, subject: &Path, config: &SnapshotConfig) -> Result<()> {
+        ensure!(
+            !self.path().exists(),
+            "'{}' already exists",
+            self.path().display()
+        );
+        let temporary = self.temporary_path()?;
+        let result = Snapshot::new(&temporary)
+            .take_in_place(subject, config)
+            .and_then(|()| {
+                // Fails if another take has put a snapshot there meanwhile.
+                fs::rename(&temporary, self.path())
+                    .with_context(|| format!("renaming snapshot to {}", self.path().display()))
+            });
+        if result.is_err() && temporary.exists() {
+            if let Err(err) = fs::remove_dir_all(&temporary) {
+                log::error!("failed to remove {}: {}", temporary.display(), err);
+            }
+        }
+        result
+    }
+
+    fn temporary_path(&self) -> Result<PathBuf> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let name = self
+            .path()
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid snapshot path {}", self.path().display()))?;
+        let mut temporary = name.to_owned();
+        temporary.push(format!(
+            ".tmp.{}.{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        Ok(self.path().with_file_name(temporary))
+    }
+
+    fn take_in_place(&self, subject: &Path, config: &SnapshotConfig) -> Result<()> {
+        let mut command = Command::new("bash");
+        command
             .arg("-c")
             .arg(OsStr::from_bytes(TAKE_SNAPSHOT_SCRIPT))
-            .arg("--")
-            .arg(subject)
-            .arg(&self.path)
-            .status()?
-            .exit_ok()?;
+            .arg("--");
+        if let Some(hash_cache) = &config.hash_cache {
+            command.arg("--hash-cache").arg(hash_cache);
+        }
+        for pattern in &config.filter.exclude {
+            command.arg("--exclude").arg(pattern);
+        }
+        if let Some(root) = &config.root {
+            command.arg("--root").arg(root);
+        }
+        command.arg(subject).arg(&self.path).status()?.exit_ok()?;
+        Ok(())
+    }
+
+    // When the walk of the subject began. subject.txt is written just before it.
+    fn taken_at(&self) -> Result<SystemTime> {
+        let path = self.path().join("subject.txt");
+        Ok(fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("reading metadata of {}", path.display()))?)
+    }
+
+    // Remove what this snapshot recorded of `subject`, deepest entries first. Entries added to
+    // `subject` since the snapshot was taken are left alone, along with the directories
+    // containing them, and so are files whose size differs from the one recorded or which were
+    // modified after the snapshot began, to the resolution of file timestamps.
+    pub fn remove_subject(&self, subject: &Path) -> Result<()> {
+        let taken_at = self.taken_at()?;
+        let entries = self.entries()?.collect::<Vec<_>>()?;
+        for entry in entries.iter().rev() {
+            let path = entry
+                .path
+                .components()
+                .iter()
+                .fold(subject.to_path_buf(), |path, component| {
+                    path.join(component.as_ref())
+                });
+            if let SnapshotEntryValue::File { shadow, .. } = &entry.value {
+                let metadata = match fs::symlink_metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => {
+                        return Err(Error::from(err).context(format!("reading {}", path.display())))
+                    }
+                };
+                let changed = !metadata.is_file()
+                    || shadow.size().map_or(false, |size| size != metadata.len())
+                    || metadata.modified()? > taken_at;
+                if changed {
+                    log::info!(
+                        "leaving {}, which has changed since the snapshot",
+                        path.display()
+                    );
+                    continue;
+                }
+            }
+            let removed = match entry.value {
+                SnapshotEntryValue::Tree => fs::remove_dir(&path),
+                _ => fs::remove_file(&path),
+            };
+            match removed {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) if err.raw_os_error() == Some(libc::ENOTEMPTY) => {
+                    log::info!("leaving {}, which is not empty", path.display());
+                }
+                Err(err) => {
+                    return Err(Error::from(err).context(format!("removing {}", path.display())))
+                }
+            }
+        }
         Ok(())
     }
 
@@ -102,8 +474,16 @@ impl<T: io::BufRead> FallibleIterator for SnapshotEntries<T> {
                     target: node_line.target,
                 },
                 'f' => {
-                    let digest_line = self.digests_entries.next()?.unwrap();
-                    assert_eq!(node_line.path, digest_line.path);
+                    let digest_line = self
+                        .digests_entries
+                        .next()?
+                        .ok_or_else(|| anyhow!("digest missing for {:?}", node_line.path))?;
+                    ensure!(
+                        node_line.path == digest_line.path,
+                        "digest for {:?} out of order at {:?}",
+                        digest_line.path,
+                        node_line.path
+                    );
                     SnapshotEntryValue::File {
                         shadow: Shadow::new(digest_line.digest.parse()?, node_line.size),
                         executable: node_line.is_executable(),
@@ -120,6 +500,17 @@ impl<T: io::BufRead> FallibleIterator for SnapshotEntries<T> {
     }
 }
 
+// Names in a snapshot are arbitrary bytes, but `ShadowPath` can only represent UTF-8, so reject
+// anything else here rather than mangling it.
+fn entry_str(buf: &[u8]) -> Result<&str> {
+    str::from_utf8(buf).map_err(|_| {
+        anyhow!(
+            "snapshot entry {:?} is not valid UTF-8, which keep cannot represent",
+            String::from_utf8_lossy(buf)
+        )
+    })
+}
+
 #[derive(Debug)]
 struct NodesEntry {
     ty: char, // [dflcbsp]
@@ -146,7 +537,7 @@ impl<T: io::BufRead> FallibleIterator for NodesEntries<T> {
     fn next(&mut self) -> Result<Option<Self::Item>, Self::Error> {
         lazy_static! {
             static ref RE: Regex = Regex::new(
-                r"^(?P<type>[dflcbsp]) 0(?P<mode>[0-9]{3}[0-9]*) (?P<size>([0-9]+|\?)) (?P<path>.*)\x00 (?P<target>.*)\x00\n$"
+                r"^(?P<type>[dflcbsp]) 0(?P<mode>[0-9]{3}[0-9]*) (?P<size>([0-9]+|\?)) (?P<path>[^\x00]*)\x00 (?P<target>[^\x00]*)\x00\n$"
             )
             .unwrap();
         }
@@ -154,12 +545,11 @@ impl<T: io::BufRead> FallibleIterator for NodesEntries<T> {
         if !self.reader.has_data_left()? {
             return Ok(None);
         }
-        // TODO handle malformed input
-        assert_ne!(self.reader.read_until(0, &mut buf)?, 0);
-        assert_ne!(self.reader.read_until(0, &mut buf)?, 0);
-        assert_eq!(self.reader.read_until(b'\n', &mut buf)?, 1);
+        self.reader.read_until(0, &mut buf)?;
+        self.reader.read_until(0, &mut buf)?;
+        self.reader.read_until(b'\n', &mut buf)?;
         let caps = RE
-            .captures(str::from_utf8(&buf)?)
+            .captures(entry_str(&buf)?)
             .ok_or(anyhow!("regex does not match"))?;
         let size = match &caps["size"] {
             "?" => None,
@@ -192,17 +582,17 @@ impl<T: io::BufRead> FallibleIterator for DigestsEntries<T> {
     fn next(&mut self) -> Result<Option<Self::Item>, Self::Error> {
         lazy_static! {
             static ref RE: Regex =
-                Regex::new(r"^(?P<digest>[a-z0-9]{64}|[?]{64}) \*(?P<path>.*)\x00\n$").unwrap();
+                Regex::new(r"^(?P<digest>[a-z0-9]{64}|[?]{64}) \*(?P<path>[^\x00]*)\x00\n$")
+                    .unwrap();
         }
         let mut buf = vec![];
         if !self.reader.has_data_left()? {
             return Ok(None);
         }
-        // TODO handle malformed input
-        assert_ne!(self.reader.read_until(0, &mut buf)?, 0);
-        assert_eq!(self.reader.read_until(b'\n', &mut buf)?, 1);
+        self.reader.read_until(0, &mut buf)?;
+        self.reader.read_until(b'\n', &mut buf)?;
         let caps = RE
-            .captures(str::from_utf8(&buf)?)
+            .captures(entry_str(&buf)?)
             .ok_or(anyhow!("regex does not match"))?;
         Ok(Some(DigestsEntry {
             digest: caps["digest"].to_string(),
@@ -210,3 +600,466 @@ impl<T: io::BufRead> FallibleIterator for DigestsEntries<T> {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use fallible_iterator::FallibleIterator;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn digests(snapshot: &Snapshot) -> Vec<String> {
+        snapshot
+            .entries()
+            .unwrap()
+            .filter_map(|entry| {
+                Ok(match entry.value {
+                    SnapshotEntryValue::File { shadow, .. } => {
+                        Some(shadow.content_hash().to_string())
+                    }
+                    _ => None,
+                })
+            })
+            .collect()
+            .unwrap()
+    }
+
+    #[test]
+    fn recorded_fields() {
+        let dir = TempDir::new();
+        let snapshot = dir.path().join("snapshot");
+        fs::create_dir(&snapshot).unwrap();
+        fs::write(snapshot.join("subject.txt"), b"/srv/data\n").unwrap();
+        let sha256sum = format!("{} *nodes\n{} *digests\n", "0".repeat(64), "1".repeat(64));
+        fs::write(snapshot.join("sha256sum.txt"), &sha256sum).unwrap();
+
+        let snapshot = Snapshot::new(&snapshot);
+        assert_eq!(snapshot.subject().unwrap(), Path::new("/srv/data"));
+        assert_eq!(snapshot.recorded_sha256sum().unwrap(), sha256sum);
+        snapshot.ensure_subject(Path::new("/srv/data")).unwrap();
+        assert!(snapshot.ensure_subject(Path::new("/srv/other")).is_err());
+
+        fs::write(snapshot.path().join("subject.txt"), b"").unwrap();
+        assert!(snapshot.subject().is_err());
+    }
+
+    #[test]
+    fn hash_cache() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        fs::create_dir(&subject).unwrap();
+        fs::write(subject.join("a"), b"hello\n").unwrap();
+        let config = SnapshotConfig {
+            hash_cache: Some(dir.path().join("cache")),
+            ..Default::default()
+        };
+
+        let first = dir.path().join("first");
+        Snapshot::new(&first).take(&subject, &config).unwrap();
+        let cache = fs::read_to_string(dir.path().join("cache")).unwrap();
+        let digest = digests(&Snapshot::new(&first)).remove(0);
+        assert!(cache.ends_with(&format!(" {}\n", digest)));
+
+        // A bogus digest in the cache shows up in the next snapshot only if no hashing took place.
+        let bogus = "0".repeat(64);
+        fs::write(dir.path().join("cache"), cache.replace(&digest, &bogus)).unwrap();
+        let second = dir.path().join("second");
+        Snapshot::new(&second).take(&subject, &config).unwrap();
+        assert_eq!(digests(&Snapshot::new(&second)), vec![bogus]);
+    }
+
+    #[test]
+    fn relative_sha256sum() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        fs::create_dir(&subject).unwrap();
+        fs::write(subject.join("a"), b"a\n").unwrap();
+        let out = dir.path().join("out");
+        Snapshot::new(&out)
+            .take(&subject, &Default::default())
+            .unwrap();
+        let sha256sum = Snapshot::new(&out).recorded_sha256sum().unwrap();
+        let recorded = sha256sum
+            .lines()
+            .map(|line| &line[64..])
+            .collect::<Vec<_>>();
+        assert_eq!(recorded, vec![" *nodes", " *digests"]);
+        assert!(Command::new("sha256sum")
+            .arg("--check")
+            .arg("--quiet")
+            .arg("sha256sum.txt")
+            .current_dir(&out)
+            .status()
+            .unwrap()
+            .success());
+    }
+
+    #[test]
+    fn unusual_names() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        let names = ["a b", "new\nline", "back\\slash", "-n"];
+        fs::create_dir(&subject).unwrap();
+        for name in &names {
+            fs::write(subject.join(name), name.as_bytes()).unwrap();
+        }
+        let out = dir.path().join("out");
+        Snapshot::new(&out)
+            .take(&subject, &Default::default())
+            .unwrap();
+        let mut paths = Snapshot::new(&out)
+            .entries()
+            .unwrap()
+            .map(|entry| Ok(entry.path.to_string()))
+            .collect::<Vec<_>>()
+            .unwrap();
+        paths.sort();
+        let mut expected = names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        expected.push("".to_owned());
+        expected.sort();
+        assert_eq!(paths, expected);
+
+        fs::write(subject.join(OsStr::from_bytes(b"\xff")), b"").unwrap();
+        let out = dir.path().join("non-utf-8");
+        Snapshot::new(&out)
+            .take(&subject, &Default::default())
+            .unwrap();
+        let err = Snapshot::new(&out)
+            .entries()
+            .unwrap()
+            .collect::<Vec<_>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("not valid UTF-8"));
+    }
+
+    #[test]
+    fn exclude() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        for path in &[
+            "a/node_modules/x",
+            "a/y.log",
+            "a/z",
+            "b/node_modules",
+            "c/w",
+        ] {
+            let path = subject.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        fs::write(
+            dir.path().join("excludes"),
+            "# dependencies\nnode_modules/\n\n*.log\n",
+        )
+        .unwrap();
+        let mut filter = SnapshotFilter {
+            exclude: vec!["/c".to_owned()],
+        };
+        filter.exclude_from(&dir.path().join("excludes")).unwrap();
+        assert_eq!(filter.exclude, vec!["/c", "node_modules/", "*.log"]);
+        assert!(filter.exclude_from(&dir.path().join("missing")).is_err());
+
+        let out = dir.path().join("out");
+        let config = SnapshotConfig {
+            filter,
+            ..Default::default()
+        };
+        Snapshot::new(&out).take(&subject, &config).unwrap();
+        let mut paths = Snapshot::new(&out)
+            .entries()
+            .unwrap()
+            .map(|entry| Ok(entry.path.to_string()))
+            .collect::<Vec<_>>()
+            .unwrap();
+        paths.sort();
+        assert_eq!(paths, vec!["", "a", "a/z", "b", "b/node_modules"]);
+    }
+
+    #[test]
+    fn exclude_vcs() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        for path in &[".git/config", "a/.hg/store", "a/x", "b/.gitignore"] {
+            let path = subject.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let mut filter = SnapshotFilter::default();
+        filter.exclude_vcs();
+        let out = dir.path().join("out");
+        let config = SnapshotConfig {
+            filter,
+            ..Default::default()
+        };
+        Snapshot::new(&out).take(&subject, &config).unwrap();
+        let mut paths = Snapshot::new(&out)
+            .entries()
+            .unwrap()
+            .map(|entry| Ok(entry.path.to_string()))
+            .collect::<Vec<_>>()
+            .unwrap();
+        paths.sort();
+        assert_eq!(paths, vec!["", "a", "a/x", "b", "b/.gitignore"]);
+    }
+
+    #[test]
+    fn root() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("mnt/backup/home");
+        fs::create_dir_all(subject.join("user")).unwrap();
+        fs::write(subject.join("user/a"), b"a\n").unwrap();
+        let paths = |out: &str, root: &str| {
+            let config = SnapshotConfig {
+                root: Some(dir.path().join(root)),
+                ..Default::default()
+            };
+            let out = dir.path().join(out);
+            let snapshot = Snapshot::new(&out);
+            snapshot.take(&subject, &config)?;
+            snapshot.validate()?;
+            snapshot
+                .entries()?
+                .map(|entry| Ok(entry.path.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            paths("out-backup", "mnt/backup").unwrap(),
+            vec!["", "home", "home/user", "home/user/a"]
+        );
+        assert_eq!(
+            Snapshot::new(&dir.path().join("out-backup"))
+                .subject()
+                .unwrap(),
+            dir.path().join("mnt/backup").canonicalize().unwrap()
+        );
+        assert_eq!(
+            paths("out-mnt", "mnt").unwrap(),
+            vec![
+                "",
+                "backup",
+                "backup/home",
+                "backup/home/user",
+                "backup/home/user/a"
+            ]
+        );
+        assert_eq!(
+            paths("out-home", "mnt/backup/home").unwrap(),
+            vec!["", "user", "user/a"]
+        );
+        assert!(paths("out-under", "mnt/backup/home/user").is_err());
+        assert!(!dir.path().join("out-under").exists());
+    }
+
+    #[test]
+    fn link_subject() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        fs::create_dir(&subject).unwrap();
+        fs::write(subject.join("a"), b"a\n").unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("subject", &link).unwrap();
+        let out = dir.path().join("out");
+        let snapshot = Snapshot::new(&out);
+        snapshot.take(&link, &Default::default()).unwrap();
+        let paths = snapshot
+            .entries()
+            .unwrap()
+            .map(|entry| Ok(entry.path.to_string()))
+            .collect::<Vec<_>>()
+            .unwrap();
+        assert_eq!(paths, vec!["", "a"]);
+        assert_eq!(snapshot.subject().unwrap(), subject.canonicalize().unwrap());
+    }
+
+    #[test]
+    fn exclude_lines() {
+        let mut filter = SnapshotFilter::default();
+        filter.exclude_lines("#\n/build/\n\n**/*.tmp");
+        assert_eq!(filter.exclude, vec!["/build/", "**/*.tmp"]);
+    }
+
+    #[test]
+    fn hooks() {
+        let dir = TempDir::new();
+        let log = dir.path().join("log");
+        let hooks = SnapshotHooks {
+            pre: Some(format!(
+                "echo pre $KEEP_SUBJECT $KEEP_SNAPSHOT_DIR >> {}",
+                log.display()
+            )),
+            post: Some(format!("echo post >> {}", log.display())),
+        };
+        let record = |line: &str| {
+            let mut content = fs::read_to_string(&log).unwrap();
+            content.push_str(line);
+            fs::write(&log, content).unwrap();
+        };
+
+        let value = hooks
+            .run(Path::new("/s"), Path::new("/d"), || {
+                record("take\n");
+                Ok(1)
+            })
+            .unwrap();
+        assert_eq!(value, 1);
+        assert_eq!(fs::read_to_string(&log).unwrap(), "pre /s /d\ntake\npost\n");
+
+        fs::remove_file(&log).unwrap();
+        let result = hooks.run(Path::new("/s"), Path::new("/d"), || -> Result<()> {
+            Err(anyhow!("snapshot failed"))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "snapshot failed");
+        assert_eq!(fs::read_to_string(&log).unwrap(), "pre /s /d\npost\n");
+
+        let failing_pre = SnapshotHooks {
+            pre: Some("false".to_owned()),
+            ..hooks
+        };
+        fs::remove_file(&log).unwrap();
+        assert!(failing_pre
+            .run(Path::new("/s"), Path::new("/d"), || Ok(()))
+            .is_err());
+        assert!(!log.exists());
+    }
+
+    #[test]
+    fn incomplete() {
+        let dir = TempDir::new();
+        let snapshot = Snapshot::new(dir.path());
+        let err = snapshot.entries().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("opening nodes of snapshot {}", dir.path().display())
+        );
+        fs::write(dir.path().join("nodes"), b"").unwrap();
+        fs::write(dir.path().join("files"), b"").unwrap();
+        let err = snapshot.ensure_complete().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} is not a snapshot directory (missing subject.txt, sha256sum.txt, digests)",
+                dir.path().display()
+            )
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        fs::create_dir(&subject).unwrap();
+        fs::write(subject.join("a"), b"a\n").unwrap();
+        fs::write(subject.join("b"), b"b\n").unwrap();
+        let out = dir.path().join("out");
+        let snapshot = Snapshot::new(&out);
+        snapshot.take(&subject, &Default::default()).unwrap();
+        snapshot.validate().unwrap();
+
+        let digests = fs::read(out.join("digests")).unwrap();
+        let first = digests.windows(2).position(|w| w == b"\0\n").unwrap() + 2;
+        fs::write(out.join("digests"), &digests[..first]).unwrap();
+        let err = snapshot.validate().unwrap_err();
+        assert!(err.to_string().starts_with("digest missing for node 3 "));
+
+        fs::write(out.join("digests"), &digests[..first - 1]).unwrap();
+        let err = snapshot.validate().unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "reading digest 1: regex does not match"
+        );
+    }
+
+    #[test]
+    fn remove_subject() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        fs::create_dir_all(subject.join("d/e")).unwrap();
+        fs::write(subject.join("d/e/a"), b"a\n").unwrap();
+        fs::write(subject.join("b"), b"b\n").unwrap();
+        fs::write(subject.join("c"), b"c\n").unwrap();
+        fs::write(subject.join("m"), b"m\n").unwrap();
+        let out = dir.path().join("out");
+        let snapshot = Snapshot::new(&out);
+        snapshot.take(&subject, &Default::default()).unwrap();
+
+        fs::write(subject.join("d/new"), b"new\n").unwrap();
+        // Changed in size, and in content and mtime alone.
+        fs::write(subject.join("c"), b"changed\n").unwrap();
+        fs::write(subject.join("m"), b"M\n").unwrap();
+        let later = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        Command::new("touch")
+            .arg("-d")
+            .arg(format!("@{}", later))
+            .arg(subject.join("m"))
+            .status()
+            .unwrap()
+            .exit_ok()
+            .unwrap();
+        snapshot.remove_subject(&subject).unwrap();
+        assert_eq!(
+            fs::read_dir(subject.join("d"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["new"]
+        );
+        assert!(!subject.join("b").exists());
+        assert!(subject.join("c").exists());
+        assert!(subject.join("m").exists());
+
+        fs::remove_file(subject.join("d/new")).unwrap();
+        fs::remove_file(subject.join("c")).unwrap();
+        fs::remove_file(subject.join("m")).unwrap();
+        snapshot.remove_subject(&subject).unwrap();
+        assert!(!subject.exists());
+    }
+
+    #[test]
+    fn image_mount_failure() {
+        let dir = TempDir::new();
+        let mut ran = false;
+        let result = with_image_mounted(&dir.path().join("missing.img"), dir.path(), || {
+            ran = true;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert!(!ran);
+    }
+
+    #[test]
+    fn concurrent_takes() {
+        let dir = TempDir::new();
+        let subject = dir.path().join("subject");
+        fs::create_dir(&subject).unwrap();
+        for i in 0..100 {
+            fs::write(subject.join(i.to_string()), i.to_string()).unwrap();
+        }
+        let out = dir.path().join("out");
+        let takes = (0..2)
+            .map(|_| {
+                let (subject, out) = (subject.clone(), out.clone());
+                std::thread::spawn(move || Snapshot::new(&out).take(&subject, &Default::default()))
+            })
+            .collect::<Vec<_>>();
+        let results = takes
+            .into_iter()
+            .map(|take| take.join().unwrap().is_ok())
+            .collect::<Vec<_>>();
+        assert_eq!(results.iter().filter(|ok| **ok).count(), 1);
+        Snapshot::new(&out).validate().unwrap();
+        // Nothing is left of the take which lost.
+        let mut names = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["out", "subject"]);
+    }
+}
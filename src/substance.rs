@@ -1,10 +1,12 @@
-use std::fs::{self, OpenOptions, Permissions};
-use std::io;
-use std::os::unix::fs::PermissionsExt;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::SystemTime;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Context, Error, Result};
 use lazy_static::lazy_static;
 use regex::bytes::Regex;
 use sha2::{Digest, Sha256};
@@ -13,28 +15,213 @@ use crate::ContentSha256;
 
 pub trait Substance {
     fn blob_path(&self, blob: &ContentSha256) -> PathBuf;
-    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<()>;
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Stored>;
+
+    // Which substance this is, for messages, like "filesystem:/var/keep/substance".
+    fn describe(&self) -> String;
 
     fn have_blob(&self, blob: &ContentSha256) -> bool {
         self.blob_path(blob).is_file()
     }
 
+    // Streaming counterpart to reading the file at `blob_path`, for substances whose blobs need
+    // decoding or do not live on the local filesystem.
+    fn open(&self, blob: &ContentSha256) -> Result<Box<dyn Read + Send>> {
+        let file =
+            File::open(self.blob_path(blob)).with_context(|| format!("opening blob {}", blob))?;
+        Ok(Box::new(file))
+    }
+
+    // Open a blob for reading ranges of it, so that a reader of many ranges, like a mount, opens
+    // it only once.
+    fn open_range_reader(&self, blob: &ContentSha256) -> Result<Box<dyn RangeReader>> {
+        let file =
+            File::open(self.blob_path(blob)).with_context(|| format!("opening blob {}", blob))?;
+        Ok(Box::new(file))
+    }
+
+    // Read up to `len` bytes of a blob starting at `offset`. Fewer bytes are returned only at the
+    // end of the blob.
+    fn read_at(&self, blob: &ContentSha256, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.open_range_reader(blob)?.read_range(offset, len)
+    }
+
     fn check_blob(&self, blob: &ContentSha256) -> Result<()> {
         check_sha256sum(blob, &self.blob_path(blob))
     }
+
+    // Whether blobs read for restore, mount, and export are checked against their content hashes
+    // when the user does not say. Only substances on local storage are trusted by default.
+    fn verify_by_default(&self) -> bool {
+        true
+    }
+
+    // The number of bytes the blob occupies in the substance, which differs from its logical size
+    // for sparse or reflinked files and for substances which encode blobs, or None if the blob is
+    // absent.
+    fn stored_size(&self, blob: &ContentSha256) -> Result<Option<u64>> {
+        match fs::metadata(self.blob_path(blob)) {
+            Ok(metadata) => Ok(Some(metadata.blocks() * 512)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::from(err).context(format!("reading metadata of blob {}", blob))),
+        }
+    }
+
+    // When the blob was last written or stored again in the substance, or None if the blob is
+    // absent.
+    fn modified(&self, blob: &ContentSha256) -> Result<Option<SystemTime>> {
+        match fs::metadata(self.blob_path(blob)) {
+            Ok(metadata) => Ok(Some(metadata.modified()?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::from(err).context(format!("reading metadata of blob {}", blob))),
+        }
+    }
+
+    // For discarding a corrupt blob so that it can be stored again.
+    fn remove_blob(&self, blob: &ContentSha256) -> Result<()> {
+        fs::remove_file(self.blob_path(blob)).with_context(|| format!("removing blob {}", blob))
+    }
+
+    // Every blob present, in order, regardless of whether any tree refers to it.
+    fn list_blobs(&self) -> Result<Vec<ContentSha256>> {
+        Err(anyhow!(
+            "listing blobs is not supported by {}",
+            self.describe()
+        ))
+    }
+
+    // Like `remove_blob`, but keep the content aside for inspection, returning where it went.
+    fn quarantine_blob(&self, blob: &ContentSha256) -> Result<PathBuf> {
+        Err(anyhow!(
+            "cannot quarantine blob {}: not supported by {}",
+            blob,
+            self.describe()
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stored {
+    New,
+    AlreadyPresent,
+}
+
+// Whether `FilesystemSubstance::store` shares the source's data with the stored blob (as with
+// `cp --reflink`) instead of copying it. This requires filesystem support, as on btrfs or XFS,
+// and both files to be on the same filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reflink {
+    Always,
+    Auto,
+    Never,
+}
+
+impl Default for Reflink {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+pub trait RangeReader: Send {
+    // Read up to `len` bytes starting at `offset`. Fewer bytes are returned only at the end.
+    fn read_range(&self, offset: u64, len: usize) -> Result<Vec<u8>>;
+}
+
+impl RangeReader for File {
+    fn read_range(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        let mut n = 0;
+        while n < len {
+            match self.read_at(&mut buf[n..], offset + n as u64) {
+                Ok(0) => break,
+                Ok(m) => n += m,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        buf.truncate(n);
+        Ok(buf)
+    }
 }
 
 pub struct FilesystemSubstance {
     path: PathBuf,
+    reflink: Reflink,
+    depth: usize,
 }
 
 impl FilesystemSubstance {
     const SPLIT: usize = 3;
+    // Blobs are sharded into this many levels of directories, each named by the next `SPLIT` hex
+    // digits of the content hash, unless the layout file says otherwise.
+    const DEFAULT_DEPTH: usize = 1;
+    const LAYOUT_FILE: &'static str = "layout";
+    // Held shared while storing a blob and exclusively while reindexing.
+    const LOCK_FILE: &'static str = "lock";
+
+    // Fails if the layout file is malformed, since guessing the depth would put blobs where no
+    // reader looks for them.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let depth = Self::read_depth(&path)?;
+        Ok(Self {
+            path,
+            reflink: Reflink::default(),
+            depth,
+        })
+    }
 
-    pub fn new(path: impl AsRef<Path>) -> Self {
-        Self {
-            path: path.as_ref().to_path_buf(),
+    pub fn set_reflink(&mut self, reflink: Reflink) {
+        self.reflink = reflink;
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // The depth recorded in the layout file of the substance at `path`, as a line like "depth 2".
+    pub fn read_depth(path: &Path) -> Result<usize> {
+        let layout_path = path.join(Self::LAYOUT_FILE);
+        let content = match fs::read_to_string(&layout_path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::DEFAULT_DEPTH),
+            Err(err) => {
+                return Err(Error::from(err).context(format!("reading {}", layout_path.display())))
+            }
+        };
+        content
+            .strip_suffix('\n')
+            .and_then(|line| line.strip_prefix("depth "))
+            .and_then(|depth| depth.parse().ok())
+            .filter(|depth| Self::check_depth(*depth).is_ok())
+            .ok_or_else(|| anyhow!("malformed {}", layout_path.display()))
+    }
+
+    // Lock the substance with `operation`, a flock(2) operation, until the returned file is
+    // closed.
+    fn lock(&self, operation: libc::c_int) -> Result<File> {
+        let lock_path = self.path.join(Self::LOCK_FILE);
+        fs::create_dir_all(&self.path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("opening {}", lock_path.display()))?;
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == -1 {
+            return Err(Error::from(io::Error::last_os_error())
+                .context(format!("locking {}", lock_path.display())));
         }
+        Ok(file)
+    }
+
+    // At least one hex digit must be left for the file name.
+    fn check_depth(depth: usize) -> Result<()> {
+        ensure!(
+            depth * Self::SPLIT < 64,
+            "depth {} leaves nothing of the content hash for file names",
+            depth
+        );
+        Ok(())
     }
 
     fn blob_dir(&self) -> PathBuf {
@@ -45,50 +232,171 @@ impl FilesystemSubstance {
         self.path.join("partial")
     }
 
-    fn blob_relative_path(blob: &ContentSha256) -> (String, String) {
-        let mut hex = blob.to_hex();
-        let child = hex.split_off(Self::SPLIT);
-        (hex, child)
+    fn quarantine_dir(&self) -> PathBuf {
+        self.path.join("quarantine")
+    }
+
+    fn blob_relative_path(blob: &ContentSha256, depth: usize) -> (PathBuf, String) {
+        let hex = blob.to_hex();
+        let parent = (0..depth)
+            .map(|i| &hex[i * Self::SPLIT..(i + 1) * Self::SPLIT])
+            .collect();
+        (parent, hex[depth * Self::SPLIT..].to_owned())
     }
 
     fn blob_parent(&self, blob: &ContentSha256) -> PathBuf {
-        let (parent, _child) = Self::blob_relative_path(blob);
+        let (parent, _child) = Self::blob_relative_path(blob, self.depth);
         self.blob_dir().join(&parent)
     }
 
     fn partial_path(&self, blob: &ContentSha256) -> PathBuf {
-        let (parent, child) = Self::blob_relative_path(blob);
+        let (parent, child) = Self::blob_relative_path(blob, self.depth);
         self.partial_dir().join(&parent).join(&child)
     }
 
     fn partial_parent(&self, blob: &ContentSha256) -> PathBuf {
-        let (parent, _child) = Self::blob_relative_path(blob);
+        let (parent, _child) = Self::blob_relative_path(blob, self.depth);
         self.partial_dir().join(&parent)
     }
+
+    // Each blob laid out with `depth` levels of directories, with its path. Entries which do not
+    // fit that layout, such as those of another layout part way through `reindex`, are skipped.
+    fn walk_blobs(&self, depth: usize) -> Result<Vec<(ContentSha256, PathBuf)>> {
+        let mut blobs = vec![];
+        let blob_dir = self.blob_dir();
+        if blob_dir.is_dir() {
+            Self::walk_blobs_in(&blob_dir, String::new(), depth, &mut blobs)?;
+        }
+        Ok(blobs)
+    }
+
+    fn walk_blobs_in(
+        dir: &Path,
+        prefix: String,
+        depth: usize,
+        blobs: &mut Vec<(ContentSha256, PathBuf)>,
+    ) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let is_dir = entry.file_type()?.is_dir();
+            let name = entry.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow!("unexpected file {}", entry.path().display()))?;
+            if depth > 0 {
+                if is_dir && name.len() == Self::SPLIT {
+                    Self::walk_blobs_in(&entry.path(), prefix.clone() + name, depth - 1, blobs)?;
+                }
+            } else if !is_dir {
+                let blob = (prefix.clone() + name)
+                    .parse()
+                    .map_err(|_| anyhow!("unexpected file {}", entry.path().display()))?;
+                blobs.push((blob, entry.path()));
+            }
+        }
+        Ok(())
+    }
+
+    // Move each blob from the layout with `from_depth` levels of directories to the one with
+    // `to_depth`, and then record `to_depth` in the layout file. Blobs are renamed one at a time,
+    // so each is always in one place or the other, and an interrupted reindex is finished by
+    // running it again. Until then, the blobs already moved are missing as far as readers using
+    // the old layout are concerned. Stores wait for the reindex to finish, and then fail rather
+    // than put blobs in the old layout. Returns the number of blobs moved.
+    //
+    // `from_depth` must be the recorded depth unless `recover` is set, as when the record was
+    // lost. Either way, the layout file is only written once every file in the blob directory
+    // fits the layout with `to_depth`, so that a wrong `from_depth` cannot strand the blobs.
+    pub fn reindex(&mut self, from_depth: usize, to_depth: usize, recover: bool) -> Result<u64> {
+        Self::check_depth(from_depth)?;
+        Self::check_depth(to_depth)?;
+        ensure!(
+            recover || from_depth == self.depth,
+            "{} is recorded as having depth {}, not {}",
+            self.describe(),
+            self.depth,
+            from_depth
+        );
+        let _lock = self.lock(libc::LOCK_EX)?;
+        let blob_dir = self.blob_dir();
+        let mut moved = 0;
+        if from_depth != to_depth {
+            for (blob, old_path) in self.walk_blobs(from_depth)? {
+                let (parent, child) = Self::blob_relative_path(&blob, to_depth);
+                let new_parent = blob_dir.join(&parent);
+                fs::create_dir_all(&new_parent)?;
+                fs::rename(&old_path, new_parent.join(&child))
+                    .with_context(|| format!("moving blob {}", blob))?;
+                moved += 1;
+                // Directories of the old layout are removed once emptied.
+                let mut dir = old_path.parent().unwrap();
+                while dir != blob_dir && fs::remove_dir(dir).is_ok() {
+                    dir = dir.parent().unwrap();
+                }
+            }
+        }
+        let (laid_out, total) = (self.walk_blobs(to_depth)?.len(), count_files(&blob_dir)?);
+        ensure!(
+            laid_out == total,
+            "{} of the {} files in {} do not fit the layout with depth {}, so it is not recorded",
+            total - laid_out,
+            total,
+            blob_dir.display(),
+            to_depth
+        );
+        let layout_path = self.path.join(Self::LAYOUT_FILE);
+        let temporary = self.path.join(format!("{}.tmp", Self::LAYOUT_FILE));
+        fs::write(&temporary, format!("depth {}\n", to_depth))?;
+        fs::rename(&temporary, &layout_path)
+            .with_context(|| format!("writing {}", layout_path.display()))?;
+        self.depth = to_depth;
+        Ok(moved)
+    }
 }
 
 impl Substance for FilesystemSubstance {
     fn blob_path(&self, blob: &ContentSha256) -> PathBuf {
-        let (parent, child) = Self::blob_relative_path(blob);
+        let (parent, child) = Self::blob_relative_path(blob, self.depth);
         self.blob_dir().join(&parent).join(&child)
     }
 
-    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<()> {
+    fn describe(&self) -> String {
+        format!("filesystem:{}", self.path.display())
+    }
+
+    fn verify_by_default(&self) -> bool {
+        false
+    }
+
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Stored> {
+        let _lock = self.lock(libc::LOCK_SH)?;
+        let depth = Self::read_depth(&self.path)?;
+        ensure!(
+            depth == self.depth,
+            "{} has been reindexed to depth {} since it was opened",
+            self.describe(),
+            depth
+        );
+
         if self.have_blob(blob) {
-            return Ok(());
+            // So that 'gc --older-than' spares it as it would a blob just written, until the
+            // snapshot storing it again is committed.
+            let blob_path = self.blob_path(blob);
+            touch(&blob_path).with_context(|| format!("touching {}", blob_path.display()))?;
+            return Ok(Stored::AlreadyPresent);
         }
 
         let blob_path = self.blob_path(blob);
         let partial_path = self.partial_path(blob);
 
-        assert!(src.is_file());
+        ensure!(src.is_file(), "{} is not a file", src.display());
         let mut source_file = OpenOptions::new().read(true).open(src)?;
 
         let partial_parent = self.partial_parent(blob);
         if partial_parent.exists() {
             assert!(partial_parent.is_dir());
         } else {
-            fs::create_dir(&partial_parent)?;
+            fs::create_dir_all(&partial_parent)?;
         }
 
         let mut partial_file = OpenOptions::new()
@@ -96,29 +404,49 @@ impl Substance for FilesystemSubstance {
             .write(true)
             .open(&partial_path)?;
 
-        // TODO
-        // - https://github.com/rust-lang/rust/blob/55ccbd090d96ec3bb28dbcb383e65bbfa3c293ff/library/std/src/sys/unix/fs.rs#L1277
-        // - linux:
-        //      - copy_file_range
-        //      - https://lwn.net/Articles/846403/, https://lwn.net/Articles/846670/
-        //      - https://github.com/rust-lang/rust/commit/4ddedd521418d67e845ecb43dc02c09b0af53022
-        // - macos:
-        //      - fclonefileat and fcopyfile
-        io::copy(&mut source_file, &mut partial_file)?;
+        // A partial blob left behind would make every later attempt fail on `create_new`.
+        let result = (|| {
+            copy_file(&mut source_file, &mut partial_file, self.reflink)?;
 
-        partial_file.set_permissions(Permissions::from_mode(0o444))?;
+            partial_file.set_permissions(Permissions::from_mode(0o444))?;
 
-        check_sha256sum(blob, &partial_path)?;
+            check_sha256sum(blob, &partial_path)?;
 
-        let blob_parent = self.blob_parent(blob);
-        if blob_parent.exists() {
-            assert!(blob_parent.is_dir());
-        } else {
-            fs::create_dir(blob_parent)?;
+            let blob_parent = self.blob_parent(blob);
+            if blob_parent.exists() {
+                assert!(blob_parent.is_dir());
+            } else {
+                fs::create_dir_all(blob_parent)?;
+            }
+
+            fs::rename(&partial_path, &blob_path)?;
+            Ok(Stored::New)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&partial_path);
         }
+        result
+    }
 
-        fs::rename(&partial_path, &blob_path)?;
-        Ok(())
+    fn list_blobs(&self) -> Result<Vec<ContentSha256>> {
+        let mut blobs = self
+            .walk_blobs(self.depth)?
+            .into_iter()
+            .map(|(blob, _)| blob)
+            .collect::<Vec<_>>();
+        blobs.sort();
+        Ok(blobs)
+    }
+
+    fn quarantine_blob(&self, blob: &ContentSha256) -> Result<PathBuf> {
+        let quarantine_dir = self.quarantine_dir();
+        if !quarantine_dir.is_dir() {
+            fs::create_dir(&quarantine_dir)?;
+        }
+        let quarantine_path = quarantine_dir.join(blob.to_hex());
+        fs::rename(self.blob_path(blob), &quarantine_path)
+            .with_context(|| format!("quarantining blob {}", blob))?;
+        Ok(quarantine_path)
     }
 }
 
@@ -139,10 +467,80 @@ impl Substance for MockSubstance {
         self.token_blob_path.clone()
     }
 
-    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<()> {
+    fn describe(&self) -> String {
+        format!("mock:{}", self.token_blob_path.display())
+    }
+
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Stored> {
         check_sha256sum(blob, src)?;
-        Ok(())
+        Ok(Stored::New)
+    }
+}
+
+// TODO
+// - https://github.com/rust-lang/rust/blob/55ccbd090d96ec3bb28dbcb383e65bbfa3c293ff/library/std/src/sys/unix/fs.rs#L1277
+// - linux:
+//      - copy_file_range
+//      - https://lwn.net/Articles/846403/, https://lwn.net/Articles/846670/
+//      - https://github.com/rust-lang/rust/commit/4ddedd521418d67e845ecb43dc02c09b0af53022
+// - macos:
+//      - fclonefileat and fcopyfile
+fn copy_file(src: &mut File, dst: &mut File, reflink: Reflink) -> Result<()> {
+    if reflink != Reflink::Never {
+        match clone_file(src, dst) {
+            Ok(()) => return Ok(()),
+            Err(err) if reflink == Reflink::Always => {
+                return Err(Error::from(err).context("reflinking blob"))
+            }
+            Err(err) => log::debug!("reflink failed, copying instead: {}", err),
+        }
     }
+    io::copy(src, dst)?;
+    Ok(())
+}
+
+// The number of files other than directories below `dir`, if it exists.
+fn count_files(dir: &Path) -> Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            count += count_files(&entry.path())?;
+        } else {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Set the modification time of the file at `path` to now.
+fn touch(path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    if unsafe { libc::futimens(file.as_raw_fd(), std::ptr::null()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn clone_file(src: &File, dst: &File) -> io::Result<()> {
+    // _IOW(0x94, 9, int) from linux/fs.h.
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE, src.as_raw_fd()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_src: &File, _dst: &File) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "reflinks are only supported on Linux",
+    ))
 }
 
 pub fn sha256sum_coreutils(path: &Path) -> Result<ContentSha256> {
@@ -179,7 +577,245 @@ pub fn sha256sum(path: &Path) -> Result<ContentSha256> {
 }
 
 fn check_sha256sum(expected: &ContentSha256, path: &Path) -> Result<()> {
-    let observerd = sha256sum(path)?;
-    assert_eq!(expected, &observerd);
+    let observed = sha256sum(path)?;
+    ensure!(
+        expected == &observed,
+        "content of {} has hash {}, expected {}",
+        path.display(),
+        observed,
+        expected
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRepo;
+
+    #[test]
+    fn store_twice() {
+        let repo = TestRepo::new();
+        let (shadow, src) = repo.write_subject_file("a", b"hello\n");
+        let hash = shadow.content_hash();
+        assert!(!repo.substance.have_blob(hash));
+        assert_eq!(repo.substance.store(hash, &src).unwrap(), Stored::New);
+        assert!(repo.substance.have_blob(hash));
+        assert_eq!(
+            repo.substance.store(hash, &src).unwrap(),
+            Stored::AlreadyPresent
+        );
+    }
+
+    #[test]
+    fn store_again_touches() {
+        let repo = TestRepo::new();
+        let (shadow, src) = repo.write_subject_file("a", b"hello\n");
+        let hash = shadow.content_hash();
+        repo.substance.store(hash, &src).unwrap();
+        assert!(Command::new("touch")
+            .arg("-d")
+            .arg("@0")
+            .arg(repo.substance.blob_path(hash))
+            .status()
+            .unwrap()
+            .success());
+        assert_eq!(
+            repo.substance.modified(hash).unwrap(),
+            Some(SystemTime::UNIX_EPOCH)
+        );
+        assert_eq!(
+            repo.substance.store(hash, &src).unwrap(),
+            Stored::AlreadyPresent
+        );
+        assert!(repo.substance.modified(hash).unwrap().unwrap() > SystemTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn store_after_mismatch() {
+        let repo = TestRepo::new();
+        let (shadow, src) = repo.write_subject_file("a", b"hello\n");
+        let hash = shadow.content_hash();
+        fs::write(&src, b"changed\n").unwrap();
+        assert!(repo.substance.store(hash, &src).is_err());
+        assert!(!repo.substance.have_blob(hash));
+
+        fs::write(&src, b"hello\n").unwrap();
+        assert_eq!(repo.substance.store(hash, &src).unwrap(), Stored::New);
+        assert!(repo.substance.have_blob(hash));
+    }
+
+    #[test]
+    fn open() {
+        let repo = TestRepo::new();
+        let (shadow, src) = repo.write_subject_file("a", b"hello\n");
+        let hash = shadow.content_hash();
+        assert!(repo.substance.open(hash).is_err());
+        repo.substance.store(hash, &src).unwrap();
+        let mut content = vec![];
+        repo.substance
+            .open(hash)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"hello\n");
+    }
+
+    #[test]
+    fn read_at() {
+        let repo = TestRepo::new();
+        let (shadow, src) = repo.write_subject_file("a", b"hello\n");
+        let hash = shadow.content_hash();
+        repo.substance.store(hash, &src).unwrap();
+        assert_eq!(repo.substance.read_at(hash, 1, 3).unwrap(), b"ell");
+        assert_eq!(repo.substance.read_at(hash, 4, 10).unwrap(), b"o\n");
+        assert_eq!(repo.substance.read_at(hash, 10, 10).unwrap(), b"");
+    }
+
+    #[test]
+    fn list_blobs() {
+        let repo = TestRepo::new();
+        assert!(repo.substance.list_blobs().unwrap().is_empty());
+        let mut blobs = vec![];
+        for (path, content) in &[("a", b"a\n"), ("b", b"b\n")] {
+            let (shadow, src) = repo.write_subject_file(path, *content);
+            repo.substance.store(shadow.content_hash(), &src).unwrap();
+            blobs.push(shadow.content_hash().clone());
+        }
+        blobs.sort();
+        assert_eq!(repo.substance.list_blobs().unwrap(), blobs);
+
+        let blob_path = repo.substance.blob_path(&blobs[0]);
+        fs::set_permissions(&blob_path, Permissions::from_mode(0o644)).unwrap();
+        fs::write(&blob_path, b"tampered\n").unwrap();
+        assert!(repo.substance.check_blob(&blobs[0]).is_err());
+        assert!(repo.substance.check_blob(&blobs[1]).is_ok());
+    }
+
+    #[test]
+    fn check_large_sparse_blob() {
+        let repo = TestRepo::new();
+        let src = repo.subject().join("sparse");
+        // Far larger than any buffer used for hashing, but quick to hash.
+        let len = 16 << 20;
+        let file = File::create(&src).unwrap();
+        file.set_len(len).unwrap();
+        file.write_at(b"end", len - 3).unwrap();
+        let hash = sha256sum(&src).unwrap();
+        let blob_path = repo.substance.blob_path(&hash);
+        fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+        fs::rename(&src, &blob_path).unwrap();
+        assert!(repo.substance.check_blob(&hash).is_ok());
+
+        file.write_at(b"middle", len / 2).unwrap();
+        assert!(repo.substance.check_blob(&hash).is_err());
+    }
+
+    #[test]
+    fn reindex() {
+        let repo = TestRepo::new();
+        let mut blobs = vec![];
+        for name in &["a", "b", "c"] {
+            let (shadow, src) = repo.write_subject_file(name, name.as_bytes());
+            repo.substance.store(shadow.content_hash(), &src).unwrap();
+            blobs.push((shadow.content_hash().clone(), name.as_bytes()));
+        }
+        let listed = repo.substance.list_blobs().unwrap();
+        let new_path = |blob: &ContentSha256| {
+            let hex = blob.to_hex();
+            repo.substance
+                .blob_dir()
+                .join(&hex[..3])
+                .join(&hex[3..6])
+                .join(&hex[6..])
+        };
+
+        // As if a reindex had been interrupted after moving one blob.
+        let moved = new_path(&blobs[0].0);
+        fs::create_dir_all(moved.parent().unwrap()).unwrap();
+        fs::rename(repo.substance.blob_path(&blobs[0].0), &moved).unwrap();
+
+        let mut substance = FilesystemSubstance::new(&repo.substance.path).unwrap();
+        assert_eq!(substance.depth(), 1);
+        // Not the recorded depth.
+        assert!(substance.reindex(3, 2, false).is_err());
+        // Nothing is at depth 3, and the layout is left alone rather than strand the blobs.
+        assert!(substance.reindex(3, 2, true).is_err());
+        assert_eq!(FilesystemSubstance::read_depth(&substance.path).unwrap(), 1);
+        // Neither is the blob already moved at depth 1.
+        assert!(substance.reindex(1, 1, false).is_err());
+        assert_eq!(substance.reindex(1, 2, false).unwrap(), 2);
+        // Done already, so there is nothing left to move.
+        assert_eq!(substance.reindex(1, 2, true).unwrap(), 0);
+
+        let substance = FilesystemSubstance::new(&repo.substance.path).unwrap();
+        assert_eq!(substance.depth(), 2);
+        assert_eq!(substance.list_blobs().unwrap(), listed);
+        for (blob, content) in &blobs {
+            let expected = new_path(blob);
+            assert_eq!(substance.blob_path(blob), expected);
+            assert_eq!(fs::read(&expected).unwrap(), *content);
+            substance.check_blob(blob).unwrap();
+        }
+        // The old layout's directories are gone.
+        assert!(!repo.substance.blob_path(&blobs[0].0).exists());
+        assert!(FilesystemSubstance::new(&repo.substance.path)
+            .unwrap()
+            .reindex(2, 22, false)
+            .is_err());
+
+        // The substance opened before the reindex would store to the old layout.
+        let (shadow, src) = repo.write_subject_file("d", b"d");
+        assert!(repo.substance.store(shadow.content_hash(), &src).is_err());
+        assert_eq!(
+            substance.store(shadow.content_hash(), &src).unwrap(),
+            Stored::New
+        );
+    }
+
+    #[test]
+    fn malformed_layout() {
+        let repo = TestRepo::new();
+        fs::write(repo.substance.path.join("layout"), b"depth two\n").unwrap();
+        assert!(FilesystemSubstance::new(&repo.substance.path).is_err());
+        fs::write(repo.substance.path.join("layout"), b"depth 2\n").unwrap();
+        assert_eq!(
+            FilesystemSubstance::new(&repo.substance.path)
+                .unwrap()
+                .depth(),
+            2
+        );
+    }
+
+    #[test]
+    fn reflink() {
+        let repo = TestRepo::new();
+        let mut substance = FilesystemSubstance::new(&repo.substance.path).unwrap();
+        let (shadow, src) = repo.write_subject_file("a", b"hello\n");
+        let hash = shadow.content_hash();
+
+        // Whether this succeeds depends on the filesystem of the temporary directory, but either
+        // way a failure must not leave anything behind.
+        substance.set_reflink(Reflink::Always);
+        if substance.store(hash, &src).is_err() {
+            assert!(!substance.have_blob(hash));
+            substance.set_reflink(Reflink::Auto);
+            assert_eq!(substance.store(hash, &src).unwrap(), Stored::New);
+        }
+        assert_eq!(fs::read(substance.blob_path(hash)).unwrap(), b"hello\n");
+
+        let (shadow, src) = repo.write_subject_file("b", b"world\n");
+        substance.set_reflink(Reflink::Never);
+        substance.store(shadow.content_hash(), &src).unwrap();
+        substance.check_blob(shadow.content_hash()).unwrap();
+    }
+
+    #[test]
+    fn describe() {
+        let repo = TestRepo::new();
+        let description = repo.substance.describe();
+        assert!(description.starts_with("filesystem:"));
+        let path = repo.dir.path().join("substance");
+        assert!(description.contains(path.to_str().unwrap()));
+    }
+}
@@ -0,0 +1,125 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use git2::{FileMode, Oid, Repository, Signature, Time};
+
+use crate::{sha256sum, Database, FilesystemSubstance, Shadow, Substance};
+
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = env::temp_dir().join(format!("keep-test-{:016x}", rand::random::<u64>()));
+        fs::create_dir(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub struct TestRepo {
+    pub dir: TempDir,
+    pub db: Database,
+    pub substance: FilesystemSubstance,
+}
+
+impl TestRepo {
+    pub fn new() -> Self {
+        let dir = TempDir::new();
+        let db = Database::new(Repository::init_bare(dir.path().join("git")).unwrap());
+        let substance_dir = dir.path().join("substance");
+        for sub in &["blobs", "partial"] {
+            fs::create_dir_all(substance_dir.join(sub)).unwrap();
+        }
+        fs::create_dir(dir.path().join("subject")).unwrap();
+        let substance = FilesystemSubstance::new(substance_dir).unwrap();
+        Self { dir, db, substance }
+    }
+
+    pub fn subject(&self) -> PathBuf {
+        self.dir.path().join("subject")
+    }
+
+    pub fn empty_tree(&self) -> Oid {
+        self.db.empty_tree().unwrap()
+    }
+
+    // Write `content` to `path` under the subject, store it in the substance, and add its
+    // shadow to `tree`.
+    pub fn add_file(&self, tree: Oid, path: &str, content: &[u8]) -> Oid {
+        let (shadow, src) = self.write_subject_file(path, content);
+        self.substance.store(shadow.content_hash(), &src).unwrap();
+        self.add_shadow(tree, path, &shadow)
+    }
+
+    // Like `add_file`, but without storing the content in the substance.
+    pub fn add_unstored_file(&self, tree: Oid, path: &str, content: &[u8]) -> Oid {
+        let (shadow, _src) = self.write_subject_file(path, content);
+        self.add_shadow(tree, path, &shadow)
+    }
+
+    pub fn add_shadow(&self, tree: Oid, path: &str, shadow: &Shadow) -> Oid {
+        let oid = self.db.repository().blob(&shadow.to_bytes()).unwrap();
+        self.add_object(tree, path, FileMode::Blob, oid)
+    }
+
+    pub fn add_link(&self, tree: Oid, path: &str, target: &str) -> Oid {
+        let oid = self.db.repository().blob(target.as_bytes()).unwrap();
+        self.add_object(tree, path, FileMode::Link, oid)
+    }
+
+    pub fn add_object(&self, tree: Oid, path: &str, mode: FileMode, oid: Oid) -> Oid {
+        self.db
+            .append(tree, &path.parse().unwrap(), mode, oid, true)
+            .unwrap()
+    }
+
+    pub fn write_subject_file(&self, path: &str, content: &[u8]) -> (Shadow, PathBuf) {
+        let src = self.subject().join(path);
+        fs::create_dir_all(src.parent().unwrap()).unwrap();
+        fs::write(&src, content).unwrap();
+        let shadow = Shadow::new(sha256sum(&src).unwrap(), Some(content.len() as u64));
+        (shadow, src)
+    }
+
+    // Delete a loose object, leaving any references to it dangling.
+    pub fn remove_object(&self, oid: Oid) {
+        let hex = oid.to_string();
+        let path = self
+            .dir
+            .path()
+            .join("git/objects")
+            .join(&hex[..2])
+            .join(&hex[2..]);
+        fs::remove_file(path).unwrap();
+    }
+
+    pub fn commit(&self, tree: Oid, parents: &[Oid]) -> Oid {
+        let repository = self.db.repository();
+        let signature = Signature::new("x", "x@x", &Time::new(0, 0)).unwrap();
+        let tree = repository.find_tree(tree).unwrap();
+        let parents = parents
+            .iter()
+            .map(|oid| repository.find_commit(*oid).unwrap())
+            .collect::<Vec<_>>();
+        repository
+            .commit(
+                None,
+                &signature,
+                &signature,
+                "x",
+                &tree,
+                &parents.iter().collect::<Vec<_>>(),
+            )
+            .unwrap()
+    }
+}
@@ -0,0 +1,163 @@
+use std::str::FromStr;
+
+use git2::Time;
+use thiserror::Error;
+
+// A point in time with a UTC offset, at the one-second resolution of git signatures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub offset_minutes: i32,
+}
+
+impl Timestamp {
+    pub fn to_git_time(&self) -> Time {
+        Time::new(self.seconds, self.offset_minutes)
+    }
+}
+
+// Parses RFC 3339 timestamps such as "2021-10-20T12:34:56+02:00". Fractional seconds are
+// accepted and truncated.
+impl FromStr for Timestamp {
+    type Err = TimestampError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let b = s.as_bytes();
+        if b.len() < 20
+            || b[4] != b'-'
+            || b[7] != b'-'
+            || !matches!(b[10], b'T' | b't' | b' ')
+            || b[13] != b':'
+            || b[16] != b':'
+        {
+            return Err(TimestampError::Malformed);
+        }
+        let year = digits(&b[0..4])?;
+        let month = digits(&b[5..7])?;
+        let day = digits(&b[8..10])?;
+        let hour = digits(&b[11..13])?;
+        let minute = digits(&b[14..16])?;
+        let second = digits(&b[17..19])?;
+
+        let mut rest = &b[19..];
+        if rest.first() == Some(&b'.') {
+            let n = rest[1..].iter().take_while(|c| c.is_ascii_digit()).count();
+            if n == 0 {
+                return Err(TimestampError::Malformed);
+            }
+            rest = &rest[1 + n..];
+        }
+        let offset_minutes = match rest {
+            b"Z" | b"z" => 0,
+            [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] => {
+                let offset_hour = digits(&[*h1, *h2])?;
+                let offset_minute = digits(&[*m1, *m2])?;
+                if offset_hour > 23 || offset_minute > 59 {
+                    return Err(TimestampError::OutOfRange);
+                }
+                let offset = (offset_hour * 60 + offset_minute) as i32;
+                if *sign == b'-' {
+                    -offset
+                } else {
+                    offset
+                }
+            }
+            _ => return Err(TimestampError::Malformed),
+        };
+
+        if !(1..=12).contains(&month)
+            || !(1..=days_in_month(year, month)).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return Err(TimestampError::OutOfRange);
+        }
+
+        let days = days_from_civil(year.into(), month, day);
+        let seconds = days * 86400 + i64::from(hour * 3600 + minute * 60 + second)
+            - i64::from(offset_minutes) * 60;
+        Ok(Self {
+            seconds,
+            offset_minutes,
+        })
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TimestampError {
+    #[error("malformed timestamp (expected RFC 3339, e.g. 2021-10-20T12:34:56Z)")]
+    Malformed,
+    #[error("timestamp field out of range")]
+    OutOfRange,
+}
+
+fn digits(b: &[u8]) -> Result<u32, TimestampError> {
+    b.iter().try_fold(0, |acc, c| {
+        if c.is_ascii_digit() {
+            Ok(acc * 10 + u32::from(c - b'0'))
+        } else {
+            Err(TimestampError::Malformed)
+        }
+    })
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar. See
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((i64::from(month) + 9) % 12) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<(i64, i32), TimestampError> {
+        s.parse::<Timestamp>()
+            .map(|timestamp| (timestamp.seconds, timestamp.offset_minutes))
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(parse("1970-01-01T00:00:00Z"), Ok((0, 0)));
+        assert_eq!(parse("2021-10-20T12:34:56Z"), Ok((1634733296, 0)));
+        assert_eq!(
+            parse("2021-10-20t14:34:56.789+02:00"),
+            Ok((1634733296, 120))
+        );
+        assert_eq!(parse("2021-10-20 07:04:56-05:30"), Ok((1634733296, -330)));
+        assert_eq!(parse("2000-02-29T00:00:00Z"), Ok((951782400, 0)));
+        assert_eq!(parse("1969-12-31T23:59:59Z"), Ok((-1, 0)));
+    }
+
+    #[test]
+    fn invalid() {
+        use TimestampError::*;
+        for (s, err) in [
+            ("2021-10-20", Malformed),
+            ("2021-10-20T12:34:56", Malformed),
+            ("2021-10-20T12:34:56+0200", Malformed),
+            ("2021-10-20T12:34:56.Z", Malformed),
+            ("2021-1x-20T12:34:56Z", Malformed),
+            ("2021-13-20T12:34:56Z", OutOfRange),
+            ("2021-02-29T12:34:56Z", OutOfRange),
+            ("2021-10-20T24:00:00Z", OutOfRange),
+        ] {
+            assert_eq!(parse(s), Err(err), "{}", s);
+        }
+    }
+}